@@ -6,6 +6,8 @@ mod process;
 use wasm_bindgen::prelude::*;
 use js_sys::Function;
 use std::iter;
+use std::collections::HashMap;
+use rayon::prelude::*;
 use wgpu::{Buffer, Device, BufferUsages, Extent3d, SamplerBindingType};
 use wgpu::util::DeviceExt;
 use winit::{
@@ -16,20 +18,34 @@ use winit::{
 };
 
 use cgmath::{InnerSpace, Vector3, prelude::*};
-use wasm::state::StateJS;
-use process::glb::{load_glb};
+use wasm::state::{StateJS, Fog};
+use process::glb::{load_glb, GLBScene};
 use process::pipeline::{create_pipeline};
-use process::bvh::{BVHNode, BVH};
+use process::refit::RefitPass;
+use process::grid;
+use process::sdf::{Sdf, SignMode};
+use process::bvh_ml::{self, Mlp};
+use process::bvh_layout::{self, NodeLayout};
+use process::vertex_order::{self, VertexOrder, reorder_vertices};
+use process::bvh::{BVHNode, BVH, TwoLevelBVH, BVHBuilder, BVHConfig, Rebuilt, source_hash};
+use primitives::aabb::{AABB, Bounded};
+use process::subdivision::{Subdivision, SubdivisionScheme, SubdivisionMode, SCHEME_LOOP, MODE_UNIFORM, MODE_ADAPTIVE};
+use process::displacement::DisplacementMap;
 use primitives::texture::Texture;
 use primitives::material::Material;
+use primitives::medium::{Media, Medium};
+use primitives::sdf_shape::{SdfGrids, SdfNode, SdfShape};
 use primitives::sphere::Sphere;
-use primitives::triangle::{Triangle, TriangleCPU};
+use primitives::triangle::{Triangle, TriangleCPU, hit_watertight};
+use primitives::instance::InstanceCPU;
+use primitives::vertex::Vertex;
+use primitives::tri_mesh::TriMesh;
 use primitives::lights::QuadLight;
 use primitives::pixel_buffer::PixelBuffer;
-use primitives::scene::{Scene, RenderConfig, SceneObject};
+use primitives::scene::{Scene, RenderConfig, SceneObject, SceneObjectCPU};
 use primitives::ray::{Ray, RayBuffer};
 use primitives::hit::HitRec;
-use primitives::camera::{Camera, CameraUniform, CameraController};
+use primitives::camera::{Camera, CameraUniform, CameraController, Projection, PROJECTION_ORTHOGRAPHIC, PROJECTION_EQUIRECTANGULAR, PROJECTION_CUBEMAP, PROJECTION_FISHEYE};
 
 
 #[rustfmt::skip]
@@ -41,10 +57,35 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
 );
 
 pub const RENDER_SIZE: [u32; 2] = [1280, 720];
+// GPU refits between CPU refits that check whether the tree needs rebuilding
+const REBUILD_CHECK_INTERVAL: u32 = 30;
+// Children per BVH node on the GPU, scenes with skinned or morphed meshes stay binary for the
+// refit pass
+const BVH_WIDTH: usize = 8;
+// Vertex storage after each mesh's BVH is built, keeps the vertex fetches of a leaf close together
+const VERTEX_ORDER: VertexOrder = VertexOrder::LeafOrder;
+
+// Where rendered frames go
+enum Target {
+    // Presented on the window's surface
+    Window { window: Window, surface: wgpu::Surface },
+    // Kept in a texture of the configured size and format, see `State::read_frame`
+    Offscreen(wgpu::Texture),
+}
+
+// Files a scene is built from besides the page state. `bvh_cache` holds bottom level BVHs saved by
+// `bvh_cache`, meshes it doesn't match are built. `density_grid` is a KGRD or VOL file shaping
+// the fog and `sdf_grid` one of distances, as `bake_sdfs` writes.
+pub struct SceneFiles {
+    pub glb: Vec<u8>,
+    pub bvh_cache: Option<Vec<u8>>,
+    pub density_grid: Option<Vec<u8>>,
+    pub sdf_grid: Option<Vec<u8>>,
+}
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 pub struct State {
-    surface: wgpu::Surface,
+    target: Target,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
@@ -57,7 +98,6 @@ pub struct State {
     camera_uniform: CameraUniform,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
-    window: Window,
     direct_diffuse_pipeline: wgpu::ComputePipeline,
     direct_diffuse_bind_group: wgpu::BindGroup,
     indirect_diffuse_pipeline: wgpu::ComputePipeline,
@@ -68,6 +108,10 @@ pub struct State {
     indirect_specular_bind_group: wgpu::BindGroup,
     sss_pipeline: wgpu::ComputePipeline,
     sss_bind_group: wgpu::BindGroup,
+    volume_pipeline: wgpu::ComputePipeline,
+    volume_bind_group: wgpu::BindGroup,
+    // The volume pass only runs while there is a medium to march through
+    media: Media,
     camera_ray_bind_group: wgpu::BindGroup,
     camera_ray_compute_pipeline: wgpu::ComputePipeline,
     camera_ray_uniform: RayBuffer,
@@ -78,10 +122,39 @@ pub struct State {
     scene_buffer: wgpu::Buffer,
     render_config: RenderConfig,
     clear_buffer: bool,
+    bvh: TwoLevelBVH,
+    bvh_buffer: wgpu::Buffer,
+    instance_buffer: wgpu::Buffer,
+    scene_objects: Vec<SceneObjectCPU>,
+    object_buffer: wgpu::Buffer,
+    vertex_buffer: wgpu::Buffer,
+    // CPU copy of the vertex buffer, for picking
+    scene_vertices: Vec<Vertex>,
+    // Scene vertex, in glb order, that each vertex of the buffer was copied from
+    vertex_sources: Vec<u32>,
+    // Only for binary trees, wide trees are refit on the CPU
+    refit_pass: Option<RefitPass>,
+    // GPU refits since the tree quality was last checked on the CPU
+    refit_count: u32,
+    // Posed again whenever the timeline moves
+    glb: GLBScene,
+    // Buffer vertices at rest by source index, in glb order
+    source_vertices: Vec<Vertex>,
+    // First source index of every glb mesh that kept its loaded vertices, refined meshes keep
+    // their rest shape when animated
+    mesh_sources: Vec<Option<u32>>,
+    // Timeline in seconds, advanced on every update while playing
+    time: f32,
+    playing: bool,
+    // Clock reading of the last update while playing
+    last_tick: Option<f64>,
+    // Page state applied on the last update
+    state_js: StateJS,
 }
 
 impl State {
-    async fn new(window: Window) -> Self {
+    // `settings` is the page state on load, for what can't change afterwards
+    async fn new(window: Window, settings: &StateJS, files: SceneFiles) -> Self {
 
         let size = window.inner_size();
 
@@ -100,27 +173,7 @@ impl State {
             })
             .await
             .unwrap();
-
-        // Log device and backend
-        let info = adapter.get_info();
-        println!("{:#?}", info);
-        log::warn!("{:#?}", info);
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::empty(),
-                    limits: if cfg!(target_arch = "wasm32") {
-                        wgpu::Limits::downlevel_webgl2_defaults()
-                    } else {
-                        wgpu::Limits::default()
-                    },
-                },
-                None, 
-            )
-            .await
-            .unwrap();
+        let (device, queue) = request_device(&adapter).await;
 
         let surface_caps = surface.get_capabilities(&adapter);
 
@@ -142,19 +195,76 @@ impl State {
         };
 
         surface.configure(&device, &config);
+        Self::with_target(Target::Window { window, surface }, device, queue, config, settings, files)
+    }
 
+    // State rendering into a texture of `size` rather than a window, for rendering from the
+    // command line. Fails without a GPU adapter.
+    pub async fn offscreen(size: [u32; 2], settings: &StateJS, files: SceneFiles) -> anyhow::Result<Self> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await
+            .ok_or_else(|| anyhow::anyhow!("no GPU adapter to render with"))?;
+        let (device, queue) = request_device(&adapter).await;
+
+        // Stored as the window would show it, sRGB encoded
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width: size[0],
+            height: size[1],
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![],
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Offscreen Texture"),
+            size: Extent3d { width: size[0], height: size[1], depth_or_array_layers: 1 },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: config.format,
+            usage: config.usage,
+            view_formats: &[],
+        });
+        Ok(Self::with_target(Target::Offscreen(texture), device, queue, config, settings, files))
+    }
+
+    fn with_target(target: Target, device: wgpu::Device, queue: wgpu::Queue, config: wgpu::SurfaceConfiguration, settings: &StateJS, files: SceneFiles) -> Self {
+        let size = PhysicalSize::new(config.width, config.height);
+        let SceneFiles { glb: glb_bytes, bvh_cache, density_grid, sdf_grid } = files;
 
         // SCENE SETUP
         //
         //
         //
-        let camera = Camera {
-            origin: (0.0, 4.0, 6.0).into(),
-            focus: (0.0, 0.0, 0.0).into(),
-            aperture: 0.0,
-            fovy: 50.0,
-            aspect: (config.width as f32 / config.height as f32).into(),
+        let glb = load_glb(&glb_bytes);
+
+        // The first camera of the glb, if it has one, sets up the view
+        let aspect = config.width as f32 / config.height as f32;
+        let mut camera = match glb.cameras().first() {
+            Some(glb_camera) => glb_camera.to_camera(aspect),
+            None => Camera::new(
+                (0.0, 4.0, 6.0).into(),
+                (0.0, 0.0, 0.0).into(),
+                0.0,
+                50.0,
+                aspect,
+            ),
         };
+        // An animated glb camera follows its node over the timeline
+        for (time, origin, focus) in glb.camera_keys() {
+            camera.add_key(time, origin, focus);
+        }
+        let camera = camera.with_shutter(0.0, settings.camera.shutter);
         let camera_controller = CameraController::new(0.2);
         let camera_uniform = CameraUniform::from(&camera);
         let camera_buffer = camera_uniform.to_buffer(&device);
@@ -234,73 +344,156 @@ impl State {
             1.4,
         );
 
-        let glb_bytes = include_bytes!("../assets/test.glb");
-        let glb = load_glb(glb_bytes);
-        let mut scene_triangles: Vec<TriangleCPU> = vec![];
+        let mut scene_objects: Vec<SceneObjectCPU> = vec![];
         let mut scene_materials: Vec<Material> = vec![];
-        let mut scene_vertices: Vec<[f32; 4]> = vec![];
-        let mut scene_normals: Vec<[f32; 4]> = vec![];
-
-        for mesh in glb.meshes() {
-            let offset = scene_vertices.len() as u32;        
-            for vertex in &mesh.vertices {
-                scene_vertices.push([vertex[0], vertex[1], vertex[2], 0.0]);
-            }
-            for normal in &mesh.normals {
-                scene_normals.push([normal[0], normal[1], normal[2], 0.0]);
+        let mut scene_vertices: Vec<Vertex> = vec![];
+        let mut blas: Vec<BVH> = vec![];
+        let mut object_offsets: Vec<u32> = vec![];
+        // Source vertex of every buffer vertex, counted over the meshes as the glb lists them
+        let mut vertex_sources: Vec<u32> = vec![];
+        let mut source_offset = 0;
+        let mut mesh_sources: Vec<Option<u32>> = vec![];
+        let cached_bvhs = bvh_cache.as_deref().map(unpack_bvh_cache).unwrap_or_default();
+
+        // Subdivision surfaces, refined on the CPU before the BVH is built
+        let subdivision = subdivision(&settings.subdivision);
+
+        // Displacement maps from the glb keyed by material index, baked into the refined vertices.
+        // Their trees are padded by the largest offset the map applies to stay conservative.
+        let displacement: HashMap<usize, DisplacementMap> = glb.displacements().iter().cloned().collect();
+
+        for (mesh_idx, mesh) in glb.meshes().iter().enumerate() {
+            let loaded = mesh.vertices.len();
+            let mut mesh = subdivision.apply(mesh, &camera, config.height);
+            let displaced = displacement.get(&mesh.material_index);
+            if let Some(map) = displaced {
+                mesh = map.displace(&mesh);
             }
+            mesh_sources.push(Some(source_offset).filter(|_| mesh.vertices.len() == loaded && displaced.is_none()));
+            let mesh = &mesh;
+            let offset = scene_vertices.len() as u32;        
+            let mesh_vertices: Vec<Vertex> = mesh.vertices.iter().enumerate()
+                .map(|(i, vertex)| Vertex::new(*vertex, mesh.normals.get(i).copied().unwrap_or([0.0, 1.0, 0.0])))
+                .collect();
 
             log::warn!("Mesh: {:#?}", mesh.vertices.len());
             log::warn!("Mesh: {:#?}", mesh.normals.len());
 
-            for chunk in mesh.indices.chunks(3) {
-                let tri = TriangleCPU::new(
-                    chunk[0] + offset,
-                    chunk[1] + offset,
-                    chunk[2] + offset,
-                    mesh.vertices[chunk[0] as usize],
-                    mesh.vertices[chunk[1] as usize],
-                    mesh.vertices[chunk[2] as usize],
-                    // mesh.normals[chunk[0] as usize],
-                    // mesh.normals[chunk[1] as usize],
-                    // mesh.normals[chunk[2] as usize],
-                    mesh.material_index as u32,
-                );
-                scene_triangles.push(tri);
+            // Bottom level BVH per unique mesh, built in object space
+            let mut mesh_objects = SceneObjectCPU::from_tri_mesh(mesh, offset);
+            let mut mesh_bvh = mesh_bvh(mesh, &mut mesh_objects, bvh_width(&glb), cached_bvhs.get(mesh_idx).copied());
+            if let Some(map) = displaced {
+                mesh_bvh.pad(map.bound());
             }
+            let (mesh_vertices, sources) = reorder_vertices(&mesh_vertices, &mut mesh_objects, offset, VERTEX_ORDER);
+            vertex_sources.extend(sources.iter().map(|&i| source_offset + i));
+            source_offset += mesh.vertices.len() as u32;
+            scene_vertices.extend(mesh_vertices);
+            object_offsets.push(scene_objects.len() as u32);
+            blas.push(mesh_bvh);
+            scene_objects.extend(mesh_objects);
         }
 
         for material in glb.materials() {
             scene_materials.push(*material);
         }
 
-        let mut bvh = BVH::new(&mut scene_triangles, 42069);
-        bvh.reorder_nodes();
+        // Participating media, surfaces opt in through `Material::with_medium`
+        let mut media = Media::new();
+        for &(material_idx, medium) in glb.volumes() {
+            match media.add(medium) {
+                Some(medium) => scene_materials[material_idx] = scene_materials[material_idx].with_medium(medium),
+                None => log::warn!("Media limit reached, material {} keeps no volume", material_idx),
+            }
+        }
+        match density_grid.as_deref().map(grid::load_grid) {
+            Some(Ok(grid)) => { media.add_grid(grid); },
+            Some(Err(err)) => log::warn!("Density grid not loaded: {}", err),
+            None => {},
+        }
+
+        let sphere1 = Sphere::new([0.0, 1.0, 0.0], 1.0, mat_orange);
+        let sphere2 = Sphere::new([2.0, 1.0, 0.0], 1.0, mat_chrome);
+        let sphere3 = Sphere::new([-2.0, 1.0, 0.0], 1.0, mat_white);
+        let sphere4 = Sphere::new([0.0, 1.0, 2.0], 1.0, mat_black);
+        let sphere5 = Sphere::new([0.0, 1.0, -2.0], 1.0, mat_orange);
+        let sphere6 = Sphere::new([0.0, 0.25, 0.0], 0.25, mat_gold);
+        let ground = Sphere::new([0.0, -100.0, 0.0], 100.0, mat_gray);
+        let mut scene_spheres = vec![sphere2, sphere3, sphere4, sphere5, sphere6, ground];
+        let new_sphere = Sphere::new([0.0, 2.5, 0.0], 0.5, mat_emissive);
+        scene_spheres.push(new_sphere);
+
+        // Analytic primitives share one bottom level BVH placed with an identity instance
+        let mut analytic_objects: Vec<SceneObjectCPU> = vec![];
+        for sphere in &scene_spheres {
+            scene_materials.push(sphere.material());
+            let material_idx = (scene_materials.len() - 1) as u32;
+            analytic_objects.push(SceneObjectCPU::sphere(&mut scene_vertices, sphere.center(), sphere.radius(), material_idx));
+        }
+        // analytic_objects.push(SceneObjectCPU::quad(&mut scene_vertices, [-2.0, 3.0, -2.0], [4.0, 0.0, 0.0], [0.0, 0.0, 4.0], 0));
+        // analytic_objects.push(SceneObjectCPU::disk(&mut scene_vertices, [0.0, 0.01, 0.0], [0.0, 1.0, 0.0], 3.0, 0));
+
+        // Distance field objects, a baked grid is placed where its mesh was baked
+        let mut sdf_grids = SdfGrids::new();
+        match sdf_grid.as_deref().map(grid::load_grid) {
+            Some(Ok(distances)) => {
+                let grid = sdf_grids.add(distances);
+                scene_materials.push(mat_white);
+                let material_idx = (scene_materials.len() - 1) as u32;
+                let nodes = [SdfNode::new(SdfShape::Grid { grid, offset: [0.0; 3] })];
+                analytic_objects.push(SceneObjectCPU::sdf(&mut scene_vertices, &nodes, &sdf_grids, material_idx));
+            },
+            Some(Err(err)) => log::warn!("SDF grid not loaded: {}", err),
+            None => {},
+        }
+        let sdf_texture = sdf_grids.to_texture(&device, &queue);
+
+        let mut instance_meshes = glb.instances().clone();
+        if !analytic_objects.is_empty() {
+            let analytic_config = BVHConfig::default().with_builder(BVHBuilder::BinnedSAH).with_width(bvh_width(&glb));
+            let analytic_bvh = BVH::with_config(&mut analytic_objects, &analytic_config);
+            object_offsets.push(scene_objects.len() as u32);
+            instance_meshes.push((blas.len(), cgmath::Matrix4::identity()));
+            blas.push(analytic_bvh);
+            scene_objects.extend(analytic_objects);
+        }
+
+        // Top level BVH over every mesh node placed in the scene, the glb's animation moves them
+        // over the camera shutter from `set_time`
+        let instances: Vec<InstanceCPU> = instance_meshes.iter()
+            .map(|(mesh_idx, transform)| InstanceCPU::new(*mesh_idx, *transform, &blas[*mesh_idx].bounds()))
+            .collect();
+        let bvh = TwoLevelBVH::new(blas, object_offsets, instances);
         let bvh_buffer = bvh.to_buffer(&device);   
+        let instance_buffer = bvh.instance_buffer(&device);
         
-        let scene_triangles_gpu: Vec<Triangle> = TriangleCPU::to_buffer_vec(&scene_triangles);
+        let scene_objects_gpu: Vec<SceneObject> = SceneObjectCPU::to_buffer_vec(&scene_objects);
  
-        let triangle_bytes = bytemuck::cast_slice(&scene_triangles_gpu);
-        let triangle_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Triangle Buffer"),
-            contents: &triangle_bytes,
+        let object_bytes = bytemuck::cast_slice(&scene_objects_gpu);
+        let object_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Scene Object Buffer"),
+            contents: &object_bytes,
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
 
+        // Vertices added after the meshes are stored as they were added
+        let unordered = scene_vertices.len() - vertex_sources.len();
+        vertex_sources.extend((0..unordered as u32).map(|i| source_offset + i));
+        let source_count = vertex_sources.iter().max().map_or(0, |&s| s as usize + 1);
+        let mut source_vertices = scene_vertices[..source_count].to_vec();
+        for (vertex, &source) in scene_vertices.iter().zip(&vertex_sources) {
+            source_vertices[source as usize] = *vertex;
+        }
+
         let vertex_bytes = bytemuck::cast_slice(&scene_vertices);
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Vertex Buffer"),
             contents: &vertex_bytes,
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
         });
-
-
-        let normal_bytes = bytemuck::cast_slice(&scene_normals);
-        let normal_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Normal Buffer"),
-            contents: &normal_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-        });
+        let refit_pass = Some(bvh.width())
+            .filter(|&width| width == 2)
+            .map(|_| RefitPass::new(&device, &bvh, &bvh_buffer, &object_buffer, &vertex_buffer, &instance_buffer));
 
 
         let material_bytes = bytemuck::cast_slice(&scene_materials);
@@ -311,28 +504,6 @@ impl State {
         });
 
 
-        let sphere1 = Sphere::new([0.0, 1.0, 0.0], 1.0, mat_orange);
-        let sphere2 = Sphere::new([2.0, 1.0, 0.0], 1.0, mat_chrome);
-        let sphere3 = Sphere::new([-2.0, 1.0, 0.0], 1.0, mat_white);
-        let sphere4 = Sphere::new([0.0, 1.0, 2.0], 1.0, mat_black);
-        let sphere5 = Sphere::new([0.0, 1.0, -2.0], 1.0, mat_orange);
-        let sphere6 = Sphere::new([0.0, 0.25, 0.0], 0.25, mat_gold);
-        let ground = Sphere::new([0.0, -100.0, 0.0], 100.0, mat_gray);
-        let mut scene_spheres = vec![sphere2, sphere3, sphere4, sphere5, sphere6, ground];
-        let mut scene_objects = SceneObject::from_tri_vec(&scene_triangles_gpu);
-
-        let new_sphere = Sphere::new([0.0, 2.5, 0.0], 0.5, mat_emissive);
-        scene_spheres.push(new_sphere);
-        SceneObject::add(&mut scene_objects, Some(&[new_sphere]), None);
-
-        let sphere_bytes = bytemuck::cast_slice(&scene_spheres);
-        let sphere_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Sphere Buffer"),
-            contents: &sphere_bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
-        });
-        
-
         let render_config = RenderConfig::new(
             size.into(), // pixel dimensions
             4, // ray depth
@@ -342,7 +513,10 @@ impl State {
         let sky_bytes = include_bytes!("../assets/sky7.png");
         let sky_texture = Texture::from_bytes(&device, &queue, sky_bytes, "sky.png").unwrap();
 
-        let scene = Scene::from(render_config, camera_uniform);
+        let density_texture = media.to_texture(&device, &queue);
+
+        let mut scene = Scene::from(render_config, camera_uniform);
+        scene.media = media.to_uniform();
         let scene_buffer = scene.to_buffer(&device);
 
         let camera_bind_group_layout =
@@ -376,7 +550,12 @@ impl State {
         //
         //        
         let shader_structs = include_str!("./shaders/structs.wgsl");
-        let shader_functions = include_str!("./shaders/functions.wgsl");
+        let bvh_traversal = if bvh.width() > 2 {
+            include_str!("./shaders/bvh_wide.wgsl")
+        } else {
+            include_str!("./shaders/bvh_binary.wgsl")
+        };
+        let shader_functions = format!("{}\n{}\n{}", include_str!("./shaders/functions.wgsl"), include_str!("./shaders/motion.wgsl"), bvh_traversal);
         let traversal_buffers = include_str!("./shaders/traversal_buffers.wgsl");
         let ggx = include_str!("./shaders/ggx.wgsl");
         let accumulation_array = PixelBuffer::new([size.width, size.height]);
@@ -487,12 +666,15 @@ impl State {
             &camera_ray_buffer,
             &bvh_buffer,
             &material_buffer,
-            &triangle_buffer,
+            &object_buffer,
             &vertex_buffer,
-            &normal_buffer,
+            &instance_buffer,
             &light_buffer,
             &sky_texture.view,
             &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
             &direct_diffuse_view,
             None,
         );
@@ -533,12 +715,15 @@ impl State {
             &camera_ray_buffer,
             &bvh_buffer,
             &material_buffer,
-            &triangle_buffer,
+            &object_buffer,
             &vertex_buffer,
-            &normal_buffer,
+            &instance_buffer,
             &light_buffer,
             &sky_texture.view,
             &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
             &indirect_diffuse_view,
             None,
         );
@@ -579,12 +764,15 @@ impl State {
             &camera_ray_buffer,
             &bvh_buffer,
             &material_buffer,
-            &triangle_buffer,
+            &object_buffer,
             &vertex_buffer,
-            &normal_buffer,
+            &instance_buffer,
             &light_buffer,
             &sky_texture.view,
             &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
             &direct_specular_view,
             None,
         );
@@ -642,12 +830,15 @@ impl State {
             &camera_ray_buffer,
             &bvh_buffer,
             &material_buffer,
-            &triangle_buffer,
+            &object_buffer,
             &vertex_buffer,
-            &normal_buffer,
+            &instance_buffer,
             &light_buffer,
             &sky_texture.view,
             &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
             &indirect_specular_view,
             Some(&sky_render_view)
         );
@@ -687,17 +878,69 @@ impl State {
             &camera_ray_buffer,
             &bvh_buffer,
             &material_buffer,
-            &triangle_buffer,
+            &object_buffer,
             &vertex_buffer,
-            &normal_buffer,
+            &instance_buffer,
             &light_buffer,
             &sky_texture.view,
             &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
             &sss_view,
             None,
         );
 
 
+        // VOLUME COMPUTE PIPELINE
+        let volume_shader = include_str!("./shaders/compute_volume.wgsl");
+        let combined_volume_shader = format!("{}\n{}\n{}\n{}", traversal_buffers, shader_structs, shader_functions, volume_shader);
+        let volume_module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Volume Shader"),
+            source: wgpu::ShaderSource::Wgsl(combined_volume_shader.into()),
+        });
+
+        let volume_texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: None,
+            size: Extent3d {
+                width: size.width,
+                height: size.height,
+                depth_or_array_layers: 1,
+            }, 
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8Unorm,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let volume_view = volume_texture.create_view(&Default::default());     
+
+        let (
+            volume_pipeline, 
+            volume_pipeline_layout,
+            volume_bind_group,
+        ) = create_pipeline(
+            &device,
+            &volume_module,
+            &scene_buffer,
+            &camera_ray_buffer,
+            &bvh_buffer,
+            &material_buffer,
+            &object_buffer,
+            &vertex_buffer,
+            &instance_buffer,
+            &light_buffer,
+            &sky_texture.view,
+            &sky_texture.sampler,
+            &density_texture.view,
+            &density_texture.sampler,
+            &sdf_texture.view,
+            &volume_view,
+            None,
+        );
+
+
         // RENDER PIPELINE
         let render_shader = include_str!("./shaders/shader.wgsl");
         let combined_render_shader = format!("{}\n{}", shader_structs, render_shader);
@@ -800,6 +1043,16 @@ impl State {
                         },
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
@@ -843,6 +1096,10 @@ impl State {
                     binding: 8,
                     resource: wgpu::BindingResource::TextureView(&sky_render_view),
                 },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::TextureView(&volume_view),
+                },
             ],
             label: Some("Texture Bind Group"),
         });    
@@ -901,8 +1158,9 @@ impl State {
         });
 
 
-        Self {
-            surface,
+        let camera_time = camera.time;
+        let mut state = Self {
+            target,
             device,
             queue,
             config,
@@ -914,7 +1172,6 @@ impl State {
             camera_buffer,
             camera_bind_group,
             camera_uniform,
-            window,
             direct_diffuse_pipeline,
             direct_diffuse_bind_group,
             indirect_diffuse_pipeline,
@@ -925,6 +1182,9 @@ impl State {
             indirect_specular_bind_group,
             sss_pipeline,
             sss_bind_group,
+            volume_pipeline,
+            volume_bind_group,
+            media,
             camera_ray_bind_group,
             camera_ray_compute_pipeline,
             camera_ray_uniform,
@@ -935,11 +1195,36 @@ impl State {
             scene_buffer,
             render_config,
             clear_buffer: false,
+            bvh,
+            bvh_buffer,
+            instance_buffer,
+            scene_objects,
+            object_buffer,
+            vertex_buffer,
+            scene_vertices,
+            vertex_sources,
+            refit_pass,
+            refit_count: 0,
+            glb,
+            source_vertices,
+            mesh_sources,
+            time: camera_time,
+            playing: false,
+            last_tick: None,
+            state_js: StateJS::new(),
+        };
+        // Skinned meshes load in their bind pose
+        if state.glb.is_animated() || state.glb.is_deformed() {
+            state.set_time(camera_time);
         }
+        state
     }
 
-    pub fn window(&self) -> &Window {
-        &self.window
+    pub fn window(&self) -> Option<&Window> {
+        match &self.target {
+            Target::Window { window, .. } => Some(window),
+            Target::Offscreen(_) => None,
+        }
     }
 
     fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
@@ -949,7 +1234,9 @@ impl State {
             self.config.height = new_size.height;
             self.render_config.size = new_size.into();
             self.render_config.pixel_size = [1.0 / new_size.width as f32, 1.0 / new_size.height as f32];
-            self.surface.configure(&self.device, &self.config);
+            if let Target::Window { surface, .. } = &self.target {
+                surface.configure(&self.device, &self.config);
+            }
 
             // Update buffers            
             self.camera.aspect = new_size.width as f32 / new_size.height as f32;            
@@ -967,31 +1254,114 @@ impl State {
         self.camera_controller.process_events(event)
     }
 
+    // Applies what the page changed since the last update. Autofocus and the lens controls move
+    // the camera too, so the page is compared with its previous state rather than the camera.
     fn match_js(&mut self, state_js: &StateJS) {
-        if  self.camera.aperture != state_js.camera.aperture{
-            self.camera.aperture = state_js.camera.aperture;
+        let (previous, camera) = (self.state_js.camera, state_js.camera);
+        if camera.aperture != previous.aperture {
+            self.camera.aperture = camera.aperture;
             self.clear_buffer = true;
         };
-        if self.camera.fovy != state_js.camera.fov {
-
-            self.camera.fovy = state_js.camera.fov;
+        if camera.fov != previous.fov {
+            self.camera.fovy = camera.fov;
+            self.clear_buffer = true;
+        };
+        if camera.focal_length != previous.focal_length || camera.f_stop != previous.f_stop {
+            self.camera.set_lens(camera.focal_length, camera.f_stop);
+            self.clear_buffer = true;
+        };
+        if camera.focus_distance != previous.focus_distance {
+            self.camera.focus_distance = camera.focus_distance;
+            self.clear_buffer = true;
+        };
+        if camera.blades != previous.blades || camera.blade_rotation != previous.blade_rotation {
+            self.camera.blades = camera.blades;
+            self.camera.blade_rotation = camera.blade_rotation;
             self.clear_buffer = true;
         };
+        if camera.projection != previous.projection || camera.ortho_height != previous.ortho_height || camera.fisheye_fov != previous.fisheye_fov {
+            self.camera.projection = match camera.projection {
+                PROJECTION_ORTHOGRAPHIC => Projection::Orthographic { height: camera.ortho_height },
+                PROJECTION_EQUIRECTANGULAR => Projection::Equirectangular,
+                PROJECTION_CUBEMAP => Projection::Cubemap,
+                PROJECTION_FISHEYE => Projection::Fisheye { fov: camera.fisheye_fov },
+                _ => Projection::Perspective,
+            };
+            self.clear_buffer = true;
+        };
+        if camera.shutter != previous.shutter {
+            self.camera.shutter = [0.0, camera.shutter.max(0.0)];
+            // Places the instances and the camera over the new interval
+            self.set_time(self.time);
+        };
+        if camera.focus_request != previous.focus_request {
+            self.focus_at(camera.focus_point);
+        };
+        let (previous, timeline) = (self.state_js.timeline, state_js.timeline);
+        if timeline.playing != previous.playing {
+            if timeline.playing {
+                self.play();
+            } else {
+                self.pause();
+            }
+        };
+        if timeline.time != previous.time {
+            self.set_time(timeline.time);
+        };
         if state_js.config.size[0] != self.render_config.size[0] || state_js.config.size[1] != self.render_config.size[1] {
             // TODO: Need to update texture resolution and pipelines to handle size change
             // self.resize(state_js.config.size.into());
             // self.clear_buffer = true;
         };
+        if state_js.fog != self.state_js.fog {
+            self.set_fog(state_js.fog);
+        };
         if state_js.config.sky_intensity != self.render_config.sky_intensity {
             self.render_config.sky_intensity = state_js.config.sky_intensity;
             self.scene.config.sky_intensity = state_js.config.sky_intensity;
             self.clear_buffer = true;
         };
+        self.state_js = *state_js;
+    }
+
+    // Fills the scene bounds with fog, or the loaded density grid when there is one
+    fn set_fog(&mut self, fog: Fog) {
+        let medium = match fog.density > 0.0 {
+            false => None,
+            true if !self.media.grids().is_empty() => Some(Medium::heterogeneous([0.0; 3], fog.color, fog.anisotropy, fog.density, 0)),
+            true => Some(Medium::fog(fog.color, fog.density, fog.anisotropy, self.bvh.bounds())),
+        };
+        if !self.media.set_fog(medium) {
+            log::warn!("Media limit reached, no room for the fog");
+            return;
+        }
+        self.scene.media = self.media.to_uniform();
+        self.clear_buffer = true;
+    }
+
+    // Focuses on the surface under `point`, in window coordinates from the top left in 0..1.
+    // Nothing changes where the ray only sees the sky.
+    fn focus_at(&mut self, point: [f32; 2]) {
+        // The kernels store pixels mirrored horizontally, see their flipped_idx
+        let ray = self.camera.primary_ray([1.0 - point[0], point[1]]);
+        let (objects, vertices) = (&self.scene_objects, &self.scene_vertices);
+        if let Some((_, t)) = self.bvh.traverse(&ray, |i, ray| objects[i].hit(ray, vertices)) {
+            self.camera.focus_on(&ray, t);
+            self.clear_buffer = true;
+        }
     }
 
     fn update(&mut self, &state_js: &StateJS) {
 
         self.match_js(&state_js);
+        if self.playing {
+            let now = clock_seconds();
+            let elapsed = self.last_tick.map_or(0.0, |last| (now - last) as f32);
+            self.last_tick = Some(now);
+            let duration = self.glb.animation_duration();
+            let time = self.time + elapsed;
+            self.set_time(if duration > 0.0 { time % duration } else { time });
+        }
         self.camera_controller.update_camera(&mut self.camera, &mut self.clear_buffer);
         self.accumulation_array.update_buffer(&mut self.accumulation_buffer, &self.clear_buffer, &self.queue);
         self.camera_uniform = CameraUniform::from(&self.camera);    
@@ -1001,12 +1371,106 @@ impl State {
         self.clear_buffer = false;
     }
 
+    // Refits the tree to moved instances and vertices. Binary trees are refit on the GPU and
+    // only every few calls on the CPU too, measuring tree quality and rebuilding meshes that
+    // degraded. Compressed wide nodes can't be refit in place, they are refit on the CPU and
+    // uploaded on every call.
+    fn refit(&mut self) {
+        if let Some(refit_pass) = &self.refit_pass {
+            self.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&self.bvh.instances()));
+            let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Refit Encoder"),
+            });
+            refit_pass.encode(&mut encoder);
+            self.queue.submit(iter::once(encoder.finish()));
+
+            self.refit_count += 1;
+            if self.refit_count < REBUILD_CHECK_INTERVAL {
+                return;
+            }
+            self.refit_count = 0;
+        }
+        let rebuilt = self.bvh.refit(&self.scene_vertices, &mut self.scene_objects);
+        if rebuilt.any() || self.refit_pass.is_none() {
+            self.upload_bvh(rebuilt);
+        }
+    }
+
+    // Uploads the tree and instances after a CPU refit. The bvh buffer is sized for any rebuild,
+    // and rebuilds reorder a mesh's objects without adding any, so the object buffer fits too.
+    fn upload_bvh(&mut self, rebuilt: Rebuilt) {
+        self.queue.write_buffer(&self.bvh_buffer, 0, &self.bvh.to_bytes());
+        if rebuilt.bottom_levels {
+            let objects = SceneObjectCPU::to_buffer_vec(&self.scene_objects);
+            self.queue.write_buffer(&self.object_buffer, 0, bytemuck::cast_slice(&objects));
+        }
+        self.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&self.bvh.instances()));
+        // The refit schedule follows the topology
+        if rebuilt.any() && self.refit_pass.is_some() {
+            self.refit_pass = Some(RefitPass::new(&self.device, &self.bvh, &self.bvh_buffer, &self.object_buffer, &self.vertex_buffer, &self.instance_buffer));
+        }
+    }
+
+    // Moves the scene to `time` seconds on the timeline. The glb's first animation places its
+    // instances and camera, both blurred over the camera shutter, and skinned or morphed meshes
+    // are deformed at shutter open. Both levels are refit rather than
+    // rebuilt between frames until they degrade, see `refit`.
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+        self.camera.set_time(time);
+        let shutter = [time + self.camera.shutter[0], time + self.camera.shutter[1]];
+
+        let mut placements = vec![None; self.bvh.instance_count()];
+        if self.glb.is_animated() {
+            for (placement, transforms) in placements.iter_mut().zip(self.glb.placements(shutter[0], shutter[1])) {
+                *placement = Some(transforms);
+            }
+        }
+        self.bvh.place_instances(&placements);
+
+        let deformed = self.glb.deformed_meshes(shutter[0]);
+        if deformed.iter().any(Option::is_some) {
+            let mut sources = self.source_vertices.clone();
+            for (vertices, offset) in deformed.iter().zip(&self.mesh_sources) {
+                if let (Some(vertices), Some(offset)) = (vertices, offset) {
+                    let offset = *offset as usize;
+                    sources[offset..offset + vertices.len()].copy_from_slice(vertices);
+                }
+            }
+            self.scene_vertices = self.vertex_sources.iter().map(|&s| sources[s as usize]).collect();
+            self.queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.scene_vertices));
+        }
+
+        self.refit();
+        self.clear_buffer = true;
+    }
+
+    // Plays the timeline on from the current time, looping over the glb's animation
+    pub fn play(&mut self) {
+        self.playing = true;
+        self.last_tick = None;
+    }
+
+    pub fn pause(&mut self) {
+        self.playing = false;
+    }
+
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
+        let output = match &self.target {
+            Target::Window { surface, .. } => surface.get_current_texture()?,
+            Target::Offscreen(texture) => {
+                self.draw(&texture.create_view(&wgpu::TextureViewDescriptor::default()));
+                return Ok(());
+            },
+        };
+        self.draw(&output.texture.create_view(&wgpu::TextureViewDescriptor::default()));
+        output.present();
 
+        Ok(())
+    }
+
+    // Traces one sample into the accumulation and draws the running average to `view`
+    fn draw(&self, view: &wgpu::TextureView) {
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -1063,11 +1527,19 @@ impl State {
             sss_compute_pass.dispatch_workgroups(self.size.width / 16, self.size.height / 16, 1);
         }
 
+        if !self.media.is_empty() {
+            // Participating media
+            let mut volume_compute_pass = encoder.begin_compute_pass(&Default::default());
+            volume_compute_pass.set_pipeline(&self.volume_pipeline);
+            volume_compute_pass.set_bind_group(0, &self.volume_bind_group, &[]);
+            volume_compute_pass.dispatch_workgroups(self.size.width / 16, self.size.height / 16, 1);
+        }
+
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
+                    view,
                     resolve_target: None,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
@@ -1086,9 +1558,401 @@ impl State {
         }
 
         self.queue.submit(iter::once(encoder.finish()));
-        output.present();
+    }
 
-        Ok(())
+    // RGBA pixels of the last frame rendered offscreen, rows from the top. None for a window.
+    fn read_frame(&self) -> Option<Vec<u8>> {
+        let texture = match &self.target {
+            Target::Offscreen(texture) => texture,
+            Target::Window { .. } => return None,
+        };
+        // Rows are copied out padded to the alignment copies need
+        let row_bytes = 4 * self.config.width;
+        let padded_row_bytes = row_bytes.div_ceil(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT) * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let readback = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Readback Buffer"),
+            size: (padded_row_bytes * self.config.height) as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Readback Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row_bytes),
+                    rows_per_image: Some(self.config.height),
+                },
+            },
+            Extent3d { width: self.config.width, height: self.config.height, depth_or_array_layers: 1 },
+        );
+        self.queue.submit(iter::once(encoder.finish()));
+
+        let slice = readback.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |_| {});
+        self.device.poll(wgpu::Maintain::Wait);
+        let pixels = slice.get_mapped_range()
+            .chunks(padded_row_bytes as usize)
+            .flat_map(|row| row[..row_bytes as usize].to_vec())
+            .collect();
+        readback.unmap();
+        Some(pixels)
+    }
+}
+
+async fn request_device(adapter: &wgpu::Adapter) -> (wgpu::Device, wgpu::Queue) {
+    // Log device and backend
+    let info = adapter.get_info();
+    println!("{:#?}", info);
+    log::warn!("{:#?}", info);
+
+    adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: wgpu::Features::empty(),
+                limits: if cfg!(target_arch = "wasm32") {
+                    wgpu::Limits::downlevel_webgl2_defaults()
+                } else {
+                    wgpu::Limits::default()
+                },
+            },
+            None,
+        )
+        .await
+        .unwrap()
+}
+
+
+// Builds every mesh of a glb with each builder and reports build time and tree quality
+pub fn bvh_report(glb_bytes: &[u8]) -> String {
+    let glb = load_glb(glb_bytes);
+    let mut report = String::new();
+    for (mesh_idx, mesh) in glb.meshes().iter().enumerate() {
+        let objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+        report += &format!("mesh {} ({} triangles)\n", mesh_idx, objects.len());
+        for builder in [BVHBuilder::Variance, BVHBuilder::BinnedSAH, BVHBuilder::LBVH, BVHBuilder::PLOC, BVHBuilder::SBVH] {
+            let config = BVHConfig::default().with_builder(builder);
+            let mut primitives = objects.clone();
+            let start = std::time::Instant::now();
+            let bvh = BVH::with_config(&mut primitives, &config);
+            let elapsed = start.elapsed().as_secs_f32();
+            report += &format!("{:?} built in {:.3}s\n{}\n\n", builder, elapsed, bvh.stats(&config));
+        }
+    }
+    report
+}
+
+// Node fetches per ray of every mesh of a glb for each binary node layout, over primary rays
+// looking at the mesh from all sides, and how far apart a leaf's vertices are in each vertex order
+pub fn bvh_layout_report(glb_bytes: &[u8]) -> String {
+    let glb = load_glb(glb_bytes);
+    let mut report = String::new();
+    for (mesh_idx, mesh) in glb.meshes().iter().enumerate() {
+        let mut objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+        report += &format!("mesh {} ({} triangles)\n", mesh_idx, objects.len());
+        let mut bvh = BVH::with_config(&mut objects, &BVHConfig::default().with_builder(BVHBuilder::PLOC));
+        let rays = bvh_layout::view_rays(&bvh.bounds(), 64);
+        for layout in [NodeLayout::DepthFirst, NodeLayout::LargerFirst, NodeLayout::BreadthFirst, NodeLayout::VanEmdeBoas] {
+            bvh.set_layout(layout);
+            let stats = bvh.traversal_stats(&rays, |i, ray| {
+                let [a, b, c] = objects[i].object().indices().map(|v| mesh.vertices[v as usize]);
+                hit_watertight(ray, &[a, b, c])
+            });
+            report += &format!("  {:<13} {}\n", format!("{:?}", layout), stats);
+        }
+
+        let leaves: Vec<(usize, usize)> = bvh.nodes().iter()
+            .filter(|node| node.is_leaf())
+            .map(|node| (node.first as usize, node.count as usize))
+            .collect();
+        let vertices: Vec<Vertex> = mesh.vertices.iter().map(|v| Vertex::new(*v, [0.0, 1.0, 0.0])).collect();
+        for order in [VertexOrder::Source, VertexOrder::LeafOrder, VertexOrder::Gathered] {
+            let mut ordered = objects.clone();
+            let (stored, _) = reorder_vertices(&vertices, &mut ordered, 0, order);
+            let span = vertex_order::mean_leaf_span(&ordered, &leaves);
+            report += &format!("  {:<13} {} vertices, {:.1} apart within a leaf\n", format!("{:?}", order), stored.len(), span);
+        }
+    }
+    report
+}
+
+// Signed distance field of every mesh of a glb with `resolution` voxels along its longest side,
+// as a VOL file when `vol` is set and the grid format otherwise
+pub fn bake_sdfs(glb_bytes: &[u8], resolution: u32, ray_parity: bool, vol: bool) -> Vec<Vec<u8>> {
+    let sign = if ray_parity { SignMode::RayParity } else { SignMode::WindingNumber };
+    load_glb(glb_bytes).meshes().iter()
+        .map(|mesh| {
+            let distances = Sdf::bake(mesh, resolution, sign).to_grid();
+            if vol { grid::save_vol(&distances) } else { grid::save_grid(&distances) }
+        })
+        .collect()
+}
+
+// Split training rows from every mesh of the given glbs, meshes numbered in order
+fn training_rows(glbs: &[Vec<u8>]) -> Vec<bvh_ml::BVHNodeData> {
+    let config = BVHConfig::default().with_builder(BVHBuilder::BinnedSAH);
+    let mut rows = Vec::new();
+    let mut mesh_id = 0;
+    for glb_bytes in glbs {
+        for mesh in load_glb(glb_bytes).meshes() {
+            let mut objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+            for mut row in bvh_ml::training_data(&mut objects, &config) {
+                row.mesh = mesh_id;
+                rows.push(row);
+            }
+            mesh_id += 1;
+        }
+    }
+    rows
+}
+
+// Training rows as .npy features and labels with one row per node and as csv, see
+// bvh_ml::LABEL_COLUMNS for the label layout
+pub fn bvh_training_data(glbs: &[Vec<u8>]) -> (Vec<u8>, Vec<u8>, String) {
+    let rows = training_rows(glbs);
+    let features: Vec<f32> = rows.iter().flat_map(|row| row.features.iter().cloned()).collect();
+    let labels: Vec<f32> = rows.iter().flat_map(|row| row.labels()).collect();
+    (
+        bvh_ml::npy(&[rows.len(), bvh_ml::FEATURE_COUNT], &features),
+        bvh_ml::npy(&[rows.len(), bvh_ml::LABEL_COLUMNS.len()], &labels),
+        bvh_ml::csv(&rows),
+    )
+}
+
+// Weights of an MLP trained on the rows of the given glbs and its mean loss over them
+pub fn bvh_ml_train(glbs: &[Vec<u8>], seed: u64) -> (Vec<u8>, f32) {
+    let rows = training_rows(glbs);
+    let mlp = Mlp::train(&rows, seed);
+    (mlp.save(), mlp.loss(&rows))
+}
+
+// SAH cost of every mesh of a glb built with the MLP in `weights` against the heuristic builders
+pub fn bvh_ml_benchmark(glb_bytes: &[u8], weights: &[u8]) -> anyhow::Result<String> {
+    let mlp = Mlp::load(weights)?;
+    let glb = load_glb(glb_bytes);
+    let mut report = String::new();
+    for (mesh_idx, mesh) in glb.meshes().iter().enumerate() {
+        let objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+        report += &format!("mesh {} ({} triangles)\n", mesh_idx, objects.len());
+        let mut results = Vec::new();
+        for builder in [Some(BVHBuilder::Variance), Some(BVHBuilder::BinnedSAH), None] {
+            let config = BVHConfig::default().with_builder(builder.unwrap_or(BVHBuilder::BinnedSAH));
+            let mut primitives = objects.clone();
+            let start = std::time::Instant::now();
+            let bvh = match builder {
+                Some(_) => BVH::with_config(&mut primitives, &config),
+                None => BVH::with_predictor(&mut primitives, &config, &mlp),
+            };
+            let elapsed = start.elapsed().as_secs_f32();
+            let name = builder.map_or("MLP".to_string(), |b| format!("{:?}", b));
+            results.push((name, bvh.stats(&config).sah_cost, elapsed));
+        }
+        let binned = results[1].1.max(f32::EPSILON);
+        for (name, cost, elapsed) in results {
+            report += &format!("  {:<10} SAH cost {:>10.3} ({:.3}x binned), built in {:.3}s\n", name, cost, cost / binned, elapsed);
+        }
+    }
+    Ok(report)
+}
+
+// Builds every mesh of a glb as State::new does and saves the trees into one file, which the web
+// build fetches next to the glb. Meshes refined before the build hash differently and are rebuilt.
+pub fn bvh_cache(glb_bytes: &[u8]) -> Vec<u8> {
+    let glb = load_glb(glb_bytes);
+    let saved: Vec<Vec<u8>> = glb.meshes().iter()
+        .map(|mesh| {
+            let mut objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+            mesh_bvh(mesh, &mut objects, bvh_width(&glb), None).save(mesh_hash(mesh))
+        })
+        .collect();
+
+    // u32 tree count, then every saved tree after its u32 length
+    let mut out = (saved.len() as u32).to_le_bytes().to_vec();
+    for bytes in &saved {
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+    out
+}
+
+fn unpack_bvh_cache(data: &[u8]) -> Vec<&[u8]> {
+    let read_u32 = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    let mut saved = Vec::new();
+    let mut at = 4;
+    for _ in 0..read_u32(0).unwrap_or(0) {
+        match read_u32(at).and_then(|len| data.get(at + 4..at + 4 + len)) {
+            Some(bytes) => {
+                at += 4 + bytes.len();
+                saved.push(bytes);
+            },
+            None => {
+                log::warn!("BVH cache is truncated after {} trees", saved.len());
+                break;
+            },
+        }
+    }
+    saved
+}
+
+// Children per BVH node for a glb, binary when skinned or morphed meshes are refit on the GPU
+fn bvh_width(glb: &GLBScene) -> usize {
+    if glb.is_deformed() { 2 } else { BVH_WIDTH }
+}
+
+// Loads the bottom level BVH of a mesh from `cached` when it matches, building it otherwise
+fn mesh_bvh(mesh: &TriMesh, objects: &mut Vec<SceneObjectCPU>, width: usize, cached: Option<&[u8]>) -> BVH {
+    let config = BVHConfig::default().with_builder(BVHBuilder::PLOC).with_width(width);
+    if let Some(bytes) = cached {
+        match BVH::load(bytes, objects, mesh_hash(mesh), &config) {
+            Ok(bvh) => return bvh,
+            Err(e) => log::warn!("Rebuilding cached BVH: {}", e),
+        }
+    }
+    BVH::with_config(objects, &config)
+}
+
+fn subdivision(settings: &wasm::state::Subdivision) -> Subdivision {
+    let scheme = match settings.scheme {
+        SCHEME_LOOP => SubdivisionScheme::Loop,
+        _ => SubdivisionScheme::CatmullClark,
+    };
+    let mode = match settings.mode {
+        MODE_UNIFORM => SubdivisionMode::Uniform(settings.levels),
+        MODE_ADAPTIVE => SubdivisionMode::Adaptive { max_level: settings.levels, edge_pixels: settings.edge_pixels },
+        _ => SubdivisionMode::None,
+    };
+    Subdivision::new(scheme, mode)
+}
+
+fn mesh_hash(mesh: &TriMesh) -> u64 {
+    let bytes = [bytemuck::cast_slice::<[f32; 3], u8>(&mesh.vertices), bytemuck::cast_slice(&mesh.indices)].concat();
+    source_hash(&bytes)
+}
+
+// Frames of a glb's first animation from time 0 at `fps`, rendered offscreen by `State` as the
+// page shows them after `samples` accumulated samples. `write` takes each frame number and PNG.
+pub fn render_sequence(glb_bytes: &[u8], frames: u32, fps: f32, samples: u32, size: [u32; 2], mut write: impl FnMut(u32, Vec<u8>)) -> anyhow::Result<()> {
+    let settings = StateJS::new();
+    let files = SceneFiles { glb: glb_bytes.to_vec(), bvh_cache: None, density_grid: None, sdf_grid: None };
+    let mut state = pollster::block_on(State::offscreen(size, &settings, files))?;
+    for frame in 0..frames {
+        state.set_time(frame as f32 / fps);
+        for _ in 0..samples.max(1) {
+            state.update(&settings);
+            state.render()?;
+        }
+        let pixels = state.read_frame().expect("offscreen state has a frame to read");
+        let mut png = Vec::new();
+        let encoder = image::codecs::png::PngEncoder::new(&mut png);
+        image::ImageEncoder::write_image(encoder, &pixels, size[0], size[1], image::ColorType::Rgba8)?;
+        write(frame, png);
+    }
+    Ok(())
+}
+
+// Debug tool apart from `render_sequence`, for checking the animation and the refits without a
+// GPU. Frames are posed as `State::set_time` poses them, but only the glb meshes are traced, on
+// the CPU, each pixel the diffuse color shaded by how squarely the surface faces the camera.
+// Returns PNG files.
+pub fn debug_sequence(glb_bytes: &[u8], frames: u32, fps: f32, size: [u32; 2]) -> Vec<Vec<u8>> {
+    let glb = load_glb(glb_bytes);
+    let mut objects: Vec<SceneObjectCPU> = vec![];
+    let mut rest: Vec<Vertex> = vec![];
+    let mut blas: Vec<BVH> = vec![];
+    let mut object_offsets: Vec<u32> = vec![];
+    for mesh in glb.meshes() {
+        let offset = rest.len() as u32;
+        rest.extend(mesh.vertices.iter().enumerate()
+            .map(|(i, vertex)| Vertex::new(*vertex, mesh.normals.get(i).copied().unwrap_or([0.0, 1.0, 0.0]))));
+        let mut mesh_objects = SceneObjectCPU::from_tri_mesh(mesh, offset);
+        blas.push(mesh_bvh(mesh, &mut mesh_objects, bvh_width(&glb), None));
+        object_offsets.push(objects.len() as u32);
+        objects.extend(mesh_objects);
+    }
+    let instances: Vec<InstanceCPU> = glb.instances().iter()
+        .map(|(mesh_idx, transform)| InstanceCPU::new(*mesh_idx, *transform, &blas[*mesh_idx].bounds()))
+        .collect();
+
+    // Without a glb camera the view frames the scene at rest
+    let aspect = size[0] as f32 / size[1] as f32;
+    let camera = match glb.cameras().first() {
+        Some(glb_camera) => glb_camera.to_camera(aspect),
+        None => {
+            let bounds = instances.iter().fold(AABB::empty(), |bounds, instance| bounds.union(&instance.bounding_box(0.0, 0.0)));
+            let center = cgmath::Point3::from(bounds.centroid());
+            let radius = 0.5 * Vector3::new(bounds.max[0] - bounds.min[0], bounds.max[1] - bounds.min[1], bounds.max[2] - bounds.min[2]).magnitude();
+            Camera::new(center + Vector3::new(0.0, 0.5, 1.0).normalize() * radius.max(0.1) * 2.5, center, 0.0, 50.0, aspect)
+        },
+    };
+    let mut bvh = TwoLevelBVH::new(blas, object_offsets, instances);
+
+    (0..frames)
+        .map(|frame| {
+            let time = frame as f32 / fps;
+            let placements: Vec<_> = glb.placements(time, time).into_iter().map(Some).collect();
+            bvh.place_instances(&placements);
+            let mut vertices = rest.clone();
+            let mut offset = 0;
+            for (mesh, deformed) in glb.meshes().iter().zip(glb.deformed_meshes(time)) {
+                if let Some(deformed) = deformed {
+                    vertices[offset..offset + deformed.len()].copy_from_slice(&deformed);
+                }
+                offset += mesh.vertices.len();
+            }
+            bvh.refit(&vertices, &mut objects);
+            debug_frame(&bvh, &objects, &vertices, glb.materials(), &camera, size)
+        })
+        .collect()
+}
+
+// One frame of `debug_sequence` as a PNG, pixels mirrored horizontally as the kernels store them
+fn debug_frame(bvh: &TwoLevelBVH, objects: &[SceneObjectCPU], vertices: &[Vertex], materials: &[Material], camera: &Camera, size: [u32; 2]) -> Vec<u8> {
+    let walker = bvh.walker();
+    let position = |i: u32| Vector3::new(vertices[i as usize].position[0], vertices[i as usize].position[1], vertices[i as usize].position[2]);
+    let pixels: Vec<u8> = (0..size[0] * size[1]).into_par_iter()
+        .flat_map_iter(|pixel| {
+            let (x, y) = (pixel % size[0], pixel / size[0]);
+            let ray = camera.primary_ray([1.0 - (x as f32 + 0.5) / size[0] as f32, (y as f32 + 0.5) / size[1] as f32]);
+            // The last hit reported is the closest, each is bounded by the one before
+            let mut closest = None;
+            walker.traverse(&ray, |i, ray| {
+                let t = objects[i].hit(ray, vertices)?;
+                closest = Some((i, Vector3::from(ray.direction)));
+                Some(t)
+            });
+            let color = match closest {
+                Some((i, direction)) => {
+                    let object = objects[i].object();
+                    let [a, b, c] = object.indices().map(position);
+                    let facing = (b - a).cross(c - a).normalize().dot(direction.normalize()).abs();
+                    let diffuse = materials.get(object.material() as usize).map_or([0.8; 4], |material| material.diffuse);
+                    [0, 1, 2].map(|channel| diffuse[channel] * (0.2 + 0.8 * facing))
+                },
+                None => [0.5, 0.6, 0.7],
+            };
+            color.map(|c| (c.max(0.0).powf(1.0 / 2.2).min(1.0) * 255.0) as u8)
+        })
+        .collect();
+
+    let mut png = Vec::new();
+    let encoder = image::codecs::png::PngEncoder::new(&mut png);
+    image::ImageEncoder::write_image(encoder, &pixels, size[0], size[1], image::ColorType::Rgb8).expect("failed to encode frame");
+    png
+}
+
+// Seconds on the wall clock, for timeline playback
+fn clock_seconds() -> f64 {
+    cfg_if::cfg_if! {
+        if #[cfg(target_arch = "wasm32")] {
+            js_sys::Date::now() / 1000.0
+        } else {
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0.0, |d| d.as_secs_f64())
+        }
     }
 }
 
@@ -1100,7 +1964,7 @@ pub fn confirm() {
 }
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
-pub async fn run(get_js: Function) {
+pub async fn run(get_js: Function, bvh_cache: Option<Vec<u8>>, density_grid: Option<Vec<u8>>, sdf_grid: Option<Vec<u8>>) {
     cfg_if::cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
             std::panic::set_hook(Box::new(console_error_panic_hook::hook));
@@ -1131,14 +1995,16 @@ pub async fn run(get_js: Function) {
             .expect("Couldn't append canvas to document body.");
     }
 
-    let mut state = State::new(window).await;
+    let settings: StateJS = get_js.call0(&JsValue::null()).unwrap().into_serde().unwrap();
+    let files = SceneFiles { glb: include_bytes!("../assets/test.glb").to_vec(), bvh_cache, density_grid, sdf_grid };
+    let mut state = State::new(window, &settings, files).await;
 
     event_loop.run(move |event, _, control_flow| {
         match event {
             Event::WindowEvent {
                 ref event,
                 window_id,
-            } if window_id == state.window().id() => {
+            } if state.window().map(Window::id) == Some(window_id) => {
                 if !state.input(event) {
                     match event {
                         WindowEvent::CloseRequested
@@ -1163,7 +2029,7 @@ pub async fn run(get_js: Function) {
                     }
                 }
             }
-            Event::RedrawRequested(window_id) if window_id == state.window().id() => {
+            Event::RedrawRequested(window_id) if state.window().map(Window::id) == Some(window_id) => {
 
                 let state_js: StateJS = get_js.call0(&JsValue::null()).unwrap().into_serde().unwrap();
                 // let state_js = StateJS::new();
@@ -1181,7 +2047,9 @@ pub async fn run(get_js: Function) {
                 }
             }
             Event::MainEventsCleared => {
-                state.window().request_redraw();
+                if let Some(window) = state.window() {
+                    window.request_redraw();
+                }
             }
             _ => {}
         }
@@ -1,6 +1,143 @@
-use krusty::{run};
+use krusty::{run, bake_sdfs, bvh_report, bvh_layout_report, bvh_cache, bvh_training_data, bvh_ml_train, bvh_ml_benchmark, render_sequence, debug_sequence, RENDER_SIZE};
 
 fn main() {
-    println!("Rendering in browser...");
-    // pollster::block_on(run(None));
-}
\ No newline at end of file
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(|arg| arg.as_str()) {
+        // krusty bvh-stats <file.glb>...
+        Some("bvh-stats") => {
+            for path in &args[2..] {
+                let bytes = std::fs::read(path).expect("failed to read glb");
+                println!("{}\n{}", path, bvh_report(&bytes));
+            }
+        },
+        // krusty bvh-layouts <file.glb>..., node fetches and cache behaviour of each node and vertex layout
+        Some("bvh-layouts") => {
+            for path in &args[2..] {
+                let bytes = std::fs::read(path).expect("failed to read glb");
+                println!("{}\n{}", path, bvh_layout_report(&bytes));
+            }
+        },
+        // krusty bvh-cache <file.glb>... [--out <folder>], writes <file.glb>.kbvh for the web build
+        // to fetch, next to the glb by default
+        Some("bvh-cache") => {
+            let out_dir = args.iter().position(|arg| arg == "--out").map(|i| args.get(i + 1).expect("missing output folder"));
+            let paths = args[2..].iter().filter(|arg| Some(*arg) != out_dir && *arg != "--out");
+            for path in paths {
+                let bytes = std::fs::read(path).expect("failed to read glb");
+                let path = std::path::Path::new(path);
+                let name = format!("{}.kbvh", path.file_name().expect("missing glb name").to_string_lossy());
+                let out = match out_dir {
+                    Some(dir) => std::path::Path::new(dir).join(name),
+                    None => path.with_file_name(name),
+                };
+                std::fs::write(&out, bvh_cache(&bytes)).expect("failed to write BVH cache");
+                println!("{} -> {}", path.display(), out.display());
+            }
+        },
+        // krusty bvh-ml-dump <glb folder> <out folder>, writes features.npy, labels.npy and nodes.csv
+        Some("bvh-ml-dump") => {
+            let (dir, out) = (args.get(2).expect("missing glb folder"), args.get(3).expect("missing output folder"));
+            let (paths, glbs) = read_glbs(dir);
+            let (features, labels, csv) = bvh_training_data(&glbs);
+            std::fs::create_dir_all(out).expect("failed to create output folder");
+            let out = std::path::Path::new(out);
+            std::fs::write(out.join("features.npy"), features).expect("failed to write features");
+            std::fs::write(out.join("labels.npy"), labels).expect("failed to write labels");
+            std::fs::write(out.join("nodes.csv"), csv).expect("failed to write csv");
+            for path in &paths {
+                println!("{}", path.display());
+            }
+        },
+        // krusty bvh-ml-train <glb folder> <out.kmlp> [seed], assets/bvh_split.kmlp is trained on assets
+        Some("bvh-ml-train") => {
+            let (dir, out) = (args.get(2).expect("missing glb folder"), args.get(3).expect("missing output file"));
+            let seed = args.get(4).map_or(0, |s| s.parse().expect("seed is not a number"));
+            let (paths, glbs) = read_glbs(dir);
+            let (weights, loss) = bvh_ml_train(&glbs, seed);
+            std::fs::write(out, weights).expect("failed to write weights");
+            for path in &paths {
+                println!("{}", path.display());
+            }
+            println!("mean loss {:.4} -> {}", loss, out);
+        },
+        // krusty bvh-ml-bench <weights.kmlp> <file.glb>..., SAH cost of MLP guided splits
+        Some("bvh-ml-bench") => {
+            let weights = std::fs::read(args.get(2).expect("missing weights")).expect("failed to read weights");
+            for path in &args[3..] {
+                let bytes = std::fs::read(path).expect("failed to read glb");
+                match bvh_ml_benchmark(&bytes, &weights) {
+                    Ok(report) => println!("{}\n{}", path, report),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    },
+                }
+            }
+        },
+        // krusty bake-sdf <file.glb> <resolution> [parity] [vol], writes <file.glb>.<mesh>.kgrd or .vol
+        Some("bake-sdf") => {
+            let path = args.get(2).expect("missing glb");
+            let resolution = args.get(3).and_then(|r| r.parse().ok()).expect("missing resolution");
+            let flags = &args[4.min(args.len())..];
+            let (parity, vol) = (flags.iter().any(|f| f == "parity"), flags.iter().any(|f| f == "vol"));
+            let bytes = std::fs::read(path).expect("failed to read glb");
+            for (mesh, sdf) in bake_sdfs(&bytes, resolution, parity, vol).iter().enumerate() {
+                let out = format!("{}.{}.{}", path, mesh, if vol { "vol" } else { "kgrd" });
+                std::fs::write(&out, sdf).expect("failed to write SDF");
+                println!("{} -> {}", path, out);
+            }
+        },
+        // krusty sequence <file.glb> <frames> <fps> [samples] [out folder], renders the animation
+        // on the GPU with `samples` per frame, 64 by default, as frame_0000.png onwards into
+        // <file.glb>.frames by default
+        Some("sequence") => {
+            let path = args.get(2).expect("missing glb");
+            let frames = args.get(3).and_then(|f| f.parse().ok()).expect("missing frame count");
+            let fps = args.get(4).and_then(|f| f.parse().ok()).expect("missing fps");
+            let samples = args.get(5).map_or(64, |s| s.parse().expect("samples is not a number"));
+            let out = args.get(6).cloned().unwrap_or_else(|| format!("{}.frames", path));
+            let bytes = std::fs::read(path).expect("failed to read glb");
+            std::fs::create_dir_all(&out).expect("failed to create output folder");
+            let written = render_sequence(&bytes, frames, fps, samples, RENDER_SIZE, |frame, png| {
+                let file = std::path::Path::new(&out).join(format!("frame_{:04}.png", frame));
+                std::fs::write(&file, png).expect("failed to write frame");
+                println!("{}", file.display());
+            });
+            if let Err(e) = written {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        // krusty sequence-debug <file.glb> <frames> <fps> [out folder], a debug view of the
+        // animation without a GPU: flat shaded glb meshes traced on the CPU, not renders. Writes
+        // debug_0000.png onwards into <file.glb>.debug by default.
+        Some("sequence-debug") => {
+            let path = args.get(2).expect("missing glb");
+            let frames = args.get(3).and_then(|f| f.parse().ok()).expect("missing frame count");
+            let fps = args.get(4).and_then(|f| f.parse().ok()).expect("missing fps");
+            let out = args.get(5).cloned().unwrap_or_else(|| format!("{}.debug", path));
+            let bytes = std::fs::read(path).expect("failed to read glb");
+            std::fs::create_dir_all(&out).expect("failed to create output folder");
+            for (frame, png) in debug_sequence(&bytes, frames, fps, RENDER_SIZE).iter().enumerate() {
+                let file = std::path::Path::new(&out).join(format!("debug_{:04}.png", frame));
+                std::fs::write(&file, png).expect("failed to write frame");
+                println!("{}", file.display());
+            }
+        },
+        _ => {
+            println!("Rendering in browser...");
+            // pollster::block_on(run(None));
+        },
+    }
+}
+
+// Every glb of a folder in name order
+fn read_glbs(dir: &str) -> (Vec<std::path::PathBuf>, Vec<Vec<u8>>) {
+    let mut paths: Vec<_> = std::fs::read_dir(dir).expect("failed to read glb folder")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("glb")))
+        .collect();
+    paths.sort();
+    let glbs = paths.iter().map(|path| std::fs::read(path).expect("failed to read glb")).collect();
+    (paths, glbs)
+}
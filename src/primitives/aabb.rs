@@ -1,4 +1,18 @@
 use crate::primitives::triangle::{Triangle, TriangleCPU};
+use crate::primitives::ray::Ray;
+
+
+// Anything the BVH builders can partition
+pub trait Bounded {
+    fn bounding_box(&self, t0: f32, t1: f32) -> AABB;
+    fn centroid(&self) -> [f32; 3];
+    fn bbox_surface_area(&self) -> f32;
+
+    // Bounds of the part inside `clip`, spatial splits use it to tighten straddling primitives
+    fn clipped_bounds(&self, clip: &AABB) -> AABB {
+        self.bounding_box(0.0, 0.0).intersection(clip).unwrap_or(AABB::empty())
+    }
+}
 
 
 #[repr(C)]
@@ -72,7 +86,7 @@ impl AABB {
         }
     }
 
-    pub fn bounding_box_for_slice(primitives: &[TriangleCPU], start: usize, end: usize) -> Self {
+    pub fn bounding_box_for_slice<T: Bounded>(primitives: &[T], start: usize, end: usize) -> Self {
         if start >= end {
             return AABB::empty();
         }
@@ -93,11 +107,108 @@ impl AABB {
         }
     }
 
+    // Grown by `margin` on every side
+    pub fn padded(&self, margin: f32) -> AABB {
+        let mut out = *self;
+        for i in 0..3 {
+            out.min[i] -= margin;
+            out.max[i] += margin;
+        }
+        out
+    }
+
+    pub fn centroid(&self) -> [f32; 3] {
+        [
+            (self.min[0] + self.max[0]) * 0.5,
+            (self.min[1] + self.max[1]) * 0.5,
+            (self.min[2] + self.max[2]) * 0.5,
+        ]
+    }
+
+    // Bounds of the eight transformed corners
+    pub fn transform(&self, matrix: &cgmath::Matrix4<f32>) -> AABB {
+        let mut out = AABB::empty();
+        for i in 0..8 {
+            let corner = cgmath::Vector4::new(
+                if i & 1 == 0 { self.min[0] } else { self.max[0] },
+                if i & 2 == 0 { self.min[1] } else { self.max[1] },
+                if i & 4 == 0 { self.min[2] } else { self.max[2] },
+                1.0,
+            );
+            let p = matrix * corner;
+            for axis in 0..3 {
+                out.min[axis] = out.min[axis].min(p[axis]);
+                out.max[axis] = out.max[axis].max(p[axis]);
+            }
+        }
+        out.min[3] = 0.0;
+        out.max[3] = 0.0;
+        out
+    }
+
+    // Bounds of a planar polygon clipped against the box, one Sutherland-Hodgman pass per face
+    pub fn clip_polygon(&self, points: &[[f32; 3]]) -> AABB {
+        let mut polygon = points.to_vec();
+        for axis in 0..3 {
+            for &(plane, keep_below) in &[(self.min[axis], false), (self.max[axis], true)] {
+                let inside = |p: &[f32; 3]| if keep_below { p[axis] <= plane } else { p[axis] >= plane };
+                let mut clipped = Vec::with_capacity(polygon.len() + 1);
+                for i in 0..polygon.len() {
+                    let a = polygon[i];
+                    let b = polygon[(i + 1) % polygon.len()];
+                    if inside(&a) {
+                        clipped.push(a);
+                    }
+                    if inside(&a) != inside(&b) {
+                        let t = (plane - a[axis]) / (b[axis] - a[axis]);
+                        let mut p = [a[0] + (b[0] - a[0]) * t, a[1] + (b[1] - a[1]) * t, a[2] + (b[2] - a[2]) * t];
+                        p[axis] = plane;
+                        clipped.push(p);
+                    }
+                }
+                polygon = clipped;
+                if polygon.is_empty() {
+                    return AABB::empty();
+                }
+            }
+        }
+
+        let mut out = AABB::empty();
+        for p in &polygon {
+            out.extend(&AABB::new([p[0], p[1], p[2], 0.0], [p[0], p[1], p[2], 0.0]));
+        }
+        out.min[3] = 0.0;
+        out.max[3] = 0.0;
+        out
+    }
+
+    pub fn is_empty(&self) -> bool {
+        (0..3).any(|axis| self.min[axis] > self.max[axis])
+    }
+
     pub fn contract(&mut self, other: &AABB) {
         for i in 0..3 {
             self.min[i] = self.min[i].max(other.min[i]);
             self.max[i] = self.max[i].min(other.max[i]);
         }
     }
+
+    // Entry distance along the ray, clamped to the origin, as distance_to_aabb in the shaders
+    pub fn hit_distance(&self, ray: &Ray) -> Option<f32> {
+        let mut t_enter = f32::NEG_INFINITY;
+        let mut t_exit = f32::INFINITY;
+        for axis in 0..3 {
+            let inv_direction = 1.0 / ray.direction[axis];
+            let t1 = (self.min[axis] - ray.origin[axis]) * inv_direction;
+            let t2 = (self.max[axis] - ray.origin[axis]) * inv_direction;
+            t_enter = t_enter.max(t1.min(t2));
+            t_exit = t_exit.min(t1.max(t2));
+        }
+        if t_exit < 0.0 || t_exit < t_enter {
+            None
+        } else {
+            Some(t_enter.max(0.0))
+        }
+    }
     
 }
\ No newline at end of file
@@ -1,13 +1,86 @@
 use cgmath::{InnerSpace, Rotation3, Rotation};
 use winit::event::*;
 use wgpu::util::DeviceExt;
+use crate::primitives::ray::Ray;
+
+// Height of the film the focal length is measured against, a full frame 35mm sensor
+const SENSOR_HEIGHT_MM: f32 = 24.0;
+// Scene units are metres
+const SCENE_UNITS_PER_MM: f32 = 0.001;
+
+// Projection kinds in place of an enum, matching structs.wgsl
+pub const PROJECTION_PERSPECTIVE: u32 = 0;
+pub const PROJECTION_ORTHOGRAPHIC: u32 = 1;
+pub const PROJECTION_EQUIRECTANGULAR: u32 = 2;
+pub const PROJECTION_CUBEMAP: u32 = 3;
+pub const PROJECTION_FISHEYE: u32 = 4;
+
+
+// How the image maps to ray directions around the camera's view
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    // Pinhole through `fovy`
+    Perspective,
+    // Parallel rays over a view `height` scene units tall, for technical drawings
+    Orthographic { height: f32 },
+    // Whole sphere around the origin, longitude across and latitude down, best at 2:1
+    Equirectangular,
+    // The six faces of a cube around the origin in a 3 by 2 grid, best at 3:2. Right, left and up
+    // on the top row, down, forward and back below.
+    Cubemap,
+    // Equidistant fisheye of `fov` degrees across the image height, angles keep growing past it
+    // into the corners
+    Fisheye { fov: f32 },
+}
+
+// Where the camera is at a time on the scene timeline, for fly-throughs
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraKey {
+    pub time: f32,
+    pub origin: cgmath::Point3<f32>,
+    pub focus: cgmath::Point3<f32>,
+}
+
+impl Projection {
+    // Kind and its parameter for CameraUniform
+    fn to_uniform(&self) -> (u32, f32) {
+        match *self {
+            Projection::Perspective => (PROJECTION_PERSPECTIVE, 0.0),
+            Projection::Orthographic { height } => (PROJECTION_ORTHOGRAPHIC, height),
+            Projection::Equirectangular => (PROJECTION_EQUIRECTANGULAR, 0.0),
+            Projection::Cubemap => (PROJECTION_CUBEMAP, 0.0),
+            Projection::Fisheye { fov } => (PROJECTION_FISHEYE, fov),
+        }
+    }
+}
+
+// World up the camera's right is taken against, x when looking straight up or down where y has
+// no cross product with the view. The shaders' camera bases fall back the same way.
+fn world_up(forward: cgmath::Vector3<f32>) -> cgmath::Vector3<f32> {
+    if forward.normalize().y.abs() < 0.999 { cgmath::Vector3::unit_y() } else { cgmath::Vector3::unit_x() }
+}
+
 
 pub struct Camera {
     pub origin: cgmath::Point3<f32>,
     pub focus: cgmath::Point3<f32>,
+    // Lens radius in scene units, zero for a pinhole
     pub aperture: f32,
     pub fovy: f32,
     pub aspect: f32,
+    // Distance along the view direction to the plane in focus
+    pub focus_distance: f32,
+    // Aperture blades, fewer than three for a round lens
+    pub blades: u32,
+    // Degrees
+    pub blade_rotation: f32,
+    pub projection: Projection,
+    // Open and close in seconds from `time`, equal for no motion blur
+    pub shutter: [f32; 2],
+    // Of the frame on the scene timeline
+    pub time: f32,
+    // Keyed path, sorted by time, empty for a camera only moved by hand
+    keys: Vec<CameraKey>,
 }
 
 impl Camera {
@@ -18,8 +91,121 @@ impl Camera {
             aperture,
             fovy,
             aspect,
+            focus_distance: (focus - origin).magnitude(),
+            blades: 0,
+            blade_rotation: 0.0,
+            projection: Projection::Perspective,
+            shutter: [0.0, 0.0],
+            time: 0.0,
+            keys: Vec::new(),
         }   
     }
+
+    pub fn with_projection(mut self, projection: Projection) -> Self {
+        self.projection = projection;
+        self
+    }
+
+    pub fn with_shutter(mut self, open: f32, close: f32) -> Self {
+        self.shutter = [open, close.max(open)];
+        self
+    }
+
+    pub fn add_key(&mut self, time: f32, origin: cgmath::Point3<f32>, focus: cgmath::Point3<f32>) {
+        let index = self.keys.partition_point(|key| key.time <= time);
+        self.keys.insert(index, CameraKey { time, origin, focus });
+    }
+
+    // Moves to the keyed placement at shutter open of the frame at `time`
+    pub fn set_time(&mut self, time: f32) {
+        self.time = time;
+        if let Some((origin, focus)) = self.keyed_at(time + self.shutter[0]) {
+            self.origin = origin;
+            self.focus = focus;
+        }
+    }
+
+    // Placement at shutter close, moved from the current one as much as the keyed path moves
+    // while the shutter is open
+    pub fn close_placement(&self) -> (cgmath::Point3<f32>, cgmath::Point3<f32>) {
+        let open = self.keyed_at(self.time + self.shutter[0]);
+        let close = self.keyed_at(self.time + self.shutter[1]);
+        match (open, close) {
+            (Some(open), Some(close)) => (self.origin + (close.0 - open.0), self.focus + (close.1 - open.1)),
+            _ => (self.origin, self.focus),
+        }
+    }
+
+    // Linear between keys, held before the first and after the last
+    fn keyed_at(&self, time: f32) -> Option<(cgmath::Point3<f32>, cgmath::Point3<f32>)> {
+        let next = self.keys.partition_point(|key| key.time <= time);
+        let (a, b) = match (next.checked_sub(1).map(|i| self.keys[i]), self.keys.get(next)) {
+            (Some(a), Some(&b)) => (a, b),
+            (Some(key), None) | (None, Some(&key)) => return Some((key.origin, key.focus)),
+            (None, None) => return None,
+        };
+        let u = (time - a.time) / (b.time - a.time);
+        Some((a.origin + (b.origin - a.origin) * u, a.focus + (b.focus - a.focus) * u))
+    }
+
+    // Field of view and aperture of a lens of `focal_length` millimetres at `f_stop`
+    pub fn set_lens(&mut self, focal_length: f32, f_stop: f32) {
+        self.fovy = (2.0 * (0.5 * SENSOR_HEIGHT_MM / focal_length.max(1e-3)).atan()).to_degrees();
+        self.aperture = 0.5 * focal_length / f_stop.max(1e-3) * SCENE_UNITS_PER_MM;
+    }
+
+    // Ray through `uv`, from the top left of the image, as compute_camera_rays.wgsl
+    pub fn primary_ray(&self, uv: [f32; 2]) -> Ray {
+        let forward = (self.focus - self.origin).normalize();
+        let right = world_up(forward).cross(forward).normalize();
+        let up = forward.cross(right);
+        let x = uv[0] * 2.0 - 1.0;
+        let y = 1.0 - uv[1] * 2.0;
+        let direction = match self.projection {
+            Projection::Perspective => {
+                let half_height = (0.5 * self.fovy.to_radians()).tan();
+                right * x * self.aspect * half_height + up * y * half_height + forward
+            },
+            Projection::Orthographic { height } => {
+                let origin = self.origin + (right * x * self.aspect + up * y) * 0.5 * height;
+                return Ray::new(origin.into(), forward.into());
+            },
+            Projection::Equirectangular => {
+                let (longitude, latitude) = (x * std::f32::consts::PI, y * std::f32::consts::FRAC_PI_2);
+                (right * longitude.sin() + forward * longitude.cos()) * latitude.cos() + up * latitude.sin()
+            },
+            Projection::Cubemap => {
+                let (column, row) = ((uv[0] * 3.0).floor().min(2.0), (uv[1] * 2.0).floor().min(1.0));
+                let x = (uv[0] * 3.0 - column) * 2.0 - 1.0;
+                let y = 1.0 - (uv[1] * 2.0 - row) * 2.0;
+                // Each face's view direction and the directions x and y move along. The image is
+                // mirrored when displayed, and `right` with it, so the columns count from the right.
+                let (face, face_x, face_y) = match (row * 3.0 + column) as u32 {
+                    0 => (up, right, -forward),
+                    1 => (right, -forward, up),
+                    2 => (-right, forward, up),
+                    3 => (-forward, -right, up),
+                    4 => (forward, right, up),
+                    _ => (-up, right, forward),
+                };
+                face + face_x * x + face_y * y
+            },
+            Projection::Fisheye { fov } => {
+                let (x, y) = (x * self.aspect, y);
+                let radius = (x * x + y * y).sqrt();
+                let theta = (radius * 0.5 * fov.to_radians()).min(std::f32::consts::PI);
+                let around = if radius > 0.0 { (right * x + up * y) / radius } else { right };
+                forward * theta.cos() + around * theta.sin()
+            },
+        };
+        Ray::new(self.origin.into(), direction.normalize().into())
+    }
+
+    // Focus on the plane through the point `t` along `ray`, a ray from the camera origin
+    pub fn focus_on(&mut self, ray: &Ray, t: f32) {
+        let forward = (self.focus - self.origin).normalize();
+        self.focus_distance = t * forward.dot(ray.direction.into());
+    }
 }
 
 #[repr(C)]
@@ -30,7 +216,15 @@ pub struct CameraUniform {
     pub aperture: f32,
     pub fovy: f32,
     pub aspect: f32,
-    _padding: [f32; 1],
+    pub focus_distance: f32,
+    pub blades: u32,
+    pub blade_rotation: f32,
+    pub projection: u32,
+    // Orthographic view height or fisheye field of view
+    pub projection_scale: f32,
+    // Placement at shutter close, the same as origin and focus for a still camera
+    pub origin_close: [f32; 4],
+    pub focus_close: [f32; 4],
 }
 
 impl CameraUniform {
@@ -41,18 +235,32 @@ impl CameraUniform {
             aperture: 0.0,
             fovy: 0.0,
             aspect: 0.0,
-            _padding: [0.0; 1],
+            focus_distance: 1.0,
+            blades: 0,
+            blade_rotation: 0.0,
+            projection: PROJECTION_PERSPECTIVE,
+            projection_scale: 0.0,
+            origin_close: [0.0; 4],
+            focus_close: [0.0; 4],
         }
     }
 
     pub fn from(camera: &Camera) -> Self {
+        let (projection, projection_scale) = camera.projection.to_uniform();
+        let (origin_close, focus_close) = camera.close_placement();
         Self {
             origin: [camera.origin.x, camera.origin.y, camera.origin.z, 0.0],
             focus: [camera.focus.x, camera.focus.y, camera.focus.z, 0.0],
             aperture: camera.aperture,
             fovy: camera.fovy,
             aspect: camera.aspect,
-            _padding: [0.0; 1],
+            focus_distance: camera.focus_distance,
+            blades: camera.blades,
+            blade_rotation: camera.blade_rotation,
+            projection,
+            projection_scale,
+            origin_close: [origin_close.x, origin_close.y, origin_close.z, 0.0],
+            focus_close: [focus_close.x, focus_close.y, focus_close.z, 0.0],
         }
     }  
 
@@ -197,7 +405,7 @@ impl CameraController {
                         let yaw = cgmath::Deg(delta_x * sensitivity);
                         let pitch = cgmath::Deg(delta_y * sensitivity);
                         let direction = (camera.focus - camera.origin).normalize();
-                        let right = direction.cross(cgmath::Vector3::unit_y()).normalize();
+                        let right = direction.cross(world_up(direction)).normalize();
                         let up = right.cross(direction).normalize();
         
                         let yaw_rotation = cgmath::Quaternion::from_axis_angle(up, yaw);
@@ -223,7 +431,7 @@ impl CameraController {
                     let delta_y = last_y - initial_y;
         
                     let direction = (camera.focus - camera.origin).normalize();
-                    let right = direction.cross(cgmath::Vector3::unit_y()).normalize();
+                    let right = direction.cross(world_up(direction)).normalize();
                     let up = right.cross(direction).normalize();
         
                     let pan_direction_local = cgmath::Vector3::new(delta_x, delta_y, 0.0) * sensitivity;
@@ -247,7 +455,7 @@ impl CameraController {
             camera.origin -= forward_norm * self.speed;
         }
 
-        let right = forward_norm.cross(cgmath::Vector3::unit_y()).normalize();
+        let right = forward_norm.cross(world_up(forward_norm)).normalize();
 
         // Redo radius calc in case the up/ down is pressed.
         let forward = camera.focus - camera.origin;
@@ -291,3 +499,19 @@ impl CameraController {
 //         self.origin = camera.origin.into();
 //     }
 // }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looking_straight_down_has_a_basis() {
+        for focus in [[0.0, -1.0, 0.0], [0.0, 1.0, 0.0], [0.0, -1.0, 1e-4]] {
+            let camera = Camera::new(cgmath::Point3::new(0.0, 0.0, 0.0), focus.into(), 0.0, 45.0, 1.5);
+            let (corner, center) = (camera.primary_ray([0.0, 0.0]), camera.primary_ray([0.5, 0.5]));
+            assert!(corner.direction.iter().chain(&center.direction).all(|d| d.is_finite()), "{:?}", focus);
+            assert!((center.direction[1] - focus[1]).abs() < 1e-3 && corner.direction != center.direction);
+        }
+    }
+}
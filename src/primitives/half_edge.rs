@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use cgmath::{Vector3, InnerSpace};
+use crate::primitives::tri_mesh::TriMesh;
+
+// Crease sharpness of boundaries and hard edges, survives any number of subdivision levels
+pub const SHARP: f32 = f32::INFINITY;
+
+#[derive(Debug, Copy, Clone)]
+pub struct HalfEdge {
+    pub vertex: usize,
+    pub next: usize,
+    pub twin: Option<usize>,
+    pub face: usize,
+    pub uv: [f32; 2],
+    pub sharpness: f32,
+}
+
+#[derive(Debug, Clone)]
+pub struct HalfEdgeMesh {
+    pub positions: Vec<[f32; 3]>,
+    pub half_edges: Vec<HalfEdge>,
+    pub faces: Vec<usize>,
+    pub material_index: usize,
+}
+
+impl HalfEdgeMesh {
+    // Polygons index into `positions`, corner uvs and sharpness are given per polygon corner,
+    // the sharpness of corner i applies to the edge leaving it
+    pub fn from_polygons(
+        positions: Vec<[f32; 3]>,
+        polygons: &[Vec<usize>],
+        uvs: &[Vec<[f32; 2]>],
+        sharpness: &[Vec<f32>],
+        material_index: usize,
+    ) -> Self {
+        let mut half_edges = Vec::with_capacity(polygons.len() * 4);
+        let mut faces = Vec::with_capacity(polygons.len());
+        let mut edge_map: HashMap<(usize, usize), usize> = HashMap::new();
+
+        for (face, polygon) in polygons.iter().enumerate() {
+            let first = half_edges.len();
+            let n = polygon.len();
+            for i in 0..n {
+                half_edges.push(HalfEdge {
+                    vertex: polygon[i],
+                    next: first + (i + 1) % n,
+                    twin: None,
+                    face,
+                    uv: uvs[face][i],
+                    sharpness: sharpness[face][i],
+                });
+            }
+            faces.push(first);
+        }
+
+        // Pair twins, edges shared by more than two faces are left as boundaries
+        for h in 0..half_edges.len() {
+            let from = half_edges[h].vertex;
+            let to = half_edges[half_edges[h].next].vertex;
+            if let Some(&twin) = edge_map.get(&(to, from)) {
+                if half_edges[twin].twin.is_none() {
+                    half_edges[twin].twin = Some(h);
+                    half_edges[h].twin = Some(twin);
+                    let sharpness = half_edges[h].sharpness.max(half_edges[twin].sharpness);
+                    half_edges[h].sharpness = sharpness;
+                    half_edges[twin].sharpness = sharpness;
+                    continue;
+                }
+            }
+            edge_map.entry((from, to)).or_insert(h);
+        }
+
+        HalfEdgeMesh { positions, half_edges, faces, material_index }
+    }
+
+    // Welds vertices by position, hard normal seams become sharp creases.
+    // With `recover_quads`, consecutive triangle pairs sharing their longest edge are merged back into quads.
+    pub fn from_tri_mesh(mesh: &TriMesh, recover_quads: bool) -> Self {
+        let mut positions = Vec::new();
+        let mut welded = Vec::with_capacity(mesh.vertices.len());
+        let mut lookup: HashMap<[u32; 3], usize> = HashMap::new();
+        for v in &mesh.vertices {
+            let key = [v[0].to_bits(), v[1].to_bits(), v[2].to_bits()];
+            let index = *lookup.entry(key).or_insert_with(|| {
+                positions.push(*v);
+                positions.len() - 1
+            });
+            welded.push(index);
+        }
+
+        let uv = |i: u32| mesh.uvs.get(i as usize).copied().unwrap_or([0.0, 0.0]);
+        let triangles: Vec<[u32; 3]> = mesh.indices.chunks(3).map(|c| [c[0], c[1], c[2]]).collect();
+
+        // Corners keep their unwelded index so normals can be compared afterwards
+        let mut corners: Vec<Vec<u32>> = Vec::with_capacity(triangles.len());
+        let mut i = 0;
+        while i < triangles.len() {
+            if recover_quads && i + 1 < triangles.len() {
+                if let Some(quad) = merge_quad(&positions, &welded, triangles[i], triangles[i + 1]) {
+                    corners.push(quad.to_vec());
+                    i += 2;
+                    continue;
+                }
+            }
+            corners.push(triangles[i].to_vec());
+            i += 1;
+        }
+
+        let polygons: Vec<Vec<usize>> = corners.iter().map(|c| c.iter().map(|&i| welded[i as usize]).collect()).collect();
+        let uvs: Vec<Vec<[f32; 2]>> = corners.iter().map(|c| c.iter().map(|&i| uv(i)).collect()).collect();
+        let sharpness: Vec<Vec<f32>> = corners.iter().map(|c| vec![0.0; c.len()]).collect();
+        let mut he_mesh = HalfEdgeMesh::from_polygons(positions, &polygons, &uvs, &sharpness, mesh.material_index);
+
+        if !mesh.normals.is_empty() {
+            let corner_normals: Vec<u32> = corners.iter().flatten().copied().collect();
+            let normal = |h: usize| Vector3::from(mesh.normals[corner_normals[h] as usize]);
+            for h in 0..he_mesh.half_edges.len() {
+                if let Some(twin) = he_mesh.half_edges[h].twin {
+                    let next = he_mesh.half_edges[h].next;
+                    let twin_next = he_mesh.half_edges[twin].next;
+                    let split = normal(h).dot(normal(twin_next)) < 0.999 || normal(next).dot(normal(twin)) < 0.999;
+                    if split {
+                        he_mesh.half_edges[h].sharpness = SHARP;
+                    }
+                }
+            }
+        }
+        he_mesh
+    }
+
+    pub fn face_half_edges(&self, face: usize) -> Vec<usize> {
+        let first = self.faces[face];
+        let mut out = vec![first];
+        let mut h = self.half_edges[first].next;
+        while h != first {
+            out.push(h);
+            h = self.half_edges[h].next;
+        }
+        out
+    }
+
+    pub fn face_vertices(&self, face: usize) -> Vec<usize> {
+        self.face_half_edges(face).iter().map(|&h| self.half_edges[h].vertex).collect()
+    }
+
+    pub fn is_triangulated(&self) -> bool {
+        (0..self.faces.len()).all(|f| self.face_half_edges(f).len() == 3)
+    }
+
+    // Undirected edge id shared by a half-edge and its twin
+    pub fn edge_id(&self, h: usize) -> usize {
+        match self.half_edges[h].twin {
+            Some(twin) => h.min(twin),
+            None => h,
+        }
+    }
+
+    pub fn dest(&self, h: usize) -> usize {
+        self.half_edges[self.half_edges[h].next].vertex
+    }
+
+    pub fn prev(&self, h: usize) -> usize {
+        let mut p = h;
+        while self.half_edges[p].next != h {
+            p = self.half_edges[p].next;
+        }
+        p
+    }
+
+    // Boundaries are always sharp
+    pub fn edge_sharpness(&self, h: usize) -> f32 {
+        match self.half_edges[h].twin {
+            Some(_) => self.half_edges[h].sharpness,
+            None => SHARP,
+        }
+    }
+
+    pub fn set_crease(&mut self, a: usize, b: usize, sharpness: f32) {
+        for h in 0..self.half_edges.len() {
+            let from = self.half_edges[h].vertex;
+            let to = self.dest(h);
+            if (from == a && to == b) || (from == b && to == a) {
+                self.half_edges[h].sharpness = sharpness;
+            }
+        }
+    }
+
+    pub fn face_centroid(&self, face: usize) -> Vector3<f32> {
+        let vertices = self.face_vertices(face);
+        let sum = vertices.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, &v| acc + Vector3::from(self.positions[v]));
+        sum / vertices.len() as f32
+    }
+
+    // Newell's method, the length is twice the polygon area
+    pub fn face_normal(&self, face: usize) -> Vector3<f32> {
+        let vertices = self.face_vertices(face);
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        for i in 0..vertices.len() {
+            let a = Vector3::from(self.positions[vertices[i]]);
+            let b = Vector3::from(self.positions[vertices[(i + 1) % vertices.len()]]);
+            normal += (a - b).cross(a + b) * -1.0;
+        }
+        normal
+    }
+
+    // Averages face normals around the corner's vertex without crossing hard edges
+    fn corner_normal(&self, h: usize, face_normals: &[Vector3<f32>]) -> Vector3<f32> {
+        let mut normal = face_normals[self.half_edges[h].face];
+
+        // Rotate across the incoming edge
+        let mut current = h;
+        loop {
+            let incoming = self.prev(current);
+            if self.edge_sharpness(incoming) >= 1.0 {
+                break;
+            }
+            let twin = self.half_edges[incoming].twin.unwrap();
+            if twin == h {
+                return normal.normalize();
+            }
+            normal += face_normals[self.half_edges[twin].face];
+            current = twin;
+            if current == h {
+                return normal.normalize();
+            }
+        }
+
+        // Rotate across the outgoing edge until the other side of the fan
+        let mut current = h;
+        loop {
+            if self.edge_sharpness(current) >= 1.0 {
+                break;
+            }
+            let twin = self.half_edges[current].twin.unwrap();
+            let next = self.half_edges[twin].next;
+            if next == h {
+                break;
+            }
+            normal += face_normals[self.half_edges[next].face];
+            current = next;
+        }
+        normal.normalize()
+    }
+
+    pub fn to_tri_mesh(&self) -> TriMesh {
+        let face_normals: Vec<Vector3<f32>> = (0..self.faces.len()).map(|f| self.face_normal(f)).collect();
+        let mut mesh = TriMesh::new();
+        mesh.material_index = self.material_index;
+        let mut lookup: HashMap<(usize, [u32; 5]), u32> = HashMap::new();
+
+        for face in 0..self.faces.len() {
+            let corners: Vec<u32> = self.face_half_edges(face).iter().map(|&h| {
+                let vertex = self.half_edges[h].vertex;
+                let mut normal = self.corner_normal(h, &face_normals);
+                if normal.x.is_nan() {
+                    normal = Vector3::new(0.0, 1.0, 0.0);
+                }
+                let uv = self.half_edges[h].uv;
+                let key = (vertex, [normal.x.to_bits(), normal.y.to_bits(), normal.z.to_bits(), uv[0].to_bits(), uv[1].to_bits()]);
+                *lookup.entry(key).or_insert_with(|| {
+                    mesh.vertices.push(self.positions[vertex]);
+                    mesh.normals.push(normal.into());
+                    mesh.uvs.push(uv);
+                    (mesh.vertices.len() - 1) as u32
+                })
+            }).collect();
+
+            // Fan triangulation, subdivided faces are convex quads or quads with T-junction vertices
+            for i in 1..corners.len() - 1 {
+                mesh.indices.extend_from_slice(&[corners[0], corners[i], corners[i + 1]]);
+            }
+        }
+        mesh
+    }
+}
+
+fn merge_quad(positions: &[[f32; 3]], welded: &[usize], t0: [u32; 3], t1: [u32; 3]) -> Option<[u32; 4]> {
+    let p = |i: u32| Vector3::from(positions[welded[i as usize]]);
+    let w = |i: u32| welded[i as usize];
+    let longest = |t: [u32; 3]| (0..3).max_by(|&a, &b| {
+        let la = (p(t[a]) - p(t[(a + 1) % 3])).magnitude2();
+        let lb = (p(t[b]) - p(t[(b + 1) % 3])).magnitude2();
+        la.partial_cmp(&lb).unwrap_or(std::cmp::Ordering::Equal)
+    }).unwrap();
+
+    let i = longest(t0);
+    let j = longest(t1);
+    let (a, b) = (t0[i], t0[(i + 1) % 3]);
+    let (c, d) = (t1[j], t1[(j + 1) % 3]);
+    if w(a) != w(d) || w(b) != w(c) {
+        return None;
+    }
+
+    let n0 = (p(t0[1]) - p(t0[0])).cross(p(t0[2]) - p(t0[0]));
+    let n1 = (p(t1[1]) - p(t1[0])).cross(p(t1[2]) - p(t1[0]));
+    if n0.normalize().dot(n1.normalize()) < 0.95 {
+        return None;
+    }
+
+    // Insert the opposite vertex of t1 between the shared diagonal's endpoints
+    let opposite = t1[(j + 2) % 3];
+    Some([a, opposite, b, t0[(i + 2) % 3]])
+}
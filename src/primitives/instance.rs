@@ -0,0 +1,103 @@
+use cgmath::{Matrix4, SquareMatrix};
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::primitives::motion::{self, Pose};
+
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Instance {
+    object_to_world: [[f32; 4]; 4],
+    world_to_object: [[f32; 4]; 4],
+    blas_root: i32,
+    // Nonzero when the instance moves over the shutter, the poses replace the matrices
+    moving: u32,
+    _padding: [u32; 2],
+    // Poses at shutter open and close, see `Pose::to_gpu`
+    motion: [[f32; 4]; 6],
+}
+
+
+#[derive(Copy, Clone, Debug)]
+pub struct InstanceCPU {
+    pub mesh: usize,
+    // Position in the list the top level was built from, which it reorders
+    pub id: usize,
+    pub transform: Matrix4<f32>,
+    // Poses at shutter open and close, `transform` is the open one
+    motion: Option<[Pose; 2]>,
+    bbox: AABB,
+    centroid: [f32; 3],
+    bbox_surface_area: f32,
+}
+
+impl InstanceCPU {
+    // `blas_bounds` are the object space bounds of the mesh's bottom level BVH
+    pub fn new(mesh: usize, transform: Matrix4<f32>, blas_bounds: &AABB) -> Self {
+        let mut instance = Self {
+            mesh,
+            id: 0,
+            transform,
+            motion: None,
+            bbox: AABB::empty(),
+            centroid: [0.0; 3],
+            bbox_surface_area: 0.0,
+        };
+        instance.set_bounds(blas_bounds);
+        instance
+    }
+
+    // Moves to new transforms at shutter open and close, the bounds follow on `set_bounds`
+    pub fn place(&mut self, open: Matrix4<f32>, close: Matrix4<f32>) {
+        self.transform = open;
+        self.motion = if open == close { None } else { Some([Pose::from_matrix(&open), Pose::from_matrix(&close)]) };
+    }
+
+    // After the bottom level BVH changed shape
+    pub fn set_bounds(&mut self, blas_bounds: &AABB) {
+        self.bbox = match &self.motion {
+            Some([open, close]) => motion::motion_bounds(blas_bounds, open, close),
+            None => blas_bounds.transform(&self.transform),
+        };
+        self.centroid = self.bbox.centroid();
+        self.bbox_surface_area = self.bbox.surface_area();
+    }
+
+    // Object to world at `time` in the shutter, 0 at open and 1 at close
+    pub fn transform_at(&self, time: f32) -> Matrix4<f32> {
+        match &self.motion {
+            Some([open, close]) => open.lerp(close, time).matrix(),
+            None => self.transform,
+        }
+    }
+
+    pub fn to_gpu(&self, blas_root: i32) -> Instance {
+        let inverse = self.transform.invert().unwrap_or_else(Matrix4::identity);
+        let mut motion = [[0.0; 4]; 6];
+        if let Some([open, close]) = &self.motion {
+            motion[..3].copy_from_slice(&open.to_gpu());
+            motion[3..].copy_from_slice(&close.to_gpu());
+        }
+        Instance {
+            object_to_world: self.transform.into(),
+            world_to_object: inverse.into(),
+            blas_root,
+            moving: self.motion.is_some() as u32,
+            _padding: [0; 2],
+            motion,
+        }
+    }
+}
+
+impl Bounded for InstanceCPU {
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> AABB {
+        self.bbox
+    }
+
+    fn centroid(&self) -> [f32; 3] {
+        self.centroid
+    }
+
+    fn bbox_surface_area(&self) -> f32 {
+        self.bbox_surface_area
+    }
+}
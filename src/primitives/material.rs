@@ -7,7 +7,8 @@ pub struct Material {
     pub metallic: f32,
     pub refract: f32,
     pub ior: f32,
-    _padding: [u32; 3],
+    pub medium: i32,
+    _padding: [u32; 2],
 }
 
 impl Material {
@@ -26,7 +27,14 @@ impl Material {
             metallic,
             refract,
             ior,
-            _padding: [0; 3],
+            medium: -1,
+            _padding: [0; 2],
         }
     }
+
+    // Turns the surface into the boundary of a participating medium
+    pub fn with_medium(mut self, medium: i32) -> Self {
+        self.medium = medium;
+        self
+    }
 }
\ No newline at end of file
@@ -0,0 +1,265 @@
+use crate::primitives::aabb::AABB;
+use crate::primitives::texture::Texture;
+
+
+pub const MAX_MEDIA: usize = 8;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Medium {
+    pub sigma_a: [f32; 4],
+    pub sigma_s: [f32; 4],
+    pub bounds: AABB,
+    grid_origin: [f32; 4],
+    grid_scale: [f32; 4],
+    pub anisotropy: f32,
+    pub density: f32,
+    grid: i32,
+    _padding: f32,
+}
+
+impl Medium {
+    pub fn homogeneous(sigma_a: [f32; 3], sigma_s: [f32; 3], anisotropy: f32, density: f32) -> Self {
+        Medium {
+            sigma_a: [sigma_a[0], sigma_a[1], sigma_a[2], 0.0],
+            sigma_s: [sigma_s[0], sigma_s[1], sigma_s[2], 0.0],
+            bounds: AABB::new([-1e6, -1e6, -1e6, 0.0], [1e6, 1e6, 1e6, 0.0]),
+            grid_origin: [0.0; 4],
+            grid_scale: [0.0; 4],
+            anisotropy,
+            density,
+            grid: -1,
+            _padding: 0.0,
+        }
+    }
+
+    pub fn heterogeneous(sigma_a: [f32; 3], sigma_s: [f32; 3], anisotropy: f32, density: f32, grid: usize) -> Self {
+        Medium {
+            grid: grid as i32,
+            ..Medium::homogeneous(sigma_a, sigma_s, anisotropy, density)
+        }
+    }
+
+    pub fn fog(color: [f32; 3], density: f32, anisotropy: f32, bounds: AABB) -> Self {
+        Medium {
+            bounds,
+            ..Medium::homogeneous([0.0; 3], color, anisotropy, density)
+        }
+    }
+
+    pub fn is_heterogeneous(&self) -> bool {
+        self.grid >= 0
+    }
+}
+
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MediaUniform {
+    pub fog: i32,
+    pub count: u32,
+    _padding: [u32; 2],
+    pub media: [Medium; MAX_MEDIA],
+}
+
+impl MediaUniform {
+    pub fn new() -> Self {
+        Self {
+            fog: -1,
+            count: 0,
+            _padding: [0; 2],
+            media: [Medium::homogeneous([0.0; 3], [0.0; 3], 0.0, 0.0); MAX_MEDIA],
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct DensityGrid {
+    pub resolution: [u32; 3],
+    pub bounds: AABB,
+    pub data: Vec<f32>,
+    max: f32,
+}
+
+impl DensityGrid {
+    pub fn new(resolution: [u32; 3], bounds: AABB, data: Vec<f32>) -> Self {
+        assert_eq!(data.len(), (resolution[0] * resolution[1] * resolution[2]) as usize, "grid data does not match resolution");
+        let max = data.iter().fold(0.0f32, |acc, &d| acc.max(d));
+        Self { resolution, bounds, data, max }
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    pub fn at(&self, x: u32, y: u32, z: u32) -> f32 {
+        self.data[((z * self.resolution[1] + y) * self.resolution[0] + x) as usize]
+    }
+}
+
+
+pub struct Media {
+    media: Vec<Medium>,
+    grids: Vec<DensityGrid>,
+    // Fills the space outside every surface, stored after the other media
+    fog: Option<Medium>,
+}
+
+impl Media {
+    pub fn new() -> Self {
+        Media {
+            media: Vec::new(),
+            grids: Vec::new(),
+            fog: None,
+        }
+    }
+
+    // Returns the index to store in `Material::medium`, None once MAX_MEDIA are used
+    pub fn add(&mut self, medium: Medium) -> Option<i32> {
+        if self.len() >= MAX_MEDIA {
+            return None;
+        }
+        self.media.push(medium);
+        Some((self.media.len() - 1) as i32)
+    }
+
+    pub fn add_grid(&mut self, grid: DensityGrid) -> usize {
+        self.grids.push(grid);
+        self.grids.len() - 1
+    }
+
+    // Replaces the fog, None clears it. Returns false when a new fog finds every slot used.
+    pub fn set_fog(&mut self, fog: Option<Medium>) -> bool {
+        if fog.is_some() && self.fog.is_none() && self.len() >= MAX_MEDIA {
+            return false;
+        }
+        self.fog = fog;
+        true
+    }
+
+    // Media the volume pass tracks through, the fog included
+    pub fn len(&self) -> usize {
+        self.media.len() + self.fog.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn media(&self) -> &Vec<Medium> {
+        &self.media
+    }
+
+    pub fn grids(&self) -> &Vec<DensityGrid> {
+        &self.grids
+    }
+
+    // Grids are stacked along z in a single 3D texture with an empty slice between them
+    fn atlas_layout(&self) -> ([u32; 3], Vec<u32>) {
+        let mut size = [1, 1, 0];
+        let mut offsets = Vec::with_capacity(self.grids.len());
+        for grid in &self.grids {
+            size[0] = size[0].max(grid.resolution[0]);
+            size[1] = size[1].max(grid.resolution[1]);
+            offsets.push(size[2]);
+            size[2] += grid.resolution[2] + 1;
+        }
+        size[2] = size[2].max(1);
+        (size, offsets)
+    }
+
+    pub fn to_uniform(&self) -> MediaUniform {
+        let (size, offsets) = self.atlas_layout();
+        let mut uniform = MediaUniform::new();
+        uniform.fog = if self.fog.is_some() { self.media.len() as i32 } else { -1 };
+        uniform.count = self.len() as u32;
+
+        for (i, medium) in self.media.iter().chain(&self.fog).enumerate() {
+            let mut medium = *medium;
+            if medium.is_heterogeneous() {
+                let grid_index = medium.grid as usize;
+                let grid = self.grids.get(grid_index).expect("Medium references a missing density grid");
+                let res = grid.resolution;
+                medium.bounds = grid.bounds;
+                medium.grid_origin = [0.0, 0.0, offsets[grid_index] as f32 / size[2] as f32, 0.0];
+                medium.grid_scale = [
+                    res[0] as f32 / size[0] as f32,
+                    res[1] as f32 / size[1] as f32,
+                    res[2] as f32 / size[2] as f32,
+                    0.0,
+                ];
+                // Texture stores density normalized to the grid max, fold it back into the scale
+                medium.density *= grid.max();
+            }
+            uniform.media[i] = medium;
+        }
+        uniform
+    }
+
+    pub fn to_texture(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+        let (size, offsets) = self.atlas_layout();
+        let mut texels = vec![0u8; (size[0] * size[1] * size[2]) as usize];
+
+        for (grid, offset) in self.grids.iter().zip(offsets) {
+            let scale = if grid.max() > 0.0 { 255.0 / grid.max() } else { 0.0 };
+            for z in 0..grid.resolution[2] {
+                for y in 0..grid.resolution[1] {
+                    for x in 0..grid.resolution[0] {
+                        let index = (((offset + z) * size[1] + y) * size[0] + x) as usize;
+                        texels[index] = (grid.at(x, y, z) * scale).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            }
+        }
+
+        let extent = wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: size[2],
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Density Grid Atlas"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &texels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(size[0]),
+                rows_per_image: Some(size[1]),
+            },
+            extent,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Texture {
+            texture,
+            view,
+            sampler,
+        }
+    }
+}
@@ -9,4 +9,10 @@ pub mod pixel_buffer;
 pub mod lights;
 pub mod texture;
 pub mod tri_mesh;
-pub mod aabb;
\ No newline at end of file
+pub mod aabb;
+pub mod medium;
+pub mod half_edge;
+pub mod instance;
+pub mod vertex;
+pub mod sdf_shape;
+pub mod motion;
\ No newline at end of file
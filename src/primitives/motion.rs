@@ -0,0 +1,99 @@
+use cgmath::{InnerSpace, Matrix3, Matrix4, Quaternion, SquareMatrix, Vector3, Zero};
+use crate::primitives::aabb::AABB;
+
+// Poses sampled across the shutter for motion bounds, as MOTION_BOUND_STEPS in structs.wgsl
+pub const MOTION_BOUND_STEPS: usize = 8;
+
+
+// Translation, rotation and scale of an instance, applied scale first
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Pose {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Pose {
+    pub fn new(translation: Vector3<f32>, rotation: Quaternion<f32>, scale: Vector3<f32>) -> Self {
+        Self { translation, rotation: rotation.normalize(), scale }
+    }
+
+    #[allow(dead_code)]
+    pub fn identity() -> Self {
+        Self::new(Vector3::zero(), Quaternion::new(1.0, 0.0, 0.0, 0.0), Vector3::new(1.0, 1.0, 1.0))
+    }
+
+    // Shear is dropped, a mirroring matrix gets a negative x scale
+    pub fn from_matrix(matrix: &Matrix4<f32>) -> Self {
+        let (x, y, z) = (matrix.x.truncate(), matrix.y.truncate(), matrix.z.truncate());
+        let sign = if matrix.determinant() < 0.0 { -1.0 } else { 1.0 };
+        let scale = Vector3::new(sign * x.magnitude(), y.magnitude(), z.magnitude());
+        let rotation = Matrix3::from_cols(x / scale.x, y / scale.y, z / scale.z);
+        Self::new(matrix.w.truncate(), Quaternion::from(rotation), scale)
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    // Linear in translation and scale, the rotation moves along the shorter arc. Normalized
+    // rather than spherical interpolation, as motion.wgsl does.
+    pub fn lerp(&self, other: &Pose, u: f32) -> Pose {
+        let target = if self.rotation.dot(other.rotation) < 0.0 { -other.rotation } else { other.rotation };
+        Pose {
+            translation: self.translation + (other.translation - self.translation) * u,
+            rotation: (self.rotation * (1.0 - u) + target * u).normalize(),
+            scale: self.scale + (other.scale - self.scale) * u,
+        }
+    }
+
+    // Translation, rotation as xyzw and scale, the layout of a pose in Instance
+    pub fn to_gpu(&self) -> [[f32; 4]; 3] {
+        [
+            [self.translation.x, self.translation.y, self.translation.z, 0.0],
+            [self.rotation.v.x, self.rotation.v.y, self.rotation.v.z, self.rotation.s],
+            [self.scale.x, self.scale.y, self.scale.z, 0.0],
+        ]
+    }
+}
+
+
+// Bounds of `bounds` carried from pose `open` to `close`, the union of poses sampled across the
+// shutter. Each sample is grown by how far any point can stray before the next one, so the box
+// stays inside at every time between.
+pub fn motion_bounds(bounds: &AABB, open: &Pose, close: &Pose) -> AABB {
+    // Farthest corner from the object space origin, rotation and scale move points around it
+    let reach = (0..8)
+        .map(|i| {
+            let corner = Vector3::new(
+                if i & 1 != 0 { bounds.max[0] } else { bounds.min[0] },
+                if i & 2 != 0 { bounds.max[1] } else { bounds.min[1] },
+                if i & 4 != 0 { bounds.max[2] } else { bounds.min[2] },
+            );
+            corner.magnitude()
+        })
+        .fold(0.0, f32::max);
+
+    let mut union = AABB::empty();
+    for step in 0..=MOTION_BOUND_STEPS {
+        let pose = open.lerp(close, step as f32 / MOTION_BOUND_STEPS as f32);
+        let next = open.lerp(close, (step + 1).min(MOTION_BOUND_STEPS) as f32 / MOTION_BOUND_STEPS as f32);
+        let stray = pose_stray(&pose, &next, reach);
+        let sample = bounds.transform(&pose.matrix());
+        union = union.union(&AABB::new(
+            [sample.min[0] - stray, sample.min[1] - stray, sample.min[2] - stray, 0.0],
+            [sample.max[0] + stray, sample.max[1] + stray, sample.max[2] + stray, 0.0],
+        ));
+    }
+    union
+}
+
+// Farthest a point within `reach` of the object space origin moves between poses `a` and `b`,
+// the rotation adds at most the arc it sweeps
+fn pose_stray(a: &Pose, b: &Pose, reach: f32) -> f32 {
+    let angle = 2.0 * a.rotation.dot(b.rotation).abs().min(1.0).acos();
+    let scale = a.scale.x.abs().max(a.scale.y.abs()).max(a.scale.z.abs());
+    (b.translation - a.translation).magnitude() + (b.scale - a.scale).magnitude() * reach + angle * scale * reach
+}
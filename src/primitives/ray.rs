@@ -6,12 +6,28 @@ use cgmath::prelude::*;
 
 const MAX_SIZE: usize = 1920 * 1080;
 
+// Farthest distance of an unbounded ray, as RAY_T_MAX in the shaders
+pub const T_MAX: f32 = 1e30;
+
+// Offsets from Waechter and Binder, Ray Tracing Gems chapter 6. Points are moved a fixed number of
+// float steps, which grows with their magnitude like the error of computing them, except close to
+// the origin where a small absolute offset is used instead.
+const OFFSET_ORIGIN: f32 = 1.0 / 32.0;
+const OFFSET_FLOAT_SCALE: f32 = 1.0 / 65536.0;
+const OFFSET_INT_SCALE: f32 = 256.0;
+
+
+// Hits count only within (t_min, t_max), laid out like Ray in the shaders
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Ray {
     pub origin: [f32; 3],
+    pub t_min: f32,
     pub direction: [f32; 3],
-    _padding: [f32; 2],
+    pub t_max: f32,
+    // Within the shutter, 0 at open and 1 at close, for moving instances and cameras
+    pub time: f32,
+    _padding: [f32; 3],
 }
 
 impl Ray {
@@ -19,20 +35,76 @@ impl Ray {
         let o  = cgmath::Vector3::new(0.0, 0.0, 0.0);
         let d = (cgmath::Vector3::new(0.0, 0.0, 1.0) - o).normalize();
 
-        Self {
-            origin: [o.x, o.y, o.z],
-            direction: [d.x, d.y, d.z],
-            _padding: [0f32; 2] 
-        }
+        Self::new([o.x, o.y, o.z], [d.x, d.y, d.z])
     } 
 
     pub fn new(origin: [f32; 3], direction: [f32; 3]) -> Self {
+        Self::with_interval(origin, direction, 0.0, T_MAX)
+    }
+
+    pub fn with_interval(origin: [f32; 3], direction: [f32; 3], t_min: f32, t_max: f32) -> Self {
         Self {
             origin,
+            t_min,
             direction,
-            _padding: [0f32; 2] 
+            t_max,
+            time: 0.0,
+            _padding: [0.0; 3],
+        }
+    }
+
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
+    }
+
+    // Leaves a surface hit at `p`, starting on the side of the geometric normal the ray heads to
+    pub fn spawn(p: [f32; 3], geometric_normal: [f32; 3], direction: [f32; 3]) -> Self {
+        Self::new(offset_origin(p, facing(geometric_normal, direction)), direction)
+    }
+
+    // Segment between two surface points, both offset off their surfaces, for visibility tests
+    pub fn spawn_to(p: [f32; 3], geometric_normal: [f32; 3], target: [f32; 3], target_normal: [f32; 3]) -> Self {
+        let to_target = [target[0] - p[0], target[1] - p[1], target[2] - p[2]];
+        let origin = offset_origin(p, facing(geometric_normal, to_target));
+        let back = [-to_target[0], -to_target[1], -to_target[2]];
+        let end = offset_origin(target, facing(target_normal, back));
+        let direction = cgmath::Vector3::new(end[0] - origin[0], end[1] - origin[1], end[2] - origin[2]);
+        let distance = direction.magnitude();
+        Self::with_interval(origin, (direction / distance).into(), 0.0, distance)
+    }
+
+    pub fn at(&self, t: f32) -> [f32; 3] {
+        [
+            self.origin[0] + self.direction[0] * t,
+            self.origin[1] + self.direction[1] * t,
+            self.origin[2] + self.direction[2] * t,
+        ]
+    }
+}
+
+// Moves `p` off its surface along `n`, as offset_ray_origin in the shaders
+pub fn offset_origin(p: [f32; 3], n: [f32; 3]) -> [f32; 3] {
+    let mut out = [0.0; 3];
+    for axis in 0..3 {
+        if p[axis].abs() < OFFSET_ORIGIN {
+            out[axis] = p[axis] + OFFSET_FLOAT_SCALE * n[axis];
+        } else {
+            let offset = (OFFSET_INT_SCALE * n[axis]) as i32;
+            let offset = if p[axis] < 0.0 { -offset } else { offset };
+            out[axis] = f32::from_bits((p[axis].to_bits() as i32).wrapping_add(offset) as u32);
         }
     }
+    out
+}
+
+// The normal flipped onto the side of `direction`
+fn facing(n: [f32; 3], direction: [f32; 3]) -> [f32; 3] {
+    if n[0] * direction[0] + n[1] * direction[1] + n[2] * direction[2] < 0.0 {
+        [-n[0], -n[1], -n[2]]
+    } else {
+        n
+    }
 }
 
 #[repr(C)]
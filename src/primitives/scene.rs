@@ -1,6 +1,12 @@
-use crate::primitives::sphere::Sphere;
-use crate::primitives::triangle::Triangle;
+use cgmath::{Vector3, InnerSpace};
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::primitives::triangle::{TriangleCPU, hit_watertight};
+use crate::primitives::ray::Ray;
+use crate::primitives::vertex::Vertex;
+use crate::primitives::tri_mesh::TriMesh;
 use crate::primitives::camera::CameraUniform;
+use crate::primitives::medium::MediaUniform;
+use crate::primitives::sdf_shape::{SdfNode, SdfGrids};
 use wgpu::util::DeviceExt;
 use rand::random;
 
@@ -10,6 +16,7 @@ use rand::random;
 pub struct Scene {
     pub config: RenderConfig,
     pub camera: CameraUniform,
+    pub media: MediaUniform,
 }
 
 impl Scene {
@@ -17,6 +24,7 @@ impl Scene {
         Scene {
             config: RenderConfig::default(),
             camera: CameraUniform::new(),
+            media: MediaUniform::new(),
         }
     }
 
@@ -24,6 +32,7 @@ impl Scene {
         Scene {
             config,
             camera,
+            media: MediaUniform::new(),
         }
     }
 
@@ -38,8 +47,9 @@ impl Scene {
     pub fn to_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
         let config_bytes = bytemuck::bytes_of(&self.config);
         let camera_bytes = bytemuck::bytes_of(&self.camera);
+        let media_bytes = bytemuck::bytes_of(&self.media);
         // let objects_bytes = bytemuck::cast_slice(&self.objects);
-        let buffer_contents = [config_bytes, camera_bytes].concat();
+        let buffer_contents = [config_bytes, camera_bytes, media_bytes].concat();
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Scene Buffer"),
             contents: &buffer_contents,
@@ -55,8 +65,9 @@ impl Scene {
         }
         let config_bytes = bytemuck::bytes_of(&self.config);
         let camera_bytes = bytemuck::bytes_of(&self.camera);
+        let media_bytes = bytemuck::bytes_of(&self.media);
         // let objects_bytes = bytemuck::cast_slice(&self.objects);
-        let buffer_contents = [config_bytes, camera_bytes].concat();
+        let buffer_contents = [config_bytes, camera_bytes, media_bytes].concat();
         queue.write_buffer(buffer, 0, &buffer_contents);
     }
 }
@@ -118,65 +129,295 @@ impl RenderConfig {
     }
 }
 
+// Scene object types in place of an enum, matching structs.wgsl
+pub const TRIANGLE_TYPE: u32 = 0;
+pub const SPHERE_TYPE: u32 = 1;
+pub const PLANE_TYPE: u32 = 2;
+pub const DISK_TYPE: u32 = 4;
+pub const SDF_TYPE: u32 = 5;
+
+// Any primitive the BVH leaves can reference, `indices` point into the vertex buffer:
+//   triangle: three corners
+//   sphere: center with the radius in w
+//   plane: corner, corner + u and corner + v of a parallelogram
+//   disk: center with the radius in w and the vertex normal as the disk normal
+//   sdf: bounds min in the position and max in the normal, the first node vertex and the number
+//        of node vertices, see `SdfNode::to_vertices`
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct SceneObject {
+    indices: [u32; 3],
+    material: u32,
     object_type: u32,
-    index: u32,
+    _padding: [u32; 3],
 }
 
 impl SceneObject {
-    pub fn new(object_type: u32, index: u32) -> Self {
+    pub fn new(object_type: u32, indices: [u32; 3], material: u32) -> Self {
         Self {
+            indices,
+            material,
             object_type,
-            index,
+            _padding: [0; 3],
         }
     }
 
-    pub fn from_sphere(index: u32) -> Self {
-        Self::new(1 as u32, index)
+    pub fn object_type(&self) -> u32 {
+        self.object_type
     }
 
-    pub fn from_triangle(index: u32) -> Self {
-        Self::new(0 as u32, index)
+    pub fn indices(&self) -> [u32; 3] {
+        self.indices
     }
 
-    pub fn from_sphere_vec(spheres: &[Sphere]) -> Vec<Self> {
-        let out = spheres
-            .into_iter()
-            .enumerate()
-            .map(|(i, _)| Self::new(1 as u32, i as u32))
-            .collect();
-        out
+    pub fn material(&self) -> u32 {
+        self.material
+    }
+}
+
+
+#[derive(Copy, Clone, Debug)]
+pub struct SceneObjectCPU {
+    object: SceneObject,
+    // Outline of flat primitives for spatial split clipping, triangles repeat their last corner
+    polygon: Option<[[f32; 3]; 4]>,
+    bbox: AABB,
+    centroid: [f32; 3],
+    bbox_surface_area: f32,
+}
+
+impl SceneObjectCPU {
+    fn new(object: SceneObject, bbox: AABB, polygon: Option<[[f32; 3]; 4]>) -> Self {
+        let bbox = pad_flat(bbox);
+        Self {
+            object,
+            polygon,
+            bbox,
+            centroid: bbox.centroid(),
+            bbox_surface_area: bbox.surface_area(),
+        }
     }
 
-    pub fn from_tri_vec(triangles: &[Triangle]) -> Vec<Self> {
-        let out = triangles
-            .into_iter()
-            .enumerate()
-            .map(|(i, _)| Self::new(0 as u32, i as u32))
-            .collect();
-        out
+    pub fn from_triangle(triangle: &TriangleCPU) -> Self {
+        let object = SceneObject::new(TRIANGLE_TYPE, triangle.indices(), triangle.material());
+        let [a, b, c] = triangle.positions();
+        Self::new(object, triangle.bounding_box(0.0, 0.0), Some([a, b, c, c]))
+    }
+
+    // Triangles of a mesh whose vertices start at `offset` in the vertex buffer
+    pub fn from_tri_mesh(mesh: &TriMesh, offset: u32) -> Vec<Self> {
+        mesh.indices.chunks(3).map(|chunk| {
+            let tri = TriangleCPU::new(
+                chunk[0] + offset,
+                chunk[1] + offset,
+                chunk[2] + offset,
+                mesh.vertices[chunk[0] as usize],
+                mesh.vertices[chunk[1] as usize],
+                mesh.vertices[chunk[2] as usize],
+                mesh.material_index as u32,
+            );
+            Self::from_triangle(&tri)
+        }).collect()
+    }
+
+    pub fn sphere(vertices: &mut Vec<Vertex>, center: [f32; 3], radius: f32, material: u32) -> Self {
+        let index = vertices.len() as u32;
+        vertices.push(Vertex {
+            position: [center[0], center[1], center[2], radius],
+            normal: [0.0; 4],
+        });
+        Self::from_object(SceneObject::new(SPHERE_TYPE, [index; 3], material), vertices)
+    }
+
+    pub fn quad(vertices: &mut Vec<Vertex>, corner: [f32; 3], u: [f32; 3], v: [f32; 3], material: u32) -> Self {
+        let q = Vector3::from(corner);
+        let (u, v) = (Vector3::from(u), Vector3::from(v));
+        let normal: [f32; 3] = u.cross(v).normalize().into();
+        let index = vertices.len() as u32;
+        for p in &[q, q + u, q + v] {
+            vertices.push(Vertex::new((*p).into(), normal));
+        }
+        Self::from_object(SceneObject::new(PLANE_TYPE, [index, index + 1, index + 2], material), vertices)
+    }
+
+    pub fn disk(vertices: &mut Vec<Vertex>, center: [f32; 3], normal: [f32; 3], radius: f32, material: u32) -> Self {
+        let n = Vector3::from(normal).normalize();
+        let index = vertices.len() as u32;
+        vertices.push(Vertex {
+            position: [center[0], center[1], center[2], radius],
+            normal: [n.x, n.y, n.z, 0.0],
+        });
+        Self::from_object(SceneObject::new(DISK_TYPE, [index; 3], material), vertices)
+    }
+
+    // Distance field of `nodes` joined in order, sphere traced within its bounds
+    pub fn sdf(vertices: &mut Vec<Vertex>, nodes: &[SdfNode], grids: &SdfGrids, material: u32) -> Self {
+        let mut bounds = AABB::empty();
+        for node in nodes {
+            // A smooth union bulges out by at most a quarter of its blend
+            let pad = 0.25 * node.blend;
+            let b = node.bounds(grids);
+            bounds = bounds.union(&AABB::new(
+                [b.min[0] - pad, b.min[1] - pad, b.min[2] - pad, 0.0],
+                [b.max[0] + pad, b.max[1] + pad, b.max[2] + pad, 0.0],
+            ));
+        }
+        let header = vertices.len() as u32;
+        vertices.push(Vertex {
+            position: [bounds.min[0], bounds.min[1], bounds.min[2], 0.0],
+            normal: [bounds.max[0], bounds.max[1], bounds.max[2], 0.0],
+        });
+        for node in nodes {
+            vertices.extend(node.to_vertices(grids));
+        }
+        let object = SceneObject::new(SDF_TYPE, [header, header + 1, 2 * nodes.len() as u32], material);
+        Self::from_object(object, vertices)
     }
 
-    pub fn add(scene_objects: &mut Vec<SceneObject>, spheres: Option<&[Sphere]>, tris: Option<&[Triangle]>) {
-        if let Some(spheres) = spheres {
-            let mut i = scene_objects.len();
-            for sphere in spheres {
-                scene_objects.push(SceneObject::from_sphere(i as u32));
-                i += 1;
-            }
+    // Bounds from the vertex buffer the object indexes, the same as compute_refit.wgsl
+    pub fn from_object(object: SceneObject, vertices: &[Vertex]) -> Self {
+        let position = |i: u32| Vector3::new(vertices[i as usize].position[0], vertices[i as usize].position[1], vertices[i as usize].position[2]);
+        let [a, b, c] = object.indices;
+        match object.object_type {
+            SPHERE_TYPE => {
+                let (center, radius) = (position(a), vertices[a as usize].position[3]);
+                let bbox = AABB::new(
+                    [center.x - radius, center.y - radius, center.z - radius, 0.0],
+                    [center.x + radius, center.y + radius, center.z + radius, 0.0],
+                );
+                Self::new(object, bbox, None)
+            },
+            DISK_TYPE => {
+                let (center, radius) = (position(a), vertices[a as usize].position[3]);
+                let normal = vertices[a as usize].normal;
+                let n = Vector3::new(normal[0], normal[1], normal[2]).normalize();
+
+                // Extent of a disk along each axis
+                let mut min = [0.0; 4];
+                let mut max = [0.0; 4];
+                for axis in 0..3 {
+                    let extent = radius * (1.0 - n[axis] * n[axis]).max(0.0).sqrt();
+                    min[axis] = center[axis] - extent;
+                    max[axis] = center[axis] + extent;
+                }
+                Self::new(object, AABB::new(min, max), None)
+            },
+            SDF_TYPE => {
+                let header = vertices[a as usize];
+                let (min, max) = (header.position, header.normal);
+                Self::new(object, AABB::new([min[0], min[1], min[2], 0.0], [max[0], max[1], max[2], 0.0]), None)
+            },
+            _ => {
+                let (a, b, c) = (position(a), position(b), position(c));
+                let polygon = if object.object_type == PLANE_TYPE {
+                    [a.into(), b.into(), (b + c - a).into(), c.into()]
+                } else {
+                    [a.into(), b.into(), c.into(), c.into()]
+                };
+                let mut min = [0.0; 4];
+                let mut max = [0.0; 4];
+                for axis in 0..3 {
+                    min[axis] = polygon.iter().fold(f32::INFINITY, |m, p: &[f32; 3]| m.min(p[axis]));
+                    max[axis] = polygon.iter().fold(f32::NEG_INFINITY, |m, p: &[f32; 3]| m.max(p[axis]));
+                }
+                Self::new(object, AABB::new(min, max), Some(polygon))
+            },
         }
-    
-        if let Some(tris) = tris {
-            let mut i = scene_objects.len();
-            for tri in tris {
-                scene_objects.push(SceneObject::from_triangle(i as u32));
-                i += 1;
-            }
+    }
+
+    // Distance along `ray` to the object within the ray's interval, as hit_object in the shaders.
+    // Distance fields stop where the ray enters their bounds, close enough for picking and focus.
+    pub fn hit(&self, ray: &Ray, vertices: &[Vertex]) -> Option<f32> {
+        let position = |i: u32| Vector3::new(vertices[i as usize].position[0], vertices[i as usize].position[1], vertices[i as usize].position[2]);
+        let (origin, direction) = (Vector3::from(ray.origin), Vector3::from(ray.direction));
+        let within = |t: f32| if t > ray.t_min && t < ray.t_max { Some(t) } else { None };
+        let [a, b, c] = self.object.indices;
+        match self.object.object_type {
+            SPHERE_TYPE => {
+                let (center, radius) = (position(a), vertices[a as usize].position[3]);
+                let oc = origin - center;
+                let (qa, qb, qc) = (direction.dot(direction), 2.0 * oc.dot(direction), oc.dot(oc) - radius * radius);
+                let discriminant = qb * qb - 4.0 * qa * qc;
+                if discriminant < 0.0 {
+                    return None;
+                }
+                let root = discriminant.sqrt();
+                within((-qb - root) / (2.0 * qa)).or_else(|| within((-qb + root) / (2.0 * qa)))
+            },
+            PLANE_TYPE => {
+                let q = position(a);
+                let (u, v) = (position(b) - q, position(c) - q);
+                let n = u.cross(v);
+                let t = within(n.dot(q - origin) / n.dot(direction))?;
+                let planar = origin + direction * t - q;
+                let w = n / n.dot(n);
+                let (alpha, beta) = (w.dot(planar.cross(v)), w.dot(u.cross(planar)));
+                if (0.0..=1.0).contains(&alpha) && (0.0..=1.0).contains(&beta) { Some(t) } else { None }
+            },
+            DISK_TYPE => {
+                let (center, radius) = (position(a), vertices[a as usize].position[3]);
+                let normal = vertices[a as usize].normal;
+                let n = Vector3::new(normal[0], normal[1], normal[2]);
+                let t = within(n.dot(center - origin) / n.dot(direction))?;
+                let d = origin + direction * t - center;
+                if d.magnitude2() <= radius * radius { Some(t) } else { None }
+            },
+            SDF_TYPE => self.bbox.hit_distance(ray).and_then(within),
+            _ => hit_watertight(ray, &[position(a).into(), position(b).into(), position(c).into()]),
         }
     }
+
+    // Follows vertices that moved since the object was created
+    pub fn refit(&mut self, vertices: &[Vertex]) {
+        *self = Self::from_object(self.object, vertices);
+    }
+
+    // Points the object at vertices that moved within the buffer, their positions are unchanged
+    pub fn set_indices(&mut self, indices: [u32; 3]) {
+        self.object.indices = indices;
+    }
+
+    pub fn object(&self) -> SceneObject {
+        self.object
+    }
+
+    pub fn to_buffer_vec(objects: &[SceneObjectCPU]) -> Vec<SceneObject> {
+        objects.iter().map(|o| o.object).collect()
+    }
 }
 
+impl Bounded for SceneObjectCPU {
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> AABB {
+        self.bbox
+    }
+
+    fn centroid(&self) -> [f32; 3] {
+        self.centroid
+    }
 
+    fn bbox_surface_area(&self) -> f32 {
+        self.bbox_surface_area
+    }
 
+    fn clipped_bounds(&self, clip: &AABB) -> AABB {
+        let clipped = match self.polygon {
+            Some(polygon) => clip.clip_polygon(&polygon),
+            None => self.bbox.intersection(clip).unwrap_or(AABB::empty()),
+        };
+        if clipped.is_empty() {
+            return clipped;
+        }
+        pad_flat(clipped)
+    }
+}
+
+// Flat primitives get a little thickness so the slab test never sees a zero width box
+fn pad_flat(mut bbox: AABB) -> AABB {
+    for axis in 0..3 {
+        if bbox.max[axis] - bbox.min[axis] < 1e-4 {
+            bbox.min[axis] -= 1e-4;
+            bbox.max[axis] += 1e-4;
+        }
+    }
+    bbox
+}
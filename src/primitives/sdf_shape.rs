@@ -0,0 +1,204 @@
+use crate::primitives::aabb::AABB;
+use crate::primitives::medium::DensityGrid;
+use crate::primitives::texture::Texture;
+use crate::primitives::vertex::Vertex;
+
+// Shape kinds in place of an enum, matching structs.wgsl
+pub const SDF_SPHERE: u32 = 0;
+pub const SDF_BOX: u32 = 1;
+pub const SDF_TORUS: u32 = 2;
+pub const SDF_CAPSULE: u32 = 3;
+pub const SDF_GRID: u32 = 4;
+
+
+// Primitive of a distance field object, negative inside
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SdfShape {
+    Sphere { center: [f32; 3], radius: f32 },
+    // Edges rounded off by growing the box by `rounding`
+    Box { center: [f32; 3], half_extents: [f32; 3], rounding: f32 },
+    // Ring in the xz plane
+    Torus { center: [f32; 3], major: f32, minor: f32 },
+    Capsule { a: [f32; 3], b: [f32; 3], radius: f32 },
+    // Baked grid of `SdfGrids`, moved by `offset`
+    Grid { grid: usize, offset: [f32; 3] },
+}
+
+// A shape joined to the nodes before it, smoothly over `blend` for a fillet where they meet
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SdfNode {
+    pub shape: SdfShape,
+    pub blend: f32,
+}
+
+impl SdfNode {
+    pub fn new(shape: SdfShape) -> Self {
+        Self { shape, blend: 0.0 }
+    }
+
+    pub fn with_blend(mut self, blend: f32) -> Self {
+        self.blend = blend.max(0.0);
+        self
+    }
+
+    pub fn bounds(&self, grids: &SdfGrids) -> AABB {
+        let (min, max) = match self.shape {
+            SdfShape::Sphere { center, radius } => (offset(center, -radius), offset(center, radius)),
+            SdfShape::Box { center, half_extents, rounding } => {
+                let extent = half_extents.map(|h| h + rounding);
+                (sub(center, extent), add(center, extent))
+            },
+            SdfShape::Torus { center, major, minor } => {
+                let extent = [major + minor, minor, major + minor];
+                (sub(center, extent), add(center, extent))
+            },
+            SdfShape::Capsule { a, b, radius } => {
+                let (lo, hi) = ([a[0].min(b[0]), a[1].min(b[1]), a[2].min(b[2])], [a[0].max(b[0]), a[1].max(b[1]), a[2].max(b[2])]);
+                (offset(lo, -radius), offset(hi, radius))
+            },
+            SdfShape::Grid { grid, offset } => {
+                let bounds = grids.grid(grid).bounds;
+                (add([bounds.min[0], bounds.min[1], bounds.min[2]], offset), add([bounds.max[0], bounds.max[1], bounds.max[2]], offset))
+            },
+        };
+        AABB::new([min[0], min[1], min[2], 0.0], [max[0], max[1], max[2], 0.0])
+    }
+
+    // Two vertices per node, the first holds point a with the kind in w and vector b with the blend
+    // in w, the second holds the scalars in its position:
+    //   sphere: a center, radius
+    //   box: a center, b half extents, rounding
+    //   torus: a center, major and minor radius
+    //   capsule: a and b ends, radius
+    //   grid: a and b bounds, the second vertex holds the atlas offset and the resolution
+    pub fn to_vertices(&self, grids: &SdfGrids) -> [Vertex; 2] {
+        let (kind, a, b, scalars) = match self.shape {
+            SdfShape::Sphere { center, radius } => (SDF_SPHERE, center, [0.0; 3], [radius, 0.0]),
+            SdfShape::Box { center, half_extents, rounding } => (SDF_BOX, center, half_extents, [rounding, 0.0]),
+            SdfShape::Torus { center, major, minor } => (SDF_TORUS, center, [0.0; 3], [major, minor]),
+            SdfShape::Capsule { a, b, radius } => (SDF_CAPSULE, a, b, [radius, 0.0]),
+            SdfShape::Grid { grid, .. } => {
+                let bounds = self.bounds(grids);
+                let atlas = grids.atlas_offset(grid);
+                let r = grids.grid(grid).resolution;
+                return [
+                    Vertex {
+                        position: [bounds.min[0], bounds.min[1], bounds.min[2], SDF_GRID as f32],
+                        normal: [bounds.max[0], bounds.max[1], bounds.max[2], self.blend],
+                    },
+                    Vertex {
+                        position: [atlas[0] as f32, atlas[1] as f32, atlas[2] as f32, 0.0],
+                        normal: [r[0] as f32, r[1] as f32, r[2] as f32, 0.0],
+                    },
+                ];
+            },
+        };
+        [
+            Vertex { position: [a[0], a[1], a[2], kind as f32], normal: [b[0], b[1], b[2], self.blend] },
+            Vertex { position: [scalars[0], scalars[1], 0.0, 0.0], normal: [0.0; 4] },
+        ]
+    }
+}
+
+
+// Baked distance grids referenced by `SdfShape::Grid`, stacked along z in one float texture
+pub struct SdfGrids {
+    grids: Vec<DensityGrid>,
+}
+
+impl SdfGrids {
+    pub fn new() -> Self {
+        SdfGrids { grids: Vec::new() }
+    }
+
+    // Distances as from `Sdf::to_grid` or a loaded grid file, returns the index for `SdfShape::Grid`
+    pub fn add(&mut self, grid: DensityGrid) -> usize {
+        self.grids.push(grid);
+        self.grids.len() - 1
+    }
+
+    pub fn grid(&self, index: usize) -> &DensityGrid {
+        self.grids.get(index).expect("SdfShape references a missing distance grid")
+    }
+
+    fn atlas_offset(&self, index: usize) -> [u32; 3] {
+        [0, 0, self.grids[..index].iter().map(|g| g.resolution[2]).sum()]
+    }
+
+    // Texels are read without filtering, the shader interpolates within each grid
+    pub fn to_texture(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Texture {
+        let mut size = [1, 1, 0];
+        for grid in &self.grids {
+            size[0] = size[0].max(grid.resolution[0]);
+            size[1] = size[1].max(grid.resolution[1]);
+            size[2] += grid.resolution[2];
+        }
+        size[2] = size[2].max(1);
+
+        let mut texels = vec![0.0f32; (size[0] * size[1] * size[2]) as usize];
+        for (index, grid) in self.grids.iter().enumerate() {
+            let offset = self.atlas_offset(index)[2];
+            for z in 0..grid.resolution[2] {
+                for y in 0..grid.resolution[1] {
+                    for x in 0..grid.resolution[0] {
+                        texels[(((offset + z) * size[1] + y) * size[0] + x) as usize] = grid.at(x, y, z);
+                    }
+                }
+            }
+        }
+
+        let extent = wgpu::Extent3d {
+            width: size[0],
+            height: size[1],
+            depth_or_array_layers: size[2],
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Distance Grid Atlas"),
+            size: extent,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(&texels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * size[0]),
+                rows_per_image: Some(size[1]),
+            },
+            extent,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+
+        Texture {
+            texture,
+            view,
+            sampler,
+        }
+    }
+}
+
+
+fn add(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn offset(a: [f32; 3], by: f32) -> [f32; 3] {
+    [a[0] + by, a[1] + by, a[2] + by]
+}
@@ -12,4 +12,16 @@ impl Sphere {
     pub fn new(center: [f32; 3], radius: f32, material: Material) -> Self {
         Sphere {center, radius, material}
     }
+
+    pub fn center(&self) -> [f32; 3] {
+        self.center
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn material(&self) -> Material {
+        self.material
+    }
 }
\ No newline at end of file
@@ -1,9 +1,15 @@
 
+#[derive(Debug, Clone)]
 pub struct TriMesh {
     pub vertices: Vec<[f32; 3]>,
     pub indices: Vec<u32>,
     pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
     pub material_index: usize,
+    // Skin influences per vertex, empty for rigid meshes
+    pub joints: Vec<[u16; 4]>,
+    pub weights: Vec<[f32; 4]>,
+    pub morph_targets: Vec<MorphTarget>,
 }
 
 impl TriMesh {
@@ -12,7 +18,27 @@ impl TriMesh {
             vertices: Vec::new(),
             indices: Vec::new(),
             normals: Vec::new(),
+            uvs: Vec::new(),
             material_index: 0,
+            joints: Vec::new(),
+            weights: Vec::new(),
+            morph_targets: Vec::new(),
         }
     }
-}
\ No newline at end of file
+}
+
+// Offsets added to the vertices by the target's weight, vertices past the end don't move
+#[derive(Debug, Clone)]
+pub struct MorphTarget {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+}
+
+impl MorphTarget {
+    pub fn new() -> Self {
+        MorphTarget {
+            positions: Vec::new(),
+            normals: Vec::new(),
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use crate::primitives::material::Material;
-use crate::primitives::aabb::AABB;
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::primitives::ray::Ray;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -61,22 +62,26 @@ impl TriangleCPU {
         }
     }
 
-    pub fn bounding_box(&self, _t0: f32, _t1: f32) -> AABB {
-        self.bbox
+    pub fn indices(&self) -> [u32; 3] {
+        [self.a_index, self.b_index, self.c_index]
     }
 
-    pub fn centroid(&self) -> [f32; 3] {
-        self.centroid
+    pub fn positions(&self) -> [[f32; 3]; 3] {
+        [
+            [self.a[0], self.a[1], self.a[2]],
+            [self.b[0], self.b[1], self.b[2]],
+            [self.c[0], self.c[1], self.c[2]],
+        ]
+    }
+
+    pub fn material(&self) -> u32 {
+        self.material
     }
 
     pub fn surface_area(&self) -> f32 {
         self.surface_area
     }
 
-    pub fn bbox_surface_area(&self) -> f32 {
-        self.bbox_surface_area
-    }
-    
     pub fn to_buffer_vec(triangles: &Vec<TriangleCPU>) -> Vec<Triangle> {
         let mut buffer_vec = Vec::with_capacity(triangles.len());
         for triangle in triangles {
@@ -87,4 +92,109 @@ impl TriangleCPU {
         }
         buffer_vec
     }
-}
\ No newline at end of file
+}
+
+
+impl Bounded for TriangleCPU {
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> AABB {
+        self.bbox
+    }
+
+    fn centroid(&self) -> [f32; 3] {
+        self.centroid
+    }
+
+    fn bbox_surface_area(&self) -> f32 {
+        self.bbox_surface_area
+    }
+
+    fn clipped_bounds(&self, clip: &AABB) -> AABB {
+        clip.clip_polygon(&self.positions())
+    }
+}
+
+// CPU port of hit_triangle_watertight, after Woop, Benthin and Wald 2013. Vertices are sheared
+// into a space where the ray runs along +z, and each edge function is evaluated from the same two
+// vertices by both triangles sharing the edge, so their signs agree exactly and nothing slips
+// between them. Edge functions that round to zero are redone with exact products.
+pub fn hit_watertight(ray: &Ray, positions: &[[f32; 3]; 3]) -> Option<f32> {
+    let d = ray.direction;
+    let kz = if d[0].abs() > d[1].abs() {
+        if d[0].abs() > d[2].abs() { 0 } else { 2 }
+    } else if d[1].abs() > d[2].abs() { 1 } else { 2 };
+    let (mut kx, mut ky) = ((kz + 1) % 3, (kz + 2) % 3);
+    if d[kz] < 0.0 {
+        std::mem::swap(&mut kx, &mut ky);
+    }
+    let (sx, sy, sz) = (d[kx] / d[kz], d[ky] / d[kz], 1.0 / d[kz]);
+
+    let [a, b, c] = positions.map(|p| sub(p, ray.origin));
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        u = difference_of_products(cx, by, cy, bx);
+        v = difference_of_products(ax, cy, ay, cx);
+        w = difference_of_products(bx, ay, by, ax);
+    }
+    if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    if t > ray.t_min && t < ray.t_max { Some(t) } else { None }
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+// a * b - c * d with both products split exactly into a rounded value and its error, after
+// Dekker. The sign is exact, which the edge tests need once the plain difference rounds to zero.
+// functions.wgsl splits the same way but can't promise the sign, see difference_of_products there.
+fn difference_of_products(a: f32, b: f32, c: f32, d: f32) -> f32 {
+    let (ab, ab_error) = two_product(a, b);
+    let (cd, cd_error) = two_product(c, d);
+    (ab - cd) + (ab_error - cd_error)
+}
+
+fn two_product(a: f32, b: f32) -> (f32, f32) {
+    let product = a * b;
+    let ((a_hi, a_lo), (b_hi, b_lo)) = (split(a), split(b));
+    let error = ((a_hi * b_hi - product) + a_hi * b_lo + a_lo * b_hi) + a_lo * b_lo;
+    (product, error)
+}
+
+// Halves of 12 significant bits whose products are exact
+fn split(a: f32) -> (f32, f32) {
+    let hi = f32::from_bits(a.to_bits() & 0xffff_f000);
+    (hi, a - hi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn difference_of_products_has_the_exact_sign() {
+        let mut rng = StdRng::seed_from_u64(40);
+        for _ in 0..100_000 {
+            let (a, b, c): (f32, f32, f32) = (rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0));
+            // Nearly cancelling, a * b - c * d is within rounding of zero
+            let d = a * b / c;
+            let exact = a as f64 * b as f64 - c as f64 * d as f64;
+            let found = difference_of_products(a, b, c, d);
+            assert_eq!(exact.partial_cmp(&0.0), found.partial_cmp(&0.0), "{} {} {} {}", a, b, c, d);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+// Position and shading normal interleaved in one storage buffer
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Vertex {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+}
+
+impl Vertex {
+    pub fn new(position: [f32; 3], normal: [f32; 3]) -> Self {
+        Self {
+            position: [position[0], position[1], position[2], 0.0],
+            normal: [normal[0], normal[1], normal[2], 0.0],
+        }
+    }
+}
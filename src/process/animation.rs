@@ -0,0 +1,330 @@
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3, Zero};
+use crate::primitives::motion::Pose;
+use crate::primitives::tri_mesh::TriMesh;
+use crate::primitives::vertex::Vertex;
+
+
+// Node property an animation channel drives
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Property {
+    Translation,
+    Rotation,
+    Scale,
+    // Morph target weights of the node's mesh
+    Weights,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    Step,
+    // Spherical for rotations
+    Linear,
+    // Hermite spline, every key stores an in tangent, the value and an out tangent
+    CubicSpline,
+}
+
+// Keyed values of one property of one node, times in seconds
+#[derive(Debug, Clone)]
+pub struct Channel {
+    pub node: usize,
+    pub property: Property,
+    pub interpolation: Interpolation,
+    pub times: Vec<f32>,
+    // Components of every value flattened, rotations as xyzw
+    pub values: Vec<f32>,
+}
+
+impl Channel {
+    // Components per value, the morph target count for weights
+    fn width(&self) -> usize {
+        let elements = self.times.len() * if self.interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+        self.values.len() / elements.max(1)
+    }
+
+    // `part` picks the in tangent, value or out tangent of a cubic spline key
+    fn element(&self, key: usize, part: usize) -> &[f32] {
+        let width = self.width();
+        let index = if self.interpolation == Interpolation::CubicSpline { key * 3 + part } else { key };
+        &self.values[index * width..(index + 1) * width]
+    }
+
+    // Value at `time`, held before the first key and after the last
+    pub fn sample(&self, time: f32) -> Vec<f32> {
+        if self.times.is_empty() {
+            return Vec::new();
+        }
+        let next = self.times.partition_point(|&t| t <= time);
+        if next == 0 || next == self.times.len() {
+            return self.element(next.saturating_sub(1), 1).to_vec();
+        }
+
+        let key = next - 1;
+        let dt = self.times[next] - self.times[key];
+        let u = (time - self.times[key]) / dt;
+        let (a, b) = (self.element(key, 1), self.element(next, 1));
+        match self.interpolation {
+            Interpolation::Step => a.to_vec(),
+            Interpolation::Linear if self.property == Property::Rotation => {
+                let q = quaternion(a).slerp(quaternion(b), u);
+                vec![q.v.x, q.v.y, q.v.z, q.s]
+            },
+            Interpolation::Linear => a.iter().zip(b).map(|(a, b)| a + (b - a) * u).collect(),
+            Interpolation::CubicSpline => {
+                // Tangents are per second, scaled to the key interval
+                let (out_tangent, in_tangent) = (self.element(key, 2), self.element(next, 0));
+                let (u2, u3) = (u * u, u * u * u);
+                (0..a.len())
+                    .map(|i| {
+                        (2.0 * u3 - 3.0 * u2 + 1.0) * a[i]
+                            + (u3 - 2.0 * u2 + u) * dt * out_tangent[i]
+                            + (-2.0 * u3 + 3.0 * u2) * b[i]
+                            + (u3 - u2) * dt * in_tangent[i]
+                    })
+                    .collect()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub channels: Vec<Channel>,
+}
+
+impl Animation {
+    // Time of the last key of any channel
+    pub fn duration(&self) -> f32 {
+        self.channels.iter().filter_map(|channel| channel.times.last().copied()).fold(0.0, f32::max)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct SceneNode {
+    pub parent: Option<usize>,
+    // Transform relative to the parent while no animation moves the node
+    pub rest: Pose,
+    pub mesh: Option<usize>,
+    pub skin: Option<usize>,
+    // Morph target weights while no animation drives them
+    pub weights: Vec<f32>,
+}
+
+// Joints of a skin, with the inverse bind matrices taking the mesh into each joint's space
+#[derive(Debug, Clone)]
+pub struct Skin {
+    pub joints: Vec<usize>,
+    pub inverse_bind: Vec<Matrix4<f32>>,
+}
+
+// Scene graph evaluated at one time
+#[derive(Debug, Clone)]
+pub struct ScenePose {
+    // Object to world of every node
+    pub world: Vec<Matrix4<f32>>,
+    pub weights: Vec<Vec<f32>>,
+}
+
+// Node hierarchy of a glb with the skins and animations that move it
+#[derive(Debug, Clone)]
+pub struct SceneGraph {
+    pub nodes: Vec<SceneNode>,
+    pub skins: Vec<Skin>,
+    pub animations: Vec<Animation>,
+    // Nodes with parents before children
+    order: Vec<usize>,
+}
+
+impl SceneGraph {
+    pub fn new(nodes: Vec<SceneNode>, skins: Vec<Skin>, animations: Vec<Animation>) -> Self {
+        let depth = |mut node: usize| {
+            let mut depth = 0;
+            while let Some(parent) = nodes[node].parent {
+                depth += 1;
+                node = parent;
+            }
+            depth
+        };
+        let mut order: Vec<usize> = (0..nodes.len()).collect();
+        order.sort_by_key(|&node| depth(node));
+        SceneGraph { nodes, skins, animations, order }
+    }
+
+    // Nodes `animation` drives take its values at `time`, the others stay at rest. An index past
+    // the animations leaves the whole graph at rest.
+    pub fn evaluate(&self, animation: usize, time: f32) -> ScenePose {
+        let mut local: Vec<Pose> = self.nodes.iter().map(|node| node.rest).collect();
+        let mut weights: Vec<Vec<f32>> = self.nodes.iter().map(|node| node.weights.clone()).collect();
+        for channel in self.animations.get(animation).map_or(&[][..], |a| &a.channels) {
+            let value = channel.sample(time);
+            if value.is_empty() {
+                continue;
+            }
+            let pose = &mut local[channel.node];
+            match channel.property {
+                Property::Translation => pose.translation = Vector3::new(value[0], value[1], value[2]),
+                Property::Rotation => pose.rotation = quaternion(&value).normalize(),
+                Property::Scale => pose.scale = Vector3::new(value[0], value[1], value[2]),
+                Property::Weights => weights[channel.node] = value,
+            }
+        }
+
+        let mut world = vec![Matrix4::identity(); self.nodes.len()];
+        for &node in &self.order {
+            let parent = self.nodes[node].parent.map_or(Matrix4::identity(), |parent| world[parent]);
+            world[node] = parent * local[node].matrix();
+        }
+        ScenePose { world, weights }
+    }
+
+    // Vertices of `mesh` as placed by `node`, morph targets blended by the node's weights and then
+    // skinned by its skin. glTF ignores the transform of a skinned mesh's node, the skinned
+    // vertices are taken back into the node's object space so its instance transform still
+    // applies. None for rigid meshes.
+    pub fn deform(&self, mesh: &TriMesh, node: usize, pose: &ScenePose) -> Option<Vec<Vertex>> {
+        let skin = self.nodes[node].skin.and_then(|skin| self.skins.get(skin)).filter(|_| !mesh.joints.is_empty());
+        if skin.is_none() && mesh.morph_targets.is_empty() {
+            return None;
+        }
+
+        let joints: Vec<Matrix4<f32>> = skin.map_or(Vec::new(), |skin| {
+            let to_node = pose.world[node].invert().unwrap_or_else(Matrix4::identity);
+            skin.joints.iter().enumerate()
+                .map(|(i, &joint)| to_node * pose.world[joint] * skin.inverse_bind.get(i).copied().unwrap_or_else(Matrix4::identity))
+                .collect()
+        });
+        let weights = &pose.weights[node];
+
+        let vertices = mesh.vertices.iter().enumerate().map(|(i, &position)| {
+            let mut position = Vector3::from(position);
+            let mut normal = Vector3::from(mesh.normals.get(i).copied().unwrap_or([0.0, 1.0, 0.0]));
+            for (target, &weight) in mesh.morph_targets.iter().zip(weights) {
+                if let Some(offset) = target.positions.get(i) {
+                    position += Vector3::from(*offset) * weight;
+                }
+                if let Some(offset) = target.normals.get(i) {
+                    normal += Vector3::from(*offset) * weight;
+                }
+            }
+
+            if let (Some(influences), Some(influence_weights)) = (mesh.joints.get(i), mesh.weights.get(i)) {
+                let mut skinning = Matrix4::zero();
+                for (&joint, &weight) in influences.iter().zip(influence_weights) {
+                    if let Some(matrix) = joints.get(joint as usize).filter(|_| weight > 0.0) {
+                        skinning += matrix * weight;
+                    }
+                }
+                if skinning != Matrix4::zero() {
+                    position = (skinning * position.extend(1.0)).truncate();
+                    normal = (skinning * normal.extend(0.0)).truncate();
+                }
+            }
+
+            let normal = if normal.magnitude2() > 0.0 { normal.normalize() } else { Vector3::unit_y() };
+            Vertex::new(position.into(), normal.into())
+        });
+        Some(vertices.collect())
+    }
+}
+
+fn quaternion(xyzw: &[f32]) -> Quaternion<f32> {
+    Quaternion::new(xyzw[3], xyzw[0], xyzw[1], xyzw[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Rotation3};
+    use crate::primitives::tri_mesh::MorphTarget;
+
+    fn channel(property: Property, interpolation: Interpolation, times: &[f32], values: &[f32]) -> Channel {
+        Channel { node: 0, property, interpolation, times: times.to_vec(), values: values.to_vec() }
+    }
+
+    fn assert_close(a: &[f32], b: &[f32]) {
+        assert_eq!(a.len(), b.len(), "{:?} != {:?}", a, b);
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < 1e-5, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn node(parent: Option<usize>, rest: Pose) -> SceneNode {
+        SceneNode { parent, rest, mesh: None, skin: None, weights: Vec::new() }
+    }
+
+    #[test]
+    fn step_holds_the_previous_key() {
+        let step = channel(Property::Translation, Interpolation::Step, &[0.0, 1.0], &[0.0, 0.0, 0.0, 10.0, 20.0, 30.0]);
+        assert_close(&step.sample(0.99), &[0.0, 0.0, 0.0]);
+        assert_close(&step.sample(1.0), &[10.0, 20.0, 30.0]);
+    }
+
+    #[test]
+    fn linear_interpolates_and_holds_outside_the_keys() {
+        let linear = channel(Property::Weights, Interpolation::Linear, &[1.0, 3.0], &[0.0, 1.0, 10.0, 0.0]);
+        assert_close(&linear.sample(1.5), &[2.5, 0.75]);
+        assert_close(&linear.sample(-1.0), &[0.0, 1.0]);
+        assert_close(&linear.sample(4.0), &[10.0, 0.0]);
+    }
+
+    #[test]
+    fn linear_rotations_slerp() {
+        let quarter = Quaternion::from_angle_y(Deg(90.0));
+        let values = [0.0, 0.0, 0.0, 1.0, quarter.v.x, quarter.v.y, quarter.v.z, quarter.s];
+        let rotation = channel(Property::Rotation, Interpolation::Linear, &[0.0, 1.0], &values);
+        let eighth = Quaternion::from_angle_y(Deg(45.0));
+        assert_close(&rotation.sample(0.5), &[eighth.v.x, eighth.v.y, eighth.v.z, eighth.s]);
+    }
+
+    #[test]
+    fn cubic_scales_tangents_to_the_key_interval() {
+        // In tangent, value and out tangent per key, leaving the first key at one unit per second
+        let cubic = channel(Property::Weights, Interpolation::CubicSpline, &[0.0, 2.0], &[0.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        // Hermite basis at u = 0.25: 0.15625 of the second value, 0.140625 of the out tangent times 2 s
+        assert_close(&cubic.sample(0.5), &[0.4375]);
+        assert_close(&cubic.sample(-1.0), &[0.0]);
+        assert_close(&cubic.sample(3.0), &[1.0]);
+    }
+
+    #[test]
+    fn morph_targets_offset_by_weight() {
+        let mut mesh = TriMesh::new();
+        mesh.vertices = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]];
+        mesh.normals = vec![[0.0, 1.0, 0.0]; 2];
+        let mut target = MorphTarget::new();
+        target.positions = vec![[0.0, 2.0, 0.0]];
+        mesh.morph_targets = vec![target];
+
+        let mut morphed = node(None, Pose::identity());
+        morphed.weights = vec![0.25];
+        let graph = SceneGraph::new(vec![morphed], Vec::new(), Vec::new());
+        let pose = graph.evaluate(0, 0.0);
+        let vertices = graph.deform(&mesh, 0, &pose).unwrap();
+        assert_close(&vertices[0].position[..3], &[0.0, 0.5, 0.0]);
+        assert_close(&vertices[1].position[..3], &[1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn two_joint_skin_bends_at_the_second_joint() {
+        // Root joint at the origin, the second a unit above it turned a quarter about z, and the
+        // skinned mesh's node moved away, which the deformed vertices are taken back out of
+        let bend = Pose::new(Vector3::new(0.0, 1.0, 0.0), Quaternion::from_angle_z(Deg(90.0)), Vector3::new(1.0, 1.0, 1.0));
+        let mut mesh_node = node(None, Pose::identity());
+        mesh_node.rest.translation = Vector3::new(5.0, 0.0, 0.0);
+        mesh_node.skin = Some(0);
+        let nodes = vec![node(None, Pose::identity()), node(Some(0), bend), mesh_node];
+        let skin = Skin { joints: vec![0, 1], inverse_bind: vec![Matrix4::identity(), Matrix4::from_translation(Vector3::new(0.0, -1.0, 0.0))] };
+        let graph = SceneGraph::new(nodes, vec![skin], Vec::new());
+
+        let mut mesh = TriMesh::new();
+        mesh.vertices = vec![[0.0, 0.5, 0.0], [0.0, 1.5, 0.0], [0.0, 1.5, 0.0]];
+        mesh.joints = vec![[0, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]];
+        mesh.weights = vec![[1.0, 0.0, 0.0, 0.0], [1.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.0, 0.0]];
+
+        let pose = graph.evaluate(0, 0.0);
+        let vertices = graph.deform(&mesh, 2, &pose).unwrap();
+        assert_close(&vertices[0].position[..3], &[-5.0, 0.5, 0.0]);
+        assert_close(&vertices[1].position[..3], &[-5.5, 1.0, 0.0]);
+        assert_close(&vertices[2].position[..3], &[-5.25, 1.25, 0.0]);
+    }
+}
@@ -1,55 +1,201 @@
 use std::cmp::Ordering;
+use anyhow::{bail, Result};
+use cgmath::{Matrix4, SquareMatrix};
 use wgpu::util::DeviceExt;
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
 
-use crate::primitives::aabb::AABB;
-use crate::primitives::triangle::TriangleCPU;
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::primitives::instance::{Instance, InstanceCPU};
+use crate::primitives::ray::Ray;
+use crate::primitives::scene::SceneObjectCPU;
+use crate::primitives::vertex::Vertex;
+use crate::process::bvh_binned::binned_sah_split;
+use crate::process::bvh_layout::{NodeLayout, TraversalStats, apply_order, layout_order};
+use crate::process::bvh_lbvh::{build_lbvh, build_ploc, permute};
+use crate::process::bvh_ml::{SplitPredictor, build_predicted};
+use crate::process::bvh_spatial::build_sbvh;
+use crate::process::bvh_stats::BvhStats;
+use crate::process::{bvh_threaded, bvh_wide};
+use crate::process::bvh_threaded::{ThreadedBVHNode, thread};
+use crate::process::bvh_wide::{STACK_SIZE, WideBVHNode, collapse, offset_nodes, stack_size, wide_primitive_order};
+use crate::process::reader::Reader;
 
+// Saved BVH format (little endian):
+//   b"KBVH", u32 version, u64 source hash,
+//   u32 builder, u32 max_leaf_size, f32 traversal_cost, f32 intersection_cost, f32 spatial_budget,
+//   f32 rebuild_threshold, u32 width, u32 layout,
+//   i32 root, f32 build_cost, u32 node count, nodes (f32 min[3], f32 max[3], i32 left, right, first, count),
+//   u32 primitive count, u32 source index of the primitive at every leaf position
+const KBVH_MAGIC: &[u8; 4] = b"KBVH";
+const KBVH_VERSION: u32 = 2;
+const KBVH_NODE_SIZE: usize = 40;
 
+
+// Discriminants are stored in saved BVHs, append new builders at the end
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BVHBuilder {
+    // Variance picked axis with bucketed sweeps over the sorted range
+    Variance,
+    // Centroid binned SAH over every axis
+    BinnedSAH,
+    // Morton ordered radix tree, fastest to build, for interactive edits
+    LBVH,
+    // Morton order refined by locally ordered clustering, near SAH quality at LBVH speed
+    PLOC,
+    // Binned SAH that may also split space, duplicating primitives that straddle the plane
+    SBVH,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BVHConfig {
+    pub builder: BVHBuilder,
+    pub max_leaf_size: usize,
+    // SAH costs of a traversal step and of one primitive intersection
+    pub traversal_cost: f32,
+    pub intersection_cost: f32,
+    // Extra primitive references spatial splits may add, as a fraction of the primitive count
+    pub spatial_budget: f32,
+    // Growth of the SAH cost through refits, relative to the built tree, that warrants a rebuild
+    pub rebuild_threshold: f32,
+    // Children per node on the GPU, above 2 primitives are ordered for the collapsed wide tree
+    pub width: usize,
+    // Storage order of the binary nodes, wide nodes are always breadth first
+    pub layout: NodeLayout,
+}
+
+impl BVHConfig {
+    pub fn default() -> Self {
+        Self {
+            builder: BVHBuilder::Variance,
+            max_leaf_size: 4,
+            // Node fetches dominate on the GPU, favours fuller leaves
+            traversal_cost: 2.0,
+            intersection_cost: 1.0,
+            spatial_budget: 0.3,
+            rebuild_threshold: 1.3,
+            width: 2,
+            layout: NodeLayout::DepthFirst,
+        }
+    }
+
+    pub fn with_builder(mut self, builder: BVHBuilder) -> Self {
+        self.builder = builder;
+        self
+    }
+
+    pub fn with_width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    pub fn with_layout(mut self, layout: NodeLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    // Cost of splitting a node of area `area` into the given children
+    pub fn split_cost(&self, area: f32, left_area: f32, left_count: usize, right_area: f32, right_count: usize) -> f32 {
+        self.traversal_cost
+            + self.intersection_cost * (left_area * left_count as f32 + right_area * right_count as f32) / area.max(f32::EPSILON)
+    }
+}
+
+
+// Leaves hold `count` primitives starting at `first`, interior nodes have a count of 0
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BVHNode {
-    aabb: AABB,
-    left: i32,
-    right: i32,
-    triangle: i32,
-    _padding: i32, 
+    pub(crate) aabb: AABB,
+    pub(crate) left: i32,
+    pub(crate) right: i32,
+    pub(crate) first: i32,
+    pub(crate) count: i32,
 }
 
 impl BVHNode {
-    fn new(nodes: &mut Vec<BVHNode>, primitives: &mut [TriangleCPU], start: usize, end: usize) -> i32 {
+    pub(crate) fn new<T: Bounded + Send + Sync>(nodes: &mut Vec<BVHNode>, primitives: &mut [T], start: usize, end: usize, config: &BVHConfig) -> i32 {
         if start >= end {
             panic!("BVHNode::new called with invalid range");
         }
 
         let object_span = end - start;
-        let mut left = -1;
-        let mut right = -1;
-        let mut triangle = -1;
-        let mut aabb;
-
-        if object_span == 1 {
-            aabb = primitives[start].bounding_box(0.0, 0.0);
-            triangle = start as i32;
-        } else {            
-            let split = best_divide(primitives, start, end);
-            left = BVHNode::new(nodes, primitives, start, split);
-            right = BVHNode::new(nodes, primitives, split, end);
-            triangle = -1;
-            aabb = nodes[left as usize].aabb.union(&nodes[right as usize].aabb);
+        let aabb = AABB::bounding_box_for_slice(&primitives[start..end], 0, object_span);
+
+        if object_span > 1 {
+            let (split, split_cost) = match config.builder {
+                BVHBuilder::Variance => {
+                    let split = best_divide(primitives, start, end);
+                    let left_area = AABB::bounding_box_for_slice(&primitives[start..split], 0, split - start).surface_area();
+                    let right_area = AABB::bounding_box_for_slice(&primitives[split..end], 0, end - split).surface_area();
+                    (split, config.split_cost(aabb.surface_area(), left_area, split - start, right_area, end - split))
+                },
+                BVHBuilder::BinnedSAH => binned_sah_split(primitives, start, end, &aabb, config),
+                BVHBuilder::LBVH | BVHBuilder::PLOC | BVHBuilder::SBVH => unreachable!("builder has its own recursion"),
+            };
+
+            // Split unless a leaf is within the size limit and cheaper by SAH
+            let leaf_cost = config.intersection_cost * object_span as f32;
+            let make_leaf = object_span <= config.max_leaf_size && leaf_cost <= split_cost;
+
+            if !make_leaf {
+                let left = BVHNode::new(nodes, primitives, start, split, config);
+                let right = BVHNode::new(nodes, primitives, split, end, config);
+                nodes.push(BVHNode {
+                    aabb,
+                    left,
+                    right,
+                    first: -1,
+                    count: 0,
+                });
+                return (nodes.len() - 1) as i32;
+            }
         }
 
+        nodes.push(BVHNode {
+            aabb,
+            left: -1,
+            right: -1,
+            first: start as i32,
+            count: object_span as i32,
+        });
+
+        (nodes.len() - 1) as i32
+    }
+
+    // Object median split on the longest axis, the depth stays logarithmic in the primitive count
+    fn median<T: Bounded>(nodes: &mut Vec<BVHNode>, primitives: &mut [T], start: usize, end: usize, config: &BVHConfig) -> i32 {
+        let span = end - start;
+        let aabb = AABB::bounding_box_for_slice(&primitives[start..end], 0, span);
+        if span <= config.max_leaf_size {
+            nodes.push(BVHNode {
+                aabb,
+                left: -1,
+                right: -1,
+                first: start as i32,
+                count: span as i32,
+            });
+            return (nodes.len() - 1) as i32;
+        }
+
+        let axis = (0..3)
+            .max_by(|&a, &b| (aabb.max[a] - aabb.min[a]).total_cmp(&(aabb.max[b] - aabb.min[b])))
+            .unwrap();
+        primitives[start..end].select_nth_unstable_by(span / 2, |a, b| a.centroid()[axis].total_cmp(&b.centroid()[axis]));
+        let left = BVHNode::median(nodes, primitives, start, start + span / 2, config);
+        let right = BVHNode::median(nodes, primitives, start + span / 2, end, config);
         nodes.push(BVHNode {
             aabb,
             left,
             right,
-            triangle,
-            _padding: 0 as i32,
+            first: -1,
+            count: 0,
         });
-
         (nodes.len() - 1) as i32
     }
+
+    pub fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
 }
 
 
@@ -59,19 +205,249 @@ pub struct BVH {
     root: i32,
     _padding: [i32; 3],
     nodes: Vec<BVHNode>,
+    config: BVHConfig,
+    // SAH cost when built and after the latest refit
+    build_cost: f32,
+    cost: f32,
+    // Index before the build of the primitive at each leaf position, repeated for spatial splits
+    order: Vec<u32>,
+    // Leaf bounds are grown by this much, see `pad`
+    margin: f32,
 }
 
 impl BVH {
-    pub fn new(primitives: &mut [TriangleCPU], seed: u64) -> BVH {
-        let mut nodes = Vec::new();
-        let root = BVHNode::new(&mut nodes, primitives, 0, primitives.len());
-        BVH { root, _padding: [0; 3], nodes }
+    pub fn new<T: Bounded + Clone + Send + Sync>(primitives: &mut Vec<T>, builder: BVHBuilder) -> BVH {
+        BVH::with_config(primitives, &BVHConfig::default().with_builder(builder))
+    }
+
+    // Primitives are reordered to match the leaves, spatial splits may also duplicate some
+    pub fn with_config<T: Bounded + Clone + Send + Sync>(primitives: &mut Vec<T>, config: &BVHConfig) -> BVH {
+        BVH::indexed_build(primitives, config, None)
+    }
+
+    // Splits large nodes where `predictor` says, the rest and any rebuild use binned SAH
+    pub fn with_predictor<T: Bounded + Clone + Send + Sync>(primitives: &mut Vec<T>, config: &BVHConfig, predictor: &dyn SplitPredictor) -> BVH {
+        BVH::indexed_build(primitives, &config.with_builder(BVHBuilder::BinnedSAH), Some(predictor))
+    }
+
+    fn indexed_build<T: Bounded + Clone + Send + Sync>(primitives: &mut Vec<T>, config: &BVHConfig, predictor: Option<&dyn SplitPredictor>) -> BVH {
+        // Tagged so the order can be saved and replayed over the same primitives by `load`
+        let mut indexed: Vec<Indexed<T>> = primitives.drain(..)
+            .enumerate()
+            .map(|(index, primitive)| Indexed { primitive, index: index as u32 })
+            .collect();
+        let mut bvh = BVH::build(&mut indexed, config, predictor);
+        bvh.order = indexed.iter().map(|p| p.index).collect();
+        primitives.extend(indexed.into_iter().map(|p| p.primitive));
+        bvh
+    }
+
+    fn build<T: Bounded + Clone + Send + Sync>(primitives: &mut Vec<T>, config: &BVHConfig, predictor: Option<&dyn SplitPredictor>) -> BVH {
+        let (mut nodes, root) = match (config.builder, predictor) {
+            (_, Some(predictor)) => build_predicted(primitives, config, predictor),
+            (BVHBuilder::LBVH, _) => build_lbvh(primitives, config),
+            (BVHBuilder::PLOC, _) => build_ploc(primitives, config),
+            (BVHBuilder::SBVH, _) => build_sbvh(primitives, config),
+            _ => {
+                let mut nodes = Vec::new();
+                let end = primitives.len();
+                let root = BVHNode::new(&mut nodes, primitives, 0, end, config);
+                (nodes, root)
+            },
+        };
+        let mut root = root;
+        if config.width > 2 {
+            fit_stack(&mut nodes, &mut root, primitives, config, STACK_SIZE);
+            let order = wide_primitive_order(&mut nodes, root, config.width);
+            permute(primitives, &order);
+        }
+
+        let build_cost = BvhStats::new(&nodes, root, config).sah_cost;
+        let mut bvh = BVH { root, _padding: [0; 3], nodes, config: *config, build_cost, cost: build_cost, order: Vec::new(), margin: 0.0 };
+        // Also drops the subtrees replaced by median splits
+        bvh.reorder_nodes();
+        bvh
+    }
+
+    // Rebuilds with the original config over primitives whose bounds have changed
+    pub fn rebuild<T: Bounded + Clone + Send + Sync>(&mut self, primitives: &mut Vec<T>) {
+        let (order, margin) = (std::mem::take(&mut self.order), self.margin);
+        *self = BVH::with_config(primitives, &self.config);
+        // Still relative to the primitives of the first build
+        self.order = self.order.iter().map(|&i| order[i as usize]).collect();
+        self.pad(margin);
+    }
+
+    // Grows every node by `margin`, kept through refits and rebuilds. The padded tree is the
+    // new baseline for the cost ratio `refit` returns.
+    pub fn pad(&mut self, margin: f32) {
+        let grow = margin - self.margin;
+        for node in self.nodes.iter_mut() {
+            node.aabb = node.aabb.padded(grow);
+        }
+        self.margin = margin;
+        self.build_cost = self.stats(&self.config).sah_cost;
+        self.cost = self.build_cost;
+    }
+
+    // `source_hash` identifies what the primitives were made from, `load` rejects any other
+    pub fn save(&self, source_hash: u64) -> Vec<u8> {
+        let mut out = Vec::with_capacity(64 + KBVH_NODE_SIZE * self.nodes.len() + 4 * self.order.len());
+        out.extend_from_slice(KBVH_MAGIC);
+        out.extend_from_slice(&KBVH_VERSION.to_le_bytes());
+        out.extend_from_slice(&source_hash.to_le_bytes());
+
+        let config = &self.config;
+        out.extend_from_slice(&(config.builder as u32).to_le_bytes());
+        out.extend_from_slice(&(config.max_leaf_size as u32).to_le_bytes());
+        for v in [config.traversal_cost, config.intersection_cost, config.spatial_budget, config.rebuild_threshold] {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+        out.extend_from_slice(&(config.width as u32).to_le_bytes());
+        out.extend_from_slice(&(config.layout as u32).to_le_bytes());
+
+        out.extend_from_slice(&self.root.to_le_bytes());
+        out.extend_from_slice(&self.build_cost.to_le_bytes());
+        out.extend_from_slice(&(self.nodes.len() as u32).to_le_bytes());
+        for node in &self.nodes {
+            for v in node.aabb.min[..3].iter().chain(node.aabb.max[..3].iter()) {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+            for v in [node.left, node.right, node.first, node.count] {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out.extend_from_slice(&(self.order.len() as u32).to_le_bytes());
+        for i in &self.order {
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        out
+    }
+
+    // Counterpart of `save`, reorders `primitives` as the saved build did. Fails if the data is
+    // from another source or was built with a config other than `config`, rebuild in that case.
+    pub fn load<T: Clone>(data: &[u8], primitives: &mut Vec<T>, source_hash: u64, config: &BVHConfig) -> Result<BVH> {
+        if !data.starts_with(KBVH_MAGIC) {
+            bail!("not a saved BVH");
+        }
+        let mut reader = Reader::new(data, KBVH_MAGIC.len());
+        let version = reader.u32()?;
+        if version != KBVH_VERSION {
+            bail!("unsupported BVH version {}", version);
+        }
+        if reader.u64()? != source_hash {
+            bail!("saved BVH was built from a different source");
+        }
+
+        let builder = match reader.u32()? {
+            0 => BVHBuilder::Variance,
+            1 => BVHBuilder::BinnedSAH,
+            2 => BVHBuilder::LBVH,
+            3 => BVHBuilder::PLOC,
+            4 => BVHBuilder::SBVH,
+            other => bail!("unknown BVH builder {}", other),
+        };
+        let max_leaf_size = reader.u32()? as usize;
+        let (traversal_cost, intersection_cost) = (reader.f32()?, reader.f32()?);
+        let (spatial_budget, rebuild_threshold) = (reader.f32()?, reader.f32()?);
+        let width = reader.u32()? as usize;
+        let layout = match reader.u32()? {
+            0 => NodeLayout::DepthFirst,
+            1 => NodeLayout::LargerFirst,
+            2 => NodeLayout::BreadthFirst,
+            3 => NodeLayout::VanEmdeBoas,
+            other => bail!("unknown BVH node layout {}", other),
+        };
+        let saved = BVHConfig { builder, max_leaf_size, traversal_cost, intersection_cost, spatial_budget, rebuild_threshold, width, layout };
+        if saved != *config {
+            bail!("saved BVH was built with {:?}", saved);
+        }
+
+        let root = reader.i32()?;
+        let build_cost = reader.f32()?;
+        let node_count = reader.count(KBVH_NODE_SIZE)?;
+        let mut nodes = Vec::with_capacity(node_count);
+        for _ in 0..node_count {
+            let aabb = reader.bounds()?;
+            nodes.push(BVHNode { aabb, left: reader.i32()?, right: reader.i32()?, first: reader.i32()?, count: reader.i32()? });
+        }
+        let order_count = reader.count(4)?;
+        let mut order = Vec::with_capacity(order_count);
+        for _ in 0..order_count {
+            order.push(reader.u32()?);
+        }
+
+        // Indices are checked once here so traversal can trust them
+        let in_tree = |i: i32| i >= 0 && (i as usize) < node_count;
+        if !in_tree(root) {
+            bail!("saved BVH root {} is out of range", root);
+        }
+        for node in &nodes {
+            let valid = if node.is_leaf() {
+                node.first >= 0 && node.first as usize + node.count as usize <= order_count
+            } else {
+                in_tree(node.left) && in_tree(node.right)
+            };
+            if !valid {
+                bail!("saved BVH node {:?} is out of range", node);
+            }
+        }
+        if let Some(i) = order.iter().find(|&&i| i as usize >= primitives.len()) {
+            bail!("saved BVH references primitive {} of {}", i, primitives.len());
+        }
+
+        // Every node is reached at most once from the root, so the tree has no cycles for thread() to loop on
+        let mut reached = vec![false; node_count];
+        let mut stack = vec![root as usize];
+        while let Some(i) = stack.pop() {
+            if std::mem::replace(&mut reached[i], true) {
+                bail!("saved BVH node {} is reached twice", i);
+            }
+            let node = &nodes[i];
+            if !node.is_leaf() {
+                stack.push(node.left as usize);
+                stack.push(node.right as usize);
+            }
+        }
+
+        *primitives = order.iter().map(|&i| primitives[i as usize].clone()).collect();
+        Ok(BVH { root, _padding: [0; 3], nodes, config: saved, build_cost, cost: build_cost, order, margin: 0.0 })
+    }
+
+    // Moves the objects to the current vertices and grows node bounds to match, bottom up and
+    // keeping the topology. Returns the SAH cost relative to the tree as built.
+    pub fn refit(&mut self, vertices: &[Vertex], objects: &mut [SceneObjectCPU]) -> f32 {
+        for object in objects.iter_mut() {
+            object.refit(vertices);
+        }
+        self.refit_bounds(objects)
+    }
+
+    // Grows node bounds to primitives that moved, in the order the tree was built over
+    fn refit_bounds<T: Bounded>(&mut self, primitives: &[T]) -> f32 {
+        self.refit_node(self.root, primitives);
+        self.cost = self.stats(&self.config).sah_cost;
+        self.cost / self.build_cost.max(f32::EPSILON)
+    }
+
+    fn refit_node<T: Bounded>(&mut self, index: i32, primitives: &[T]) -> AABB {
+        let node = self.nodes[index as usize];
+        let aabb = if node.is_leaf() {
+            let (first, count) = (node.first as usize, node.count as usize);
+            AABB::bounding_box_for_slice(primitives, first, first + count).padded(self.margin)
+        } else {
+            let left = self.refit_node(node.left, primitives);
+            left.union(&self.refit_node(node.right, primitives))
+        };
+        self.nodes[index as usize].aabb = aabb;
+        aabb
     }
 
     pub fn to_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
         let root_bytes = bytemuck::bytes_of(&self.root);
         let padding_bytes = bytemuck::bytes_of(&self._padding);
-        let node_bytes = bytemuck::cast_slice(&self.nodes);
+        let nodes = thread(&self.nodes, &[self.root]);
+        let node_bytes = bytemuck::cast_slice(&nodes);
         let bytes: Vec<u8> = [&root_bytes, &padding_bytes, node_bytes].concat();
 
         device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -85,75 +461,463 @@ impl BVH {
         &self.nodes
     }
 
+    pub fn stats(&self, config: &BVHConfig) -> BvhStats {
+        BvhStats::new(&self.nodes, self.root, config)
+    }
+
+    pub fn config(&self) -> &BVHConfig {
+        &self.config
+    }
+
     pub fn root(&self) -> i32 {
         self.root
     }
 
+    pub fn bounds(&self) -> AABB {
+        self.nodes[self.root as usize].aabb
+    }
+
+    // Stores the nodes in the config's layout, the tree itself is unchanged
     pub fn reorder_nodes(&mut self) {
-        let mut ordered_nodes = Vec::with_capacity(self.nodes.len());
-        let new_root = self.reorder_nodes_recursive(self.root, &mut ordered_nodes);
-        
-        // Update the root index
-        self.root = new_root;
-        
-        // Replace the original nodes with the reordered nodes
-        self.nodes = ordered_nodes;
+        let order = layout_order(&self.nodes, self.root, self.config.layout);
+        let (nodes, root) = apply_order(&self.nodes, self.root, &order);
+        self.nodes = nodes;
+        self.root = root;
+    }
+
+    pub fn set_layout(&mut self, layout: NodeLayout) {
+        self.config.layout = layout;
+        self.reorder_nodes();
+    }
+
+    // Node fetches of the binary traversal over `rays` in the current layout, `hit` intersects one
+    // primitive as for bvh_threaded::traverse
+    pub fn traversal_stats<F: FnMut(usize, &Ray) -> Option<f32>>(&self, rays: &[Ray], hit: F) -> TraversalStats {
+        TraversalStats::new(&thread(&self.nodes, &[self.root]), self.root, rays, hit)
+    }
+}
+
+// A primitive with its index before the build
+#[derive(Clone)]
+struct Indexed<T> {
+    primitive: T,
+    index: u32,
+}
+
+impl<T: Bounded> Bounded for Indexed<T> {
+    fn bounding_box(&self, t0: f32, t1: f32) -> AABB {
+        self.primitive.bounding_box(t0, t1)
+    }
+
+    fn centroid(&self) -> [f32; 3] {
+        self.primitive.centroid()
+    }
+
+    fn bbox_surface_area(&self) -> f32 {
+        self.primitive.bbox_surface_area()
+    }
+
+    fn clipped_bounds(&self, clip: &AABB) -> AABB {
+        self.primitive.clipped_bounds(clip)
     }
+}
+
+// FNV-1a over the bytes a BVH was built from, for `BVH::save` and `BVH::load`
+pub fn source_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+// Top level BVH over instances, each referencing a bottom level BVH built once per unique mesh.
+// Trees `TwoLevelBVH::refit` rebuilt rather than refit
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Rebuilt {
+    // Bottom levels, which reorders the scene objects
+    pub bottom_levels: bool,
+    // The top level, which reorders the instances
+    pub top_level: bool,
+}
+
+impl Rebuilt {
+    pub fn any(&self) -> bool {
+        self.bottom_levels || self.top_level
+    }
+}
 
-    fn reorder_nodes_recursive(&self, node_index: i32, ordered_nodes: &mut Vec<BVHNode>) -> i32 {
-        if node_index == -1 {
-            return -1;
+// All levels share one node array: the top level first, then every bottom level in order.
+#[derive(Debug, Clone)]
+pub struct TwoLevelBVH {
+    tlas: BVH,
+    blas: Vec<BVH>,
+    object_offsets: Vec<u32>,
+    instances: Vec<InstanceCPU>,
+}
+
+impl TwoLevelBVH {
+    // `blas[i]` leaves index objects starting at `object_offsets[i]` in the scene object buffer
+    // The top level takes the GPU node width of the bottom levels
+    pub fn new(blas: Vec<BVH>, object_offsets: Vec<u32>, mut instances: Vec<InstanceCPU>) -> Self {
+        for (id, instance) in instances.iter_mut().enumerate() {
+            instance.id = id;
         }
+        let (width, layout) = blas.first().map_or((2, NodeLayout::DepthFirst), |b| (b.config.width, b.config.layout));
+        let config = BVHConfig::default().with_builder(BVHBuilder::BinnedSAH).with_width(width).with_layout(layout);
+        let tlas = BVH::with_config(&mut instances, &config);
+        TwoLevelBVH { tlas, blas, object_offsets, instances }
+    }
 
-        let node = self.nodes[node_index as usize];
-        let new_index = ordered_nodes.len() as i32;
-        ordered_nodes.push(node);
+    pub fn width(&self) -> usize {
+        self.tlas.config.width
+    }
 
-        // Recursively reorder left and right child nodes
-        let new_left = self.reorder_nodes_recursive(node.left, ordered_nodes);
-        let new_right = self.reorder_nodes_recursive(node.right, ordered_nodes);
+    pub fn bounds(&self) -> AABB {
+        self.tlas.bounds()
+    }
+
+    // Refits every bottom level to the current vertices and then the top level to the moved
+    // instances, rebuilding the trees that degraded past their threshold. Without rebuilds the
+    // topology and so the GPU refit schedule stay as they were. Rebuilds keep every object where
+    // the object buffer has room for it, so SBVH trees, whose spatial splits would duplicate
+    // objects differently, are only ever refit.
+    pub fn refit(&mut self, vertices: &[Vertex], objects: &mut [SceneObjectCPU]) -> Rebuilt {
+        let mut rebuilt = Rebuilt::default();
+        for mesh in 0..self.blas.len() {
+            let start = self.object_offsets[mesh] as usize;
+            let end = self.object_offsets.get(mesh + 1).map_or(objects.len(), |&o| o as usize);
+            let ratio = self.blas[mesh].refit(vertices, &mut objects[start..end]);
+            if ratio <= self.blas[mesh].config.rebuild_threshold || self.blas[mesh].config.builder == BVHBuilder::SBVH {
+                continue;
+            }
 
-        // Update the left and right indices of the current node
-        let mut new_node = ordered_nodes[new_index as usize];
-        new_node.left = new_left;
-        new_node.right = new_right;
-        ordered_nodes[new_index as usize] = new_node;
+            let mut mesh_objects = objects[start..end].to_vec();
+            self.blas[mesh].rebuild(&mut mesh_objects);
+            objects[start..end].clone_from_slice(&mesh_objects);
+            rebuilt.bottom_levels = true;
+        }
 
-        new_index
+        for instance in &mut self.instances {
+            instance.set_bounds(&self.blas[instance.mesh].bounds());
+        }
+        if self.tlas.refit_bounds(&self.instances) > self.tlas.config.rebuild_threshold {
+            self.tlas = BVH::with_config(&mut self.instances, &self.tlas.config);
+            rebuilt.top_level = true;
+        }
+        rebuilt
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instances.len()
+    }
+
+    // Transforms at shutter open and close by instance id, the order the instances were given to
+    // `new` in. Instances without a placement stay put. `refit` follows with the new bounds.
+    pub fn place_instances(&mut self, placements: &[Option<(Matrix4<f32>, Matrix4<f32>)>]) {
+        for instance in &mut self.instances {
+            if let Some(Some((open, close))) = placements.get(instance.id) {
+                instance.place(*open, *close);
+            }
+        }
     }
+
+    pub fn tlas_node_count(&self) -> usize {
+        self.tlas.nodes.len()
+    }
+
+    fn blas_node_offsets(&self) -> Vec<i32> {
+        let mut offsets = Vec::with_capacity(self.blas.len());
+        let mut offset = self.tlas.nodes.len() as i32;
+        for blas in &self.blas {
+            offsets.push(offset);
+            offset += blas.nodes.len() as i32;
+        }
+        offsets
+    }
+
+    pub fn nodes(&self) -> Vec<BVHNode> {
+        let mut nodes = self.tlas.nodes.clone();
+        for ((blas, offset), object_offset) in self.blas.iter().zip(self.blas_node_offsets()).zip(&self.object_offsets) {
+            for node in &blas.nodes {
+                let mut node = *node;
+                if node.is_leaf() {
+                    node.first += *object_offset as i32;
+                } else {
+                    node.left += offset;
+                    node.right += offset;
+                }
+                nodes.push(node);
+            }
+        }
+        nodes
+    }
+
+    // Every tree threaded on its own, as the binary traversal walks them
+    pub fn threaded_nodes(&self) -> Vec<ThreadedBVHNode> {
+        let mut roots = vec![self.tlas.root];
+        roots.extend(self.blas_roots());
+        thread(&self.nodes(), &roots)
+    }
+
+    // Bottom level roots in the GPU layout, per mesh
+    fn blas_roots(&self) -> Vec<i32> {
+        if self.width() > 2 {
+            self.wide_node_offsets().iter().map(|&offset| offset as i32).collect()
+        } else {
+            self.blas_node_offsets().iter().zip(&self.blas).map(|(offset, blas)| offset + blas.root).collect()
+        }
+    }
+
+    // Instances in top level leaf order, pointing at the bottom level roots of the GPU layout
+    pub fn instances(&self) -> Vec<Instance> {
+        let roots = self.blas_roots();
+        self.instances.iter()
+            .map(|instance| instance.to_gpu(roots[instance.mesh]))
+            .collect()
+    }
+
+    // Runs the GPU traversal on the CPU over the same nodes, for checking the layouts. `hit`
+    // intersects a scene object with the object space ray, bounded by the closest hit so far.
+    // Returns the closest object and its distance.
+    pub fn traverse<F: FnMut(usize, &Ray) -> Option<f32>>(&self, ray: &Ray, hit: F) -> Option<(usize, f32)> {
+        self.walker().traverse(ray, hit)
+    }
+
+    // CPU port of `occluded`, true if any object is hit within the ray's interval
+    pub fn occluded<F: FnMut(usize, &Ray) -> Option<f32>>(&self, ray: &Ray, hit: F) -> bool {
+        self.walker().occluded(ray, hit)
+    }
+
+    // The GPU layout prepared once, for tracing many rays on the CPU
+    pub fn walker(&self) -> BVHWalker<'_> {
+        let wide = self.width() > 2;
+        BVHWalker {
+            bvh: self,
+            roots: self.blas_roots(),
+            wide_nodes: if wide { self.wide_nodes() } else { Vec::new() },
+            threaded_nodes: if wide { Vec::new() } else { self.threaded_nodes() },
+        }
+    }
+
+    // Root and padding followed by every threaded node, as BVHBuffer in the shaders, or the
+    // collapsed wide nodes with the top level root first when the width is above 2
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.width() > 2 {
+            let nodes = self.wide_nodes();
+            let header = [0i32; 4];
+            return [bytemuck::bytes_of(&header), bytemuck::cast_slice(&nodes)].concat();
+        }
+
+        let root = self.tlas.root;
+        let root_bytes = bytemuck::bytes_of(&root);
+        let padding_bytes = bytemuck::bytes_of(&self.tlas._padding);
+        let nodes = self.threaded_nodes();
+        let node_bytes = bytemuck::cast_slice(&nodes);
+        [&root_bytes, &padding_bytes, node_bytes].concat()
+    }
+
+    fn wide_node_offsets(&self) -> Vec<u32> {
+        let mut offset = collapse(&self.tlas.nodes, self.tlas.root, self.width()).len() as u32;
+        self.blas.iter().map(|blas| {
+            let blas_offset = offset;
+            offset += collapse(&blas.nodes, blas.root, self.width()).len() as u32;
+            blas_offset
+        }).collect()
+    }
+
+    pub fn wide_nodes(&self) -> Vec<WideBVHNode> {
+        let mut nodes = collapse(&self.tlas.nodes, self.tlas.root, self.width());
+        for (blas, object_offset) in self.blas.iter().zip(&self.object_offsets) {
+            let mut blas_nodes = collapse(&blas.nodes, blas.root, self.width());
+            offset_nodes(&mut blas_nodes, nodes.len() as u32, *object_offset);
+            nodes.extend(blas_nodes);
+        }
+        nodes
+    }
+
+    // Upper bound of `to_bytes` over any rebuild of the same instances and objects. A binary tree
+    // over n primitives has at most 2n - 1 nodes, and every wide node opens a distinct inner node.
+    pub fn max_bytes(&self) -> usize {
+        let wide = self.width() > 2;
+        let max_nodes = |primitives: usize| if wide { primitives.max(1) } else { (2 * primitives).max(1) };
+        let nodes = max_nodes(self.instances.len()) + self.blas.iter()
+            .map(|blas| max_nodes(blas.nodes.iter().filter(|node| node.is_leaf()).map(|node| node.count as usize).sum()))
+            .sum::<usize>();
+        let node_size = if wide { std::mem::size_of::<WideBVHNode>() } else { std::mem::size_of::<ThreadedBVHNode>() };
+        16 + nodes * node_size
+    }
+
+    // Sized for `max_bytes` so rebuilt trees can be written over it
+    pub fn to_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
+        let mut bytes = self.to_bytes();
+        bytes.resize(self.max_bytes().max(bytes.len()), 0);
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("BVH Buffer"),
+            contents: &bytes,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        })
+    }
+
+    pub fn instance_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
+        let instances = self.instances();
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance Buffer"),
+            contents: bytemuck::cast_slice(&instances),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        })
+    }
+}
+
+// Nodes of a two level BVH in the GPU layout, walked on the CPU as the shaders walk them
+pub struct BVHWalker<'a> {
+    bvh: &'a TwoLevelBVH,
+    roots: Vec<i32>,
+    wide_nodes: Vec<WideBVHNode>,
+    threaded_nodes: Vec<ThreadedBVHNode>,
+}
+
+impl BVHWalker<'_> {
+    // See `TwoLevelBVH::traverse`
+    pub fn traverse<F: FnMut(usize, &Ray) -> Option<f32>>(&self, ray: &Ray, hit: F) -> Option<(usize, f32)> {
+        self.walk(ray, false, hit)
+    }
+
+    // See `TwoLevelBVH::occluded`
+    pub fn occluded<F: FnMut(usize, &Ray) -> Option<f32>>(&self, ray: &Ray, hit: F) -> bool {
+        self.walk(ray, true, hit).is_some()
+    }
+
+    fn walk<F: FnMut(usize, &Ray) -> Option<f32>>(&self, ray: &Ray, any_hit: bool, mut hit: F) -> Option<(usize, f32)> {
+        let bvh = self.bvh;
+        let walk_tree = |root: i32, ray: &Ray, hit: &mut dyn FnMut(usize, &Ray) -> Option<f32>| {
+            if bvh.width() > 2 {
+                bvh_wide::walk(&self.wide_nodes, root as usize, ray, any_hit, hit)
+            } else {
+                bvh_threaded::walk(&self.threaded_nodes, root, ray, any_hit, hit)
+            }
+        };
+
+        let mut closest_object = None;
+        let tlas_root = if bvh.width() > 2 { 0 } else { bvh.tlas.root };
+        let closest = walk_tree(tlas_root, ray, &mut |i, ray| {
+            let instance = bvh.instances[i];
+            let world_to_object = instance.transform_at(ray.time).invert().unwrap_or_else(Matrix4::identity);
+            let origin = world_to_object * cgmath::Vector4::new(ray.origin[0], ray.origin[1], ray.origin[2], 1.0);
+            let direction = world_to_object * cgmath::Vector4::new(ray.direction[0], ray.direction[1], ray.direction[2], 0.0);
+
+            // The direction is left unnormalized so the interval is shared between spaces
+            let local_ray = Ray::with_interval([origin.x, origin.y, origin.z], [direction.x, direction.y, direction.z], ray.t_min, ray.t_max)
+                .with_time(ray.time);
+            let (object, t) = walk_tree(self.roots[instance.mesh], &local_ray, &mut hit)?;
+            closest_object = Some(object);
+            Some(t)
+        });
+        let (_, t) = closest?;
+        closest_object.map(|object| (object, t))
+    }
+}
+
+// The wide traversal keeps a fixed size stack of `stack` entries. Trees `limit_stack` can't fit
+// are rebuilt with median splits instead, growing the leaves until the tree fits, which it does
+// once the root is a leaf.
+fn fit_stack<T: Bounded>(nodes: &mut Vec<BVHNode>, root: &mut i32, primitives: &mut [T], config: &BVHConfig, stack: usize) {
+    if let Err(e) = limit_stack(nodes, root, primitives, config, stack) {
+        log::warn!("{}, building with median splits", e);
+        let mut median = *config;
+        loop {
+            nodes.clear();
+            *root = BVHNode::median(nodes, primitives, 0, primitives.len(), &median);
+            if stack_size(nodes, *root, config.width) <= stack {
+                break;
+            }
+            median.max_leaf_size = median.max_leaf_size.max(1) * 2;
+        }
+    }
+}
+
+// Trees that could overflow the stack have their deepest subtrees rebuilt with median splits,
+// lowering the depth limit until the collapsed tree fits. Fails once the whole tree would be
+// balanced and still doesn't fit.
+fn limit_stack<T: Bounded>(nodes: &mut Vec<BVHNode>, root: &mut i32, primitives: &mut [T], config: &BVHConfig, stack: usize) -> Result<()> {
+    let balanced = median_height(primitives.len(), config.max_leaf_size);
+    let mut max_depth = BvhStats::new(nodes, *root, config).max_depth;
+    while stack_size(nodes, *root, config.width) > stack {
+        if max_depth <= balanced {
+            bail!("BVH over {} primitives does not fit a traversal stack of {}", primitives.len(), stack);
+        }
+        max_depth -= 1;
+        *root = limit_depth(nodes, primitives, *root, 0, max_depth, config);
+    }
+    Ok(())
 }
 
-fn box_compare(a: &TriangleCPU, b: &TriangleCPU, axis: usize) -> Ordering {
+// Keeps the tree above `max_depth`, descending while the children could still fit by median
+// splits and rebuilding the subtree once they could not
+fn limit_depth<T: Bounded>(nodes: &mut Vec<BVHNode>, primitives: &mut [T], index: i32, depth: usize, max_depth: usize, config: &BVHConfig) -> i32 {
+    let node = nodes[index as usize];
+    if node.is_leaf() || depth + BvhStats::new(nodes, index, config).max_depth <= max_depth {
+        return index;
+    }
+
+    let (start, end) = primitive_range(nodes, index);
+    if depth + 1 + median_height(end - start, config.max_leaf_size) <= max_depth {
+        let left = limit_depth(nodes, primitives, node.left, depth + 1, max_depth, config);
+        let right = limit_depth(nodes, primitives, node.right, depth + 1, max_depth, config);
+        nodes[index as usize].left = left;
+        nodes[index as usize].right = right;
+        index
+    } else {
+        BVHNode::median(nodes, primitives, start, end, config)
+    }
+}
+
+// Every builder places the primitives of a subtree next to each other
+fn primitive_range(nodes: &[BVHNode], index: i32) -> (usize, usize) {
+    let node = nodes[index as usize];
+    if node.is_leaf() {
+        return (node.first as usize, (node.first + node.count) as usize);
+    }
+    let (left_start, left_end) = primitive_range(nodes, node.left);
+    let (right_start, right_end) = primitive_range(nodes, node.right);
+    (left_start.min(right_start), left_end.max(right_end))
+}
+
+// Depth of the tree `BVHNode::median` builds over `count` primitives
+fn median_height(count: usize, max_leaf_size: usize) -> usize {
+    let mut count = count;
+    let mut height = 0;
+    while count > max_leaf_size.max(1) {
+        count = (count + 1) / 2;
+        height += 1;
+    }
+    height
+}
+
+fn box_compare<T: Bounded>(a: &T, b: &T, axis: usize) -> Ordering {
     a.bounding_box(0.0, 0.0).min[axis].partial_cmp(&b.bounding_box(0.0, 0.0).min[axis]).unwrap_or(Ordering::Equal)
 }
 
-fn best_divide(primitives: &mut [TriangleCPU], start: usize, end: usize) -> usize {
+fn best_divide<T: Bounded + Send + Sync>(primitives: &mut [T], start: usize, end: usize) -> usize {
     
     let num_primitives = end - start;
-    let centroid_sums = Mutex::new([0.0; 3]);
-    let centroid_sums_squared = Mutex::new([0.0; 3]);
-    let surface_area_sums = Mutex::new([0.0; 3]);
-
-    // Compute the sums of centroid coordinates and surface areas along each axis
-    primitives[start..end].par_iter().for_each(|primitive| {
-        let centroid = primitive.centroid();
-        let bbox_surface_area = primitive.bbox_surface_area();
-        
-        for axis in 0..3 {
-            centroid_sums.lock().unwrap()[axis] += centroid[axis];
-            centroid_sums_squared.lock().unwrap()[axis] += centroid[axis].powi(2);
-            surface_area_sums.lock().unwrap()[axis] += bbox_surface_area;
-        }
-    });
+
+    // Compute the sums of centroid coordinates, their squares and surface areas
+    let (centroid_sums, centroid_sums_squared, surface_area_sum) = primitives[start..end].par_iter()
+        .map(|primitive| {
+            let c = primitive.centroid();
+            (c, [c[0] * c[0], c[1] * c[1], c[2] * c[2]], primitive.bbox_surface_area())
+        })
+        .reduce(|| ([0.0; 3], [0.0; 3], 0.0), |a, b| (
+            [a.0[0] + b.0[0], a.0[1] + b.0[1], a.0[2] + b.0[2]],
+            [a.1[0] + b.1[0], a.1[1] + b.1[1], a.1[2] + b.1[2]],
+            a.2 + b.2,
+        ));
 
     // Compute variance
     let mut max_combined_variance = 0.0;
     let mut best_axis = 0;
 
     for axis in 0..3 {
-        let centroid_mean = centroid_sums.lock().unwrap()[axis] / num_primitives as f32;
-        let centroid_variance = centroid_sums_squared.lock().unwrap()[axis] / num_primitives as f32 - centroid_mean.powi(2);
-        let surface_area_mean = surface_area_sums.lock().unwrap()[axis] / num_primitives as f32;
+        let centroid_mean = centroid_sums[axis] / num_primitives as f32;
+        let centroid_variance = centroid_sums_squared[axis] / num_primitives as f32 - centroid_mean.powi(2);
+        let surface_area_mean = surface_area_sum / num_primitives as f32;
         let mut surface_area_variance_sum = 0.0;
         
         // Sum of differences^2 from the mean for surface areas
@@ -181,8 +945,8 @@ fn best_divide(primitives: &mut [TriangleCPU], start: usize, end: usize) -> usiz
 }
 
 
-fn sah_middle_out(
-    primitives: &mut [TriangleCPU],
+fn sah_middle_out<T: Bounded>(
+    primitives: &mut [T],
     start: usize,
     end: usize,
 ) -> usize {
@@ -230,8 +994,8 @@ fn sah_middle_out(
 }
 
 
-fn sah_split(
-    primitives: &mut [TriangleCPU],
+fn sah_split<T: Bounded + Sync>(
+    primitives: &mut [T],
     start: usize,
     end: usize,
 ) -> usize {
@@ -279,3 +1043,238 @@ fn sah_split(
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use crate::process::glb::load_glb;
+
+    struct TestScene {
+        bvh: TwoLevelBVH,
+        objects: Vec<SceneObjectCPU>,
+        vertices: Vec<Vertex>,
+    }
+
+    // Every mesh of a glb in its own bottom level, as `State::new` builds them
+    fn scene(glb_bytes: &[u8], width: usize) -> TestScene {
+        scene_with(glb_bytes, &BVHConfig::default().with_builder(BVHBuilder::PLOC).with_width(width))
+    }
+
+    fn scene_with(glb_bytes: &[u8], config: &BVHConfig) -> TestScene {
+        let glb = load_glb(glb_bytes);
+        let (mut objects, mut vertices, mut blas, mut object_offsets) = (vec![], vec![], vec![], vec![]);
+        for mesh in glb.meshes() {
+            let offset = vertices.len() as u32;
+            vertices.extend(mesh.vertices.iter().enumerate()
+                .map(|(i, vertex)| Vertex::new(*vertex, mesh.normals.get(i).copied().unwrap_or([0.0, 1.0, 0.0]))));
+            let mut mesh_objects = SceneObjectCPU::from_tri_mesh(mesh, offset);
+            blas.push(BVH::with_config(&mut mesh_objects, config));
+            object_offsets.push(objects.len() as u32);
+            objects.extend(mesh_objects);
+        }
+        let instances = glb.instances().iter()
+            .map(|(mesh, transform)| InstanceCPU::new(*mesh, *transform, &blas[*mesh].bounds()))
+            .collect();
+        TestScene { bvh: TwoLevelBVH::new(blas, object_offsets, instances), objects, vertices }
+    }
+
+    // Rays from around the scene bounds towards points inside them
+    fn rays(bounds: &AABB, count: usize, seed: u64) -> Vec<Ray> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let extent: Vec<f32> = (0..3).map(|axis| bounds.max[axis] - bounds.min[axis]).collect();
+        let mut point = |spread: f32| -> [f32; 3] {
+            let mut p = [0.0; 3];
+            for axis in 0..3 {
+                let u: f32 = rng.gen_range(-spread..1.0 + spread);
+                p[axis] = bounds.min[axis] + u * extent[axis];
+            }
+            p
+        };
+        (0..count)
+            .map(|_| {
+                let (origin, target) = (point(1.0), point(0.0));
+                Ray::new(origin, [target[0] - origin[0], target[1] - origin[1], target[2] - origin[2]])
+            })
+            .collect()
+    }
+
+    // Closest hit testing every object of every instance
+    fn brute_force(scene: &TestScene, ray: &Ray) -> Option<f32> {
+        let bvh = &scene.bvh;
+        bvh.instances.iter()
+            .filter_map(|instance| {
+                let world_to_object = instance.transform_at(ray.time).invert().unwrap_or_else(Matrix4::identity);
+                let origin = world_to_object * cgmath::Vector4::new(ray.origin[0], ray.origin[1], ray.origin[2], 1.0);
+                let direction = world_to_object * cgmath::Vector4::new(ray.direction[0], ray.direction[1], ray.direction[2], 0.0);
+                let local_ray = Ray::with_interval([origin.x, origin.y, origin.z], [direction.x, direction.y, direction.z], ray.t_min, ray.t_max);
+                let start = bvh.object_offsets[instance.mesh] as usize;
+                let end = bvh.object_offsets.get(instance.mesh + 1).map_or(scene.objects.len(), |&o| o as usize);
+                scene.objects[start..end].iter()
+                    .filter_map(|object| object.hit(&local_ray, &scene.vertices))
+                    .min_by(f32::total_cmp)
+            })
+            .min_by(f32::total_cmp)
+    }
+
+    fn bounds(scene: &TestScene) -> AABB {
+        scene.bvh.instances.iter().fold(AABB::empty(), |bounds, instance| bounds.union(&instance.bounding_box(0.0, 0.0)))
+    }
+
+    fn assert_matches_brute_force(scene: &TestScene, seed: u64) {
+        let walker = scene.bvh.walker();
+        for ray in rays(&bounds(scene), 300, seed) {
+            let found = walker.traverse(&ray, |i, ray| scene.objects[i].hit(ray, &scene.vertices)).map(|(_, t)| t);
+            let expected = brute_force(scene, &ray);
+            match (expected, found) {
+                (Some(a), Some(b)) => assert!((a - b).abs() <= 1e-4 * a.max(1.0), "{:?}: {} != {}", ray, a, b),
+                _ => assert_eq!(expected.is_some(), found.is_some(), "{:?}", ray),
+            }
+        }
+    }
+
+    // Every other ray ends short of its target, so some stop in front of the surface
+    fn shortened(rays: Vec<Ray>) -> Vec<Ray> {
+        rays.into_iter().enumerate()
+            .map(|(i, ray)| if i % 2 == 0 { ray } else { Ray::with_interval(ray.origin, ray.direction, ray.t_min, 0.5 + (i % 7) as f32 * 0.1) })
+            .collect()
+    }
+
+    #[test]
+    fn occluded_agrees_with_traverse() {
+        for glb in [&include_bytes!("../../assets/cube.glb")[..], &include_bytes!("../../assets/head.glb")[..]] {
+            for width in [2, 8] {
+                let scene = scene(glb, width);
+                let hit = |i: usize, ray: &Ray| scene.objects[i].hit(ray, &scene.vertices);
+                // The single tree ports walk every bottom level on its own
+                let trees: Vec<_> = scene.bvh.blas.iter().map(|blas| match width {
+                    2 => (thread(&blas.nodes, &[blas.root]), Vec::new(), blas.root),
+                    _ => (Vec::new(), collapse(&blas.nodes, blas.root, width), 0),
+                }).collect();
+
+                let walker = scene.bvh.walker();
+                let (mut occluded, mut clear) = (0, 0);
+                for ray in shortened(rays(&bounds(&scene), 1000, 38)) {
+                    let closest = walker.traverse(&ray, hit);
+                    assert_eq!(walker.occluded(&ray, hit), closest.is_some(), "width {} {:?}", width, ray);
+                    if closest.is_some() { occluded += 1 } else { clear += 1 }
+
+                    for (mesh, (threaded, wide, root)) in trees.iter().enumerate() {
+                        let offset = scene.bvh.object_offsets[mesh] as usize;
+                        let hit = |i: usize, ray: &Ray| scene.objects[offset + i].hit(ray, &scene.vertices);
+                        let (closest, occluded) = match width {
+                            2 => (bvh_threaded::traverse(threaded, *root, &ray, hit), bvh_threaded::occluded(threaded, *root, &ray, hit)),
+                            _ => (bvh_wide::traverse(wide, *root as usize, &ray, hit), bvh_wide::occluded(wide, *root as usize, &ray, hit)),
+                        };
+                        assert_eq!(occluded, closest.is_some(), "width {} {:?}", width, ray);
+                    }
+                }
+                assert!(occluded > 0 && clear > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn walkers_match_brute_force() {
+        for glb in [&include_bytes!("../../assets/cube.glb")[..], &include_bytes!("../../assets/head.glb")[..]] {
+            for width in [2, 4, 8] {
+                assert_matches_brute_force(&scene(glb, width), 37);
+            }
+        }
+    }
+
+    #[test]
+    fn stack_overflow_falls_back_to_median_splits() {
+        let mut scene = scene(include_bytes!("../../assets/head.glb"), 2);
+        let config = BVHConfig::default().with_width(8);
+        let bvh = &scene.bvh.blas[0];
+        let (mut nodes, mut root) = (bvh.nodes.clone(), bvh.root);
+        let end = scene.bvh.object_offsets.get(1).map_or(scene.objects.len(), |&o| o as usize);
+        let primitives = &mut scene.objects[..end];
+        // Two entries can't hold the children of one wide node
+        assert!(limit_stack(&mut nodes, &mut root, primitives, &config, 2).is_err());
+
+        fit_stack(&mut nodes, &mut root, primitives, &config, 6);
+        assert!(stack_size(&nodes, root, 8) <= 6);
+        assert_eq!(BvhStats::new(&nodes, root, &config).primitive_count, primitives.len());
+    }
+
+    #[test]
+    fn wide_grouping_survives_refit() {
+        let mut scene = scene(include_bytes!("../../assets/head.glb"), 8);
+        // Stretches and bends the mesh so surface areas reorder, short of a rebuild
+        for vertex in &mut scene.vertices {
+            let [x, y, z, w] = vertex.position;
+            vertex.position = [x * 2.5 + 0.3 * y * y, y, z * 0.4, w];
+        }
+        let TestScene { bvh, objects, vertices } = &mut scene;
+        assert!(!bvh.refit(vertices, objects).bottom_levels);
+        assert_matches_brute_force(&scene, 36);
+    }
+
+    #[test]
+    fn sbvh_is_refit_but_never_rebuilt() {
+        let config = BVHConfig::default().with_builder(BVHBuilder::SBVH).with_width(2);
+        let mut scene = scene_with(include_bytes!("../../assets/head.glb"), &config);
+        // Folds the mesh over itself, far past the rebuild threshold
+        for vertex in &mut scene.vertices {
+            let [x, y, z, w] = vertex.position;
+            vertex.position = [x.abs() * 4.0, y * 0.2, z + x * x, w];
+        }
+        let object_count = scene.objects.len();
+        let TestScene { bvh, objects, vertices } = &mut scene;
+        assert!(!bvh.refit(vertices, objects).bottom_levels);
+        assert_eq!(scene.objects.len(), object_count);
+        assert_matches_brute_force(&scene, 37);
+    }
+
+    #[test]
+    fn padding_survives_refit_and_rebuild() {
+        let mut scene = scene(include_bytes!("../../assets/cube.glb"), 2);
+        let TestScene { bvh, objects, vertices } = &mut scene;
+        let end = bvh.object_offsets.get(1).map_or(objects.len(), |&o| o as usize);
+        let blas = &mut bvh.blas[0];
+        let padded = blas.bounds().padded(0.25);
+        let assert_padded = |blas: &BVH| {
+            let bounds = blas.bounds();
+            for axis in 0..3 {
+                assert!((bounds.min[axis] - padded.min[axis]).abs() < 1e-5 && (bounds.max[axis] - padded.max[axis]).abs() < 1e-5);
+            }
+        };
+
+        blas.pad(0.25);
+        assert_padded(blas);
+        assert!(blas.refit(vertices, &mut objects[..end]) <= blas.config.rebuild_threshold);
+        assert_padded(blas);
+        let mut mesh_objects = objects[..end].to_vec();
+        blas.rebuild(&mut mesh_objects);
+        assert_padded(blas);
+    }
+
+    #[test]
+    fn corrupt_saves_are_rejected() {
+        let scene = scene(include_bytes!("../../assets/cube.glb"), 2);
+        let blas = &scene.bvh.blas[0];
+        let end = scene.bvh.object_offsets.get(1).map_or(scene.objects.len(), |&o| o as usize);
+        let saved = blas.save(7);
+        let load = |data: &[u8]| BVH::load(data, &mut scene.objects[..end].to_vec(), 7, &blas.config);
+        assert!(load(&saved).is_ok());
+
+        // Header, then root, build cost and the node count
+        let (count_at, nodes_at) = (56, 60);
+        let mut huge = saved.clone();
+        huge[count_at..count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(load(&huge).is_err());
+
+        // The root as its own left child
+        let root = blas.root as usize;
+        let left_at = nodes_at + root * KBVH_NODE_SIZE + 24;
+        let mut cyclic = saved.clone();
+        cyclic[left_at..left_at + 4].copy_from_slice(&blas.root.to_le_bytes());
+        assert!(!blas.nodes[root].is_leaf());
+        assert!(load(&cyclic).is_err());
+
+        assert!(load(&saved[..saved.len() - 4]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::process::bvh::BVHConfig;
+
+pub(crate) const BIN_COUNT: usize = 16;
+
+#[derive(Copy, Clone)]
+struct Bin {
+    bounds: AABB,
+    count: usize,
+}
+
+// Cheapest binned SAH plane found over every axis. Centroids in the first `left_bins` bins of
+// `axis` go left.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct BinnedPlane {
+    pub(crate) axis: usize,
+    pub(crate) left_bins: usize,
+    pub(crate) cost: f32,
+    pub(crate) centroid_bounds: AABB,
+}
+
+// Bins centroids along each axis, sweeps the bin boundaries for the cheapest SAH split and
+// partitions the range around it. Returns the split index and its cost, falling back to a
+// median split with infinite cost when every centroid falls in the same place.
+pub fn binned_sah_split<T: Bounded>(primitives: &mut [T], start: usize, end: usize, bounds: &AABB, config: &BVHConfig) -> (usize, f32) {
+    match best_plane(&primitives[start..end], bounds, config) {
+        Some(plane) => (partition(primitives, start, end, plane.axis, plane.left_bins, &plane.centroid_bounds), plane.cost),
+        None => (start + (end - start) / 2, f32::INFINITY),
+    }
+}
+
+// None when every centroid falls in the same place
+pub(crate) fn best_plane<T: Bounded>(primitives: &[T], bounds: &AABB, config: &BVHConfig) -> Option<BinnedPlane> {
+    let centroid_bounds = centroid_bounds(primitives);
+    let area = bounds.surface_area();
+    let mut best: Option<BinnedPlane> = None;
+
+    for axis in 0..3 {
+        let extent = centroid_bounds.max[axis] - centroid_bounds.min[axis];
+        if extent <= 0.0 {
+            continue;
+        }
+
+        let mut bins = [Bin { bounds: AABB::empty(), count: 0 }; BIN_COUNT];
+        for primitive in primitives {
+            let b = bin_index(primitive.centroid()[axis], centroid_bounds.min[axis], extent);
+            bins[b].bounds.extend(&primitive.bounding_box(0.0, 0.0));
+            bins[b].count += 1;
+        }
+
+        // Sweep from the right to get the area and count right of every boundary
+        let mut right_areas = [0.0; BIN_COUNT];
+        let mut right_counts = [0; BIN_COUNT];
+        let mut right = AABB::empty();
+        let mut right_count = 0;
+        for i in (1..BIN_COUNT).rev() {
+            right.extend(&bins[i].bounds);
+            right_count += bins[i].count;
+            right_areas[i] = if right_count > 0 { right.surface_area() } else { 0.0 };
+            right_counts[i] = right_count;
+        }
+
+        let mut left = AABB::empty();
+        let mut left_count = 0;
+        for i in 0..BIN_COUNT - 1 {
+            left.extend(&bins[i].bounds);
+            left_count += bins[i].count;
+            if left_count == 0 || right_counts[i + 1] == 0 {
+                continue;
+            }
+            let cost = config.split_cost(area, left.surface_area(), left_count, right_areas[i + 1], right_counts[i + 1]);
+            if best.map_or(true, |b| cost < b.cost) {
+                best = Some(BinnedPlane { axis, left_bins: i + 1, cost, centroid_bounds });
+            }
+        }
+    }
+
+    best
+}
+
+// Moves the primitives in the first `left_bins` bins of `axis` to the front of the range, returns
+// where the right side starts
+pub(crate) fn partition<T: Bounded>(primitives: &mut [T], start: usize, end: usize, axis: usize, left_bins: usize, centroid_bounds: &AABB) -> usize {
+    let min = centroid_bounds.min[axis];
+    let extent = centroid_bounds.max[axis] - min;
+    let mut split = start;
+    for i in start..end {
+        if bin_index(primitives[i].centroid()[axis], min, extent) < left_bins {
+            primitives.swap(i, split);
+            split += 1;
+        }
+    }
+    split
+}
+
+pub(crate) fn centroid_bounds<T: Bounded>(primitives: &[T]) -> AABB {
+    let mut bounds = AABB::empty();
+    for primitive in primitives {
+        let c = primitive.centroid();
+        bounds.extend(&AABB::new([c[0], c[1], c[2], 0.0], [c[0], c[1], c[2], 0.0]));
+    }
+    bounds
+}
+
+fn bin_index(centroid: f32, min: f32, extent: f32) -> usize {
+    (((centroid - min) / extent * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::primitives::aabb::AABB;
+use crate::primitives::ray::Ray;
+use crate::process::bvh::BVHNode;
+use crate::process::bvh_threaded::{self, ThreadedBVHNode};
+
+// Simulated cache for the fetch statistics, 64 sets of 8 lines of 64 bytes
+const CACHE_LINE: usize = 64;
+const CACHE_SETS: usize = 64;
+const CACHE_WAYS: usize = 8;
+// Root and padding ahead of the nodes in BVHBuffer
+const BUFFER_HEADER: usize = 16;
+
+
+// Order binary nodes are stored in, which decides the nodes that share cache lines. Traversal
+// order is the same in all of them. Discriminants are stored in saved BVHs, append at the end.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum NodeLayout {
+    // Every node followed by its left subtree, then its right
+    DepthFirst,
+    // Depth first with the child of larger surface area, the likelier hit, next to its parent
+    LargerFirst,
+    // Level by level from the root
+    BreadthFirst,
+    // The top half of the tree by height, then every bottom half tree, each laid out the same way
+    // recursively. Subtrees of any height stay within a few consecutive runs of nodes.
+    VanEmdeBoas,
+}
+
+// Old index of the node at every position of `layout`, nodes unreachable from `root` are dropped
+pub fn layout_order(nodes: &[BVHNode], root: i32, layout: NodeLayout) -> Vec<i32> {
+    let mut order = Vec::with_capacity(nodes.len());
+    match layout {
+        NodeLayout::DepthFirst | NodeLayout::LargerFirst => {
+            let mut stack = vec![root];
+            while let Some(index) = stack.pop() {
+                order.push(index);
+                let node = nodes[index as usize];
+                if node.is_leaf() {
+                    continue;
+                }
+                let larger_right = nodes[node.right as usize].aabb.surface_area() > nodes[node.left as usize].aabb.surface_area();
+                if layout == NodeLayout::LargerFirst && larger_right {
+                    stack.extend([node.left, node.right]);
+                } else {
+                    stack.extend([node.right, node.left]);
+                }
+            }
+        },
+        NodeLayout::BreadthFirst => {
+            let mut queue = VecDeque::from([root]);
+            while let Some(index) = queue.pop_front() {
+                order.push(index);
+                let node = nodes[index as usize];
+                if !node.is_leaf() {
+                    queue.extend([node.left, node.right]);
+                }
+            }
+        },
+        NodeLayout::VanEmdeBoas => {
+            let mut below = Vec::new();
+            van_emde_boas(nodes, root, height(nodes, root), &mut order, &mut below);
+        },
+    }
+    order
+}
+
+// Nodes in `order` with their child links moved to match, and the new root
+pub fn apply_order(nodes: &[BVHNode], root: i32, order: &[i32]) -> (Vec<BVHNode>, i32) {
+    let mut position = vec![-1; nodes.len()];
+    for (new, &old) in order.iter().enumerate() {
+        position[old as usize] = new as i32;
+    }
+    let moved = order.iter()
+        .map(|&old| {
+            let mut node = nodes[old as usize];
+            if !node.is_leaf() {
+                node.left = position[node.left as usize];
+                node.right = position[node.right as usize];
+            }
+            node
+        })
+        .collect();
+    (moved, position[root as usize])
+}
+
+// Lays out the top `levels` levels below `index` and collects the nodes just under them in `below`
+fn van_emde_boas(nodes: &[BVHNode], index: i32, levels: usize, order: &mut Vec<i32>, below: &mut Vec<i32>) {
+    let node = nodes[index as usize];
+    if levels <= 1 {
+        order.push(index);
+        if !node.is_leaf() {
+            below.extend([node.left, node.right]);
+        }
+        return;
+    }
+    let top = levels / 2;
+    let mut middle = Vec::new();
+    van_emde_boas(nodes, index, top, order, &mut middle);
+    for subtree in middle {
+        van_emde_boas(nodes, subtree, levels - top, order, below);
+    }
+}
+
+// Levels in the subtree at `index`, a lone leaf has one
+fn height(nodes: &[BVHNode], index: i32) -> usize {
+    let mut max = 0;
+    let mut stack = vec![(index, 1)];
+    while let Some((index, depth)) = stack.pop() {
+        max = max.max(depth);
+        let node = nodes[index as usize];
+        if !node.is_leaf() {
+            stack.extend([(node.left, depth + 1), (node.right, depth + 1)]);
+        }
+    }
+    max
+}
+
+
+// Node fetches of the binary traversal over a set of rays and how they fall on cache lines
+#[derive(Debug, Clone, Default)]
+pub struct TraversalStats {
+    pub rays: usize,
+    pub node_fetches: usize,
+    // Distinct cache lines each ray reads, summed over the rays
+    pub lines_touched: usize,
+    // Misses of a small cache kept across the rays in order, as coherent rays share it
+    pub cache_misses: usize,
+}
+
+impl TraversalStats {
+    // Traces `rays` through the threaded nodes, `hit` as for bvh_threaded::traverse
+    pub fn new<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[ThreadedBVHNode], root: i32, rays: &[Ray], mut hit: F) -> Self {
+        let node_size = std::mem::size_of::<ThreadedBVHNode>();
+        let mut stats = TraversalStats { rays: rays.len(), ..Default::default() };
+        let mut cache = vec![Vec::with_capacity(CACHE_WAYS); CACHE_SETS];
+        let mut lines = Vec::new();
+        for ray in rays {
+            lines.clear();
+            bvh_threaded::walk_observed(nodes, root, ray, false, &mut hit, |index| {
+                stats.node_fetches += 1;
+                let start = BUFFER_HEADER + index * node_size;
+                for line in start / CACHE_LINE..=(start + node_size - 1) / CACHE_LINE {
+                    lines.push(line);
+                    // Most recently used last
+                    let set: &mut Vec<usize> = &mut cache[line % CACHE_SETS];
+                    match set.iter().position(|&l| l == line) {
+                        Some(way) => {
+                            set.remove(way);
+                        },
+                        None => {
+                            stats.cache_misses += 1;
+                            if set.len() == CACHE_WAYS {
+                                set.remove(0);
+                            }
+                        },
+                    }
+                    set.push(line);
+                }
+            });
+            lines.sort_unstable();
+            lines.dedup();
+            stats.lines_touched += lines.len();
+        }
+        stats
+    }
+}
+
+impl fmt::Display for TraversalStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rays = self.rays.max(1) as f32;
+        write!(
+            f,
+            "{:.2} node fetches, {:.2} cache lines, {:.2} cache misses per ray",
+            self.node_fetches as f32 / rays,
+            self.lines_touched as f32 / rays,
+            self.cache_misses as f32 / rays,
+        )
+    }
+}
+
+// Primary rays of pinhole cameras looking at `bounds` from six sides, each a `resolution`
+// squared grid in scanline order like the GPU traces them
+pub fn view_rays(bounds: &AABB, resolution: usize) -> Vec<Ray> {
+    let center = bounds.centroid();
+    let radius = 0.5 * (0..3).map(|a| (bounds.max[a] - bounds.min[a]).powi(2)).sum::<f32>().sqrt();
+    let mut rays = Vec::with_capacity(6 * resolution * resolution);
+    for view in 0..6 {
+        let (axis, sign) = (view / 2, if view % 2 == 0 { 1.0 } else { -1.0 });
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let mut origin = center;
+        origin[axis] += sign * 3.0 * radius;
+        for y in 0..resolution {
+            for x in 0..resolution {
+                let mut target = center;
+                target[u] += ((x as f32 + 0.5) / resolution as f32 * 2.0 - 1.0) * radius;
+                target[v] += ((y as f32 + 0.5) / resolution as f32 * 2.0 - 1.0) * radius;
+                let direction = [target[0] - origin[0], target[1] - origin[1], target[2] - origin[2]];
+                rays.push(Ray::new(origin, direction));
+            }
+        }
+    }
+    rays
+}
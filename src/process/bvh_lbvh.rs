@@ -0,0 +1,271 @@
+use rayon::prelude::*;
+
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::process::bvh::{BVHConfig, BVHNode};
+
+// Neighbours searched on each side of a cluster during PLOC merging
+const PLOC_RADIUS: usize = 16;
+
+
+// Karras style radix tree over 30 bit Morton codes, every internal node built independently
+pub fn build_lbvh<T: Bounded + Send + Sync>(primitives: &mut [T], config: &BVHConfig) -> (Vec<BVHNode>, i32) {
+    let (codes, bounds, order) = morton_sort(primitives);
+    let n = codes.len();
+
+    // Children below n are leaves, internal node i is stored as n + i
+    let internal: Vec<(usize, usize)> = (0..n - 1).into_par_iter()
+        .map(|i| radix_node(&codes, i))
+        .collect();
+
+    // Internal node 0 is the root, a single primitive is its own root
+    let root = if n == 1 { 0 } else { n };
+    finish(primitives, &bounds, &order, &internal, root, config)
+}
+
+// Starts from the Morton sorted leaves and repeatedly merges mutual nearest neighbours within a
+// small window, giving SAH quality close to a full sweep at LBVH speed
+pub fn build_ploc<T: Bounded + Send + Sync>(primitives: &mut [T], config: &BVHConfig) -> (Vec<BVHNode>, i32) {
+    let (_, bounds, order) = morton_sort(primitives);
+    let n = bounds.len();
+
+    let mut internal: Vec<(usize, usize)> = Vec::with_capacity(n.saturating_sub(1));
+    let mut clusters: Vec<(usize, AABB)> = bounds.iter().enumerate().map(|(i, b)| (i, *b)).collect();
+
+    while clusters.len() > 1 {
+        let nearest: Vec<usize> = (0..clusters.len()).into_par_iter()
+            .map(|i| {
+                let lo = i.saturating_sub(PLOC_RADIUS);
+                let hi = (i + PLOC_RADIUS + 1).min(clusters.len());
+                let mut best = (f32::INFINITY, i);
+                for j in (lo..hi).filter(|&j| j != i) {
+                    let area = clusters[i].1.union(&clusters[j].1).surface_area();
+                    if area < best.0 {
+                        best = (area, j);
+                    }
+                }
+                best.1
+            })
+            .collect();
+
+        // Ties resolve to the lowest index so the closest pair is always mutual
+        let mut merged = Vec::with_capacity(clusters.len());
+        for i in 0..clusters.len() {
+            let j = nearest[i];
+            if nearest[j] != i {
+                merged.push(clusters[i]);
+            } else if i < j {
+                internal.push((clusters[i].0, clusters[j].0));
+                merged.push((n + internal.len() - 1, clusters[i].1.union(&clusters[j].1)));
+            }
+        }
+        clusters = merged;
+    }
+
+    finish(primitives, &bounds, &order, &internal, clusters[0].0, config)
+}
+
+
+// Sorts primitives by the Morton code of their centroid. Returns the codes and bounds in sorted
+// order along with the original index of every sorted primitive.
+fn morton_sort<T: Bounded + Send + Sync>(primitives: &[T]) -> (Vec<u32>, Vec<AABB>, Vec<usize>) {
+    if primitives.is_empty() {
+        panic!("BVH built over no primitives");
+    }
+
+    let centroids: Vec<[f32; 3]> = primitives.par_iter().map(|p| p.centroid()).collect();
+    let (min, max) = centroids.par_iter()
+        .fold(|| ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]), |(mut min, mut max), c| {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(c[axis]);
+                max[axis] = max[axis].max(c[axis]);
+            }
+            (min, max)
+        })
+        .reduce(|| ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]), |(a_min, a_max), (b_min, b_max)| {
+            let mut min = a_min;
+            let mut max = a_max;
+            for axis in 0..3 {
+                min[axis] = min[axis].min(b_min[axis]);
+                max[axis] = max[axis].max(b_max[axis]);
+            }
+            (min, max)
+        });
+
+    let mut keys: Vec<(u32, usize)> = centroids.par_iter().enumerate()
+        .map(|(i, c)| {
+            let mut cell = [0u32; 3];
+            for axis in 0..3 {
+                let extent = max[axis] - min[axis];
+                let t = if extent > 0.0 { (c[axis] - min[axis]) / extent } else { 0.5 };
+                cell[axis] = (t * 1023.0).max(0.0).min(1023.0) as u32;
+            }
+            (expand_bits(cell[0]) << 2 | expand_bits(cell[1]) << 1 | expand_bits(cell[2]), i)
+        })
+        .collect();
+    keys.par_sort_unstable();
+
+    let codes = keys.iter().map(|k| k.0).collect();
+    let order: Vec<usize> = keys.iter().map(|k| k.1).collect();
+    let bounds = order.par_iter().map(|&i| primitives[i].bounding_box(0.0, 0.0)).collect();
+    (codes, bounds, order)
+}
+
+// Spreads the low 10 bits so two zero bits follow each one
+fn expand_bits(v: u32) -> u32 {
+    let mut v = v & 0x3ff;
+    v = (v | v << 16) & 0x030000ff;
+    v = (v | v << 8) & 0x0300f00f;
+    v = (v | v << 4) & 0x030c30c3;
+    v = (v | v << 2) & 0x09249249;
+    v
+}
+
+// Length of the common prefix of two sorted codes, duplicates fall back to their indices
+fn common_prefix(codes: &[u32], i: i64, j: i64) -> i64 {
+    if j < 0 || j >= codes.len() as i64 {
+        return -1;
+    }
+    let (a, b) = (codes[i as usize], codes[j as usize]);
+    if a == b {
+        32 + (i as u32 ^ j as u32).leading_zeros() as i64
+    } else {
+        (a ^ b).leading_zeros() as i64
+    }
+}
+
+// Range covered by internal node i and where it splits, from Karras 2012
+fn radix_node(codes: &[u32], i: usize) -> (usize, usize) {
+    let n = codes.len();
+    let i = i as i64;
+    let d = if common_prefix(codes, i, i + 1) > common_prefix(codes, i, i - 1) { 1 } else { -1 };
+
+    // Upper bound on the range length, then binary search for the other end
+    let min_prefix = common_prefix(codes, i, i - d);
+    let mut max_len = 2;
+    while common_prefix(codes, i, i + max_len * d) > min_prefix {
+        max_len *= 2;
+    }
+    let mut len = 0;
+    let mut t = max_len / 2;
+    while t >= 1 {
+        if common_prefix(codes, i, i + (len + t) * d) > min_prefix {
+            len += t;
+        }
+        t /= 2;
+    }
+    let j = i + len * d;
+
+    // Find the split, the last position sharing more than the node's prefix with i
+    let node_prefix = common_prefix(codes, i, j);
+    let mut split = 0;
+    let mut t = len;
+    loop {
+        t = (t + 1) / 2;
+        if common_prefix(codes, i, i + (split + t) * d) > node_prefix {
+            split += t;
+        }
+        if t <= 1 {
+            break;
+        }
+    }
+    let gamma = (i + split * d + d.min(0)) as usize;
+
+    let left = if i.min(j) as usize == gamma { gamma } else { n + gamma };
+    let right = if i.max(j) as usize == gamma + 1 { gamma + 1 } else { n + gamma + 1 };
+    (left, right)
+}
+
+// Flattens the binary tree into range leaves and puts the primitives in leaf order
+fn finish<T: Bounded>(primitives: &mut [T], bounds: &[AABB], order: &[usize], internal: &[(usize, usize)], root: usize, config: &BVHConfig) -> (Vec<BVHNode>, i32) {
+    let n = bounds.len();
+    let mut collapse = Collapse {
+        bounds,
+        internal,
+        n,
+        config,
+        nodes: Vec::with_capacity(2 * n),
+        leaf_order: Vec::with_capacity(n),
+    };
+    let (root, _) = collapse.node(root);
+
+    let permutation: Vec<usize> = collapse.leaf_order.iter().map(|&i| order[i]).collect();
+    permute(primitives, &permutation);
+    (collapse.nodes, root)
+}
+
+struct Collapse<'a> {
+    bounds: &'a [AABB],
+    internal: &'a [(usize, usize)],
+    n: usize,
+    config: &'a BVHConfig,
+    nodes: Vec<BVHNode>,
+    leaf_order: Vec<usize>,
+}
+
+impl Collapse<'_> {
+    // Returns the node index and the number of primitives below it
+    fn node(&mut self, id: usize) -> (i32, usize) {
+        let first = self.leaf_order.len();
+        if id < self.n {
+            self.leaf_order.push(id);
+            return (self.push_leaf(self.bounds[id], first, 1), 1);
+        }
+
+        let mark = self.nodes.len();
+        let (l, r) = self.internal[id - self.n];
+        let (left, left_count) = self.node(l);
+        let (right, right_count) = self.node(r);
+        let (left_box, right_box) = (self.nodes[left as usize].aabb, self.nodes[right as usize].aabb);
+        let aabb = left_box.union(&right_box);
+        let count = left_count + right_count;
+
+        // Same leaf rule as the top down builders, the subtree is dropped for a cheaper leaf
+        if count <= self.config.max_leaf_size {
+            let split_cost = self.config.split_cost(aabb.surface_area(), left_box.surface_area(), left_count, right_box.surface_area(), right_count);
+            if self.config.intersection_cost * count as f32 <= split_cost {
+                self.nodes.truncate(mark);
+                return (self.push_leaf(aabb, first, count), count);
+            }
+        }
+
+        self.nodes.push(BVHNode {
+            aabb,
+            left,
+            right,
+            first: -1,
+            count: 0,
+        });
+        ((self.nodes.len() - 1) as i32, count)
+    }
+
+    fn push_leaf(&mut self, aabb: AABB, first: usize, count: usize) -> i32 {
+        self.nodes.push(BVHNode {
+            aabb,
+            left: -1,
+            right: -1,
+            first: first as i32,
+            count: count as i32,
+        });
+        (self.nodes.len() - 1) as i32
+    }
+}
+
+// Moves primitives[permutation[i]] to i by following cycles of swaps
+pub(crate) fn permute<T>(primitives: &mut [T], permutation: &[usize]) {
+    let mut done = vec![false; permutation.len()];
+    for start in 0..permutation.len() {
+        if done[start] {
+            continue;
+        }
+        let mut i = start;
+        loop {
+            done[i] = true;
+            let source = permutation[i];
+            if source == start {
+                break;
+            }
+            primitives.swap(i, source);
+            i = source;
+        }
+    }
+}
@@ -1,435 +1,457 @@
-use std::cmp::Ordering;
-use wgpu::util::DeviceExt;
+use anyhow::{bail, Result};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
 use rayon::prelude::*;
-use std::sync::{Arc, Mutex};
 
-use crate::primitives::aabb::AABB;
-use crate::primitives::triangle::TriangleCPU;
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::process::bvh::{BVHBuilder, BVHConfig, BVHNode};
+use crate::process::bvh_binned::{BIN_COUNT, best_plane, binned_sah_split, centroid_bounds, partition};
+use crate::process::reader::Reader;
 
+// Cells per axis of the centroid grid describing a node
 const BIN_SIZE: usize = 8;
+// Grid cells, the node extents relative to the longest one and log2 of the primitive count
+pub const FEATURE_COUNT: usize = BIN_SIZE * BIN_SIZE * BIN_SIZE + 4;
+// Smaller nodes are cheap to split exactly, they are neither sampled nor predicted
+pub const MIN_PREDICTED: usize = 32;
+// Label columns of the dumped rows, the features follow them in the csv
+pub const LABEL_COLUMNS: [&str; 8] = ["mesh", "node_index", "parent_index", "parent_axis", "depth", "count", "axis", "split"];
+
+// Weights file (little endian):
+//   b"KMLP", u32 version, u32 layer count,
+//   per layer u32 inputs, u32 outputs, f32 weights[outputs][inputs], f32 biases[outputs]
+// Hidden layers use ReLU. The last has three axis scores and the split position as a logit.
+const KMLP_MAGIC: &[u8; 4] = b"KMLP";
+const KMLP_VERSION: u32 = 1;
+const MLP_OUTPUTS: usize = 4;
+
+// Training of `train`, Adam over shuffled minibatches
+const TRAIN_HIDDEN: usize = 32;
+const TRAIN_EPOCHS: usize = 40;
+const TRAIN_BATCH: usize = 32;
+const TRAIN_RATE: f32 = 1e-3;
+
+
+// Normalized centroid counts over a BIN_SIZE^3 grid spanning the node
+fn eval_primitive_bins<T: Bounded + Sync>(primitives: &[T], bounds: &AABB, grid_size: usize) -> Vec<f32> {
+    let cell = |c: f32, axis: usize| {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        if extent > 0.0 {
+            (((c - bounds.min[axis]) / extent * grid_size as f32) as usize).min(grid_size - 1)
+        } else {
+            0
+        }
+    };
 
-fn eval_primitive_bins(primitives: &[TriangleCPU], start: usize, end: usize, grid_size: usize) -> Vec<f32> {
-    let primitive_bins = Arc::new(Mutex::new(vec![0.0; grid_size * grid_size * grid_size]));
-    let bbox = AABB::bounding_box_for_slice(&primitives[start..end], 0, end - start);
-    let min = bbox.min;
-    let max = bbox.max;
-
-    primitives[start..end].par_iter().for_each(|tri| {
-        let centroid = tri.centroid();
-        let x = ((centroid[0] - min[0]) / (max[0] - min[0]) * (grid_size as f32)).min(grid_size as f32 - 1.0) as usize;
-        let y = ((centroid[1] - min[1]) / (max[1] - min[1]) * (grid_size as f32)).min(grid_size as f32 - 1.0) as usize;
-        let z = ((centroid[2] - min[2]) / (max[2] - min[2]) * (grid_size as f32)).min(grid_size as f32 - 1.0) as usize;
-        let bin_index = x + y * grid_size + z * grid_size * grid_size;
-
-        let mut bins = primitive_bins.lock().unwrap();
-        bins[bin_index] += 1.0;
-    });
-
-    let bins = primitive_bins.lock().unwrap();
-    let max_count = bins.iter().max_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)).unwrap_or(&1.0);
-    let normalized_bins: Vec<f32> = bins.iter().map(|count| count / max_count).collect();
+    let bins = primitives.par_iter()
+        .fold(|| vec![0.0; grid_size * grid_size * grid_size], |mut bins, primitive| {
+            let c = primitive.centroid();
+            bins[cell(c[0], 0) + cell(c[1], 1) * grid_size + cell(c[2], 2) * grid_size * grid_size] += 1.0;
+            bins
+        })
+        .reduce(|| vec![0.0; grid_size * grid_size * grid_size], |mut a, b| {
+            a.iter_mut().zip(&b).for_each(|(a, b)| *a += b);
+            a
+        });
 
-    normalized_bins
+    let max_count = bins.iter().cloned().fold(1.0, f32::max);
+    bins.iter().map(|count| count / max_count).collect()
 }
 
-
-// fn eval_primitive_features(primitives: &[TriangleCPU], start: usize, end: usize) -> Vec<f32> {
-//     let mut primitive_features = vec![0.0; 6];
-//     let bbox = AABB::bounding_box_for_slice(&primitives[start..end], 0, end - start);
-//     let min = bbox.min;
-//     let max = bbox.max;
-//     let mut centroid_sums = [0.0; 3];
-//     let mut centroid_sums_squared = [0.0; 3];
-//     let mut surface_area_sums = [0.0; 3];
-//     for tri in &primitives[start..end] {
-//         let centroid = tri.centroid();
-//         let bbox_surface_area = tri.bbox_surface_area();
-//         for axis in 0..3 {
-//             centroid_sums[axis] += centroid[axis];
-//             centroid_sums_squared[axis] += centroid[axis].powi(2);
-//             surface_area_sums[axis] += bbox_surface_area;
-//         }
-//     }
-//     for axis in 0..3 {
-//         let centroid_mean = centroid_sums[axis] / (end - start) as f32;
-//         let centroid_variance = centroid_sums_squared[axis] / (end - start) as f32 - centroid_mean.powi(2);
-//         let surface_area_mean = surface_area_sums[axis] / (end - start) as f32;
-//         let mut surface_area_variance_sum = 0.0;
-//         for i in start..end {
-//             let bbox = primitives[i].bounding_box(0.0, 0.0);
-//             let bbox_surface_area = primitives[i].bbox_surface_area();
-//             let difference = bbox_surface_area - surface_area_mean;
-//             surface_area_variance_sum += difference * difference;
-//         }
-//         let surface_area_variance = surface_area_variance_sum / (end - start) as f32;
-//         primitive_features[axis] = centroid_mean;
-//         primitive_features[axis + 3] = centroid_variance + surface_area_variance;
-//     }
-//     primitive_features
-// }
+// Inputs of a SplitPredictor for the node over `primitives`
+pub fn node_features<T: Bounded + Sync>(primitives: &[T], bounds: &AABB) -> Vec<f32> {
+    let mut features = eval_primitive_bins(primitives, bounds, BIN_SIZE);
+    let extents = [0, 1, 2].map(|axis| bounds.max[axis] - bounds.min[axis]);
+    let longest = extents[0].max(extents[1]).max(extents[2]).max(f32::MIN_POSITIVE);
+    features.extend(extents.iter().map(|e| e / longest));
+    features.push((primitives.len() as f32).log2());
+    features
+}
 
 
+// One sampled node, labelled with the binned SAH split it takes. The root has no parent, -1.
 #[derive(Debug, Clone)]
 pub struct BVHNodeData {
-    primitive_bins: Vec<f32>,
-    // parent_primitive_bins: Vec<f32>,
-    node_index: u32,
-    axis: u32,
-    split: u32,
-    parent_axis: u32,
-    parent_index: u32,
+    pub features: Vec<f32>,
+    pub mesh: u32,
+    pub node_index: u32,
+    pub parent_index: i32,
+    pub parent_axis: i32,
+    pub depth: u32,
+    pub count: u32,
+    pub axis: u32,
+    // Split plane as a fraction of the centroid extent along `axis`
+    pub split: f32,
 }
 
 impl BVHNodeData {
-    pub fn empty() -> BVHNodeData {
-        BVHNodeData {
-            primitive_bins: vec![],
-            // parent_primitive_bins: vec![],
-            node_index: 0,
-            axis: 0,
-            split: 0,
-            parent_axis: 0,
-            parent_index: 0,
-        }
+    pub fn labels(&self) -> [f32; LABEL_COLUMNS.len()] {
+        [
+            self.mesh as f32,
+            self.node_index as f32,
+            self.parent_index as f32,
+            self.parent_axis as f32,
+            self.depth as f32,
+            self.count as f32,
+            self.axis as f32,
+            self.split,
+        ]
     }
 }
 
-
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct BVHNode {
-    aabb: AABB,
-    left: i32,
-    right: i32,
-    triangle: i32,
-    _padding: i32, 
+// Rows for every node of at least MIN_PREDICTED primitives in a binned SAH build, parents before
+// their children. Primitives are left in the order of the build.
+pub fn training_data<T: Bounded + Sync>(primitives: &mut [T], config: &BVHConfig) -> Vec<BVHNodeData> {
+    let mut rows = Vec::new();
+    let end = primitives.len();
+    sample(primitives, 0, end, (-1, -1), 0, config, &mut rows);
+    rows
 }
-impl BVHNode {
-    fn new(nodes: &mut Vec<BVHNode>, primitives: &mut [TriangleCPU], start: usize, end: usize, out_data: &mut Vec<BVHNodeData>) -> i32 {
-        if start >= end {
-            panic!("BVHNode::new called with invalid range");
-        }
-
-        let object_span = end - start;
-        let mut aabb;
-        let mut left: i32 = -1;
-        let mut right: i32 = -1;
-        let mut triangle: i32 = -1;
-
-        if object_span == 1 {
-            // Leaf 
-            aabb = primitives[start].bounding_box(0.0, 0.0);
-            triangle = start as i32;
-        } else {
-            // Branch
-            let (axis, split) = best_divide(primitives, start, end);
-            nodes.push(BVHNode {
-                aabb: AABB::empty(), // Placeholder AABB
-                left: -1,
-                right: -1,
-                triangle: -1,
-                _padding: 0,
-            });
-            let node_index = (nodes.len() - 1) as u32;
-            left = BVHNode::new(nodes, primitives, start, split, out_data);
-            right = BVHNode::new(nodes, primitives, split, end, out_data);
-            aabb = nodes[left as usize].aabb.union(&nodes[right as usize].aabb);
-
-            let parent_data = if out_data.is_empty() {
-                BVHNodeData::empty()
-            } else {
-                out_data.last().unwrap().clone()
-            };
-
-            let ml_data = BVHNodeData {
-                primitive_bins: eval_primitive_bins(primitives, start, end, BIN_SIZE),
-                node_index,
-                axis: axis as u32,
-                split: split as u32,
-                parent_axis: parent_data.axis,
-                parent_index: parent_data.node_index,
-            };
-            out_data.push(ml_data);
-
-            // Update the placeholder node
-            nodes[node_index as usize] = BVHNode {
-                aabb,
-                left,
-                right,
-                triangle,
-                _padding: 0 as i32,
-            };
 
-            return node_index as i32;
-        }
-
-        // Leaves
-        nodes.push(BVHNode {
-            aabb,
-            left,
-            right,
-            triangle,
-            _padding: 0 as i32,
-        });
+fn sample<T: Bounded + Sync>(primitives: &mut [T], start: usize, end: usize, parent: (i32, i32), depth: u32, config: &BVHConfig, rows: &mut Vec<BVHNodeData>) {
+    let span = end - start;
+    if span < MIN_PREDICTED {
+        return;
+    }
+    let bounds = AABB::bounding_box_for_slice(&primitives[start..end], 0, span);
+    let plane = match best_plane(&primitives[start..end], &bounds, config) {
+        Some(plane) => plane,
+        None => return,
+    };
+    if span <= config.max_leaf_size && config.intersection_cost * span as f32 <= plane.cost {
+        return;
+    }
 
-        let node_index = (nodes.len() - 1) as u32;
-        
-        if object_span == 1 {
-            let parent_data = if out_data.is_empty() {
-                BVHNodeData::empty()
-            } else {
-                out_data.last().unwrap().clone()
-            };
+    let node_index = rows.len() as u32;
+    rows.push(BVHNodeData {
+        features: node_features(&primitives[start..end], &bounds),
+        mesh: 0,
+        node_index,
+        parent_index: parent.0,
+        parent_axis: parent.1,
+        depth,
+        count: span as u32,
+        axis: plane.axis as u32,
+        split: plane.left_bins as f32 / BIN_COUNT as f32,
+    });
 
-            let ml_data = BVHNodeData {
-                primitive_bins: eval_primitive_bins(primitives, start, end, BIN_SIZE),
-                node_index,
-                axis: 0, 
-                split: 0, 
-                parent_axis: parent_data.axis,
-                parent_index: parent_data.node_index,
-            };
-            out_data.push(ml_data);
-        }
+    let split = partition(primitives, start, end, plane.axis, plane.left_bins, &plane.centroid_bounds);
+    let this = (node_index as i32, plane.axis as i32);
+    sample(primitives, start, split, this, depth + 1, config, rows);
+    sample(primitives, split, end, this, depth + 1, config, rows);
+}
 
-        node_index as i32
+pub fn csv(rows: &[BVHNodeData]) -> String {
+    let mut out = LABEL_COLUMNS.join(",");
+    for i in 0..FEATURE_COUNT {
+        out += &format!(",f{}", i);
+    }
+    out.push('\n');
+    for row in rows {
+        let values: Vec<String> = row.labels().iter().chain(row.features.iter()).map(|v| v.to_string()).collect();
+        out += &values.join(",");
+        out.push('\n');
     }
+    out
 }
 
+// NumPy .npy file (format 1.0) of a little endian f32 array with the given shape
+pub fn npy(shape: &[usize], data: &[f32]) -> Vec<u8> {
+    let dims: Vec<String> = shape.iter().map(|d| d.to_string()).collect();
+    let shape = if dims.len() == 1 { format!("({},)", dims[0]) } else { format!("({})", dims.join(", ")) };
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {}, }}", shape);
+    // Magic, version and header length take 10 bytes, the data starts 64 byte aligned
+    while (10 + header.len() + 1) % 64 != 0 {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut out = Vec::with_capacity(10 + header.len() + 4 * data.len());
+    out.extend_from_slice(b"\x93NUMPY\x01\x00");
+    out.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    out.extend_from_slice(header.as_bytes());
+    for v in data {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
 
 
+// Chooses where a node splits from its `node_features`, as an axis and the plane position as a
+// fraction of the centroid extent along it. None, or a plane leaving one side empty, falls back
+// to binned SAH for that node.
+pub trait SplitPredictor {
+    fn predict(&self, features: &[f32]) -> Option<(usize, f32)>;
+}
 
-#[repr(C)]
-#[derive(Debug, Clone)]
-pub struct BVH {
-    root: i32,
-    _padding: [i32; 3],
-    nodes: Vec<BVHNode>,
-    ml_data: Vec<BVHNodeData>,
+// Top down build asking `predictor` to split every node of at least MIN_PREDICTED primitives,
+// smaller ones are built with binned SAH
+pub fn build_predicted<T: Bounded + Send + Sync>(primitives: &mut [T], config: &BVHConfig, predictor: &dyn SplitPredictor) -> (Vec<BVHNode>, i32) {
+    let mut nodes = Vec::new();
+    let end = primitives.len();
+    let binned = config.with_builder(BVHBuilder::BinnedSAH);
+    let root = predicted_node(&mut nodes, primitives, 0, end, &binned, predictor);
+    (nodes, root)
 }
 
-impl BVH {
-    pub fn new(primitives: &mut [TriangleCPU], seed: u64) -> BVH {
-        let mut nodes = Vec::new();
-        let mut ml_data: Vec<BVHNodeData> = Vec::new();
-        let root = BVHNode::new(&mut nodes, primitives, 0, primitives.len(), &mut ml_data);
-        for n in &mut ml_data {
-            log::warn!("{:#?}", n);
-        }
-        BVH { root, _padding: [0; 3], nodes, ml_data }
+fn predicted_node<T: Bounded + Send + Sync>(nodes: &mut Vec<BVHNode>, primitives: &mut [T], start: usize, end: usize, config: &BVHConfig, predictor: &dyn SplitPredictor) -> i32 {
+    let span = end - start;
+    if span < MIN_PREDICTED {
+        return BVHNode::new(nodes, primitives, start, end, config);
     }
 
-    pub fn to_buffer(&self, device: &wgpu::Device) -> wgpu::Buffer {
-        let root_bytes = bytemuck::bytes_of(&self.root);
-        let padding_bytes = bytemuck::bytes_of(&self._padding);
-        let node_bytes = bytemuck::cast_slice(&self.nodes);
-        let bytes: Vec<u8> = [&root_bytes, &padding_bytes, node_bytes].concat();
-
-        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("BVH Buffer"),
-            contents: &bytes,
-            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    let aabb = AABB::bounding_box_for_slice(&primitives[start..end], 0, span);
+    let centroids = centroid_bounds(&primitives[start..end]);
+    let split = predictor.predict(&node_features(&primitives[start..end], &aabb))
+        .filter(|&(axis, _)| axis < 3 && centroids.max[axis] > centroids.min[axis])
+        .map(|(axis, fraction)| {
+            let left_bins = ((fraction * BIN_COUNT as f32).round() as usize).max(1).min(BIN_COUNT - 1);
+            partition(primitives, start, end, axis, left_bins, &centroids)
         })
-    }
+        .filter(|&split| split > start && split < end)
+        .or_else(|| {
+            let (split, cost) = binned_sah_split(primitives, start, end, &aabb, config);
+            Some(split).filter(|_| cost.is_finite())
+        });
 
-    pub fn nodes(&self) -> &Vec<BVHNode> {
-        &self.nodes
+    match split {
+        Some(split) => {
+            let left = predicted_node(nodes, primitives, start, split, config, predictor);
+            let right = predicted_node(nodes, primitives, split, end, config, predictor);
+            nodes.push(BVHNode {
+                aabb,
+                left,
+                right,
+                first: -1,
+                count: 0,
+            });
+            (nodes.len() - 1) as i32
+        },
+        // Every centroid in one place, the regular builder settles the leaf
+        None => BVHNode::new(nodes, primitives, start, end, config),
     }
+}
 
-    pub fn root(&self) -> i32 {
-        self.root
-    }
 
-    pub fn ml_data(&self) -> &Vec<BVHNodeData> {
-        &self.ml_data
-    }
+struct Layer {
+    inputs: usize,
+    outputs: usize,
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
 
-    pub fn reorder_nodes(&mut self) {
-        let mut ordered_nodes = Vec::with_capacity(self.nodes.len());
-        let new_root = self.reorder_nodes_recursive(self.root, &mut ordered_nodes);
-        
-        // Update the root index
-        self.root = new_root;
-        
-        // Replace the original nodes with the reordered nodes
-        self.nodes = ordered_nodes;
-    }
+// Small fully connected network trained on the dumped rows
+pub struct Mlp {
+    layers: Vec<Layer>,
+}
 
-    fn reorder_nodes_recursive(&self, node_index: i32, ordered_nodes: &mut Vec<BVHNode>) -> i32 {
-        if node_index == -1 {
-            return -1;
+impl Mlp {
+    pub fn load(data: &[u8]) -> Result<Mlp> {
+        if !data.starts_with(KMLP_MAGIC) {
+            bail!("not an MLP weights file");
+        }
+        let mut reader = Reader::new(data, KMLP_MAGIC.len());
+        let version = reader.u32()?;
+        if version != KMLP_VERSION {
+            bail!("unsupported MLP weights version {}", version);
         }
 
-        let node = self.nodes[node_index as usize];
-        let new_index = ordered_nodes.len() as i32;
-        ordered_nodes.push(node);
-
-        // Recursively reorder left and right child nodes
-        let new_left = self.reorder_nodes_recursive(node.left, ordered_nodes);
-        let new_right = self.reorder_nodes_recursive(node.right, ordered_nodes);
-
-        // Update the left and right indices of the current node
-        let mut new_node = ordered_nodes[new_index as usize];
-        new_node.left = new_left;
-        new_node.right = new_right;
-        ordered_nodes[new_index as usize] = new_node;
+        let layer_count = reader.u32()? as usize;
+        let mut layers: Vec<Layer> = Vec::with_capacity(layer_count.min(16));
+        for _ in 0..layer_count {
+            let inputs = reader.u32()? as usize;
+            let outputs = reader.u32()? as usize;
+            let expected = layers.last().map_or(FEATURE_COUNT, |l| l.outputs);
+            if inputs != expected {
+                bail!("MLP layer {} takes {} inputs, expected {}", layers.len(), inputs, expected);
+            }
+            let weights = match inputs.checked_mul(outputs) {
+                Some(n) => reader.f32s(n)?,
+                None => bail!("MLP layer {} has {}x{} weights", layers.len(), outputs, inputs),
+            };
+            let biases = reader.f32s(outputs)?;
+            layers.push(Layer { inputs, outputs, weights, biases });
+        }
 
-        new_index
+        match layers.last() {
+            Some(last) if last.outputs == MLP_OUTPUTS => Ok(Mlp { layers }),
+            Some(last) => bail!("MLP has {} outputs, expected {}", last.outputs, MLP_OUTPUTS),
+            None => bail!("MLP has no layers"),
+        }
     }
-}
 
-fn box_compare(a: &TriangleCPU, b: &TriangleCPU, axis: usize) -> Ordering {
-    a.bounding_box(0.0, 0.0).min[axis].partial_cmp(&b.bounding_box(0.0, 0.0).min[axis]).unwrap_or(Ordering::Equal)
-}
+    pub fn save(&self) -> Vec<u8> {
+        let mut out = KMLP_MAGIC.to_vec();
+        out.extend_from_slice(&KMLP_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.layers.len() as u32).to_le_bytes());
+        for layer in &self.layers {
+            out.extend_from_slice(&(layer.inputs as u32).to_le_bytes());
+            out.extend_from_slice(&(layer.outputs as u32).to_le_bytes());
+            for v in layer.weights.iter().chain(&layer.biases) {
+                out.extend_from_slice(&v.to_le_bytes());
+            }
+        }
+        out
+    }
 
-fn best_divide(primitives: &mut [TriangleCPU], start: usize, end: usize) -> (usize, usize) {
-    
-    let num_primitives = end - start;
-    let centroid_sums = Mutex::new([0.0; 3]);
-    let centroid_sums_squared = Mutex::new([0.0; 3]);
-    let surface_area_sums = Mutex::new([0.0; 3]);
-
-    // Compute the sums of centroid coordinates and surface areas along each axis
-    primitives[start..end].par_iter().for_each(|primitive| {
-        let centroid = primitive.centroid();
-        let bbox_surface_area = primitive.bbox_surface_area();
-        
-        for axis in 0..3 {
-            centroid_sums.lock().unwrap()[axis] += centroid[axis];
-            centroid_sums_squared.lock().unwrap()[axis] += centroid[axis].powi(2);
-            surface_area_sums.lock().unwrap()[axis] += bbox_surface_area;
+    // Fits one hidden layer to the rows of `training_data`, the axis as a softmax over the three
+    // scores and the split through a sigmoid. The same rows and seed give the same weights.
+    pub fn train(rows: &[BVHNodeData], seed: u64) -> Mlp {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut mlp = Mlp { layers: vec![Layer::random(FEATURE_COUNT, TRAIN_HIDDEN, &mut rng), Layer::random(TRAIN_HIDDEN, MLP_OUTPUTS, &mut rng)] };
+        let mut grads: Vec<Layer> = mlp.layers.iter().map(Layer::zeroed).collect();
+        let mut moments: Vec<(Layer, Layer)> = mlp.layers.iter().map(|l| (Layer::zeroed(l), Layer::zeroed(l))).collect();
+
+        let mut order: Vec<usize> = (0..rows.len()).collect();
+        let mut step = 0;
+        for _ in 0..TRAIN_EPOCHS {
+            order.shuffle(&mut rng);
+            for batch in order.chunks(TRAIN_BATCH) {
+                grads.iter_mut().for_each(|g| g.params().for_each(|p| *p = 0.0));
+                for &i in batch {
+                    mlp.backward(&rows[i], &mut grads);
+                }
+
+                step += 1;
+                let (beta1, beta2) = (0.9f32, 0.999f32);
+                let rate = TRAIN_RATE * (1.0 - beta2.powi(step)).sqrt() / (1.0 - beta1.powi(step));
+                for ((layer, grad), (m, v)) in mlp.layers.iter_mut().zip(&mut grads).zip(&mut moments) {
+                    for (((p, g), m), v) in layer.params().zip(grad.params()).zip(m.params()).zip(v.params()) {
+                        let g = *g / batch.len() as f32;
+                        *m = beta1 * *m + (1.0 - beta1) * g;
+                        *v = beta2 * *v + (1.0 - beta2) * g * g;
+                        *p -= rate * *m / (v.sqrt() + 1e-8);
+                    }
+                }
+            }
         }
-    });
+        mlp
+    }
 
-    // Compute variance
-    let mut max_combined_variance = 0.0;
-    let mut best_axis = 0;
-
-    for axis in 0..3 {
-        let centroid_mean = centroid_sums.lock().unwrap()[axis] / num_primitives as f32;
-        let centroid_variance = centroid_sums_squared.lock().unwrap()[axis] / num_primitives as f32 - centroid_mean.powi(2);
-        let surface_area_mean = surface_area_sums.lock().unwrap()[axis] / num_primitives as f32;
-        let mut surface_area_variance_sum = 0.0;
-        
-        // Sum of differences^2 from the mean for surface areas
-        for i in start..end {
-            let bbox = primitives[i].bounding_box(0.0, 0.0);
-            let bbox_surface_area = primitives[i].bbox_surface_area();
-            let difference = bbox_surface_area - surface_area_mean;
-            surface_area_variance_sum += difference * difference;
+    // Adds the loss gradients of one row to `grads`, returns the loss
+    fn backward(&self, row: &BVHNodeData, grads: &mut [Layer]) -> f32 {
+        let mut activations = vec![row.features.clone()];
+        for (i, layer) in self.layers.iter().enumerate() {
+            let values = layer.forward(&activations[i], i + 1 < self.layers.len());
+            activations.push(values);
         }
-        let surface_area_variance = surface_area_variance_sum / num_primitives as f32;
-
-        // Combine variances
-        let combined_variance = centroid_variance + surface_area_variance;
-        
-        if combined_variance > max_combined_variance {
-            max_combined_variance = combined_variance;
-            best_axis = axis;
+
+        let out = &activations[self.layers.len()];
+        let max = out[..3].iter().cloned().fold(f32::MIN, f32::max);
+        let exps: Vec<f32> = out[..3].iter().map(|s| (s - max).exp()).collect();
+        let sum: f32 = exps.iter().sum();
+        let split = 1.0 / (1.0 + (-out[3]).exp());
+        let axis = row.axis as usize;
+        let loss = (sum / exps[axis]).ln() - row.split * split.max(1e-7).ln() - (1.0 - row.split) * (1.0 - split).max(1e-7).ln();
+
+        let mut delta: Vec<f32> = (0..3).map(|a| exps[a] / sum - if a == axis { 1.0 } else { 0.0 }).collect();
+        delta.push(split - row.split);
+        for (i, (layer, grad)) in self.layers.iter().zip(grads.iter_mut()).enumerate().rev() {
+            let input = &activations[i];
+            for (o, d) in delta.iter().enumerate() {
+                grad.biases[o] += d;
+                for (g, x) in grad.weights[o * layer.inputs..(o + 1) * layer.inputs].iter_mut().zip(input) {
+                    *g += d * x;
+                }
+            }
+            // ReLU passes the gradient where its output was positive
+            delta = (0..layer.inputs)
+                .map(|k| if i > 0 && input[k] > 0.0 { (0..layer.outputs).map(|o| delta[o] * layer.weights[o * layer.inputs + k]).sum() } else { 0.0 })
+                .collect();
         }
+        loss
     }
 
-    primitives[start..end].par_sort_by(|a, b| box_compare(a, b, best_axis));
-    let best_index = sah_split(primitives, start, end);
+    // Mean loss of `backward` over the rows
+    pub fn loss(&self, rows: &[BVHNodeData]) -> f32 {
+        let mut grads: Vec<Layer> = self.layers.iter().map(Layer::zeroed).collect();
+        rows.iter().map(|row| self.backward(row, &mut grads)).sum::<f32>() / rows.len().max(1) as f32
+    }
 
-    (best_axis, best_index)
+    fn forward(&self, features: &[f32]) -> Vec<f32> {
+        let mut values = features.to_vec();
+        for (i, layer) in self.layers.iter().enumerate() {
+            values = layer.forward(&values, i + 1 < self.layers.len());
+        }
+        values
+    }
 }
 
+impl Layer {
+    // Uniform He initialization, the biases start at zero
+    fn random(inputs: usize, outputs: usize, rng: &mut StdRng) -> Layer {
+        let scale = (6.0 / inputs as f32).sqrt();
+        let weights = (0..inputs * outputs).map(|_| rng.gen_range(-scale..scale)).collect();
+        Layer { inputs, outputs, weights, biases: vec![0.0; outputs] }
+    }
 
-fn sah_middle_out(
-    primitives: &mut [TriangleCPU],
-    start: usize,
-    end: usize,
-) -> usize {
-
-    let range = end - start;
-    let mut split = range / 2;
-    let mut best_cost = f32::INFINITY;
-    let mut best_index = split;
-
-    // Go forward from middle
-    let mut i = split;
-    while i > 0 {
-        let left = AABB::bounding_box_for_slice(&primitives[start..start + i], 0, i).surface_area();
-        let right = AABB::bounding_box_for_slice(&primitives[start + i..end], 0, range - i).surface_area();
-        let cost = left * i as f32 + right * (range - i) as f32;
-
-        if cost < best_cost {
-            best_cost = cost;
-            best_index = i;
-        } else {
-            break;
-        }
+    fn zeroed(layer: &Layer) -> Layer {
+        Layer { inputs: layer.inputs, outputs: layer.outputs, weights: vec![0.0; layer.weights.len()], biases: vec![0.0; layer.outputs] }
+    }
 
-        i -= 1;
+    fn params(&mut self) -> impl Iterator<Item = &mut f32> {
+        self.weights.iter_mut().chain(self.biases.iter_mut())
     }
 
-    // Go backward from middle
-    let mut i = split + 1;
-    while i < range {
-        let left = AABB::bounding_box_for_slice(&primitives[start..start + i], 0, i).surface_area();
-        let right = AABB::bounding_box_for_slice(&primitives[start + i..end], 0, range - i).surface_area();
-        let cost = left * i as f32 + right * (range - i) as f32;
+    fn forward(&self, values: &[f32], hidden: bool) -> Vec<f32> {
+        (0..self.outputs)
+            .map(|o| {
+                let row = &self.weights[o * self.inputs..(o + 1) * self.inputs];
+                let sum = self.biases[o] + row.iter().zip(values).map(|(w, x)| w * x).sum::<f32>();
+                if hidden { sum.max(0.0) } else { sum }
+            })
+            .collect()
+    }
+}
 
-        if cost < best_cost {
-            best_cost = cost;
-            best_index = i;
-        } else {
-            break;
+impl SplitPredictor for Mlp {
+    fn predict(&self, features: &[f32]) -> Option<(usize, f32)> {
+        if features.len() != FEATURE_COUNT {
+            return None;
         }
-
-        i += 1;
+        let out = self.forward(features);
+        let axis = (0..3).max_by(|&a, &b| out[a].total_cmp(&out[b]))?;
+        let split = 1.0 / (1.0 + (-out[3]).exp());
+        if !split.is_finite() {
+            return None;
+        }
+        Some((axis, split))
     }
-
-    start + best_index
 }
 
 
-fn sah_split(
-    primitives: &mut [TriangleCPU],
-    start: usize,
-    end: usize,
-) -> usize {
-
-    let range = end - start;
-    let bucket_count = 12;
-    let bucket_size = range / bucket_count;
-    let mut best_cost = f32::INFINITY;
-    let mut best_index = 0;
-
-    let indices: Vec<_> = (0..bucket_count)
-        .into_par_iter()
-        .map(|i| {
-            let bucket_start = start + i * bucket_size;
-            let bucket_end = if i < bucket_count - 1 {
-                start + (i + 1) * bucket_size
-            } else {
-                end
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::scene::SceneObjectCPU;
+    use crate::process::glb::load_glb;
 
-            if bucket_start < bucket_end {
-                let left = AABB::bounding_box_for_slice(&primitives[start..bucket_end], 0, bucket_end - start).surface_area();
-                let right = AABB::bounding_box_for_slice(&primitives[bucket_end..end], 0, end - bucket_end).surface_area();
-                let cost = left * (bucket_end - start) as f32 + right * (end - bucket_end) as f32;
+    fn rows() -> Vec<BVHNodeData> {
+        let glb = load_glb(include_bytes!("../../assets/head.glb"));
+        let mut objects = SceneObjectCPU::from_tri_mesh(&glb.meshes()[0], 0);
+        training_data(&mut objects, &BVHConfig::default().with_builder(BVHBuilder::BinnedSAH)).into_iter().take(200).collect()
+    }
 
-                (cost, (bucket_start + bucket_end) / 2)
-            } else {
-                (f32::INFINITY, 0)
-            }
-        })
-        .collect();
+    #[test]
+    fn training_is_reproducible_and_lowers_the_loss() {
+        let rows = rows();
+        let untrained = Mlp { layers: vec![Layer::random(FEATURE_COUNT, TRAIN_HIDDEN, &mut StdRng::seed_from_u64(1)), Layer::random(TRAIN_HIDDEN, MLP_OUTPUTS, &mut StdRng::seed_from_u64(2))] };
+        let mlp = Mlp::train(&rows, 3);
+        assert!(mlp.loss(&rows) < untrained.loss(&rows));
+        assert_eq!(mlp.save(), Mlp::train(&rows, 3).save());
+        assert_eq!(Mlp::load(&mlp.save()).unwrap().save(), mlp.save());
+    }
 
-    for (cost, index) in indices {
-        if cost < best_cost {
-            best_cost = cost;
-            best_index = index;
+    #[test]
+    fn oversized_layers_are_rejected() {
+        let mut data = KMLP_MAGIC.to_vec();
+        for v in [KMLP_VERSION, 1, FEATURE_COUNT as u32, u32::MAX] {
+            data.extend_from_slice(&v.to_le_bytes());
         }
-    }
+        assert!(Mlp::load(&data).is_err());
 
-    if (best_index <= start || best_index >= end) {
-        best_index = (end - start) / 2 + start;
+        let saved = Mlp::load(include_bytes!("../../assets/bvh_split.kmlp")).unwrap().save();
+        assert!(Mlp::load(&saved[..saved.len() - 4]).is_err());
     }
-
-    best_index
 }
-
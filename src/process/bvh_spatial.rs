@@ -0,0 +1,267 @@
+use crate::primitives::aabb::{AABB, Bounded};
+use crate::process::bvh::{BVHConfig, BVHNode};
+use crate::process::bvh_binned::binned_sah_split;
+
+const SPATIAL_BIN_COUNT: usize = 32;
+// Spatial splits are only tried where the object split children overlap by more than this
+// fraction of the root surface area
+const OVERLAP_THRESHOLD: f32 = 1e-5;
+
+
+// A primitive, or the part of one left after spatial splits, within a node
+#[derive(Copy, Clone)]
+struct Reference {
+    index: usize,
+    bbox: AABB,
+}
+
+impl Bounded for Reference {
+    fn bounding_box(&self, _t0: f32, _t1: f32) -> AABB {
+        self.bbox
+    }
+
+    fn centroid(&self) -> [f32; 3] {
+        self.bbox.centroid()
+    }
+
+    fn bbox_surface_area(&self) -> f32 {
+        self.bbox.surface_area()
+    }
+}
+
+struct SpatialSplit {
+    axis: usize,
+    bin: usize,
+    cost: f32,
+    // References that end up on both sides
+    duplicates: usize,
+}
+
+
+// Split BVH after Stich et al. 2009, every node weighs the binned object split against splitting
+// space itself, clipping straddling primitives into both children. Primitives referenced from
+// several leaves are duplicated in the returned order, at most `spatial_budget` times their count.
+pub fn build_sbvh<T: Bounded + Clone>(primitives: &mut Vec<T>, config: &BVHConfig) -> (Vec<BVHNode>, i32) {
+    if primitives.is_empty() {
+        panic!("BVH built over no primitives");
+    }
+
+    let references: Vec<Reference> = primitives.iter().enumerate()
+        .map(|(index, p)| Reference { index, bbox: p.bounding_box(0.0, 0.0) })
+        .collect();
+    let root_area = AABB::bounding_box_for_slice(&references, 0, references.len()).surface_area();
+
+    let mut builder = SpatialBuilder {
+        primitives,
+        config,
+        root_area: root_area.max(f32::EPSILON),
+        reference_limit: primitives.len() + (primitives.len() as f32 * config.spatial_budget) as usize,
+        reference_count: primitives.len(),
+        nodes: Vec::new(),
+        leaf_references: Vec::new(),
+    };
+    let root = builder.node(references);
+
+    let ordered = builder.leaf_references.iter().map(|r| primitives[r.index].clone()).collect();
+    let nodes = builder.nodes;
+    *primitives = ordered;
+    (nodes, root)
+}
+
+struct SpatialBuilder<'a, T> {
+    primitives: &'a [T],
+    config: &'a BVHConfig,
+    root_area: f32,
+    reference_limit: usize,
+    reference_count: usize,
+    nodes: Vec<BVHNode>,
+    leaf_references: Vec<Reference>,
+}
+
+impl<T: Bounded> SpatialBuilder<'_, T> {
+    fn node(&mut self, mut references: Vec<Reference>) -> i32 {
+        let span = references.len();
+        let aabb = AABB::bounding_box_for_slice(&references, 0, span);
+
+        if span > 1 {
+            let (split, object_cost) = binned_sah_split(&mut references, 0, span, &aabb, self.config);
+
+            // Only worth searching where the object split children overlap
+            let overlap = if object_cost.is_finite() {
+                let left = AABB::bounding_box_for_slice(&references, 0, split);
+                let right = AABB::bounding_box_for_slice(&references, split, span);
+                left.intersection(&right).map_or(0.0, |o| o.surface_area())
+            } else {
+                f32::INFINITY
+            };
+            let spatial = if overlap / self.root_area > OVERLAP_THRESHOLD && self.reference_count < self.reference_limit {
+                self.spatial_split(&references, &aabb)
+                    .filter(|s| s.cost < object_cost && self.reference_count + s.duplicates <= self.reference_limit)
+            } else {
+                None
+            };
+
+            let split_cost = spatial.as_ref().map_or(object_cost, |s| s.cost);
+            let leaf_cost = self.config.intersection_cost * span as f32;
+            let make_leaf = span <= self.config.max_leaf_size && leaf_cost <= split_cost;
+
+            if !make_leaf {
+                let (left, right) = match spatial.and_then(|s| self.split_references(&references, &aabb, &s)) {
+                    Some(children) => children,
+                    None => {
+                        let right = references.split_off(split);
+                        (references, right)
+                    },
+                };
+                let left = self.node(left);
+                let right = self.node(right);
+                self.nodes.push(BVHNode {
+                    aabb,
+                    left,
+                    right,
+                    first: -1,
+                    count: 0,
+                });
+                return (self.nodes.len() - 1) as i32;
+            }
+        }
+
+        let first = self.leaf_references.len();
+        self.leaf_references.extend(references);
+        self.nodes.push(BVHNode {
+            aabb,
+            left: -1,
+            right: -1,
+            first: first as i32,
+            count: span as i32,
+        });
+        (self.nodes.len() - 1) as i32
+    }
+
+    // Bins every reference into each slab it covers, clipped to that slab, then sweeps the bin
+    // boundaries like the object split with entry and exit counts
+    fn spatial_split(&self, references: &[Reference], bounds: &AABB) -> Option<SpatialSplit> {
+        let area = bounds.surface_area();
+        let mut best: Option<SpatialSplit> = None;
+
+        for axis in 0..3 {
+            let extent = bounds.max[axis] - bounds.min[axis];
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let mut bins = [AABB::empty(); SPATIAL_BIN_COUNT];
+            let mut entries = [0usize; SPATIAL_BIN_COUNT];
+            let mut exits = [0usize; SPATIAL_BIN_COUNT];
+            for reference in references {
+                let (first, last) = self.bin_range(reference, bounds, axis);
+                for bin in first..=last {
+                    let slab = self.slab(bounds, axis, bin);
+                    let clipped = self.clip(reference, &slab);
+                    if !clipped.is_empty() {
+                        bins[bin].extend(&clipped);
+                    }
+                }
+                entries[first] += 1;
+                exits[last] += 1;
+            }
+
+            let mut right_areas = [0.0; SPATIAL_BIN_COUNT];
+            let mut right_counts = [0; SPATIAL_BIN_COUNT];
+            let mut right = AABB::empty();
+            let mut right_count = 0;
+            for i in (1..SPATIAL_BIN_COUNT).rev() {
+                right.extend(&bins[i]);
+                right_count += exits[i];
+                right_areas[i] = if right_count > 0 { right.surface_area() } else { 0.0 };
+                right_counts[i] = right_count;
+            }
+
+            let mut left = AABB::empty();
+            let mut left_count = 0;
+            for i in 0..SPATIAL_BIN_COUNT - 1 {
+                left.extend(&bins[i]);
+                left_count += entries[i];
+                if left_count == 0 || right_counts[i + 1] == 0 {
+                    continue;
+                }
+                let cost = self.config.split_cost(area, left.surface_area(), left_count, right_areas[i + 1], right_counts[i + 1]);
+                if best.as_ref().map_or(true, |b| cost < b.cost) {
+                    best = Some(SpatialSplit {
+                        axis,
+                        bin: i,
+                        cost,
+                        duplicates: left_count + right_counts[i + 1] - references.len(),
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    // Distributes references by the chosen plane, straddling ones are clipped into both sides
+    fn split_references(&mut self, references: &[Reference], bounds: &AABB, split: &SpatialSplit) -> Option<(Vec<Reference>, Vec<Reference>)> {
+        let plane = self.slab(bounds, split.axis, split.bin).max[split.axis];
+        let mut left = Vec::with_capacity(references.len());
+        let mut right = Vec::with_capacity(references.len());
+
+        for reference in references {
+            let (first, last) = self.bin_range(reference, bounds, split.axis);
+            if last <= split.bin {
+                left.push(*reference);
+            } else if first > split.bin {
+                right.push(*reference);
+            } else {
+                let mut left_box = reference.bbox;
+                left_box.max[split.axis] = plane;
+                let mut right_box = reference.bbox;
+                right_box.min[split.axis] = plane;
+                for (side, clip) in [(&mut left, left_box), (&mut right, right_box)] {
+                    let clipped = self.clip(reference, &clip);
+                    if !clipped.is_empty() {
+                        side.push(Reference { index: reference.index, bbox: clipped });
+                    }
+                }
+            }
+        }
+
+        // Clipping can leave a side empty when a primitive only touches the plane
+        if left.is_empty() || right.is_empty() {
+            return None;
+        }
+        self.reference_count += left.len() + right.len() - references.len();
+        Some((left, right))
+    }
+
+    fn bin_range(&self, reference: &Reference, bounds: &AABB, axis: usize) -> (usize, usize) {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        let bin = |x: f32| (((x - bounds.min[axis]) / extent * SPATIAL_BIN_COUNT as f32) as usize).min(SPATIAL_BIN_COUNT - 1);
+        (bin(reference.bbox.min[axis]), bin(reference.bbox.max[axis]))
+    }
+
+    fn slab(&self, bounds: &AABB, axis: usize, bin: usize) -> AABB {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        let mut slab = *bounds;
+        slab.min[axis] = bounds.min[axis] + extent * bin as f32 / SPATIAL_BIN_COUNT as f32;
+        slab.max[axis] = if bin == SPATIAL_BIN_COUNT - 1 {
+            bounds.max[axis]
+        } else {
+            bounds.min[axis] + extent * (bin + 1) as f32 / SPATIAL_BIN_COUNT as f32
+        };
+        slab
+    }
+
+    // Primitive clipped to the box, never growing past what the reference already covered
+    fn clip(&self, reference: &Reference, clip: &AABB) -> AABB {
+        match reference.bbox.intersection(clip) {
+            Some(clip) => {
+                let mut clipped = self.primitives[reference.index].clipped_bounds(&clip);
+                if !clipped.is_empty() {
+                    clipped.contract(&reference.bbox);
+                }
+                clipped
+            },
+            None => AABB::empty(),
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use crate::process::bvh::{BVHConfig, BVHNode};
+
+#[derive(Debug, Clone)]
+pub struct BvhStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub primitive_count: usize,
+    // Expected cost of a random ray under the config's traversal and intersection costs
+    pub sah_cost: f32,
+    pub max_depth: usize,
+    // Leaves per depth
+    pub depth_histogram: Vec<usize>,
+    // Leaves per primitive count
+    pub leaf_size_histogram: Vec<usize>,
+}
+
+impl BvhStats {
+    pub fn new(nodes: &[BVHNode], root: i32, config: &BVHConfig) -> Self {
+        let mut stats = BvhStats {
+            node_count: 0,
+            leaf_count: 0,
+            primitive_count: 0,
+            sah_cost: 0.0,
+            max_depth: 0,
+            depth_histogram: Vec::new(),
+            leaf_size_histogram: Vec::new(),
+        };
+        if root < 0 || nodes.is_empty() {
+            return stats;
+        }
+
+        let root_area = nodes[root as usize].aabb.surface_area().max(f32::EPSILON);
+        let mut stack = vec![(root, 0usize)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &nodes[index as usize];
+            let relative_area = node.aabb.surface_area() / root_area;
+            stats.node_count += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+
+            if node.is_leaf() {
+                let count = node.count as usize;
+                stats.leaf_count += 1;
+                stats.primitive_count += count;
+                stats.sah_cost += config.intersection_cost * count as f32 * relative_area;
+                increment(&mut stats.depth_histogram, depth);
+                increment(&mut stats.leaf_size_histogram, count);
+            } else {
+                stats.sah_cost += config.traversal_cost * relative_area;
+                stack.push((node.left, depth + 1));
+                stack.push((node.right, depth + 1));
+            }
+        }
+        stats
+    }
+
+    pub fn average_leaf_size(&self) -> f32 {
+        self.primitive_count as f32 / self.leaf_count.max(1) as f32
+    }
+}
+
+fn increment(histogram: &mut Vec<usize>, bucket: usize) {
+    if histogram.len() <= bucket {
+        histogram.resize(bucket + 1, 0);
+    }
+    histogram[bucket] += 1;
+}
+
+impl fmt::Display for BvhStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "nodes: {}, leaves: {}, primitives: {}", self.node_count, self.leaf_count, self.primitive_count)?;
+        writeln!(f, "sah cost: {:.3}", self.sah_cost)?;
+        writeln!(f, "max depth: {}, average leaf size: {:.2}", self.max_depth, self.average_leaf_size())?;
+        write!(f, "leaf sizes:")?;
+        for (size, count) in self.leaf_size_histogram.iter().enumerate().filter(|(_, &c)| c > 0) {
+            write!(f, " {}x{}", count, size)?;
+        }
+        writeln!(f)?;
+        write!(f, "leaf depths:")?;
+        for (depth, count) in self.depth_histogram.iter().enumerate().filter(|(_, &c)| c > 0) {
+            write!(f, " {}:{}", depth, count)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use crate::primitives::aabb::AABB;
+use crate::primitives::ray::Ray;
+use crate::process::bvh::BVHNode;
+
+
+// Binary node as the GPU traverses it without a stack. A ray that hits an interior node moves on
+// to `left`, one that misses or finishes a leaf follows `miss` to the next subtree in depth first
+// order, -1 once the tree is done. The right child is always the miss link of the left one.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ThreadedBVHNode {
+    pub(crate) aabb: AABB,
+    pub(crate) left: i32,
+    pub(crate) miss: i32,
+    pub(crate) first: i32,
+    pub(crate) count: i32,
+}
+
+// Threads every tree in `nodes` starting from its root, the roots end their own traversal
+pub fn thread(nodes: &[BVHNode], roots: &[i32]) -> Vec<ThreadedBVHNode> {
+    let mut threaded: Vec<ThreadedBVHNode> = nodes.iter()
+        .map(|node| ThreadedBVHNode {
+            aabb: node.aabb,
+            left: node.left,
+            miss: -1,
+            first: node.first,
+            count: node.count,
+        })
+        .collect();
+
+    for &root in roots {
+        let mut stack = vec![(root, -1)];
+        while let Some((index, miss)) = stack.pop() {
+            threaded[index as usize].miss = miss;
+            let node = nodes[index as usize];
+            if !node.is_leaf() {
+                stack.push((node.left, node.right));
+                stack.push((node.right, miss));
+            }
+        }
+    }
+    threaded
+}
+
+// CPU port of the binary traversal in bvh_binary.wgsl. `hit` intersects one primitive with the
+// ray, whose t_max is the closest hit so far, the closest primitive and its distance are returned.
+pub fn traverse<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[ThreadedBVHNode], root: i32, ray: &Ray, hit: F) -> Option<(usize, f32)> {
+    walk(nodes, root, ray, false, hit)
+}
+
+// CPU port of `occluded`, true if any primitive is hit within the ray's interval
+pub fn occluded<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[ThreadedBVHNode], root: i32, ray: &Ray, hit: F) -> bool {
+    walk(nodes, root, ray, true, hit).is_some()
+}
+
+// Returns the first hit found rather than the closest when `any_hit` is set
+pub(crate) fn walk<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[ThreadedBVHNode], root: i32, ray: &Ray, any_hit: bool, hit: F) -> Option<(usize, f32)> {
+    walk_observed(nodes, root, ray, any_hit, hit, |_| {})
+}
+
+// `walk` calling `fetched` with the index of every node it reads
+pub(crate) fn walk_observed<F: FnMut(usize, &Ray) -> Option<f32>, G: FnMut(usize)>(nodes: &[ThreadedBVHNode], root: i32, ray: &Ray, any_hit: bool, mut hit: F, mut fetched: G) -> Option<(usize, f32)> {
+    let mut closest: Option<(usize, f32)> = None;
+    let mut ray = *ray;
+    let mut index = root;
+    while index >= 0 {
+        fetched(index as usize);
+        let node = nodes[index as usize];
+        if node.aabb.hit_distance(&ray).map_or(true, |t| t > ray.t_max) {
+            index = node.miss;
+            continue;
+        }
+        if node.count == 0 {
+            index = node.left;
+            continue;
+        }
+
+        for i in node.first..node.first + node.count {
+            if let Some(t) = hit(i as usize, &ray).filter(|&t| t > ray.t_min && t < ray.t_max) {
+                if any_hit {
+                    return Some((i as usize, t));
+                }
+                closest = Some((i as usize, t));
+                ray.t_max = t;
+            }
+        }
+        index = node.miss;
+    }
+    closest
+}
@@ -0,0 +1,302 @@
+use crate::primitives::aabb::AABB;
+use crate::primitives::ray::Ray;
+use crate::process::bvh::BVHNode;
+
+pub const MAX_WIDTH: usize = 8;
+// Entries in the traversal stack of bvh_wide.wgsl, trees are built to never need more
+pub const STACK_SIZE: usize = 64;
+
+
+// Compressed node holding up to eight children, after Ylitie et al. 2017. Child boxes are stored
+// as 8 bit offsets on a per axis power of two grid anchored at `origin`. Internal children are
+// consecutive from `child_base`, leaf primitives consecutive from `prim_base` in slot order.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct WideBVHNode {
+    origin: [f32; 3],
+    // Grid exponent per axis in the low three bytes, biased like an f32, internal child mask on top
+    exponents_imask: u32,
+    child_base: u32,
+    prim_base: u32,
+    // One byte per slot, the index among internal children or the primitive count of a leaf
+    slots: [u32; 2],
+    lo: [[u32; 2]; 3],
+    hi: [[u32; 2]; 3],
+}
+
+impl WideBVHNode {
+    fn new(nodes: &[BVHNode], slots: &[i32], child_base: u32, prim_base: u32) -> Self {
+        let mut bounds = AABB::empty();
+        for &slot in slots {
+            bounds.extend(&nodes[slot as usize].aabb);
+        }
+
+        let mut exponents = [0u32; 3];
+        let mut scale = [0.0f32; 3];
+        for axis in 0..3 {
+            let extent = (bounds.max[axis] - bounds.min[axis]).max(f32::MIN_POSITIVE);
+            let e = (extent / 255.0).log2().ceil() as i32;
+            exponents[axis] = (e + 127).max(1).min(254) as u32;
+            scale[axis] = f32::from_bits(exponents[axis] << 23);
+        }
+
+        let mut imask = 0u32;
+        let mut slot_bytes = [0u8; MAX_WIDTH];
+        let mut lo = [[0u8; MAX_WIDTH]; 3];
+        let mut hi = [[0u8; MAX_WIDTH]; 3];
+        let mut internal = 0;
+        for (i, &slot) in slots.iter().enumerate() {
+            let node = nodes[slot as usize];
+            if node.is_leaf() {
+                slot_bytes[i] = node.count as u8;
+            } else {
+                imask |= 1 << i;
+                slot_bytes[i] = internal;
+                internal += 1;
+            }
+            for axis in 0..3 {
+                let (q_lo, q_hi) = quantize(node.aabb.min[axis], node.aabb.max[axis], bounds.min[axis], scale[axis]);
+                lo[axis][i] = q_lo;
+                hi[axis][i] = q_hi;
+            }
+        }
+
+        Self {
+            origin: [bounds.min[0], bounds.min[1], bounds.min[2]],
+            exponents_imask: exponents[0] | exponents[1] << 8 | exponents[2] << 16 | imask << 24,
+            child_base,
+            prim_base,
+            slots: pack(&slot_bytes),
+            lo: [pack(&lo[0]), pack(&lo[1]), pack(&lo[2])],
+            hi: [pack(&hi[0]), pack(&hi[1]), pack(&hi[2])],
+        }
+    }
+
+    fn is_internal(&self, slot: usize) -> bool {
+        (self.exponents_imask >> (24 + slot)) & 1 == 1
+    }
+
+    fn slot_byte(bytes: [u32; 2], slot: usize) -> u32 {
+        (bytes[slot / 4] >> ((slot % 4) * 8)) & 0xff
+    }
+
+    // Decoded like the shader, so the box is exactly the one the GPU tests
+    fn child_bounds(&self, slot: usize) -> AABB {
+        let mut aabb = AABB::default();
+        for axis in 0..3 {
+            let scale = f32::from_bits(((self.exponents_imask >> (8 * axis)) & 0xff) << 23);
+            aabb.min[axis] = self.origin[axis] + Self::slot_byte(self.lo[axis], slot) as f32 * scale;
+            aabb.max[axis] = self.origin[axis] + Self::slot_byte(self.hi[axis], slot) as f32 * scale;
+        }
+        aabb
+    }
+}
+
+
+// Children of every wide node in breadth first order. Each node starts from the two children of
+// a binary node and keeps opening its largest internal child until `width` slots are used, so
+// the children of one wide node are consecutive groups. Size is the primitive count below a
+// node rather than its surface area, refits move the boxes but keep the grouping the primitive
+// order was laid out for.
+pub fn wide_groups(nodes: &[BVHNode], root: i32, width: usize) -> Vec<Vec<i32>> {
+    let width = width.max(2).min(MAX_WIDTH);
+    let sizes = subtree_sizes(nodes, root);
+    let mut groups = vec![open(nodes, &sizes, root, width)];
+    let mut next = 0;
+    while next < groups.len() {
+        for &slot in &groups[next].clone() {
+            if !nodes[slot as usize].is_leaf() {
+                groups.push(open(nodes, &sizes, slot, width));
+            }
+        }
+        next += 1;
+    }
+    groups
+}
+
+// Primitives below every node reachable from `root`
+fn subtree_sizes(nodes: &[BVHNode], root: i32) -> Vec<u32> {
+    let mut sizes = vec![0; nodes.len()];
+    // Children are summed after the nodes above them have been visited
+    let mut visited = Vec::with_capacity(nodes.len());
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        let node = nodes[index as usize];
+        visited.push(index);
+        if !node.is_leaf() {
+            stack.extend([node.left, node.right]);
+        }
+    }
+    for &index in visited.iter().rev() {
+        let node = nodes[index as usize];
+        sizes[index as usize] = if node.is_leaf() {
+            node.count as u32
+        } else {
+            sizes[node.left as usize] + sizes[node.right as usize]
+        };
+    }
+    sizes
+}
+
+fn open(nodes: &[BVHNode], sizes: &[u32], index: i32, width: usize) -> Vec<i32> {
+    let node = nodes[index as usize];
+    if node.is_leaf() {
+        return vec![index];
+    }
+    let mut slots = vec![node.left, node.right];
+    while slots.len() < width {
+        // Ties go to the first slot, so the grouping follows the topology alone
+        let largest = slots.iter().enumerate()
+            .filter(|(_, &s)| !nodes[s as usize].is_leaf())
+            .fold(None, |largest: Option<(usize, u32)>, (i, &s)| match largest {
+                Some((_, size)) if size >= sizes[s as usize] => largest,
+                _ => Some((i, sizes[s as usize])),
+            })
+            .map(|(i, _)| i);
+        match largest {
+            Some(i) => {
+                let opened = nodes[slots[i] as usize];
+                slots[i] = opened.left;
+                slots.insert(i + 1, opened.right);
+            },
+            None => break,
+        }
+    }
+    slots
+}
+
+// Primitive order that makes the leaves of every wide node consecutive. Leaf `first` indices
+// are moved to match, returns the old primitive index for each new position.
+pub fn wide_primitive_order(nodes: &mut [BVHNode], root: i32, width: usize) -> Vec<usize> {
+    let mut order = Vec::new();
+    for group in wide_groups(nodes, root, width) {
+        for slot in group {
+            let node = &mut nodes[slot as usize];
+            if node.is_leaf() {
+                let first = node.first as usize;
+                node.first = order.len() as i32;
+                order.extend(first..first + node.count as usize);
+            }
+        }
+    }
+    order
+}
+
+// Collapses a binary tree whose primitives follow `wide_primitive_order`, the root comes first
+pub fn collapse(nodes: &[BVHNode], root: i32, width: usize) -> Vec<WideBVHNode> {
+    let groups = wide_groups(nodes, root, width);
+    let mut wide = Vec::with_capacity(groups.len());
+    let mut child_base = 1;
+    for group in &groups {
+        let prim_base = group.iter()
+            .map(|&s| nodes[s as usize])
+            .find(|n| n.is_leaf())
+            .map_or(0, |n| n.first as u32);
+        wide.push(WideBVHNode::new(nodes, group, child_base, prim_base));
+        child_base += group.iter().filter(|&&s| !nodes[s as usize].is_leaf()).count() as u32;
+    }
+    wide
+}
+
+// Most stack entries the wide traversal can hold at once. Popping a node pushes its internal
+// children, so the worst case is the largest sum of siblings left behind along any path.
+pub fn stack_size(nodes: &[BVHNode], root: i32, width: usize) -> usize {
+    let sizes = subtree_sizes(nodes, root);
+    let mut max = 1;
+    let mut stack = vec![(root, 0)];
+    while let Some((index, waiting)) = stack.pop() {
+        let children: Vec<i32> = open(nodes, &sizes, index, width.max(2).min(MAX_WIDTH)).into_iter()
+            .filter(|&slot| !nodes[slot as usize].is_leaf())
+            .collect();
+        max = max.max(waiting + children.len());
+        for child in &children {
+            stack.push((*child, waiting + children.len() - 1));
+        }
+    }
+    max
+}
+
+// Moves every child and primitive index by the position of this tree in a shared buffer
+pub fn offset_nodes(wide: &mut [WideBVHNode], node_offset: u32, prim_offset: u32) {
+    for node in wide {
+        node.child_base += node_offset;
+        node.prim_base += prim_offset;
+    }
+}
+
+
+// Grid cells covering [min, max], widened until the f32 decode in the shader contains the range
+fn quantize(min: f32, max: f32, origin: f32, scale: f32) -> (u8, u8) {
+    let mut q_lo = ((min - origin) / scale).floor().max(0.0).min(255.0) as u32;
+    let mut q_hi = ((max - origin) / scale).ceil().max(0.0).min(255.0) as u32;
+    while q_lo > 0 && origin + q_lo as f32 * scale > min {
+        q_lo -= 1;
+    }
+    while q_hi < 255 && origin + q_hi as f32 * scale < max {
+        q_hi += 1;
+    }
+    (q_lo as u8, q_hi as u8)
+}
+
+fn pack(bytes: &[u8; MAX_WIDTH]) -> [u32; 2] {
+    [
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+    ]
+}
+
+// CPU port of the traversal in bvh_wide.wgsl, `hit` as for the threaded traversal
+pub fn traverse<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[WideBVHNode], root: usize, ray: &Ray, hit: F) -> Option<(usize, f32)> {
+    walk(nodes, root, ray, false, hit)
+}
+
+// CPU port of `occluded`, true if any primitive is hit within the ray's interval
+pub fn occluded<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[WideBVHNode], root: usize, ray: &Ray, hit: F) -> bool {
+    walk(nodes, root, ray, true, hit).is_some()
+}
+
+// Returns the first hit found rather than the closest when `any_hit` is set
+pub(crate) fn walk<F: FnMut(usize, &Ray) -> Option<f32>>(nodes: &[WideBVHNode], root: usize, ray: &Ray, any_hit: bool, mut hit: F) -> Option<(usize, f32)> {
+    let mut closest: Option<(usize, f32)> = None;
+    let mut ray = *ray;
+    let mut stack = vec![root];
+    while let Some(index) = stack.pop() {
+        let node = nodes[index];
+        let mut children = Vec::with_capacity(MAX_WIDTH);
+        let mut first = node.prim_base;
+        let node_t_max = ray.t_max;
+        for slot in 0..MAX_WIDTH {
+            let meta = WideBVHNode::slot_byte(node.slots, slot);
+            let internal = node.is_internal(slot);
+            if !internal && meta == 0 {
+                continue;
+            }
+
+            let t_enter = node.child_bounds(slot).hit_distance(&ray).filter(|&t| t <= node_t_max);
+            match (t_enter, internal) {
+                (Some(t), true) => children.push((t, (node.child_base + meta) as usize)),
+                (Some(_), false) => {
+                    for i in first..first + meta {
+                        if let Some(t) = hit(i as usize, &ray).filter(|&t| t > ray.t_min && t < ray.t_max) {
+                            if any_hit {
+                                return Some((i as usize, t));
+                            }
+                            closest = Some((i as usize, t));
+                            ray.t_max = t;
+                        }
+                    }
+                },
+                (None, _) => {},
+            }
+            if !internal {
+                first += meta;
+            }
+        }
+
+        // Farthest first so the closest is popped next
+        children.sort_by(|a, b| b.0.total_cmp(&a.0));
+        stack.extend(children.into_iter().map(|(_, child)| child));
+        debug_assert!(stack.len() <= STACK_SIZE, "wide traversal stack overflow");
+    }
+    closest
+}
@@ -0,0 +1,162 @@
+use anyhow::*;
+use std::collections::HashMap;
+use cgmath::{Vector3, InnerSpace};
+use crate::primitives::half_edge::HalfEdgeMesh;
+use crate::primitives::tri_mesh::TriMesh;
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DisplacementKind {
+    // Height along the normal from the red channel
+    Scalar,
+    // RGB offset in object space
+    ObjectVector,
+    // RGB offset in tangent space, x along the uv tangent, y along the bitangent, z along the normal
+    TangentVector,
+}
+
+#[derive(Debug, Clone)]
+pub struct DisplacementMap {
+    width: u32,
+    height: u32,
+    data: Vec<[f32; 3]>,
+    pub kind: DisplacementKind,
+    pub scale: f32,
+    pub midlevel: f32,
+}
+
+impl DisplacementMap {
+    pub fn from_bytes(bytes: &[u8], kind: DisplacementKind) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?;
+        Ok(Self::from_image(&img, kind))
+    }
+
+    // 16 bit maps keep their precision through the float conversion
+    pub fn from_image(img: &image::DynamicImage, kind: DisplacementKind) -> Self {
+        let rgb = img.to_rgb32f();
+        let (width, height) = rgb.dimensions();
+        let data = rgb.pixels().map(|p| p.0).collect();
+        Self {
+            width,
+            height,
+            data,
+            kind,
+            scale: 1.0,
+            midlevel: 0.5,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f32, midlevel: f32) -> Self {
+        self.scale = scale;
+        self.midlevel = midlevel;
+        self
+    }
+
+    // Bilinear lookup with repeat wrapping
+    pub fn sample(&self, uv: [f32; 2]) -> [f32; 3] {
+        let x = uv[0] * self.width as f32 - 0.5;
+        let y = uv[1] * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(self.width as i64) as u32;
+            let y = (y as i64).rem_euclid(self.height as i64) as u32;
+            Vector3::from(self.data[(y * self.width + x) as usize])
+        };
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1.0, y0) * fx;
+        let bottom = texel(x0, y0 + 1.0) * (1.0 - fx) + texel(x0 + 1.0, y0 + 1.0) * fx;
+        (top * (1.0 - fy) + bottom * fy).into()
+    }
+
+    // Largest distance any vertex can move
+    pub fn bound(&self) -> f32 {
+        let mid = Vector3::new(self.midlevel, self.midlevel, self.midlevel);
+        let max = self.data.iter().fold(0.0f32, |acc, t| match self.kind {
+            DisplacementKind::Scalar => acc.max((t[0] - self.midlevel).abs()),
+            _ => acc.max((Vector3::from(*t) - mid).magnitude()),
+        });
+        max * self.scale.abs()
+    }
+
+    fn offset(&self, uv: [f32; 2], normal: Vector3<f32>, tangent: Vector3<f32>, bitangent: Vector3<f32>) -> Vector3<f32> {
+        let t = self.sample(uv);
+        let d = Vector3::new(t[0] - self.midlevel, t[1] - self.midlevel, t[2] - self.midlevel) * self.scale;
+        match self.kind {
+            DisplacementKind::Scalar => normal * d.x,
+            DisplacementKind::ObjectVector => d,
+            DisplacementKind::TangentVector => tangent * d.x + bitangent * d.y + normal * d.z,
+        }
+    }
+
+    // Vertices sharing a position across uv or normal seams receive their averaged offset so the
+    // surface cannot crack, normals are rebuilt from the displaced surface afterwards
+    pub fn displace(&self, mesh: &TriMesh) -> TriMesh {
+        if mesh.uvs.len() != mesh.vertices.len() || mesh.normals.len() != mesh.vertices.len() {
+            log::warn!("Displacement skipped, mesh has no uvs or normals");
+            return mesh.clone();
+        }
+
+        let tangents = vertex_tangents(mesh);
+        let mut offsets: HashMap<[u32; 3], (Vector3<f32>, f32)> = HashMap::new();
+        for i in 0..mesh.vertices.len() {
+            let normal = Vector3::from(mesh.normals[i]).normalize();
+            let (tangent, bitangent) = tangents[i];
+            let offset = self.offset(mesh.uvs[i], normal, tangent, bitangent);
+            let entry = offsets.entry(position_key(mesh.vertices[i])).or_insert((Vector3::new(0.0, 0.0, 0.0), 0.0));
+            entry.0 += offset;
+            entry.1 += 1.0;
+        }
+
+        let mut displaced = mesh.clone();
+        for v in displaced.vertices.iter_mut() {
+            let (sum, count) = offsets[&position_key(*v)];
+            *v = (Vector3::from(*v) + sum / count).into();
+        }
+
+        // Original normals still mark the hard edges for the rebuilt shading normals
+        HalfEdgeMesh::from_tri_mesh(&displaced, false).to_tri_mesh()
+    }
+}
+
+
+fn position_key(p: [f32; 3]) -> [u32; 3] {
+    [p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]
+}
+
+// Per vertex tangent frames from uv derivatives, orthogonalized against the vertex normal
+fn vertex_tangents(mesh: &TriMesh) -> Vec<(Vector3<f32>, Vector3<f32>)> {
+    let mut tangents = vec![Vector3::new(0.0, 0.0, 0.0); mesh.vertices.len()];
+    let mut bitangents = vec![Vector3::new(0.0, 0.0, 0.0); mesh.vertices.len()];
+
+    for tri in mesh.indices.chunks(3) {
+        let [a, b, c] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let e1 = Vector3::from(mesh.vertices[b]) - Vector3::from(mesh.vertices[a]);
+        let e2 = Vector3::from(mesh.vertices[c]) - Vector3::from(mesh.vertices[a]);
+        let (du1, dv1) = (mesh.uvs[b][0] - mesh.uvs[a][0], mesh.uvs[b][1] - mesh.uvs[a][1]);
+        let (du2, dv2) = (mesh.uvs[c][0] - mesh.uvs[a][0], mesh.uvs[c][1] - mesh.uvs[a][1]);
+        let det = du1 * dv2 - du2 * dv1;
+        if det.abs() < 1e-12 {
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (e1 * dv2 - e2 * dv1) * r;
+        let bitangent = (e2 * du1 - e1 * du2) * r;
+        for &i in &[a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    (0..mesh.vertices.len()).map(|i| {
+        let normal = Vector3::from(mesh.normals[i]).normalize();
+        let mut tangent = tangents[i] - normal * normal.dot(tangents[i]);
+        if tangent.magnitude2() < 1e-12 {
+            // No usable uvs, any frame around the normal will do
+            let up = if normal.y.abs() < 0.999 { Vector3::unit_y() } else { Vector3::unit_x() };
+            tangent = up.cross(normal);
+        }
+        let tangent = tangent.normalize();
+        let handedness = if normal.cross(tangent).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        (tangent, normal.cross(tangent) * handedness)
+    }).collect()
+}
@@ -1,10 +1,57 @@
+use anyhow::{anyhow, bail, Result};
 use gltf::Gltf;
-use crate::primitives::tri_mesh::TriMesh;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, SquareMatrix, Transform, Vector3};
+use crate::primitives::camera::{Camera, Projection};
+use crate::primitives::motion::Pose;
+use crate::primitives::tri_mesh::{MorphTarget, TriMesh};
 use crate::primitives::material::Material;
+use crate::primitives::medium::Medium;
+use crate::primitives::vertex::Vertex;
+use crate::process::displacement::{DisplacementKind, DisplacementMap};
+use crate::process::animation::{Animation, Channel, Interpolation, Property, SceneGraph, SceneNode, Skin};
+
+// The timeline plays the glb's first animation
+const TIMELINE_ANIMATION: usize = 0;
+// Samples per second of an animated camera, the camera moves linearly between them
+const CAMERA_KEY_RATE: f32 = 30.0;
+
+// Material extension placing a displacement map, all but the texture optional:
+//   { "texture": { "index": 0 }, "kind": "scalar" | "objectVector" | "tangentVector", "scale": 1.0, "midlevel": 0.5 }
+const DISPLACEMENT_EXTENSION: &str = "KRUST_materials_displacement";
+
+// Camera node of the glb, it looks down its local -z
+#[derive(Debug, Copy, Clone)]
+pub struct GLBCamera {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+    pub projection: Projection,
+    // Degrees, perspective cameras only
+    pub fovy: f32,
+    // Scene graph node placing the camera
+    pub node: usize,
+}
+
+impl GLBCamera {
+    // The glb stores no point of interest, the camera orbits the point level with the scene origin
+    // along its view
+    pub fn to_camera(&self, aspect: f32) -> Camera {
+        Camera::new(self.origin, focus(self.origin, self.direction), 0.0, self.fovy, aspect)
+            .with_projection(self.projection)
+    }
+}
 
 pub struct GLBScene {
     meshes: Vec<TriMesh>,
     materials: Vec<Material>,
+    // Absorbing media bounded by materials with KHR_materials_volume, by material index
+    volumes: Vec<(usize, Medium)>,
+    // Displacement maps of materials with KRUST_materials_displacement, by material index
+    displacements: Vec<(usize, DisplacementMap)>,
+    instances: Vec<(usize, Matrix4<f32>)>,
+    // Scene graph node of every instance, empty when the glb has no scene
+    instance_nodes: Vec<usize>,
+    cameras: Vec<GLBCamera>,
+    graph: SceneGraph,
 }
 
 impl GLBScene {
@@ -12,6 +59,12 @@ impl GLBScene {
         GLBScene {
             meshes: Vec::new(),
             materials: Vec::new(),
+            volumes: Vec::new(),
+            displacements: Vec::new(),
+            instances: Vec::new(),
+            instance_nodes: Vec::new(),
+            cameras: Vec::new(),
+            graph: SceneGraph::new(Vec::new(), Vec::new(), Vec::new()),
         }
     }
 
@@ -22,34 +75,103 @@ impl GLBScene {
     pub fn materials(&self) -> &Vec<Material> {
         &self.materials
     }
+
+    pub fn volumes(&self) -> &Vec<(usize, Medium)> {
+        &self.volumes
+    }
+
+    pub fn displacements(&self) -> &Vec<(usize, DisplacementMap)> {
+        &self.displacements
+    }
+
+    // Mesh index and object to world transform for every mesh node in the scene graph
+    pub fn instances(&self) -> &Vec<(usize, Matrix4<f32>)> {
+        &self.instances
+    }
+
+    pub fn cameras(&self) -> &Vec<GLBCamera> {
+        &self.cameras
+    }
+
+    pub fn is_animated(&self) -> bool {
+        self.graph.animations.get(TIMELINE_ANIMATION).is_some()
+    }
+
+    // Time, origin and focus of the first camera sampled over the timeline animation, empty when
+    // the animation moves neither the camera node nor its parents
+    pub fn camera_keys(&self) -> Vec<(f32, Point3<f32>, Point3<f32>)> {
+        let (camera, animation) = match (self.cameras.first(), self.graph.animations.get(TIMELINE_ANIMATION)) {
+            (Some(camera), Some(animation)) => (camera, animation),
+            _ => return Vec::new(),
+        };
+        let mut moved = false;
+        let mut node = Some(camera.node);
+        while let Some(n) = node {
+            moved |= animation.channels.iter().any(|channel| channel.node == n);
+            node = self.graph.nodes[n].parent;
+        }
+        if !moved {
+            return Vec::new();
+        }
+
+        let duration = animation.duration();
+        let samples = (duration * CAMERA_KEY_RATE).ceil() as usize;
+        (0..=samples)
+            .map(|i| {
+                let time = duration * i as f32 / samples.max(1) as f32;
+                let world = self.graph.evaluate(TIMELINE_ANIMATION, time).world[camera.node];
+                let origin = world.transform_point(Point3::origin());
+                (time, origin, focus(origin, world.transform_vector(-Vector3::unit_z()).normalize()))
+            })
+            .collect()
+    }
+
+    // Skinned or morphed meshes, `deformed_meshes` poses them even when nothing animates
+    pub fn is_deformed(&self) -> bool {
+        self.meshes.iter().any(|mesh| !mesh.morph_targets.is_empty())
+            || self.instance_nodes.iter().any(|&node| self.graph.nodes[node].skin.is_some())
+    }
+
+    // Seconds until the last key of the animation the timeline plays, 0 without one
+    pub fn animation_duration(&self) -> f32 {
+        self.graph.animations.get(TIMELINE_ANIMATION).map_or(0.0, Animation::duration)
+    }
+
+    // Object to world of every instance at shutter `open` and `close`, in seconds
+    pub fn placements(&self, open: f32, close: f32) -> Vec<(Matrix4<f32>, Matrix4<f32>)> {
+        let poses = [open, close].map(|time| self.graph.evaluate(TIMELINE_ANIMATION, time));
+        self.instances.iter().enumerate()
+            .map(|(i, (_, rest))| match self.instance_nodes.get(i) {
+                Some(&node) => (poses[0].world[node], poses[1].world[node]),
+                None => (*rest, *rest),
+            })
+            .collect()
+    }
+
+    // Vertices of every skinned or morphed mesh at `time`, in the object space of the first node
+    // placing it, every instance of a mesh shares its shape. Rigid meshes are None.
+    pub fn deformed_meshes(&self, time: f32) -> Vec<Option<Vec<Vertex>>> {
+        let pose = self.graph.evaluate(TIMELINE_ANIMATION, time);
+        self.meshes.iter().enumerate()
+            .map(|(mesh_idx, mesh)| {
+                let node = self.instance_nodes.iter().find(|&&node| self.graph.nodes[node].mesh == Some(mesh_idx))?;
+                self.graph.deform(mesh, *node, &pose)
+            })
+            .collect()
+    }
 }
 
 pub fn load_glb(data: &[u8]) -> GLBScene {
     let mut scene = GLBScene::new();
     let (glb, buffers, _images) = gltf::import_slice(data).unwrap();
 
-    // camera
-    for node in glb.nodes() {
-        if let Some(camera) = node.camera() {
-            let location = node.transform().decomposed();
-            match camera.projection() {
-                gltf::camera::Projection::Perspective(perspective) => {
-                    let fov = perspective.yfov();
-                    let aspect_ratio = perspective.aspect_ratio().unwrap_or(1.777);
-                    let near = perspective.znear();
-                    let far = perspective.zfar().unwrap_or(1000.0);
-                },
-                _ => {}
-            }
-        }
-    }
-
     // meshes
     for mesh in glb.meshes() {
         let mut tri_mesh = TriMesh::new();
         for primitive in mesh.primitives() {
             tri_mesh.material_index = primitive.material().index().unwrap() as usize;
             let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));    
+            let start = tri_mesh.vertices.len();
 
             if let Some(iter) = reader.read_positions() {
                 for vertex_pos in iter {
@@ -62,6 +184,36 @@ pub fn load_glb(data: &[u8]) -> GLBScene {
                     tri_mesh.normals.push(vertex_norm);
                 }
             }
+
+            if let Some(iter) = reader.read_tex_coords(0) {
+                for uv in iter.into_f32() {
+                    tri_mesh.uvs.push(uv);
+                }
+            }
+
+            // Primitives without skin influences or a target are padded, leaving their vertices
+            // in place
+            if let (Some(joints), Some(weights)) = (reader.read_joints(0), reader.read_weights(0)) {
+                tri_mesh.joints.resize(start, [0; 4]);
+                tri_mesh.weights.resize(start, [0.0; 4]);
+                tri_mesh.joints.extend(joints.into_u16());
+                tri_mesh.weights.extend(weights.into_f32());
+            }
+
+            for (target_idx, (positions, normals, _)) in reader.read_morph_targets().enumerate() {
+                if tri_mesh.morph_targets.len() <= target_idx {
+                    tri_mesh.morph_targets.push(MorphTarget::new());
+                }
+                let target = &mut tri_mesh.morph_targets[target_idx];
+                target.positions.resize(start, [0.0; 3]);
+                target.normals.resize(start, [0.0; 3]);
+                if let Some(positions) = positions {
+                    target.positions.extend(positions);
+                }
+                if let Some(normals) = normals {
+                    target.normals.extend(normals);
+                }
+            }
             
             if let Some(indices) = reader.read_indices() {
                 match indices {
@@ -86,6 +238,20 @@ pub fn load_glb(data: &[u8]) -> GLBScene {
         scene.meshes.push(tri_mesh);    
     }
 
+    // instances
+    if let Some(gltf_scene) = glb.default_scene().or_else(|| glb.scenes().next()) {
+        for node in gltf_scene.nodes() {
+            collect_instances(&node, Matrix4::identity(), &mut scene.instances, &mut scene.instance_nodes);
+            collect_cameras(&node, Matrix4::identity(), &mut scene.cameras);
+        }
+    }
+    scene.graph = load_scene_graph(&glb, &buffers);
+    if scene.instances.is_empty() {
+        for mesh_idx in 0..scene.meshes.len() {
+            scene.instances.push((mesh_idx, Matrix4::identity()));
+        }
+    }
+
     // materials
     for material in glb.materials() {
         let mat_idx  = material.index();
@@ -106,6 +272,21 @@ pub fn load_glb(data: &[u8]) -> GLBScene {
         if let Some(transmission) = material.transmission() {
             refract = transmission.transmission_factor();
         };
+        // Thin walled surfaces and clear volumes bound no medium. Light keeps the attenuation
+        // color after the attenuation distance, sigma_a = -ln(color) / distance.
+        if let Some(volume) = material.volume().filter(|v| v.thickness_factor() > 0.0 && v.attenuation_distance().is_finite()) {
+            let sigma_a = volume.attenuation_color().map(|c| -c.max(1e-6).ln() / volume.attenuation_distance());
+            if sigma_a.iter().any(|&s| s > 0.0) {
+                scene.volumes.push((scene.materials.len(), Medium::homogeneous(sigma_a, [0.0; 3], 0.0, 1.0)));
+            }
+        };
+
+        if let Some(extension) = material.extension_value(DISPLACEMENT_EXTENSION) {
+            match load_displacement(&glb, &buffers, extension) {
+                Ok(map) => scene.displacements.push((scene.materials.len(), map)),
+                Err(err) => log::warn!("Displacement of material {:?} not loaded: {}", mat_idx, err),
+            }
+        };
 
         log::warn!("ior: {:?}", ior);
         log::warn!("specular: {:?}", specular);
@@ -134,3 +315,177 @@ pub fn load_glb(data: &[u8]) -> GLBScene {
     }
     scene
 }
+
+// Maps are read from images embedded in the glb's buffers
+fn load_displacement(glb: &gltf::Document, buffers: &[gltf::buffer::Data], extension: &gltf::json::Value) -> Result<DisplacementMap> {
+    let texture = extension.get("texture").and_then(|t| t.get("index")).and_then(|i| i.as_u64())
+        .and_then(|index| glb.textures().nth(index as usize))
+        .ok_or_else(|| anyhow!("missing texture"))?;
+    let bytes = match texture.source().source() {
+        gltf::image::Source::View { view, .. } => &buffers[view.buffer().index()][view.offset()..view.offset() + view.length()],
+        gltf::image::Source::Uri { uri, .. } => bail!("external image {} is not supported", uri),
+    };
+    let kind = match extension.get("kind").and_then(|k| k.as_str()).unwrap_or("scalar") {
+        "scalar" => DisplacementKind::Scalar,
+        "objectVector" => DisplacementKind::ObjectVector,
+        "tangentVector" => DisplacementKind::TangentVector,
+        other => bail!("unknown displacement kind {}", other),
+    };
+    let number = |key: &str, default: f32| extension.get(key).and_then(|v| v.as_f64()).map_or(default, |v| v as f32);
+    Ok(DisplacementMap::from_bytes(bytes, kind)?.with_scale(number("scale", 1.0), number("midlevel", 0.5)))
+}
+
+fn collect_instances(node: &gltf::Node, parent: Matrix4<f32>, instances: &mut Vec<(usize, Matrix4<f32>)>, nodes: &mut Vec<usize>) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    if let Some(mesh) = node.mesh() {
+        instances.push((mesh.index(), transform));
+        nodes.push(node.index());
+    }
+    for child in node.children() {
+        collect_instances(&child, transform, instances, nodes);
+    }
+}
+
+// Every node of the glb at rest, by glTF node index, with its skins and animations
+fn load_scene_graph(glb: &gltf::Document, buffers: &[gltf::buffer::Data]) -> SceneGraph {
+    let mut nodes: Vec<SceneNode> = glb.nodes()
+        .map(|node| {
+            let (translation, rotation, scale) = node.transform().decomposed();
+            let weights = node.weights().or_else(|| node.mesh().and_then(|mesh| mesh.weights()));
+            SceneNode {
+                parent: None,
+                rest: Pose::new(translation.into(), cgmath::Quaternion::new(rotation[3], rotation[0], rotation[1], rotation[2]), scale.into()),
+                mesh: node.mesh().map(|mesh| mesh.index()),
+                skin: node.skin().map(|skin| skin.index()),
+                weights: weights.map_or(Vec::new(), |weights| weights.to_vec()),
+            }
+        })
+        .collect();
+    for node in glb.nodes() {
+        for child in node.children() {
+            nodes[child.index()].parent = Some(node.index());
+        }
+    }
+
+    let skins = glb.skins()
+        .map(|skin| {
+            let reader = skin.reader(|buffer| Some(&buffers[buffer.index()]));
+            Skin {
+                joints: skin.joints().map(|joint| joint.index()).collect(),
+                inverse_bind: reader.read_inverse_bind_matrices()
+                    .map_or(Vec::new(), |matrices| matrices.map(Matrix4::from).collect()),
+            }
+        })
+        .collect();
+
+    let animations = glb.animations()
+        .map(|animation| {
+            let channels = animation.channels().filter_map(|channel| {
+                let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+                let (property, values): (Property, Vec<f32>) = match reader.read_outputs()? {
+                    gltf::animation::util::ReadOutputs::Translations(iter) => (Property::Translation, iter.flatten().collect()),
+                    gltf::animation::util::ReadOutputs::Rotations(iter) => (Property::Rotation, iter.into_f32().flatten().collect()),
+                    gltf::animation::util::ReadOutputs::Scales(iter) => (Property::Scale, iter.flatten().collect()),
+                    gltf::animation::util::ReadOutputs::MorphTargetWeights(iter) => (Property::Weights, iter.into_f32().collect()),
+                };
+                let interpolation = match channel.sampler().interpolation() {
+                    gltf::animation::Interpolation::Step => Interpolation::Step,
+                    gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                    gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+                };
+                Some(Channel {
+                    node: channel.target().node().index(),
+                    property,
+                    interpolation,
+                    times: reader.read_inputs()?.collect(),
+                    values,
+                })
+            });
+            Animation { channels: channels.collect() }
+        })
+        .collect();
+
+    SceneGraph::new(nodes, skins, animations)
+}
+
+// Point of interest of a camera at `origin`, as far along `direction` as the origin is from the
+// scene origin
+fn focus(origin: Point3<f32>, direction: Vector3<f32>) -> Point3<f32> {
+    origin + direction * origin.to_vec().magnitude().max(1.0)
+}
+
+fn collect_cameras(node: &gltf::Node, parent: Matrix4<f32>, cameras: &mut Vec<GLBCamera>) {
+    let transform = parent * Matrix4::from(node.transform().matrix());
+    if let Some(camera) = node.camera() {
+        // glTF stores half the orthographic height as ymag
+        let (projection, fovy) = match camera.projection() {
+            gltf::camera::Projection::Perspective(perspective) => (Projection::Perspective, perspective.yfov().to_degrees()),
+            gltf::camera::Projection::Orthographic(orthographic) => (Projection::Orthographic { height: 2.0 * orthographic.ymag() }, 50.0),
+        };
+        cameras.push(GLBCamera {
+            origin: transform.transform_point(Point3::origin()),
+            direction: transform.transform_vector(-Vector3::unit_z()).normalize(),
+            projection,
+            fovy,
+            node: node.index(),
+        });
+    }
+    for child in node.children() {
+        collect_cameras(&child, transform, cameras);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::animation::SceneNode;
+
+    fn node(parent: Option<usize>, translation: [f32; 3]) -> SceneNode {
+        let rest = Pose::new(translation.into(), cgmath::Quaternion::new(1.0, 0.0, 0.0, 0.0), [1.0; 3].into());
+        SceneNode { parent, rest, mesh: None, skin: None, weights: Vec::new() }
+    }
+
+    // A camera 5 units back on a rig sliding 2 units along x over the first second
+    fn scene(animated_node: usize) -> GLBScene {
+        let slide = Channel {
+            node: animated_node,
+            property: Property::Translation,
+            interpolation: Interpolation::Linear,
+            times: vec![0.0, 1.0],
+            values: vec![0.0, 0.0, 0.0, 2.0, 0.0, 0.0],
+        };
+        let mut scene = GLBScene::new();
+        scene.graph = SceneGraph::new(vec![node(None, [0.0; 3]), node(Some(0), [0.0, 0.0, 5.0]), node(None, [0.0; 3])], Vec::new(), vec![Animation { channels: vec![slide] }]);
+        scene.cameras.push(GLBCamera {
+            origin: Point3::new(0.0, 0.0, 5.0),
+            direction: -Vector3::unit_z(),
+            projection: Projection::Perspective,
+            fovy: 50.0,
+            node: 1,
+        });
+        scene
+    }
+
+    #[test]
+    fn camera_keys_follow_the_animated_parent() {
+        let keys = scene(0).camera_keys();
+        assert_eq!(keys.len(), 31);
+        let (time, origin, focus) = keys[15];
+        assert!((time - 0.5).abs() < 1e-6);
+        assert!((origin - Point3::new(1.0, 0.0, 5.0)).magnitude() < 1e-5, "{:?}", origin);
+        assert!((focus - Point3::new(1.0, 0.0, 5.0 - 26.0f32.sqrt())).magnitude() < 1e-5, "{:?}", focus);
+
+        let mut camera = scene(0).cameras[0].to_camera(1.0);
+        for (time, origin, focus) in keys {
+            camera.add_key(time, origin, focus);
+        }
+        camera.set_time(0.25);
+        assert!((camera.origin - Point3::new(0.5, 0.0, 5.0)).magnitude() < 1e-5, "{:?}", camera.origin);
+    }
+
+    #[test]
+    fn still_cameras_have_no_keys() {
+        assert!(scene(2).camera_keys().is_empty());
+    }
+}
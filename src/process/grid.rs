@@ -0,0 +1,151 @@
+use anyhow::*;
+use crate::primitives::medium::DensityGrid;
+use crate::process::reader::Reader;
+
+// Simple grid format (little endian):
+//   b"KGRD", u32 version, u32 nx, ny, nz, f32 min[3], f32 max[3], f32 data[nx * ny * nz] (x fastest)
+const KGRD_MAGIC: &[u8; 4] = b"KGRD";
+const KGRD_VERSION: u32 = 1;
+
+// Dense volume format written by OpenVDB/Houdini exporters for Mitsuba:
+//   b"VOL", u8 version (3), i32 encoding (1 = f32), i32 nx, ny, nz, i32 channels, f32 min[3], f32 max[3], data
+const VOL_MAGIC: &[u8; 3] = b"VOL";
+
+pub fn load_grid(data: &[u8]) -> Result<DensityGrid> {
+    if data.starts_with(KGRD_MAGIC) {
+        load_kgrd(data)
+    } else if data.starts_with(VOL_MAGIC) {
+        load_vol(data)
+    } else {
+        bail!("unrecognized density grid format")
+    }
+}
+
+fn load_kgrd(data: &[u8]) -> Result<DensityGrid> {
+    let mut reader = Reader::new(data, KGRD_MAGIC.len());
+    let version = reader.u32()?;
+    if version != KGRD_VERSION {
+        bail!("unsupported grid version {}", version);
+    }
+    let resolution = [reader.u32()?, reader.u32()?, reader.u32()?];
+    let bounds = reader.bounds()?;
+    let count = voxel_count(resolution, 1, data.len())?;
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(reader.f32()?);
+    }
+    Ok(DensityGrid::new(resolution, bounds, values))
+}
+
+fn load_vol(data: &[u8]) -> Result<DensityGrid> {
+    let mut reader = Reader::new(data, VOL_MAGIC.len());
+    let version = reader.bytes(1)?[0];
+    if version != 3 {
+        bail!("unsupported VOL version {}", version);
+    }
+    let encoding = reader.i32()?;
+    if encoding != 1 {
+        bail!("unsupported VOL encoding {}, only float32 grids are supported", encoding);
+    }
+    let dims = [reader.i32()?, reader.i32()?, reader.i32()?];
+    if dims.iter().any(|&d| d < 0) {
+        bail!("negative VOL resolution {:?}", dims);
+    }
+    let resolution = dims.map(|d| d as u32);
+    let channels = reader.i32()?.max(1) as usize;
+    let bounds = reader.bounds()?;
+    let count = voxel_count(resolution, channels, data.len())?;
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        // Multi-channel grids are reduced to their first channel
+        values.push(reader.f32()?);
+        reader.bytes(4 * (channels - 1))?;
+    }
+    Ok(DensityGrid::new(resolution, bounds, values))
+}
+
+// Voxels of `resolution` when `data` bytes could hold them as `channels` f32 values each
+fn voxel_count(resolution: [u32; 3], channels: usize, data: usize) -> Result<usize> {
+    let count = resolution.iter().try_fold(1usize, |count, &r| count.checked_mul(r as usize));
+    let bytes = count.and_then(|count| count.checked_mul(channels)?.checked_mul(4));
+    match (count, bytes) {
+        (Some(count), Some(bytes)) if bytes <= data => Ok(count),
+        _ => bail!("grid resolution {:?} does not fit {} bytes", resolution, data),
+    }
+}
+
+pub fn save_grid(grid: &DensityGrid) -> Vec<u8> {
+    let mut out = Vec::with_capacity(40 + 4 * grid.data.len());
+    out.extend_from_slice(KGRD_MAGIC);
+    out.extend_from_slice(&KGRD_VERSION.to_le_bytes());
+    for r in grid.resolution {
+        out.extend_from_slice(&r.to_le_bytes());
+    }
+    for v in grid.bounds.min[..3].iter().chain(grid.bounds.max[..3].iter()) {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in &grid.data {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+// Single channel float32 VOL, as read by `load_vol` and by Mitsuba and VDB conversion tools
+pub fn save_vol(grid: &DensityGrid) -> Vec<u8> {
+    let mut out = Vec::with_capacity(48 + 4 * grid.data.len());
+    out.extend_from_slice(VOL_MAGIC);
+    out.push(3);
+    out.extend_from_slice(&1i32.to_le_bytes());
+    for r in grid.resolution {
+        out.extend_from_slice(&(r as i32).to_le_bytes());
+    }
+    out.extend_from_slice(&1i32.to_le_bytes());
+    for v in grid.bounds.min[..3].iter().chain(grid.bounds.max[..3].iter()) {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    for v in &grid.data {
+        out.extend_from_slice(&v.to_le_bytes());
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::aabb::AABB;
+
+    fn grid() -> DensityGrid {
+        let bounds = AABB::new([-1.0, -1.0, -1.0, 0.0], [1.0, 1.0, 1.0, 0.0]);
+        DensityGrid::new([2, 3, 4], bounds, (0..24).map(|i| i as f32).collect())
+    }
+
+    // Overwrites the VOL resolution, three i32 after the magic, version and encoding
+    fn with_resolution(mut vol: Vec<u8>, resolution: [i32; 3]) -> Vec<u8> {
+        for (i, r) in resolution.iter().enumerate() {
+            vol[8 + 4 * i..12 + 4 * i].copy_from_slice(&r.to_le_bytes());
+        }
+        vol
+    }
+
+    #[test]
+    fn grids_round_trip() {
+        for data in [save_grid(&grid()), save_vol(&grid())] {
+            let loaded = load_grid(&data).unwrap();
+            assert_eq!(loaded.resolution, [2, 3, 4]);
+            assert_eq!(loaded.data, grid().data);
+        }
+    }
+
+    #[test]
+    fn bad_resolutions_are_rejected() {
+        let vol = save_vol(&grid());
+        assert!(load_grid(&with_resolution(vol.clone(), [2, -3, 4])).is_err());
+        assert!(load_grid(&with_resolution(vol.clone(), [i32::MAX, i32::MAX, i32::MAX])).is_err());
+        assert!(load_grid(&with_resolution(vol, [2, 3, 5])).is_err());
+
+        let mut kgrd = save_grid(&grid());
+        kgrd[8..20].copy_from_slice(&[u32::MAX.to_le_bytes(); 3].concat());
+        assert!(load_grid(&kgrd).is_err());
+    }
+}
@@ -1,5 +1,21 @@
 pub mod glb;
 pub mod bvh;
+pub mod bvh_binned;
+pub mod bvh_lbvh;
+pub mod bvh_spatial;
+pub mod bvh_threaded;
+pub mod bvh_wide;
+pub mod bvh_stats;
+pub mod bvh_layout;
 pub mod bvh_ml;
 pub mod pipeline;
-pub mod sdf;
\ No newline at end of file
+pub mod refit;
+#[cfg(test)]
+mod watertight;
+pub mod sdf;
+pub mod grid;
+pub mod reader;
+pub mod vertex_order;
+pub mod subdivision;
+pub mod displacement;
+pub mod animation;
\ No newline at end of file
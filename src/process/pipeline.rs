@@ -6,12 +6,15 @@ pub fn create_pipeline(
     camera_ray_buffer: &wgpu::Buffer,
     bvh_buffer: &wgpu::Buffer,
     material_buffer: &wgpu::Buffer,
-    triangle_buffer: &wgpu::Buffer,
+    object_buffer: &wgpu::Buffer,
     vertex_buffer: &wgpu::Buffer,
-    normal_buffer: &wgpu::Buffer,
+    instance_buffer: &wgpu::Buffer,
     light_buffer: &wgpu::Buffer,
     sky_texture: &wgpu::TextureView,
     sampler: &wgpu::Sampler,
+    density_texture: &wgpu::TextureView,
+    density_sampler: &wgpu::Sampler,
+    sdf_texture: &wgpu::TextureView,
     out_tex_view: &wgpu::TextureView,
     out_sky_view: Option<&wgpu::TextureView>
 ) -> (wgpu::ComputePipeline, wgpu::PipelineLayout, wgpu::BindGroup
@@ -124,6 +127,32 @@ pub fn create_pipeline(
             },
             count: None,
         },
+        wgpu::BindGroupLayoutEntry {
+            binding: 12,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D3,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 13,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        },
+        wgpu::BindGroupLayoutEntry {
+            binding: 14,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D3,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        },
     ];
 
     let mut bind_group_entries = vec![
@@ -145,7 +174,7 @@ pub fn create_pipeline(
         },
         wgpu::BindGroupEntry {
             binding: 4,
-            resource: triangle_buffer.as_entire_binding(),
+            resource: object_buffer.as_entire_binding(),
         },
         wgpu::BindGroupEntry {
             binding: 5,
@@ -153,7 +182,7 @@ pub fn create_pipeline(
         },
         wgpu::BindGroupEntry {
             binding: 6,
-            resource: normal_buffer.as_entire_binding(),
+            resource: instance_buffer.as_entire_binding(),
         },
         wgpu::BindGroupEntry {
             binding: 7,
@@ -171,6 +200,18 @@ pub fn create_pipeline(
             binding: 10,
             resource: wgpu::BindingResource::TextureView(&out_tex_view),
         },
+        wgpu::BindGroupEntry {
+            binding: 12,
+            resource: wgpu::BindingResource::TextureView(&density_texture),
+        },
+        wgpu::BindGroupEntry {
+            binding: 13,
+            resource: wgpu::BindingResource::Sampler(&density_sampler),
+        },
+        wgpu::BindGroupEntry {
+            binding: 14,
+            resource: wgpu::BindingResource::TextureView(&sdf_texture),
+        },
     ];
 
     if let Some(sky_view) = out_sky_view {
@@ -0,0 +1,64 @@
+use anyhow::*;
+use std::convert::TryInto;
+use crate::primitives::aabb::AABB;
+
+// Little endian cursor over the binary formats read by grid.rs and bvh.rs
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8], pos: usize) -> Self {
+        Reader { data, pos }
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.data.len() - self.pos {
+            bail!("unexpected end of data at byte {}", self.pos);
+        }
+        let out = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    // Element count of a following array of `size` byte records, checked against the data left
+    pub(crate) fn count(&mut self, size: usize) -> Result<usize> {
+        let count = self.u32()? as usize;
+        match count.checked_mul(size) {
+            Some(n) if n <= self.data.len() - self.pos => Ok(count),
+            _ => bail!("count {} at byte {} overruns the data", count, self.pos - 4),
+        }
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into()?))
+    }
+
+    pub(crate) fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    pub(crate) fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into()?))
+    }
+
+    // `n` floats, checked against the data left before anything is allocated
+    pub(crate) fn f32s(&mut self, n: usize) -> Result<Vec<f32>> {
+        let size = match n.checked_mul(4) {
+            Some(size) => size,
+            None => bail!("{} floats at byte {} overrun the data", n, self.pos),
+        };
+        Ok(self.bytes(size)?.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    pub(crate) fn bounds(&mut self) -> Result<AABB> {
+        let min = [self.f32()?, self.f32()?, self.f32()?, 0.0];
+        let max = [self.f32()?, self.f32()?, self.f32()?, 0.0];
+        Ok(AABB::new(min, max))
+    }
+}
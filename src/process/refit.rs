@@ -0,0 +1,177 @@
+use wgpu::util::DeviceExt;
+
+use crate::process::bvh::{BVHNode, TwoLevelBVH};
+
+// Uniform entries are bound with dynamic offsets, one per level
+const LEVEL_STRIDE: u64 = 256;
+
+
+// GPU version of `TwoLevelBVH::refit`, recomputes node bounds in the bvh buffer from the vertex
+// buffer without touching the topology. Built for one tree, recreate it after a rebuild.
+pub struct RefitPass {
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    // Nodes refitted by each dispatch, leaves first
+    level_sizes: Vec<u32>,
+}
+
+impl RefitPass {
+    pub fn new(
+        device: &wgpu::Device,
+        bvh: &TwoLevelBVH,
+        bvh_buffer: &wgpu::Buffer,
+        object_buffer: &wgpu::Buffer,
+        vertex_buffer: &wgpu::Buffer,
+        instance_buffer: &wgpu::Buffer,
+    ) -> Self {
+        let shader_structs = include_str!("../shaders/structs.wgsl");
+        let motion = include_str!("../shaders/motion.wgsl");
+        let refit_shader = include_str!("../shaders/compute_refit.wgsl");
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Refit Shader"),
+            source: wgpu::ShaderSource::Wgsl(format!("{}\n{}\n{}", shader_structs, motion, refit_shader).into()),
+        });
+
+        let levels = schedule(&bvh.nodes(), bvh.tlas_node_count());
+        let level_sizes: Vec<u32> = levels.iter().map(|level| level.len() as u32).collect();
+        let refit_nodes: Vec<i32> = levels.concat();
+
+        let mut level_bytes = vec![0u8; levels.len() * LEVEL_STRIDE as usize];
+        let mut start = 0u32;
+        for (i, size) in level_sizes.iter().enumerate() {
+            let entry = [start, *size, bvh.tlas_node_count() as u32, 0];
+            let offset = i * LEVEL_STRIDE as usize;
+            level_bytes[offset..offset + 16].copy_from_slice(bytemuck::bytes_of(&entry));
+            start += size;
+        }
+
+        let node_list_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Refit Node Buffer"),
+            contents: bytemuck::cast_slice(&refit_nodes),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+        let level_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Refit Level Buffer"),
+            contents: &level_bytes,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Refit Bind Group Layout"),
+            entries: &[
+                storage(0, false),
+                storage(1, true),
+                storage(2, true),
+                storage(3, true),
+                storage(4, true),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(16),
+                    },
+                    count: None,
+                },
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Refit Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry { binding: 0, resource: bvh_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 1, resource: object_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 2, resource: vertex_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 3, resource: instance_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry { binding: 4, resource: node_list_buffer.as_entire_binding() },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &level_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(16),
+                    }),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Refit Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Refit Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &module,
+            entry_point: "main",
+        });
+
+        Self { pipeline, bind_group, level_sizes }
+    }
+
+    // Call after writing new vertices, before any pass that traverses the bvh
+    pub fn encode(&self, encoder: &mut wgpu::CommandEncoder) {
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Refit Pass"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        for (i, size) in self.level_sizes.iter().enumerate() {
+            pass.set_bind_group(0, &self.bind_group, &[(i as u64 * LEVEL_STRIDE) as u32]);
+            pass.dispatch_workgroups((size + 63) / 64, 1, 1);
+        }
+    }
+}
+
+
+// Groups nodes by height so each dispatch only reads children finished by an earlier one. Every
+// bottom level comes before the top level, whose leaves read the bottom level roots.
+fn schedule(nodes: &[BVHNode], tlas_nodes: usize) -> Vec<Vec<i32>> {
+    let mut heights = vec![None; nodes.len()];
+    for index in 0..nodes.len() {
+        height(nodes, index as i32, &mut heights);
+    }
+    let heights: Vec<usize> = heights.into_iter().map(|h| h.unwrap()).collect();
+    let blas_height = heights[tlas_nodes..].iter().copied().max().unwrap_or(0);
+
+    let mut levels: Vec<Vec<i32>> = Vec::new();
+    for index in 0..nodes.len() {
+        let mut level = heights[index];
+        if index < tlas_nodes {
+            level += blas_height + 1;
+        }
+        if levels.len() <= level {
+            levels.resize(level + 1, Vec::new());
+        }
+        levels[level].push(index as i32);
+    }
+    levels.retain(|level| !level.is_empty());
+    levels
+}
+
+fn height(nodes: &[BVHNode], index: i32, heights: &mut Vec<Option<usize>>) -> usize {
+    if let Some(h) = heights[index as usize] {
+        return h;
+    }
+    let node = nodes[index as usize];
+    let h = if node.is_leaf() {
+        0
+    } else {
+        1 + height(nodes, node.left, heights).max(height(nodes, node.right, heights))
+    };
+    heights[index as usize] = Some(h);
+    h
+}
@@ -1,41 +1,352 @@
 use cgmath::{Vector3, InnerSpace};
+use rayon::prelude::*;
 
-struct PointCloud {
-    points: Vec<Vector3<f32>>,
-    distances: Vec<f32>,
+use crate::primitives::aabb::AABB;
+use crate::primitives::medium::DensityGrid;
+use crate::primitives::ray::Ray;
+use crate::primitives::scene::SceneObjectCPU;
+use crate::primitives::triangle::hit_watertight;
+use crate::primitives::tri_mesh::TriMesh;
+use crate::process::bvh::{BVH, BVHBuilder, BVHConfig, BVHNode};
+use crate::process::bvh_threaded::{self, ThreadedBVHNode, thread};
+
+// Empty voxels kept around the mesh on every side, so the field is valid a little outside it
+const PADDING_VOXELS: f32 = 2.0;
+// Nodes farther than this many times their radius count by their dipole in the winding number
+const WINDING_ACCURACY: f32 = 2.0;
+
+
+// How a baked voxel decides whether it is inside the mesh
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SignMode {
+    // Generalized winding number above one half, robust to holes and self intersections
+    WindingNumber,
+    // Odd crossings along the three axes, by majority, for clean closed meshes
+    RayParity,
+}
+
+// Signed distances sampled at voxel centers, negative inside, x fastest
+#[derive(Debug, Clone)]
+pub struct Sdf {
+    pub resolution: [u32; 3],
+    pub bounds: AABB,
+    pub data: Vec<f32>,
 }
 
-impl PointCloud {
-    fn new(points: Vec<Vector3<f32>>) -> Self {
-        let distances = vec![0.0; points.len()];
-        Self { points, distances }
+impl Sdf {
+    // `resolution` voxels along the longest side of the mesh, cubic voxels elsewhere
+    pub fn bake(mesh: &TriMesh, resolution: u32, sign: SignMode) -> Sdf {
+        let field = MeshField::new(mesh);
+        let bounds = field.bvh.bounds();
+        let longest = (0..3).map(|a| bounds.max[a] - bounds.min[a]).fold(f32::MIN_POSITIVE, f32::max);
+        let voxel = longest / resolution.max(1) as f32;
+        let mut resolution = [0u32; 3];
+        let (mut min, mut max) = ([0.0f32; 4], [0.0f32; 4]);
+        for axis in 0..3 {
+            let cells = ((bounds.max[axis] - bounds.min[axis]) / voxel).ceil() + 2.0 * PADDING_VOXELS;
+            resolution[axis] = cells.max(1.0) as u32;
+            let center = 0.5 * (bounds.min[axis] + bounds.max[axis]);
+            min[axis] = center - 0.5 * resolution[axis] as f32 * voxel;
+            max[axis] = center + 0.5 * resolution[axis] as f32 * voxel;
+        }
+        let grid_bounds = AABB::new(min, max);
+
+        let [nx, ny, nz] = resolution;
+        let data = (0..nx * ny * nz).into_par_iter()
+            .map(|i| {
+                let (x, y, z) = (i % nx, (i / nx) % ny, i / (nx * ny));
+                let p = Vector3::new(
+                    grid_bounds.min[0] + (x as f32 + 0.5) * voxel,
+                    grid_bounds.min[1] + (y as f32 + 0.5) * voxel,
+                    grid_bounds.min[2] + (z as f32 + 0.5) * voxel,
+                );
+                let distance = field.distance(p);
+                if field.inside(p, sign) { -distance } else { distance }
+            })
+            .collect();
+        Sdf { resolution, bounds: grid_bounds, data }
     }
 
-    fn closest_tri_distance(&self, p: Vector3<f32>) -> f32 {
-        let mut min_distance = f32::INFINITY;
+    // Trilinear distance at `p`, clamped to the outermost voxel centers
+    pub fn distance(&self, p: [f32; 3]) -> f32 {
+        let mut cell = [0u32; 3];
+        let mut t = [0.0f32; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let extent = self.bounds.max[axis] - self.bounds.min[axis];
+            let g = ((p[axis] - self.bounds.min[axis]) / extent * n as f32 - 0.5).max(0.0).min((n - 1) as f32);
+            cell[axis] = (g as u32).min(n.saturating_sub(2));
+            t[axis] = g - cell[axis] as f32;
+        }
 
-        for q in &self.points {
-            let distance = (p - *q).magnitude();
-            if distance < min_distance {
-                min_distance = distance;
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let offset = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+            let mut weight = 1.0;
+            let mut at = [0u32; 3];
+            for axis in 0..3 {
+                weight *= if offset[axis] == 1 { t[axis] } else { 1.0 - t[axis] };
+                at[axis] = (cell[axis] + offset[axis]).min(self.resolution[axis] - 1);
             }
+            value += weight * self.at(at[0], at[1], at[2]);
         }
-        min_distance
+        value
+    }
+
+    // Central difference of `distance`, points away from the surface
+    pub fn gradient(&self, p: [f32; 3]) -> [f32; 3] {
+        let mut gradient = [0.0; 3];
+        for axis in 0..3 {
+            let h = 0.5 * (self.bounds.max[axis] - self.bounds.min[axis]) / self.resolution[axis] as f32;
+            let (mut lo, mut hi) = (p, p);
+            lo[axis] -= h;
+            hi[axis] += h;
+            gradient[axis] = (self.distance(hi) - self.distance(lo)) / (2.0 * h);
+        }
+        gradient
+    }
+
+    pub fn at(&self, x: u32, y: u32, z: u32) -> f32 {
+        self.data[((z * self.resolution[1] + y) * self.resolution[0] + x) as usize]
+    }
+
+    // As a scalar grid for the formats in grid.rs
+    pub fn to_grid(&self) -> DensityGrid {
+        DensityGrid::new(self.resolution, self.bounds, self.data.clone())
+    }
+}
+
+
+// Area weighted normal and center of the triangles below a node, with the radius around the
+// center that bounds them
+#[derive(Debug, Copy, Clone)]
+struct Dipole {
+    area_normal: Vector3<f32>,
+    center: Vector3<f32>,
+    radius: f32,
+}
+
+// Triangles of a mesh under a binary BVH, for closest point and inside queries
+struct MeshField {
+    triangles: Vec<[Vector3<f32>; 3]>,
+    bvh: BVH,
+    threaded: Vec<ThreadedBVHNode>,
+    dipoles: Vec<Dipole>,
+}
+
+impl MeshField {
+    fn new(mesh: &TriMesh) -> Self {
+        let mut objects = SceneObjectCPU::from_tri_mesh(mesh, 0);
+        let bvh = BVH::with_config(&mut objects, &BVHConfig::default().with_builder(BVHBuilder::BinnedSAH));
+        let triangles = objects.iter()
+            .map(|o| o.object().indices().map(|i| Vector3::from(mesh.vertices[i as usize])))
+            .collect();
+        let threaded = thread(bvh.nodes(), &[bvh.root()]);
+        let mut field = MeshField { triangles, bvh, threaded, dipoles: Vec::new() };
+        field.dipoles = vec![Dipole { area_normal: Vector3::new(0.0, 0.0, 0.0), center: Vector3::new(0.0, 0.0, 0.0), radius: 0.0 }; field.bvh.nodes().len()];
+        field.fit_dipole(field.bvh.root());
+        field
     }
 
-    fn compute_distances(&mut self) {
-        for (i, p) in self.points.iter().enumerate() {
-            let mut min_distance = f32::INFINITY;
+    // Fills the dipoles of the subtree at `index` bottom up, returns its total area
+    fn fit_dipole(&mut self, index: i32) -> f32 {
+        let node = self.bvh.nodes()[index as usize];
+        let mut area_normal = Vector3::new(0.0, 0.0, 0.0);
+        let mut weighted = Vector3::new(0.0, 0.0, 0.0);
+        let mut area = 0.0;
+        if node.is_leaf() {
+            for [a, b, c] in &self.triangles[node.first as usize..(node.first + node.count) as usize] {
+                let n = 0.5 * (b - a).cross(c - a);
+                let triangle_area = n.magnitude();
+                area_normal += n;
+                weighted += triangle_area * (a + b + c) / 3.0;
+                area += triangle_area;
+            }
+        } else {
+            for child in [node.left, node.right] {
+                let child_area = self.fit_dipole(child);
+                let dipole = self.dipoles[child as usize];
+                area_normal += dipole.area_normal;
+                weighted += child_area * dipole.center;
+                area += child_area;
+            }
+        }
+
+        let center = if area > 0.0 { weighted / area } else { Vector3::from(node.aabb.centroid()) };
+        // Farthest box corner from the center
+        let far = Vector3::new(
+            (node.aabb.max[0] - center.x).max(center.x - node.aabb.min[0]),
+            (node.aabb.max[1] - center.y).max(center.y - node.aabb.min[1]),
+            (node.aabb.max[2] - center.z).max(center.z - node.aabb.min[2]),
+        );
+        self.dipoles[index as usize] = Dipole { area_normal, center, radius: far.magnitude() };
+        area
+    }
 
-            for (j, q) in self.points.iter().enumerate() {
-                if i != j {
-                    let distance = (*p - *q).magnitude();
-                    if distance < min_distance {
-                        min_distance = distance;
-                    }
+    // Unsigned distance to the closest triangle, nearer children first so the bound shrinks fast
+    fn distance(&self, p: Vector3<f32>) -> f32 {
+        let nodes = self.bvh.nodes();
+        let mut best = f32::INFINITY;
+        let mut stack = vec![self.bvh.root()];
+        while let Some(index) = stack.pop() {
+            let node = nodes[index as usize];
+            if box_distance2(&node, p) >= best {
+                continue;
+            }
+            if node.is_leaf() {
+                for [a, b, c] in &self.triangles[node.first as usize..(node.first + node.count) as usize] {
+                    best = best.min((closest_point_on_triangle(p, *a, *b, *c) - p).magnitude2());
                 }
+                continue;
+            }
+            let (near, far) = if box_distance2(&nodes[node.left as usize], p) <= box_distance2(&nodes[node.right as usize], p) {
+                (node.left, node.right)
+            } else {
+                (node.right, node.left)
+            };
+            stack.push(far);
+            stack.push(near);
+        }
+        best.sqrt()
+    }
+
+    fn inside(&self, p: Vector3<f32>, sign: SignMode) -> bool {
+        match sign {
+            SignMode::WindingNumber => self.winding_number(self.bvh.root(), p) > 0.5,
+            SignMode::RayParity => {
+                let odd = (0..3)
+                    .filter(|&axis| {
+                        let mut direction = [0.0; 3];
+                        direction[axis] = 1.0;
+                        self.crossings(Ray::new(p.into(), direction)) % 2 == 1
+                    })
+                    .count();
+                odd >= 2
+            },
+        }
+    }
+
+    // Fast winding numbers after Barill et al. 2018, exact solid angles near `p` and the dipole of
+    // a whole subtree far from it
+    fn winding_number(&self, index: i32, p: Vector3<f32>) -> f32 {
+        let node = self.bvh.nodes()[index as usize];
+        let dipole = self.dipoles[index as usize];
+        let to_center = dipole.center - p;
+        let distance = to_center.magnitude();
+        if distance > WINDING_ACCURACY * dipole.radius {
+            return to_center.dot(dipole.area_normal) / (4.0 * std::f32::consts::PI * distance * distance * distance);
+        }
+        if node.is_leaf() {
+            return self.triangles[node.first as usize..(node.first + node.count) as usize].iter()
+                .map(|[a, b, c]| solid_angle(a - p, b - p, c - p) / (4.0 * std::f32::consts::PI))
+                .sum();
+        }
+        self.winding_number(node.left, p) + self.winding_number(node.right, p)
+    }
+
+    // Surfaces the ray passes through, each hit moves the interval past it. Triangles meeting at
+    // an edge report the same distance and are counted once.
+    fn crossings(&self, ray: Ray) -> usize {
+        let mut ray = ray;
+        let mut count = 0;
+        let hit = |i: usize, ray: &Ray| {
+            let [a, b, c] = self.triangles[i];
+            hit_watertight(ray, &[a.into(), b.into(), c.into()])
+        };
+        while let Some((_, t)) = bvh_threaded::traverse(&self.threaded, self.bvh.root(), &ray, hit) {
+            count += 1;
+            ray.t_min = t;
+        }
+        count
+    }
+}
+
+// Van Oosterom and Strackee, signed by the winding of the corners around `p` at the origin
+fn solid_angle(a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> f32 {
+    let (la, lb, lc) = (a.magnitude(), b.magnitude(), c.magnitude());
+    let det = a.dot(b.cross(c));
+    let div = la * lb * lc + a.dot(b) * lc + b.dot(c) * la + c.dot(a) * lb;
+    2.0 * det.atan2(div)
+}
+
+// From Ericson, Real-Time Collision Detection 5.1.5
+fn closest_point_on_triangle(p: Vector3<f32>, a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> Vector3<f32> {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = p - b;
+    let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+fn box_distance2(node: &BVHNode, p: Vector3<f32>) -> f32 {
+    let p: [f32; 3] = p.into();
+    (0..3)
+        .map(|axis| (node.aabb.min[axis] - p[axis]).max(p[axis] - node.aabb.max[axis]).max(0.0).powi(2))
+        .sum()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The unit cube from the origin, wound outwards
+    fn unit_cube() -> TriMesh {
+        let mut mesh = TriMesh::new();
+        mesh.vertices = (0..8).map(|i| [(i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32]).collect();
+        let quads = [[0, 1, 3, 2], [4, 5, 7, 6], [0, 1, 5, 4], [2, 3, 7, 6], [0, 2, 6, 4], [1, 3, 7, 5]];
+        for [a, b, c, d] in quads {
+            for [a, b, c] in [[a, b, c], [a, c, d]] {
+                let [pa, pb, pc] = [a, b, c].map(|i| Vector3::from(mesh.vertices[i as usize]));
+                let outwards = (pb - pa).cross(pc - pa).dot(pa + pb + pc - Vector3::new(1.5, 1.5, 1.5)) > 0.0;
+                mesh.indices.extend(if outwards { [a, b, c] } else { [a, c, b] });
+            }
+        }
+        mesh
+    }
+
+    fn box_distance(p: [f32; 3]) -> f32 {
+        let q = Vector3::from(p.map(|x| (x - 0.5).abs() - 0.5));
+        let outside = Vector3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).magnitude();
+        outside + q.x.max(q.y).max(q.z).min(0.0)
+    }
+
+    #[test]
+    fn unit_cube_matches_the_box_distance() {
+        let mesh = unit_cube();
+        for sign in [SignMode::WindingNumber, SignMode::RayParity] {
+            let sdf = Sdf::bake(&mesh, 16, sign);
+            assert!(sdf.distance([0.5, 0.5, 0.5]) < 0.0, "{:?}", sign);
+            assert!(sdf.distance([0.5, 0.5, 1.08]) > 0.0, "{:?}", sign);
+            // Within a voxel, the field is only sampled at voxel centers
+            for p in [[0.5, 0.5, 0.5], [0.2, 0.5, 0.5], [0.5, 0.9, 0.4], [1.05, 0.5, 0.5], [0.5, 0.5, 1.08], [0.6, -0.04, 0.3]] {
+                let (found, expected) = (sdf.distance(p), box_distance(p));
+                assert!((found - expected).abs() < 1.0 / 16.0, "{:?} at {:?}: {} != {}", sign, p, found, expected);
             }
-            self.distances[i] = min_distance;
         }
     }
 }
@@ -0,0 +1,413 @@
+use cgmath::{Vector3, InnerSpace};
+use crate::primitives::camera::{Camera, Projection};
+use crate::primitives::half_edge::HalfEdgeMesh;
+use crate::primitives::tri_mesh::TriMesh;
+
+// Scheme and mode kinds as the page sends them
+pub const SCHEME_CATMULL_CLARK: u32 = 0;
+pub const SCHEME_LOOP: u32 = 1;
+pub const MODE_NONE: u32 = 0;
+pub const MODE_UNIFORM: u32 = 1;
+pub const MODE_ADAPTIVE: u32 = 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubdivisionScheme {
+    CatmullClark,
+    Loop,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SubdivisionMode {
+    None,
+    Uniform(u32),
+    // Refine until edges project below `edge_pixels` on screen
+    Adaptive { max_level: u32, edge_pixels: f32 },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Subdivision {
+    pub scheme: SubdivisionScheme,
+    pub mode: SubdivisionMode,
+    pub recover_quads: bool,
+}
+
+impl Subdivision {
+    pub fn new(scheme: SubdivisionScheme, mode: SubdivisionMode) -> Self {
+        Self {
+            scheme,
+            mode,
+            recover_quads: true,
+        }
+    }
+
+    pub fn apply(&self, mesh: &TriMesh, camera: &Camera, screen_height: u32) -> TriMesh {
+        if self.mode == SubdivisionMode::None {
+            return mesh.clone();
+        }
+
+        // Loop only operates on triangles
+        let quads = self.recover_quads && self.scheme == SubdivisionScheme::CatmullClark;
+        let he_mesh = HalfEdgeMesh::from_tri_mesh(mesh, quads);
+
+        let subdivided = match (self.scheme, self.mode) {
+            (SubdivisionScheme::CatmullClark, SubdivisionMode::Uniform(levels)) => catmull_clark(&he_mesh, levels),
+            (SubdivisionScheme::Loop, SubdivisionMode::Uniform(levels)) => loop_subdivide(&he_mesh, levels),
+            (SubdivisionScheme::CatmullClark, SubdivisionMode::Adaptive { max_level, edge_pixels }) => {
+                catmull_clark_adaptive(&he_mesh, camera, screen_height, max_level, edge_pixels)
+            },
+            // Loop has no n-gon rule for T-junctions, so the whole mesh takes the finest level it needs
+            (SubdivisionScheme::Loop, SubdivisionMode::Adaptive { max_level, edge_pixels }) => {
+                let levels = adaptive_level(&he_mesh, camera, screen_height, max_level, edge_pixels);
+                loop_subdivide(&he_mesh, levels)
+            },
+            (_, SubdivisionMode::None) => he_mesh,
+        };
+        subdivided.to_tri_mesh()
+    }
+}
+
+
+pub fn catmull_clark(mesh: &HalfEdgeMesh, levels: u32) -> HalfEdgeMesh {
+    let mut mesh = mesh.clone();
+    for _ in 0..levels {
+        let refine = vec![true; mesh.faces.len()];
+        mesh = catmull_clark_step(&mesh, &refine);
+    }
+    mesh
+}
+
+// Faces are refined independently, unrefined neighbours pick up the new edge points as extra
+// polygon vertices so the surface stays watertight
+pub fn catmull_clark_adaptive(mesh: &HalfEdgeMesh, camera: &Camera, screen_height: u32, max_level: u32, edge_pixels: f32) -> HalfEdgeMesh {
+    let pixel_density = pixel_density(camera, screen_height);
+    let mut mesh = mesh.clone();
+    for _ in 0..max_level {
+        let refine: Vec<bool> = (0..mesh.faces.len())
+            .map(|f| projected_edge_length(&mesh, f, camera, pixel_density) > edge_pixels)
+            .collect();
+        if !refine.iter().any(|&r| r) {
+            break;
+        }
+        mesh = catmull_clark_step(&mesh, &refine);
+    }
+    mesh
+}
+
+pub fn loop_subdivide(mesh: &HalfEdgeMesh, levels: u32) -> HalfEdgeMesh {
+    assert!(mesh.is_triangulated(), "Loop subdivision requires a triangle mesh");
+    let mut mesh = mesh.clone();
+    for _ in 0..levels {
+        mesh = loop_step(&mesh);
+    }
+    mesh
+}
+
+// Uniform level needed for the largest projected edge of the mesh
+pub fn adaptive_level(mesh: &HalfEdgeMesh, camera: &Camera, screen_height: u32, max_level: u32, edge_pixels: f32) -> u32 {
+    let pixel_density = pixel_density(camera, screen_height);
+    let largest = (0..mesh.faces.len())
+        .map(|f| projected_edge_length(mesh, f, camera, pixel_density))
+        .fold(0.0f32, f32::max);
+    if largest <= edge_pixels {
+        return 0;
+    }
+    ((largest / edge_pixels).log2().ceil() as u32).min(max_level)
+}
+
+
+// Pixels per radian at the center of the image, or per scene unit for orthographic cameras
+fn pixel_density(camera: &Camera, screen_height: u32) -> f32 {
+    let screen_height = screen_height as f32;
+    match camera.projection {
+        Projection::Perspective => screen_height / (2.0 * (camera.fovy.to_radians() * 0.5).tan()),
+        Projection::Orthographic { height } => screen_height / height.max(1e-4),
+        Projection::Fisheye { fov } => screen_height / fov.to_radians().max(1e-4),
+        // Latitude runs down the image height
+        Projection::Equirectangular => screen_height / std::f32::consts::PI,
+        // Faces are half the image tall over 90 degrees
+        Projection::Cubemap => screen_height * 0.25,
+    }
+}
+
+fn projected_edge_length(mesh: &HalfEdgeMesh, face: usize, camera: &Camera, pixel_density: f32) -> f32 {
+    let origin = Vector3::new(camera.origin.x, camera.origin.y, camera.origin.z);
+    // Parallel rays keep sizes the same at any distance
+    let distance = match camera.projection {
+        Projection::Orthographic { .. } => 1.0,
+        _ => (mesh.face_centroid(face) - origin).magnitude().max(1e-4),
+    };
+    let longest = mesh.face_half_edges(face).iter()
+        .map(|&h| (pos(mesh, mesh.dest(h)) - pos(mesh, mesh.half_edges[h].vertex)).magnitude())
+        .fold(0.0f32, f32::max);
+    longest * pixel_density / distance
+}
+
+fn pos(mesh: &HalfEdgeMesh, v: usize) -> Vector3<f32> {
+    Vector3::from(mesh.positions[v])
+}
+
+fn lerp(a: Vector3<f32>, b: Vector3<f32>, t: f32) -> Vector3<f32> {
+    a + (b - a) * t
+}
+
+fn mid_uv(a: [f32; 2], b: [f32; 2]) -> [f32; 2] {
+    [(a[0] + b[0]) * 0.5, (a[1] + b[1]) * 0.5]
+}
+
+// Semi-sharp creases lose one unit of sharpness per level, infinite creases stay sharp
+fn child_sharpness(sharpness: f32) -> f32 {
+    (sharpness - 1.0).max(0.0)
+}
+
+
+struct VertexRing {
+    // Neighbouring vertex and the sharpness of the edge to it
+    edges: Vec<(usize, f32)>,
+    faces: Vec<usize>,
+}
+
+fn vertex_rings(mesh: &HalfEdgeMesh) -> Vec<VertexRing> {
+    let mut rings: Vec<VertexRing> = (0..mesh.positions.len())
+        .map(|_| VertexRing { edges: Vec::new(), faces: Vec::new() })
+        .collect();
+    for h in 0..mesh.half_edges.len() {
+        let a = mesh.half_edges[h].vertex;
+        let b = mesh.dest(h);
+        let sharpness = mesh.edge_sharpness(h);
+        rings[a].edges.push((b, sharpness));
+        rings[a].faces.push(mesh.half_edges[h].face);
+
+        // Boundary edges have no twin to register them at the other end
+        if mesh.half_edges[h].twin.is_none() {
+            rings[b].edges.push((a, sharpness));
+        }
+    }
+    rings
+}
+
+// Blends the smooth rule with the crease (two sharp edges) or corner (more) rule
+fn vertex_point(mesh: &HalfEdgeMesh, v: usize, ring: &VertexRing, smooth: Option<Vector3<f32>>) -> Vector3<f32> {
+    let p = pos(mesh, v);
+    let sharp: Vec<(usize, f32)> = ring.edges.iter().copied().filter(|e| e.1 > 0.0).collect();
+    let sharp_point = match sharp.len() {
+        0 | 1 => return smooth.unwrap_or(p),
+        2 => (pos(mesh, sharp[0].0) + pos(mesh, sharp[1].0) + p * 6.0) / 8.0,
+        _ => p,
+    };
+
+    let sharpness = sharp.iter().map(|e| e.1).sum::<f32>() / sharp.len() as f32;
+    match smooth {
+        Some(smooth) if sharpness < 1.0 => lerp(smooth, sharp_point, sharpness),
+        _ => sharp_point,
+    }
+}
+
+fn edge_point(mesh: &HalfEdgeMesh, h: usize, smooth: Option<Vector3<f32>>) -> Vector3<f32> {
+    let a = pos(mesh, mesh.half_edges[h].vertex);
+    let b = pos(mesh, mesh.dest(h));
+    let midpoint = (a + b) * 0.5;
+    let sharpness = mesh.edge_sharpness(h);
+    match smooth {
+        Some(smooth) if sharpness < 1.0 => lerp(smooth, midpoint, sharpness),
+        _ => midpoint,
+    }
+}
+
+
+fn catmull_clark_step(mesh: &HalfEdgeMesh, refine: &[bool]) -> HalfEdgeMesh {
+    let centroids: Vec<Vector3<f32>> = (0..mesh.faces.len()).map(|f| mesh.face_centroid(f)).collect();
+    let rings = vertex_rings(mesh);
+    let mut positions = mesh.positions.clone();
+
+    // Vertices of refined faces move, unrefined faces sharing them follow along
+    let mut touched = vec![false; mesh.positions.len()];
+    for face in (0..mesh.faces.len()).filter(|&f| refine[f]) {
+        for v in mesh.face_vertices(face) {
+            touched[v] = true;
+        }
+    }
+    for v in (0..mesh.positions.len()).filter(|&v| touched[v]) {
+        let ring = &rings[v];
+        let n = ring.edges.len();
+        let smooth = if n >= 3 && ring.faces.len() == n {
+            let q = ring.faces.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, &f| acc + centroids[f]) / n as f32;
+            let r = ring.edges.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, e| acc + (pos(mesh, v) + pos(mesh, e.0)) * 0.5) / n as f32;
+            Some((q + r * 2.0 + pos(mesh, v) * (n as f32 - 3.0)) / n as f32)
+        } else {
+            None
+        };
+        positions[v] = vertex_point(mesh, v, ring, smooth).into();
+    }
+
+    // Edge points on every edge next to a refined face
+    let mut edge_vertex = vec![usize::MAX; mesh.half_edges.len()];
+    for h in 0..mesh.half_edges.len() {
+        let e = mesh.edge_id(h);
+        if edge_vertex[e] != usize::MAX {
+            continue;
+        }
+        let twin = mesh.half_edges[h].twin;
+        let split = refine[mesh.half_edges[h].face] || twin.map_or(false, |t| refine[mesh.half_edges[t].face]);
+        if !split {
+            continue;
+        }
+        let smooth = twin.map(|t| {
+            let a = pos(mesh, mesh.half_edges[h].vertex);
+            let b = pos(mesh, mesh.dest(h));
+            (a + b + centroids[mesh.half_edges[h].face] + centroids[mesh.half_edges[t].face]) * 0.25
+        });
+        positions.push(edge_point(mesh, h, smooth).into());
+        edge_vertex[e] = positions.len() - 1;
+    }
+
+    let mut polygons = Vec::new();
+    let mut uvs = Vec::new();
+    let mut sharpness = Vec::new();
+    for face in 0..mesh.faces.len() {
+        let hs = mesh.face_half_edges(face);
+        let n = hs.len();
+        let he = |i: usize| &mesh.half_edges[hs[i % n]];
+        let ev = |i: usize| edge_vertex[mesh.edge_id(hs[i % n])];
+
+        if refine[face] {
+            positions.push(centroids[face].into());
+            let face_vertex = positions.len() - 1;
+            let face_uv = hs.iter().fold([0.0, 0.0], |acc, &h| {
+                let uv = mesh.half_edges[h].uv;
+                [acc[0] + uv[0] / n as f32, acc[1] + uv[1] / n as f32]
+            });
+
+            for i in 0..n {
+                let prev = i + n - 1;
+                polygons.push(vec![he(i).vertex, ev(i), face_vertex, ev(prev)]);
+                uvs.push(vec![he(i).uv, mid_uv(he(i).uv, he(i + 1).uv), face_uv, mid_uv(he(prev).uv, he(i).uv)]);
+                sharpness.push(vec![child_sharpness(he(i).sharpness), 0.0, 0.0, child_sharpness(he(prev).sharpness)]);
+            }
+        } else {
+            let mut polygon = Vec::with_capacity(n * 2);
+            let mut polygon_uvs = Vec::with_capacity(n * 2);
+            let mut polygon_sharpness = Vec::with_capacity(n * 2);
+            for i in 0..n {
+                polygon.push(he(i).vertex);
+                polygon_uvs.push(he(i).uv);
+                if ev(i) == usize::MAX {
+                    polygon_sharpness.push(he(i).sharpness);
+                } else {
+                    let s = child_sharpness(he(i).sharpness);
+                    polygon_sharpness.push(s);
+                    polygon.push(ev(i));
+                    polygon_uvs.push(mid_uv(he(i).uv, he(i + 1).uv));
+                    polygon_sharpness.push(s);
+                }
+            }
+            polygons.push(polygon);
+            uvs.push(polygon_uvs);
+            sharpness.push(polygon_sharpness);
+        }
+    }
+
+    HalfEdgeMesh::from_polygons(positions, &polygons, &uvs, &sharpness, mesh.material_index)
+}
+
+
+fn loop_step(mesh: &HalfEdgeMesh) -> HalfEdgeMesh {
+    let rings = vertex_rings(mesh);
+    let mut positions = mesh.positions.clone();
+
+    for v in 0..mesh.positions.len() {
+        let ring = &rings[v];
+        let n = ring.edges.len();
+        let smooth = if n >= 3 && ring.faces.len() == n {
+            let beta = (5.0 / 8.0 - (3.0 / 8.0 + 0.25 * (2.0 * std::f32::consts::PI / n as f32).cos()).powi(2)) / n as f32;
+            let sum = ring.edges.iter().fold(Vector3::new(0.0, 0.0, 0.0), |acc, e| acc + pos(mesh, e.0));
+            Some(pos(mesh, v) * (1.0 - n as f32 * beta) + sum * beta)
+        } else {
+            None
+        };
+        positions[v] = vertex_point(mesh, v, ring, smooth).into();
+    }
+
+    let mut edge_vertex = vec![usize::MAX; mesh.half_edges.len()];
+    for h in 0..mesh.half_edges.len() {
+        let e = mesh.edge_id(h);
+        if edge_vertex[e] != usize::MAX {
+            continue;
+        }
+        let smooth = mesh.half_edges[h].twin.map(|t| {
+            let a = pos(mesh, mesh.half_edges[h].vertex);
+            let b = pos(mesh, mesh.dest(h));
+            let c = pos(mesh, mesh.half_edges[mesh.prev(h)].vertex);
+            let d = pos(mesh, mesh.half_edges[mesh.prev(t)].vertex);
+            (a + b) * (3.0 / 8.0) + (c + d) * (1.0 / 8.0)
+        });
+        positions.push(edge_point(mesh, h, smooth).into());
+        edge_vertex[e] = positions.len() - 1;
+    }
+
+    let mut polygons = Vec::with_capacity(mesh.faces.len() * 4);
+    let mut uvs = Vec::with_capacity(mesh.faces.len() * 4);
+    let mut sharpness = Vec::with_capacity(mesh.faces.len() * 4);
+    for face in 0..mesh.faces.len() {
+        let hs = mesh.face_half_edges(face);
+        let [h0, h1, h2] = [&mesh.half_edges[hs[0]], &mesh.half_edges[hs[1]], &mesh.half_edges[hs[2]]];
+        let [e0, e1, e2] = [edge_vertex[mesh.edge_id(hs[0])], edge_vertex[mesh.edge_id(hs[1])], edge_vertex[mesh.edge_id(hs[2])]];
+        let [uv0, uv1, uv2] = [mid_uv(h0.uv, h1.uv), mid_uv(h1.uv, h2.uv), mid_uv(h2.uv, h0.uv)];
+        let [s0, s1, s2] = [child_sharpness(h0.sharpness), child_sharpness(h1.sharpness), child_sharpness(h2.sharpness)];
+
+        polygons.push(vec![h0.vertex, e0, e2]);
+        uvs.push(vec![h0.uv, uv0, uv2]);
+        sharpness.push(vec![s0, 0.0, s2]);
+
+        polygons.push(vec![e0, h1.vertex, e1]);
+        uvs.push(vec![uv0, h1.uv, uv1]);
+        sharpness.push(vec![s0, s1, 0.0]);
+
+        polygons.push(vec![e2, e1, h2.vertex]);
+        uvs.push(vec![uv2, uv1, h2.uv]);
+        sharpness.push(vec![0.0, s1, s2]);
+
+        polygons.push(vec![e0, e1, e2]);
+        uvs.push(vec![uv0, uv1, uv2]);
+        sharpness.push(vec![0.0, 0.0, 0.0]);
+    }
+
+    HalfEdgeMesh::from_polygons(positions, &polygons, &uvs, &sharpness, mesh.material_index)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A unit square facing the camera from `distance` away
+    fn square_and_camera(distance: f32, projection: Projection) -> (HalfEdgeMesh, Camera) {
+        let positions = vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+        let mesh = HalfEdgeMesh::from_polygons(positions, &[vec![0, 1, 2, 3]], &[vec![[0.0; 2]; 4]], &[vec![0.0; 4]], 0);
+        let mut camera = Camera::new((0.5, 0.5, distance).into(), (0.5, 0.5, 0.0).into(), 0.0, 90.0, 1.0);
+        camera.projection = projection;
+        (mesh, camera)
+    }
+
+    fn edge_pixels(distance: f32, projection: Projection) -> f32 {
+        let (mesh, camera) = square_and_camera(distance, projection);
+        projected_edge_length(&mesh, 0, &camera, pixel_density(&camera, 512))
+    }
+
+    #[test]
+    fn orthographic_edges_ignore_distance() {
+        let projection = Projection::Orthographic { height: 4.0 };
+        assert_eq!(edge_pixels(2.0, projection), 128.0);
+        assert_eq!(edge_pixels(20.0, projection), 128.0);
+    }
+
+    #[test]
+    fn angular_projections_use_their_pixel_density() {
+        // 90 degrees over the image height puts 512 / (pi / 2) pixels in a radian
+        let fisheye = edge_pixels(10.0, Projection::Fisheye { fov: 90.0 });
+        assert!((fisheye - 512.0 / std::f32::consts::FRAC_PI_2 / 10.0).abs() < 1e-3);
+        let equirectangular = edge_pixels(10.0, Projection::Equirectangular);
+        assert!((equirectangular - 512.0 / std::f32::consts::PI / 10.0).abs() < 1e-3);
+        assert!((edge_pixels(10.0, Projection::Cubemap) - 12.8).abs() < 1e-3);
+        assert!((edge_pixels(10.0, Projection::Perspective) - 25.6).abs() < 1e-3);
+    }
+}
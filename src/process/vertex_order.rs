@@ -0,0 +1,67 @@
+use crate::primitives::scene::SceneObjectCPU;
+use crate::primitives::vertex::Vertex;
+
+
+// How the vertices of a mesh are stored once its BVH has put the objects in leaf order
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VertexOrder {
+    // As the glb lists them
+    Source,
+    // Renumbered by first use in leaf order, so the objects of a subtree read neighbouring vertices
+    LeafOrder,
+    // Three vertices per object in leaf order, a leaf reads one contiguous run. Shared vertices are
+    // repeated, about six times the vertices of a closed mesh.
+    Gathered,
+}
+
+// Lays out the vertices of one mesh, which start at `offset` in the scene, for `objects` in leaf
+// order and points the objects at them. Returns the vertices and, for each, the index of the
+// source vertex it was copied from, relative to `offset`.
+pub fn reorder_vertices(vertices: &[Vertex], objects: &mut [SceneObjectCPU], offset: u32, order: VertexOrder) -> (Vec<Vertex>, Vec<u32>) {
+    let sources: Vec<u32> = match order {
+        VertexOrder::Source => (0..vertices.len() as u32).collect(),
+        VertexOrder::LeafOrder => {
+            let mut position = vec![u32::MAX; vertices.len()];
+            let mut sources = Vec::with_capacity(vertices.len());
+            for object in objects.iter_mut() {
+                let indices = object.object().indices().map(|i| {
+                    let source = (i - offset) as usize;
+                    if position[source] == u32::MAX {
+                        position[source] = sources.len() as u32;
+                        sources.push(source as u32);
+                    }
+                    offset + position[source]
+                });
+                object.set_indices(indices);
+            }
+            // Vertices no object uses keep their place at the end
+            sources.extend((0..vertices.len() as u32).filter(|&i| position[i as usize] == u32::MAX));
+            sources
+        },
+        VertexOrder::Gathered => {
+            let mut sources = Vec::with_capacity(3 * objects.len());
+            for object in objects.iter_mut() {
+                let first = offset + sources.len() as u32;
+                sources.extend(object.object().indices().iter().map(|i| i - offset));
+                object.set_indices([first, first + 1, first + 2]);
+            }
+            sources
+        },
+    };
+
+    let reordered = sources.iter().map(|&i| vertices[i as usize]).collect();
+    (reordered, sources)
+}
+
+// Mean distance between the lowest and highest vertex each leaf of `leaves` reads, as first and
+// count into `objects`. Lower means fewer cache lines per leaf.
+pub fn mean_leaf_span(objects: &[SceneObjectCPU], leaves: &[(usize, usize)]) -> f32 {
+    let total: u64 = leaves.iter()
+        .map(|&(first, count)| {
+            let indices = objects[first..first + count].iter().flat_map(|o| o.object().indices());
+            let (lo, hi) = indices.fold((u32::MAX, 0), |(lo, hi), i| (lo.min(i), hi.max(i)));
+            (hi - lo) as u64
+        })
+        .sum();
+    total as f32 / leaves.len().max(1) as f32
+}
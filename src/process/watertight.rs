@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+use rayon::prelude::*;
+
+use crate::primitives::ray::Ray;
+use crate::primitives::tri_mesh::TriMesh;
+
+// Points probed along every shared edge, at i / EDGE_STEPS for i in 1..EDGE_STEPS
+const EDGE_STEPS: usize = 8;
+// Directions tried at each point, the first straight against the averaged face normal
+const DIRECTIONS: usize = 8;
+
+
+// A ray through a shared edge or vertex and the triangles around it. The ray sees all of them
+// from the front, so together they cover every point it can pass through near the target and
+// one of them must report a hit, however the target rounded off the exact edge.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub target: [f32; 3],
+    pub ray: Ray,
+    pub triangles: Vec<usize>,
+}
+
+// Probes along every edge shared by two consistently wound triangles and at every vertex with a
+// closed fan. Vertices are welded by position first, glb meshes split them wherever normals or
+// uvs differ.
+pub fn probes(mesh: &TriMesh) -> Vec<Probe> {
+    let mut welded: HashMap<[u32; 3], usize> = HashMap::new();
+    let ids: Vec<usize> = mesh.vertices.iter()
+        .map(|v| {
+            let next = welded.len();
+            *welded.entry([v[0].to_bits(), v[1].to_bits(), v[2].to_bits()]).or_insert(next)
+        })
+        .collect();
+
+    let triangles: Vec<[u32; 3]> = mesh.indices.chunks(3).map(|t| [t[0], t[1], t[2]]).collect();
+    let normals: Vec<[f32; 3]> = triangles.iter()
+        .map(|t| {
+            let [a, b, c] = t.map(|i| mesh.vertices[i as usize]);
+            normalize(cross(sub(b, a), sub(c, a)))
+        })
+        .collect();
+
+    // Directed edges by their welded ends, fans as the triangles and opposite edges of a vertex
+    let mut edges: HashMap<(usize, usize), Vec<(usize, u32, u32)>> = HashMap::new();
+    let mut fans: HashMap<usize, Vec<(usize, usize, usize)>> = HashMap::new();
+    for (i, t) in triangles.iter().enumerate() {
+        for k in 0..3 {
+            let (a, b, c) = (t[k], t[(k + 1) % 3], t[(k + 2) % 3]);
+            let (wa, wb) = (ids[a as usize], ids[b as usize]);
+            edges.entry((wa.min(wb), wa.max(wb))).or_default().push((i, a, b));
+            fans.entry(wa).or_default().push((i, wb, ids[c as usize]));
+        }
+    }
+
+    let (_, radius) = bounding_sphere(&mesh.vertices);
+    let mut probes = Vec::new();
+    let mut keys: Vec<&(usize, usize)> = edges.keys().collect();
+    // Hash map order isn't stable between runs
+    keys.sort();
+    for key in keys {
+        let sharing = &edges[key];
+        if sharing.len() != 2 || ids[sharing[0].1 as usize] == ids[sharing[1].1 as usize] {
+            continue;
+        }
+        let faces = vec![sharing[0].0, sharing[1].0];
+        let (pa, pb) = (mesh.vertices[sharing[0].1 as usize], mesh.vertices[sharing[0].2 as usize]);
+        for step in 1..EDGE_STEPS {
+            let s = step as f32 / EDGE_STEPS as f32;
+            let target = [
+                pa[0] + (pb[0] - pa[0]) * s,
+                pa[1] + (pb[1] - pa[1]) * s,
+                pa[2] + (pb[2] - pa[2]) * s,
+            ];
+            push_probes(&mut probes, target, &faces, &normals, radius);
+        }
+    }
+
+    let mut vertices: Vec<&usize> = fans.keys().collect();
+    vertices.sort();
+    for v in vertices {
+        let fan = &fans[v];
+        // Closed when every edge out of the vertex is shared by exactly two fan triangles
+        let mut around: HashMap<usize, usize> = HashMap::new();
+        for &(_, b, c) in fan {
+            *around.entry(b).or_default() += 1;
+            *around.entry(c).or_default() += 1;
+        }
+        if around.values().any(|&count| count != 2) {
+            continue;
+        }
+        let faces: Vec<usize> = fan.iter().map(|f| f.0).collect();
+        let corner = triangles[fan[0].0].iter().find(|&&i| ids[i as usize] == *v).unwrap();
+        push_probes(&mut probes, mesh.vertices[*corner as usize], &faces, &normals, radius);
+    }
+
+    // Far from the origin rounding can fold slivers over, drop rays that truly pass between faces
+    probes.retain(|probe| probe.triangles.iter().any(|&i| passes_through(&probe.ray, &corners(mesh, i))));
+    probes
+}
+
+// Probes whose ray hits none of their triangles with `hit`
+pub fn gaps<F: Fn(&Ray, &[[f32; 3]; 3]) -> Option<f32> + Sync>(mesh: &TriMesh, probes: &[Probe], hit: F) -> Vec<Probe> {
+    probes.par_iter()
+        .filter(|probe| probe.triangles.iter().all(|&i| hit(&probe.ray, &corners(mesh, i)).is_none()))
+        .cloned()
+        .collect()
+}
+
+// The mesh moved by `offset`, far from the origin the float grid is coarse and cracks widen
+pub fn translated(mesh: &TriMesh, offset: [f32; 3]) -> TriMesh {
+    let mut moved = mesh.clone();
+    for v in moved.vertices.iter_mut() {
+        *v = [v[0] + offset[0], v[1] + offset[1], v[2] + offset[2]];
+    }
+    moved
+}
+
+
+fn corners(mesh: &TriMesh, triangle: usize) -> [[f32; 3]; 3] {
+    let t = &mesh.indices[3 * triangle..3 * triangle + 3];
+    [mesh.vertices[t[0] as usize], mesh.vertices[t[1] as usize], mesh.vertices[t[2] as usize]]
+}
+
+// Reference for whether the ray line crosses the triangle, from the signs of the volumes it spans
+// with each edge in f64. Points on an edge count as inside.
+fn passes_through(ray: &Ray, corners: &[[f32; 3]; 3]) -> bool {
+    let d = ray.direction.map(|x| x as f64);
+    let [a, b, c] = corners.map(|p| [
+        p[0] as f64 - ray.origin[0] as f64,
+        p[1] as f64 - ray.origin[1] as f64,
+        p[2] as f64 - ray.origin[2] as f64,
+    ]);
+    let volume = |p: [f64; 3], q: [f64; 3]| {
+        d[0] * (p[1] * q[2] - p[2] * q[1]) + d[1] * (p[2] * q[0] - p[0] * q[2]) + d[2] * (p[0] * q[1] - p[1] * q[0])
+    };
+    let (u, v, w) = (volume(b, c), volume(c, a), volume(a, b));
+    (u >= 0.0 && v >= 0.0 && w >= 0.0) || (u <= 0.0 && v <= 0.0 && w <= 0.0)
+}
+
+// Rays at `target` from a cone around the averaged normal of `faces`, kept only where every face
+// is seen from the front
+fn push_probes(probes: &mut Vec<Probe>, target: [f32; 3], faces: &[usize], normals: &[[f32; 3]], radius: f32) {
+    let mut sum = [0.0; 3];
+    for &f in faces {
+        sum = [sum[0] + normals[f][0], sum[1] + normals[f][1], sum[2] + normals[f][2]];
+    }
+    if length(sum) < 1e-3 {
+        return;
+    }
+    let n = normalize(sum);
+    let (tangent, bitangent) = basis(n);
+    for k in 0..DIRECTIONS {
+        let angle = k as f32 / (DIRECTIONS - 1) as f32 * std::f32::consts::TAU;
+        let spread = if k == 0 { 0.0 } else { 0.3 };
+        let toward = normalize([
+            n[0] + spread * (angle.cos() * tangent[0] + angle.sin() * bitangent[0]),
+            n[1] + spread * (angle.cos() * tangent[1] + angle.sin() * bitangent[1]),
+            n[2] + spread * (angle.cos() * tangent[2] + angle.sin() * bitangent[2]),
+        ]);
+        if faces.iter().any(|&f| dot(toward, normals[f]) < 1e-3) {
+            continue;
+        }
+        let origin = [
+            target[0] + toward[0] * 2.0 * radius,
+            target[1] + toward[1] * 2.0 * radius,
+            target[2] + toward[2] * 2.0 * radius,
+        ];
+        let direction = [-toward[0], -toward[1], -toward[2]];
+        probes.push(Probe { target, ray: Ray::new(origin, direction), triangles: faces.to_vec() });
+    }
+}
+
+fn bounding_sphere(vertices: &[[f32; 3]]) -> ([f32; 3], f32) {
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for v in vertices {
+        for axis in 0..3 {
+            min[axis] = min[axis].min(v[axis]);
+            max[axis] = max[axis].max(v[axis]);
+        }
+    }
+    let center = [(min[0] + max[0]) * 0.5, (min[1] + max[1]) * 0.5, (min[2] + max[2]) * 0.5];
+    let radius = length([max[0] - center[0], max[1] - center[1], max[2] - center[2]]);
+    (center, radius.max(f32::MIN_POSITIVE))
+}
+
+// Two unit vectors perpendicular to `n` and each other
+fn basis(n: [f32; 3]) -> ([f32; 3], [f32; 3]) {
+    let helper = if n[0].abs() > 0.9 { [0.0, 1.0, 0.0] } else { [1.0, 0.0, 0.0] };
+    let tangent = normalize(cross(n, helper));
+    (tangent, cross(n, tangent))
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn length(v: [f32; 3]) -> f32 {
+    (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let l = length(v);
+    if l == 0.0 { [0.0, 0.0, 1.0] } else { [v[0] / l, v[1] / l, v[2] / l] }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::primitives::triangle::hit_watertight;
+    use crate::process::glb::load_glb;
+
+    #[test]
+    fn cube_has_no_gaps() {
+        let glb = load_glb(include_bytes!("../../assets/cube.glb"));
+        for mesh in glb.meshes() {
+            for offset in [[0.0, 0.0, 0.0], [1000.0, -2000.0, 500.0]] {
+                let mesh = translated(mesh, offset);
+                let probes = probes(&mesh);
+                assert!(!probes.is_empty());
+                let gaps = gaps(&mesh, &probes, hit_watertight);
+                assert!(gaps.is_empty(), "{} gaps at offset {:?}, first at {:?}", gaps.len(), offset, gaps[0].target);
+            }
+        }
+    }
+}
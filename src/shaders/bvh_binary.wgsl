@@ -0,0 +1,146 @@
+// Stackless binary BVH traversal over the threaded nodes, the layout refit by compute_refit.wgsl.
+// Children are visited in a fixed order, nodes beyond the closest hit are skipped.
+@group(0) @binding(2) var<storage, read> bvh_buffer: BVHBuffer;
+
+// Medium boundaries are skipped by surface shading unless explicitly requested.
+// Top level leaves are instances, their bottom level BVH is traversed with the ray in object space.
+fn hit_bvh_masked(ray_in: Ray, include_media: bool) -> HitRec {
+    // Shrinks to the closest hit so farther nodes and primitives are culled
+    var ray = ray_in;
+    var rec: HitRec = NULL_HIT;
+    var node_index = bvh_buffer.root;
+
+    while (node_index >= 0) {
+        let node = bvh_buffer.nodes[node_index];
+        let t = distance_to_aabb(ray, node.aabb);
+        if (t < 0.0 || t > ray.t_max) {
+            node_index = node.miss;
+            continue;
+        }
+
+        if (node.count == 0) {
+            node_index = node.left;
+            continue;
+        }
+
+        // Leaf node
+        for (var i = node.first; i < node.first + node.count; i += 1) {
+            let instance = instance_buffer.data[i];
+            let transform = instance_transform(instance, ray.time);
+            let local_ray = Ray(
+                (transform.world_to_object * vec4<f32>(ray.origin, 1.0)).xyz,
+                ray.t_min,
+                (transform.world_to_object * vec4<f32>(ray.direction, 0.0)).xyz,
+                ray.t_max,
+                ray.time
+            );
+
+            // The direction is left unnormalized so the interval is shared between spaces
+            let hit: HitRec = hit_blas(local_ray, instance.blas_root, include_media);
+            if (hit.t > 0.0) {
+                let normal_to_world = transpose(transform.world_to_object);
+                let normal = normalize((normal_to_world * vec4<f32>(hit.normal, 0.0)).xyz);
+                let geometric_normal = normalize((normal_to_world * vec4<f32>(hit.geometric_normal, 0.0)).xyz);
+                let p = (transform.object_to_world * vec4<f32>(hit.p, 1.0)).xyz;
+                rec = HitRec(hit.t, p, normal, geometric_normal, hit.material, hit.frontface);
+                ray.t_max = hit.t;
+            }
+        }
+        node_index = node.miss;
+    }
+    return rec;
+}
+
+fn hit_blas(ray_in: Ray, root: i32, include_media: bool) -> HitRec {
+    var ray = ray_in;
+    var rec: HitRec = NULL_HIT;
+    var node_index = root;
+
+    while (node_index >= 0) {
+        let node = bvh_buffer.nodes[node_index];
+        let t = distance_to_aabb(ray, node.aabb);
+        if (t < 0.0 || t > ray.t_max) {
+            node_index = node.miss;
+            continue;
+        }
+
+        if (node.count == 0) {
+            node_index = node.left;
+            continue;
+        }
+
+        // Leaf node
+        for (var i = node.first; i < node.first + node.count; i += 1) {
+            let hit: HitRec = hit_object(object_buffer.data[i], ray);
+            if (hit.t > 0.0 && (include_media || hit.material.medium < 0)) {
+                rec = hit;
+                ray.t_max = hit.t;
+            }
+        }
+        node_index = node.miss;
+    }
+    return rec;
+}
+
+// Any hit within the ray's interval ends the traversal, for visibility tests. Media are skipped like hit_bvh.
+fn occluded(ray: Ray) -> bool {
+    var node_index = bvh_buffer.root;
+
+    while (node_index >= 0) {
+        let node = bvh_buffer.nodes[node_index];
+        let t = distance_to_aabb(ray, node.aabb);
+        if (t < 0.0 || t > ray.t_max) {
+            node_index = node.miss;
+            continue;
+        }
+
+        if (node.count == 0) {
+            node_index = node.left;
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i += 1) {
+            let instance = instance_buffer.data[i];
+            let transform = instance_transform(instance, ray.time);
+            let local_ray = Ray(
+                (transform.world_to_object * vec4<f32>(ray.origin, 1.0)).xyz,
+                ray.t_min,
+                (transform.world_to_object * vec4<f32>(ray.direction, 0.0)).xyz,
+                ray.t_max,
+                ray.time
+            );
+            if (occluded_blas(local_ray, instance.blas_root)) {
+                return true;
+            }
+        }
+        node_index = node.miss;
+    }
+    return false;
+}
+
+fn occluded_blas(ray: Ray, root: i32) -> bool {
+    var node_index = root;
+
+    while (node_index >= 0) {
+        let node = bvh_buffer.nodes[node_index];
+        let t = distance_to_aabb(ray, node.aabb);
+        if (t < 0.0 || t > ray.t_max) {
+            node_index = node.miss;
+            continue;
+        }
+
+        if (node.count == 0) {
+            node_index = node.left;
+            continue;
+        }
+
+        for (var i = node.first; i < node.first + node.count; i += 1) {
+            let hit: HitRec = hit_object(object_buffer.data[i], ray);
+            if (hit.t > 0.0 && hit.material.medium < 0) {
+                return true;
+            }
+        }
+        node_index = node.miss;
+    }
+    return false;
+}
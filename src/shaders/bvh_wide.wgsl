@@ -0,0 +1,239 @@
+// Wide BVH traversal over the compressed nodes written by bvh_wide.rs
+@group(0) @binding(2) var<storage, read> bvh_buffer: WideBVHBuffer;
+
+struct WideBVHNode {
+    origin: vec3<f32>,
+    exponents_imask: u32,
+    child_base: u32,
+    prim_base: u32,
+    slots: vec2<u32>,
+    lo: array<vec2<u32>, 3>,
+    hi: array<vec2<u32>, 3>,
+}
+
+struct WideBVHBuffer {
+    root: i32,
+    nodes: array<WideBVHNode>,
+}
+
+fn slot_byte(bytes: vec2<u32>, slot: u32) -> u32 {
+    return (bytes[slot / 4u] >> ((slot % 4u) * 8u)) & 0xffu;
+}
+
+fn is_internal(node: WideBVHNode, slot: u32) -> bool {
+    return ((node.exponents_imask >> (24u + slot)) & 1u) == 1u;
+}
+
+// Entry distance of every child box the ray reaches before its t_max, -1 for misses and empty slots
+fn wide_child_hits(ray: Ray, node: WideBVHNode) -> array<f32, 8> {
+    let inv_direction = vec3<f32>(1.0) / ray.direction;
+    let e = vec3<u32>(node.exponents_imask, node.exponents_imask >> 8u, node.exponents_imask >> 16u) & vec3<u32>(0xffu);
+    let scale = vec3<f32>(bitcast<f32>(e.x << 23u), bitcast<f32>(e.y << 23u), bitcast<f32>(e.z << 23u));
+
+    var hits: array<f32, 8>;
+    for (var slot = 0u; slot < 8u; slot++) {
+        hits[slot] = -1.0;
+        if (!is_internal(node, slot) && slot_byte(node.slots, slot) == 0u) {
+            continue;
+        }
+
+        let lo = node.origin + vec3<f32>(f32(slot_byte(node.lo[0], slot)), f32(slot_byte(node.lo[1], slot)), f32(slot_byte(node.lo[2], slot))) * scale;
+        let hi = node.origin + vec3<f32>(f32(slot_byte(node.hi[0], slot)), f32(slot_byte(node.hi[1], slot)), f32(slot_byte(node.hi[2], slot))) * scale;
+        let t1 = (lo - ray.origin) * inv_direction;
+        let t2 = (hi - ray.origin) * inv_direction;
+        let tmin = min(t1, t2);
+        let tmax = max(t1, t2);
+        let t_enter = max(max(max(tmin.x, tmin.y), tmin.z), 0.0);
+        let t_exit = min(min(tmax.x, tmax.y), tmax.z);
+        if (t_exit >= t_enter && t_enter <= ray.t_max) {
+            hits[slot] = t_enter;
+        }
+    }
+    return hits;
+}
+
+// Pushes the internal children that were hit, farthest first so the closest is popped next. The
+// builder keeps trees within the 64 entries of the stack, see bvh_wide::STACK_SIZE. Deeper trees
+// drop the farthest children that don't fit rather than write past the stack.
+fn push_wide_children(node: WideBVHNode, hits: array<f32, 8>, stack: ptr<function, array<i32, 64>>, stack_top: ptr<function, i32>) {
+    var pending = hits;
+    var children = 0;
+    for (var slot = 0u; slot < 8u; slot++) {
+        if (is_internal(node, slot) && pending[slot] >= 0.0) {
+            children += 1;
+        }
+    }
+    var dropped = max(children - (63 - *stack_top), 0);
+    loop {
+        var farthest = -1;
+        var farthest_t = -1.0;
+        for (var slot = 0u; slot < 8u; slot++) {
+            if (is_internal(node, slot) && pending[slot] >= 0.0 && pending[slot] >= farthest_t) {
+                farthest = i32(slot);
+                farthest_t = pending[slot];
+            }
+        }
+        if (farthest < 0) {
+            break;
+        }
+        pending[farthest] = -1.0;
+        if (dropped > 0) {
+            dropped -= 1;
+            continue;
+        }
+        *stack_top += 1;
+        (*stack)[*stack_top] = i32(node.child_base + slot_byte(node.slots, u32(farthest)));
+    }
+}
+
+// Leaf primitives follow each other in slot order from prim_base
+fn wide_leaf_first(node: WideBVHNode, slot: u32) -> i32 {
+    var first = node.prim_base;
+    for (var s = 0u; s < slot; s++) {
+        if (!is_internal(node, s)) {
+            first += slot_byte(node.slots, s);
+        }
+    }
+    return i32(first);
+}
+
+// Medium boundaries are skipped by surface shading unless explicitly requested.
+// Top level leaves are instances, their bottom level BVH is traversed with the ray in object space.
+fn hit_bvh_masked(ray_in: Ray, include_media: bool) -> HitRec {
+    // Shrinks to the closest hit so farther nodes and primitives are culled
+    var ray = ray_in;
+    var rec: HitRec = NULL_HIT;
+    var stack: array<i32, 64>;
+    var stack_top: i32 = 0;
+    stack[stack_top] = bvh_buffer.root;
+
+    while (stack_top >= 0) {
+        let node = bvh_buffer.nodes[stack[stack_top]];
+        stack_top -= 1;
+
+        var hits = wide_child_hits(ray, node);
+        for (var slot = 0u; slot < 8u; slot++) {
+            if (hits[slot] < 0.0 || is_internal(node, slot)) {
+                continue;
+            }
+            let first = wide_leaf_first(node, slot);
+            for (var i = first; i < first + i32(slot_byte(node.slots, slot)); i += 1) {
+                let instance = instance_buffer.data[i];
+                let transform = instance_transform(instance, ray.time);
+                let local_ray = Ray(
+                    (transform.world_to_object * vec4<f32>(ray.origin, 1.0)).xyz,
+                    ray.t_min,
+                    (transform.world_to_object * vec4<f32>(ray.direction, 0.0)).xyz,
+                    ray.t_max,
+                    ray.time
+                );
+
+                // The direction is left unnormalized so the interval is shared between spaces
+                let hit: HitRec = hit_blas(local_ray, instance.blas_root, include_media);
+                if (hit.t > 0.0) {
+                    let normal_to_world = transpose(transform.world_to_object);
+                    let normal = normalize((normal_to_world * vec4<f32>(hit.normal, 0.0)).xyz);
+                    let geometric_normal = normalize((normal_to_world * vec4<f32>(hit.geometric_normal, 0.0)).xyz);
+                    let p = (transform.object_to_world * vec4<f32>(hit.p, 1.0)).xyz;
+                    rec = HitRec(hit.t, p, normal, geometric_normal, hit.material, hit.frontface);
+                    ray.t_max = hit.t;
+                }
+            }
+        }
+        push_wide_children(node, hits, &stack, &stack_top);
+    }
+    return rec;
+}
+
+fn hit_blas(ray_in: Ray, root: i32, include_media: bool) -> HitRec {
+    var ray = ray_in;
+    var rec: HitRec = NULL_HIT;
+    var stack: array<i32, 64>;
+    var stack_top: i32 = 0;
+    stack[stack_top] = root;
+
+    while (stack_top >= 0) {
+        let node = bvh_buffer.nodes[stack[stack_top]];
+        stack_top -= 1;
+
+        var hits = wide_child_hits(ray, node);
+        for (var slot = 0u; slot < 8u; slot++) {
+            if (hits[slot] < 0.0 || is_internal(node, slot)) {
+                continue;
+            }
+            let first = wide_leaf_first(node, slot);
+            for (var i = first; i < first + i32(slot_byte(node.slots, slot)); i += 1) {
+                let hit: HitRec = hit_object(object_buffer.data[i], ray);
+                if (hit.t > 0.0 && (include_media || hit.material.medium < 0)) {
+                    rec = hit;
+                    ray.t_max = hit.t;
+                }
+            }
+        }
+        push_wide_children(node, hits, &stack, &stack_top);
+    }
+    return rec;
+}
+
+// Any hit within the ray's interval ends the traversal, for visibility tests. Media are skipped like hit_bvh.
+fn occluded(ray: Ray) -> bool {
+    var stack: array<i32, 64>;
+    var stack_top: i32 = 0;
+    stack[stack_top] = bvh_buffer.root;
+
+    while (stack_top >= 0) {
+        let node = bvh_buffer.nodes[stack[stack_top]];
+        stack_top -= 1;
+
+        var hits = wide_child_hits(ray, node);
+        for (var slot = 0u; slot < 8u; slot++) {
+            if (hits[slot] < 0.0 || is_internal(node, slot)) {
+                continue;
+            }
+            let first = wide_leaf_first(node, slot);
+            for (var i = first; i < first + i32(slot_byte(node.slots, slot)); i += 1) {
+                let instance = instance_buffer.data[i];
+                let transform = instance_transform(instance, ray.time);
+                let local_ray = Ray(
+                    (transform.world_to_object * vec4<f32>(ray.origin, 1.0)).xyz,
+                    ray.t_min,
+                    (transform.world_to_object * vec4<f32>(ray.direction, 0.0)).xyz,
+                    ray.t_max,
+                    ray.time
+                );
+                if (occluded_blas(local_ray, instance.blas_root)) {
+                    return true;
+                }
+            }
+        }
+        push_wide_children(node, hits, &stack, &stack_top);
+    }
+    return false;
+}
+
+fn occluded_blas(ray: Ray, root: i32) -> bool {
+    var stack: array<i32, 64>;
+    var stack_top: i32 = 0;
+    stack[stack_top] = root;
+
+    while (stack_top >= 0) {
+        let node = bvh_buffer.nodes[stack[stack_top]];
+        stack_top -= 1;
+
+        var hits = wide_child_hits(ray, node);
+        for (var slot = 0u; slot < 8u; slot++) {
+            if (hits[slot] < 0.0 || is_internal(node, slot)) {
+                continue;
+            }
+            let first = wide_leaf_first(node, slot);
+            for (var i = first; i < first + i32(slot_byte(node.slots, slot)); i += 1) {
+                let hit: HitRec = hit_object(object_buffer.data[i], ray);
+                if (hit.t > 0.0 && hit.material.medium < 0) {
+                    return true;
+                }
+            }
+        }
+        push_wide_children(node, hits, &stack, &stack_top);
+    }
+    return false;
+}
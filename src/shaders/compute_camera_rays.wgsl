@@ -19,18 +19,60 @@ fn main(@builtin(global_invocation_id) global_ix: vec3<u32>) {
 }
 
 
+// As `Camera::primary_ray`, uv from the top left of the image
 fn create_primary_ray(uv: vec2<f32>) -> Ray {
     let forward = normalize(camera.focus.xyz - camera.origin.xyz);
-    let right = normalize(cross(vec3<f32>(0.0, 1.0, 0.0), forward));
+    // World up is x when looking straight up or down, as in Camera::primary_ray
+    let world_up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(forward.y) >= 0.999);
+    let right = normalize(cross(world_up, forward));
     let up = cross(forward, right);
-    let fov = radians(camera.fovy);
+    let x = uv.x * 2.0 - 1.0;
+    let y = 1.0 - uv.y * 2.0;
 
-    let csc = vec2<f32>(
-        (uv.x * 2.0 - 1.0) * camera.aspect * tan(fov / 2.0),
-        (1.0 - uv.y * 2.0) * tan(fov / 2.0)
-    );
+    var direction: vec3<f32>;
+    switch camera.projection {
+        case PROJECTION_ORTHOGRAPHIC: {
+            // Orthographic, parallel rays from the view plane
+            let origin = camera.origin.xyz + (x * camera.aspect * right + y * up) * 0.5 * camera.projection_scale;
+            return Ray(origin, 0.0, forward, RAY_T_MAX, 0.0);
+        }
+        case PROJECTION_EQUIRECTANGULAR: {
+            // Equirectangular
+            let longitude = x * PI;
+            let latitude = y * 0.5 * PI;
+            direction = (sin(longitude) * right + cos(longitude) * forward) * cos(latitude) + sin(latitude) * up;
+        }
+        case PROJECTION_CUBEMAP: {
+            // Cubemap, faces in a 3 by 2 grid counted from the right as the image is mirrored
+            let cell = min(floor(uv * vec2<f32>(3.0, 2.0)), vec2<f32>(2.0, 1.0));
+            let face_uv = uv * vec2<f32>(3.0, 2.0) - cell;
+            let fx = face_uv.x * 2.0 - 1.0;
+            let fy = 1.0 - face_uv.y * 2.0;
+            switch u32(cell.y * 3.0 + cell.x) {
+                case 0u: { direction = up + fx * right - fy * forward; }
+                case 1u: { direction = right - fx * forward + fy * up; }
+                case 2u: { direction = -right + fx * forward + fy * up; }
+                case 3u: { direction = -forward - fx * right + fy * up; }
+                case 4u: { direction = forward + fx * right + fy * up; }
+                default: { direction = -up + fx * right + fy * forward; }
+            }
+        }
+        case PROJECTION_FISHEYE: {
+            // Equidistant fisheye
+            let p = vec2<f32>(x * camera.aspect, y);
+            let radius = length(p);
+            let theta = min(radius * 0.5 * radians(camera.projection_scale), PI);
+            var around = right;
+            if (radius > 0.0) {
+                around = (p.x * right + p.y * up) / radius;
+            }
+            direction = cos(theta) * forward + sin(theta) * around;
+        }
+        default: {
+            let half_height = tan(radians(camera.fovy) / 2.0);
+            direction = x * camera.aspect * half_height * right + y * half_height * up + forward;
+        }
+    }
 
-    let direction = normalize(csc.x * right + csc.y * up + forward);
-
-    return Ray(camera.origin.xyz, direction);
-}
\ No newline at end of file
+    return Ray(camera.origin.xyz, 0.0, normalize(direction), RAY_T_MAX, 0.0);
+}
@@ -21,7 +21,7 @@ fn main(@builtin(global_invocation_id) global_idx: vec3<u32>) {
 
 fn sample_direct_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
     var pixel_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
     let inv_spp = 1.0 / f32(spp);
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
@@ -29,7 +29,7 @@ fn sample_direct_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f
         let rng = vec2<f32>(hash_u32(seed * scene.config.seed.x), hash_u32(seed * scene.config.seed.y));
 
         var color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
-        var ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
 
         for (var depth = max_depth; depth > 0u; depth = depth - 1u) {
             let rec = hit_bvh(ray);
@@ -53,7 +53,7 @@ fn sample_direct_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f
                     f0
                 );
                 color = specular_color * ggx.weight * light_sample.color;
-                ray = Ray(rec.p, light_sample.dir);                
+                ray = spawn_ray(rec, light_sample.dir);                
             } else {
                 if (depth == max_depth) {
                     color *= 0.0;
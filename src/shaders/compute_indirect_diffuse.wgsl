@@ -21,7 +21,7 @@ fn main(@builtin(global_invocation_id) global_ix: vec3<u32>) {
 
 fn sample_indirect_diffuse(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
     var pixel_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
     let inv_spp = 1.0 / f32(spp);
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
@@ -29,14 +29,14 @@ fn sample_indirect_diffuse(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<
         let rng = vec2<f32>(hash_u32(seed * scene.config.seed.x), hash_u32(seed * scene.config.seed.y));
 
         var color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
-        var ray: Ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var ray: Ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
 
         for (var depth = max_depth; depth > 0u; depth = depth - 1u) {
             let rec = hit_bvh(ray);
             let is_metal = rec.material.metallic > rng.x;
             if (rec.t > 0.0 && !is_metal) {
                 let sample: CosineDiffuse = cosine_weighted_hemisphere(rec, rng);
-                ray = Ray(rec.p, sample.dir);
+                ray = spawn_ray(rec, sample.dir);
                 color *= rec.material.diffuse * sample.pdf;                
             } else {
                 if (depth == max_depth) {
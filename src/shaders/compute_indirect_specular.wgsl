@@ -24,7 +24,7 @@ fn main(@builtin(global_invocation_id) global_ix: vec3<u32>) {
 fn sample_indirect_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> WithSky {
     var pixel_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
     var sky_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
     let inv_spp = 1.0 / f32(spp);
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
@@ -32,7 +32,7 @@ fn sample_indirect_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2
         let rng = vec2<f32>(hash_u32(seed * scene.config.seed.x), hash_u32(seed * scene.config.seed.y));
 
         var color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
-        var ray: Ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var ray: Ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
         var primary_hit_metal = false;
 
         for (var depth = max_depth; depth > 0u; depth = depth - 1u) {
@@ -65,7 +65,7 @@ fn sample_indirect_specular(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2
                 if (scene.config.sky_intensity < EPSILON || !rec.frontface) { // TODO: hacky wacky
                     color*= 0.0;
                 }
-                ray = Ray(rec.p, reflect(ray.direction, ggx.direction));             
+                ray = spawn_ray(rec, reflect(ray.direction, ggx.direction));             
             } else {
                 let sky_sample = sample_sky(ray.direction, scene.config.sky_intensity);
                 if (depth == max_depth) {
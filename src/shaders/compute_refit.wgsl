@@ -0,0 +1,121 @@
+// Bottom up refit of every node of one tree height, dispatched once per height from the
+// leaves up so children are always final before their parents read them
+struct RefitLevel {
+    start: u32,
+    count: u32,
+    // Nodes below this index belong to the top level and bound instances
+    tlas_nodes: u32,
+}
+
+@group(0) @binding(0) var<storage, read_write> bvh_buffer: BVHBuffer;
+@group(0) @binding(1) var<storage, read> object_buffer: SceneObjectBuffer;
+@group(0) @binding(2) var<storage, read> vertex_buffer: VertexBuffer;
+@group(0) @binding(3) var<storage, read> instance_buffer: InstanceBuffer;
+@group(0) @binding(4) var<storage, read> refit_nodes: array<i32>;
+@group(0) @binding(5) var<uniform> level: RefitLevel;
+
+
+fn empty_aabb() -> AABB {
+    return AABB(vec4<f32>(1e30, 1e30, 1e30, 0.0), vec4<f32>(-1e30, -1e30, -1e30, 0.0));
+}
+
+fn grow(aabb: AABB, p: vec3<f32>) -> AABB {
+    return AABB(vec4<f32>(min(aabb.min.xyz, p), 0.0), vec4<f32>(max(aabb.max.xyz, p), 0.0));
+}
+
+fn merge(a: AABB, b: AABB) -> AABB {
+    return AABB(vec4<f32>(min(a.min.xyz, b.min.xyz), 0.0), vec4<f32>(max(a.max.xyz, b.max.xyz), 0.0));
+}
+
+// Matches SceneObjectCPU, including the padding of flat boxes
+fn object_bounds(object: SceneObject) -> AABB {
+    let a = vertex_buffer.data[object.indices.x];
+    var aabb = empty_aabb();
+    if (object.object_type == SPHERE_TYPE) {
+        let r = vec3<f32>(a.position.w);
+        aabb = AABB(vec4<f32>(a.position.xyz - r, 0.0), vec4<f32>(a.position.xyz + r, 0.0));
+    } else if (object.object_type == DISK_TYPE) {
+        let n = normalize(a.normal.xyz);
+        let extent = a.position.w * sqrt(max(vec3<f32>(1.0) - n * n, vec3<f32>(0.0)));
+        aabb = AABB(vec4<f32>(a.position.xyz - extent, 0.0), vec4<f32>(a.position.xyz + extent, 0.0));
+    } else if (object.object_type == SDF_TYPE) {
+        aabb = AABB(vec4<f32>(a.position.xyz, 0.0), vec4<f32>(a.normal.xyz, 0.0));
+    } else {
+        let b = vertex_buffer.data[object.indices.y].position.xyz;
+        let c = vertex_buffer.data[object.indices.z].position.xyz;
+        aabb = grow(grow(grow(aabb, a.position.xyz), b), c);
+        if (object.object_type == PLANE_TYPE) {
+            aabb = grow(aabb, b + c - a.position.xyz);
+        }
+    }
+
+    let pad = select(vec3<f32>(0.0), vec3<f32>(1e-4), aabb.max.xyz - aabb.min.xyz < vec3<f32>(1e-4));
+    return AABB(vec4<f32>(aabb.min.xyz - pad, 0.0), vec4<f32>(aabb.max.xyz + pad, 0.0));
+}
+
+// Bounds of the eight corners of the instanced bottom level root, for moving instances the union
+// over the shutter as `motion_bounds` in motion.rs
+fn instance_bounds(instance: Instance) -> AABB {
+    let root = bvh_buffer.nodes[instance.blas_root].aabb;
+    if (instance.moving == 0u) {
+        return transformed_bounds(root, instance.object_to_world);
+    }
+
+    var reach = 0.0;
+    for (var i = 0u; i < 8u; i++) {
+        reach = max(reach, length(box_corner(root, i)));
+    }
+    var aabb = empty_aabb();
+    for (var n = 0u; n <= MOTION_BOUND_STEPS; n++) {
+        let pose = instance_pose(instance, f32(n) / f32(MOTION_BOUND_STEPS));
+        let next = instance_pose(instance, f32(min(n + 1u, MOTION_BOUND_STEPS)) / f32(MOTION_BOUND_STEPS));
+        let stray = vec4<f32>(vec3<f32>(pose_stray(pose, next, reach)), 0.0);
+        let sample = transformed_bounds(root, pose_transform(pose).object_to_world);
+        aabb = merge(aabb, AABB(sample.min - stray, sample.max + stray));
+    }
+    return aabb;
+}
+
+fn transformed_bounds(box: AABB, transform: mat4x4<f32>) -> AABB {
+    var aabb = empty_aabb();
+    for (var i = 0u; i < 8u; i++) {
+        aabb = grow(aabb, (transform * vec4<f32>(box_corner(box, i), 1.0)).xyz);
+    }
+    return aabb;
+}
+
+fn box_corner(box: AABB, i: u32) -> vec3<f32> {
+    return select(box.min.xyz, box.max.xyz, vec3<bool>((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u));
+}
+
+// Farthest a point within `reach` of the object space origin moves between two poses
+fn pose_stray(a: Pose, b: Pose, reach: f32) -> f32 {
+    let angle = 2.0 * acos(min(abs(dot(a.rotation, b.rotation)), 1.0));
+    let scale = max(abs(a.scale.x), max(abs(a.scale.y), abs(a.scale.z)));
+    return length(b.translation - a.translation) + length(b.scale - a.scale) * reach + angle * scale * reach;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= level.count) {
+        return;
+    }
+
+    let index = refit_nodes[level.start + id.x];
+    let node = bvh_buffer.nodes[index];
+    var aabb = empty_aabb();
+    if (node.count > 0) {
+        for (var i = node.first; i < node.first + node.count; i++) {
+            if (u32(index) < level.tlas_nodes) {
+                aabb = merge(aabb, instance_bounds(instance_buffer.data[i]));
+            } else {
+                aabb = merge(aabb, object_bounds(object_buffer.data[i]));
+            }
+        }
+    } else {
+        // The right child is where the left one goes on a miss
+        let left = bvh_buffer.nodes[node.left];
+        aabb = merge(left.aabb, bvh_buffer.nodes[left.miss].aabb);
+    }
+    bvh_buffer.nodes[index].aabb = aabb;
+}
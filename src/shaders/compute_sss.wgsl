@@ -21,7 +21,7 @@ fn main(@builtin(global_invocation_id) global_idx: vec3<u32>) {
 
 fn sample_sss(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
     var pixel_color = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
     let inv_spp = 1.0 / f32(spp);
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
@@ -29,7 +29,7 @@ fn sample_sss(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_
         let rng = vec2<f32>(hash_u32(seed * scene.config.seed.x), hash_u32(seed * scene.config.seed.y));
 
         var color = vec4<f32>(1.0, 1.0, 1.0, 1.0);
-        var sample_ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var sample_ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
 
         let rec = hit_bvh(sample_ray);
         var is_metal = rec.material.metallic > rng.x;
@@ -107,7 +107,7 @@ fn randomwalk_step(hit: vec3<f32>, normal: vec3<f32>, rng: vec2<f32>, g: f32) ->
     let r = 2.0 * rng.x - 1.0;
     var cos_theta = r;
     if (g != 0.0) {
-        cos_theta = 1.0 / (2.0 * g) * (1 + g*g - pow((1 - g*g) / (1 + g*r), 2.0));
+        cos_theta = 1.0 / (2.0 * g) * (1.0 + g*g - pow((1.0 - g*g) / (1.0 + g*r), 2.0));
     };
     let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
 
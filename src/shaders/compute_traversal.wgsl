@@ -21,7 +21,7 @@ fn main(@builtin(global_invocation_id) global_ix: vec3<u32>) {
 
 fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
     var outColor = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
         let seed = sample_idx * global_idx + sample_idx + 999u * global_idx;
@@ -34,7 +34,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
         var directDiffColor = compositeColor;
         var skyColor = vec4<f32>(0.0, 0.0, 0.0, 1.0);
 
-        var directSpecRay = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var directSpecRay = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
         var indirectSpecRay = directSpecRay;
         var directDiffRay = directSpecRay;
         var indirectDiffRay = directSpecRay;
@@ -74,7 +74,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
                     f0
                 );
                 directSpecColor = specular_color * ggxDirect.weight * light_sample.color;
-                directSpecRay = Ray(directSpecRec.p, light_sample.dir);                
+                directSpecRay = spawn_ray(directSpecRec, light_sample.dir);                
             } else if (directSpecActive) {
                 if (depth == max_depth) {
                     directSpecColor *= 0.0;
@@ -104,7 +104,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
                     rng
                 );    
                 indirectSpecColor *= specular_color * ggxIndirect.weight;
-                indirectSpecRay = Ray(indirectSpecRec.p, reflect(indirectSpecRay.direction, ggxIndirect.direction));
+                indirectSpecRay = spawn_ray(indirectSpecRec, reflect(indirectSpecRay.direction, ggxIndirect.direction));
             } else if (indirectSpecActive) {
                 let skySample = sample_sky(indirectSpecRay.direction, scene.config.sky_intensity);
                 if (depth == max_depth) {
@@ -121,7 +121,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
             if (directDiffRec.t > 0.0 && !is_metal) { 
                 let light_sample = sample_quad_light(directDiffRec, rng);             
                 directDiffColor *= directDiffRec.material.diffuse * light_sample.color;  
-                directDiffRay = Ray(directDiffRec.p, cosine_weighted_hemisphere(directDiffRec, rng).dir);   
+                directDiffRay = spawn_ray(directDiffRec, cosine_weighted_hemisphere(directDiffRec, rng).dir);   
             } else if (directDiffActive) {
                 if (depth == max_depth) {
                     directDiffColor *= 0.0;
@@ -133,7 +133,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
             is_metal = indirectDiffRec.material.metallic > rng.x;
             if (indirectDiffRec.t > 0.0 && !is_metal && indirectDiffActive) {
                 indirectDiffColor *= indirectDiffRec.material.diffuse;
-                indirectDiffRay = Ray(indirectDiffRec.p, cosine_weighted_hemisphere(indirectDiffRec, rng).dir);                   
+                indirectDiffRay = spawn_ray(indirectDiffRec, cosine_weighted_hemisphere(indirectDiffRec, rng).dir);                   
             } else if (indirectDiffActive) {
                 if (depth == max_depth) {
                     indirectDiffColor *= 0.0;
@@ -153,7 +153,7 @@ fn sample_scene(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, globa
 
 fn sample_scene_alt(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
     var outColor = vec4<f32>(0.0, 0.0, 0.0, 1.0);
-    let focus_distance: f32 = length(ray.origin);
+    let focus_distance: f32 = scene.camera.focus_distance;
 
     for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
         let seed = sample_idx * global_idx + sample_idx + 999u * global_idx;
@@ -163,7 +163,7 @@ fn sample_scene_alt(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, g
         var rayColor = compositeColor;
         var skyColor = vec4<f32>(0.0, 0.0, 0.0, 1.0);
 
-        var ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count);
+        var ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
         var rayActive = true;
         var primary_hit_metal = false;   
 
@@ -191,14 +191,14 @@ fn sample_scene_alt(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, g
                     f0
                 );
                 rayColor = color * ggx.weight * light_sample.color;
-                ray = Ray(rec.p, light_sample.dir);                
+                ray = spawn_ray(rec, light_sample.dir);                
 
 
                 // Direct diffuse
                 if (!is_metal) { 
                     let light_sample = sample_quad_light(rec, rng);             
                     rayColor += rec.material.diffuse * light_sample.color;  
-                    ray = Ray(rec.p, cosine_weighted_hemisphere(rec, rng).dir);   
+                    ray = spawn_ray(rec, cosine_weighted_hemisphere(rec, rng).dir);   
                 }
 
                 // Indirect lighting
@@ -210,12 +210,12 @@ fn sample_scene_alt(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, g
                     rng
                 );    
                 rayColor += color * ggxIndirect.weight;
-                ray = Ray(rec.p, reflect(ray.direction, ggxIndirect.direction));
+                ray = spawn_ray(rec, reflect(ray.direction, ggxIndirect.direction));
 
                 // Indirect diffuse
                 if (!is_metal) {
                     rayColor += rec.material.diffuse;
-                    ray = Ray(rec.p, cosine_weighted_hemisphere(rec, rng).dir);                   
+                    ray = spawn_ray(rec, cosine_weighted_hemisphere(rec, rng).dir);                   
                 }
             } else if (rayActive) {
                 if (depth == max_depth) {
@@ -0,0 +1,161 @@
+// Participating media compute shader
+@group(0) @binding(10) var output_tex: texture_storage_2d<rgba8unorm, write>;
+
+@compute @workgroup_size(16, 16)
+fn main(@builtin(global_invocation_id) global_idx: vec3<u32>) {
+    let idx = global_idx.xy;
+    let ray_idx = idx.y * scene.config.size.x + idx.x;
+    let ray = rays.data[ray_idx];
+    let pixel_color = sample_volume(
+        ray,
+        scene.config.max_depth,
+        scene.config.samples,
+        scene.config.pixel_size,
+        ray_idx
+        );
+
+    // TODO: investigate coord system mismatch
+    let flipped_idx = vec2<i32>(i32(scene.config.size.x) - i32(idx.x) - 1, i32(idx.y));
+    textureStore(output_tex, flipped_idx, pixel_color);
+}
+
+// In-scattered radiance in rgb, primary ray transmittance in alpha for compositing
+fn sample_volume(ray: Ray, max_depth: u32, spp: u32, pixel_size: vec2<f32>, global_idx: u32) -> vec4<f32> {
+    if (scene.media.count == 0u) {
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    var transmittance = vec3<f32>(0.0, 0.0, 0.0);
+    let focus_distance: f32 = scene.camera.focus_distance;
+    let inv_spp = 1.0 / f32(spp);
+
+    for (var sample_idx = 0u; sample_idx < spp; sample_idx = sample_idx + 1u) {
+        let seed = sample_idx * global_idx + sample_idx + 999u * global_idx;
+        let rng = vec2<f32>(hash_u32(seed * scene.config.seed.x), hash_u32(seed * scene.config.seed.y));
+        var state = random_state(rng);
+
+        let sample_ray = get_strat_offset_ray(ray, pixel_size, focus_distance, rng, scene.config.count, seed);
+        transmittance += medium_transmittance(sample_ray, 1e30, &state) * inv_spp;
+        radiance += delta_tracking(sample_ray, max_depth, &state) * inv_spp;
+    }
+
+    let alpha = (transmittance.x + transmittance.y + transmittance.z) / 3.0;
+    return vec4<f32>(radiance, alpha);
+}
+
+fn delta_tracking(primary_ray: Ray, max_depth: u32, state: ptr<function, u32>) -> vec3<f32> {
+    var radiance = vec3<f32>(0.0, 0.0, 0.0);
+    var throughput = vec3<f32>(1.0, 1.0, 1.0);
+    var ray = primary_ray;
+    var depth = 0u;
+    var crossings = 0u;
+
+    while (depth < max_depth && crossings < MAX_MEDIUM_BOUNDARIES) {
+        let segment = next_medium_segment(ray);
+        let majorant = medium_majorant(segment.medium);
+        var scattered = false;
+
+        if (majorant > 0.0) {
+            var t = 0.0;
+            for (var i = 0u; i < MAX_TRACKING_STEPS; i = i + 1u) {
+                t -= log(1.0 - random_next(state)) / majorant;
+                if (t >= segment.t_max) {
+                    break;
+                }
+
+                let p = point_at(ray, t);
+                let sigma_t = medium_extinction(segment.medium, p);
+                let sigma_t_avg = (sigma_t.x + sigma_t.y + sigma_t.z) / 3.0;
+                let p_real = sigma_t_avg / majorant;
+
+                if (random_next(state) < p_real) {
+                    // Real collision, weighted by the per-channel scattering albedo
+                    throughput *= medium_scattering(segment.medium, p) / sigma_t_avg;
+                    let g = medium_anisotropy(segment.medium);
+                    radiance += throughput * sample_quad_light_medium(p, ray.direction, g, state);
+                    let rng = vec2<f32>(random_next(state), random_next(state));
+                    ray = new_ray(p, sample_hg(ray.direction, g, rng));
+                    scattered = true;
+                    break;
+                }
+
+                // Null collision, corrects the channels that disagree with the average
+                throughput *= (vec3<f32>(majorant) - sigma_t) / max(majorant - sigma_t_avg, EPSILON);
+            }
+        }
+
+        if (scattered) {
+            depth += 1u;
+
+            // Russian roulette on low throughput paths
+            let max_throughput = max(max(throughput.x, throughput.y), throughput.z);
+            if (max_throughput < 0.1) {
+                if (random_next(state) < 0.5) {
+                    break;
+                }
+                throughput *= 2.0;
+            }
+            continue;
+        }
+
+        // Pass straight through medium boundaries
+        if (segment.boundary.t > 0.0 && segment.boundary.material.medium >= 0) {
+            ray = spawn_ray(segment.boundary, ray.direction);
+            crossings += 1u;
+            continue;
+        }
+
+        // Scattered paths that escape pick up the sky, opaque surfaces are left to the surface passes
+        if (segment.boundary.t < 0.0 && depth > 0u) {
+            radiance += throughput * sample_sky(ray.direction, scene.config.sky_intensity).xyz;
+        }
+        break;
+    }
+    return radiance;
+}
+
+fn sample_quad_light_medium(p: vec3<f32>, direction: vec3<f32>, g: f32, state: ptr<function, u32>) -> vec3<f32> {
+    let light = quad_light_buffer.data[0];
+    let rng = vec2<f32>(random_next(state), random_next(state));
+    let sample = random_on_quad(light.position.xyz, light.normal.xyz, light.u.xyz, light.v.xyz, rng);
+    let dist = length(sample - p);
+    let to_light = (sample - p) / dist;
+    // Scatter points are inside the medium, away from any surface that would need an offset
+    let ray = Ray(p, 0.0, to_light, dist, path_time);
+    if (occluded(ray)) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let transmittance = medium_transmittance(ray, dist, state);
+    let cos_light = abs(dot(light.normal.xyz, to_light));
+    let phase = phase_hg(g, dot(direction, to_light));
+    return light.color * light.intensity * transmittance * phase * cos_light / (dist * dist);
+}
+
+// Henyey-Greenstein phase function
+fn phase_hg(g: f32, cos_theta: f32) -> f32 {
+    let denom = 1.0 + g * g - 2.0 * g * cos_theta;
+    return (1.0 - g * g) / (4.0 * PI * denom * sqrt(denom));
+}
+
+// Importance samples the Henyey-Greenstein lobe around the propagation direction
+fn sample_hg(direction: vec3<f32>, g: f32, rng: vec2<f32>) -> vec3<f32> {
+    let r = 2.0 * rng.x - 1.0;
+    var cos_theta = r;
+    if (abs(g) > 1e-3) {
+        cos_theta = 1.0 / (2.0 * g) * (1.0 + g * g - pow((1.0 - g * g) / (1.0 + g * r), 2.0));
+    };
+    cos_theta = clamp(cos_theta, -1.0, 1.0);
+    let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = TWO_PI * rng.y;
+
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(direction.y) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, direction));
+    let bitangent = cross(direction, tangent);
+
+    return normalize(tangent * (sin_theta * cos(phi)) + bitangent * (sin_theta * sin(phi)) + direction * cos_theta);
+}
@@ -1,4 +1,20 @@
 // INTERSECTIONS
+fn hit_object(object: SceneObject, ray: Ray) -> HitRec {
+    if (object.object_type == SPHERE_TYPE) {
+        let v = vertex_buffer.data[object.indices.x].position;
+        return hit_sphere(Sphere(v.xyz, v.w, material_buffer.data[object.material]), ray);
+    } else if (object.object_type == PLANE_TYPE) {
+        return hit_quad(object, ray);
+    } else if (object.object_type == DISK_TYPE) {
+        return hit_disk(object, ray);
+    } else if (object.object_type == SDF_TYPE) {
+        return hit_sdf(object, ray);
+    } else if (WATERTIGHT_TRIANGLES) {
+        return hit_triangle_watertight(object, ray);
+    }
+    return hit_triangle(object, ray);
+}
+
 fn hit_sphere(sphere: Sphere, ray: Ray) -> HitRec {
     let oc: vec3<f32> = ray.origin - sphere.center;
     let a: f32 = dot(ray.direction, ray.direction);
@@ -7,22 +23,226 @@ fn hit_sphere(sphere: Sphere, ray: Ray) -> HitRec {
     let discriminant: f32 = b * b - 4.0 * a * c;
 
     if discriminant >= 0.0 {
-        let t: f32 = (-b - sqrt(discriminant)) / (2.0 * a);
-        if t > 0.0 {
+        var t: f32 = (-b - sqrt(discriminant)) / (2.0 * a);
+
+        // Far root when the near one is outside the interval, the ray starts inside
+        if (t <= ray.t_min) {
+            t = (-b + sqrt(discriminant)) / (2.0 * a);
+        }
+        if (t > ray.t_min && t < ray.t_max) {
             let p: vec3<f32> = point_at(ray, t);
-            let normal: vec3<f32> = (p - sphere.center) / sphere.radius;
+            var normal: vec3<f32> = (p - sphere.center) / sphere.radius;
             let frontface = dot(ray.direction, normal) < 0.0;
-            return HitRec(t, p, normal, sphere.material, frontface);
+            if (!frontface) {
+                normal = -normal;
+            }
+            return HitRec(t, p, normal, normal, sphere.material, frontface);
         }    
     }
 
     return NULL_HIT;
 }
 
-fn hit_triangle(triangle: Triangle, ray: Ray) -> HitRec {
-    let a = vertex_buffer.data[triangle.indices.x].xyz;
-    let b = vertex_buffer.data[triangle.indices.y].xyz;
-    let c = vertex_buffer.data[triangle.indices.z].xyz;
+// Parallelogram spanned by u = b - a and v = c - a
+fn hit_quad(object: SceneObject, ray: Ray) -> HitRec {
+    let q = vertex_buffer.data[object.indices.x].position.xyz;
+    let u = vertex_buffer.data[object.indices.y].position.xyz - q;
+    let v = vertex_buffer.data[object.indices.z].position.xyz - q;
+    let n = cross(u, v);
+    let denom = dot(n, ray.direction);
+
+    if (abs(denom) < EPSILON * EPSILON) {
+        return NULL_HIT;
+    }
+
+    let t = dot(n, q - ray.origin) / denom;
+    if (t <= ray.t_min || t >= ray.t_max) {
+        return NULL_HIT;
+    }
+
+    let p = point_at(ray, t);
+    let w = n / dot(n, n);
+    let planar = p - q;
+    let alpha = dot(w, cross(planar, v));
+    let beta = dot(w, cross(u, planar));
+    if (alpha < 0.0 || alpha > 1.0 || beta < 0.0 || beta > 1.0) {
+        return NULL_HIT;
+    }
+
+    var normal = normalize(n);
+    let frontface = dot(ray.direction, normal) < 0.0;
+    if (!frontface) {
+        normal = -normal;
+    }
+    return HitRec(t, p, normal, normal, material_buffer.data[object.material], frontface);
+}
+
+fn hit_disk(object: SceneObject, ray: Ray) -> HitRec {
+    let vertex = vertex_buffer.data[object.indices.x];
+    let center = vertex.position.xyz;
+    let radius = vertex.position.w;
+    let n = vertex.normal.xyz;
+    let denom = dot(n, ray.direction);
+
+    if (abs(denom) < EPSILON * EPSILON) {
+        return NULL_HIT;
+    }
+
+    let t = dot(n, center - ray.origin) / denom;
+    if (t <= ray.t_min || t >= ray.t_max) {
+        return NULL_HIT;
+    }
+
+    let p = point_at(ray, t);
+    let d = p - center;
+    if (dot(d, d) > radius * radius) {
+        return NULL_HIT;
+    }
+
+    var normal = n;
+    let frontface = denom < 0.0;
+    if (!frontface) {
+        normal = -normal;
+    }
+    return HitRec(t, p, normal, normal, material_buffer.data[object.material], frontface);
+}
+
+// Sphere traces the field between where the ray enters and leaves the object bounds. Steps never
+// shrink below a fraction of the bounds so rays grazing the surface still finish, a change of sign
+// between two steps is a crossing and is bisected down to the surface. Rays starting inside find
+// where they leave.
+fn hit_sdf(object: SceneObject, ray: Ray) -> HitRec {
+    let header = vertex_buffer.data[object.indices.x];
+    let bounds = AABB(vec4<f32>(header.position.xyz, 0.0), vec4<f32>(header.normal.xyz, 0.0));
+    let t_enter = distance_to_aabb(ray, bounds);
+    if (t_enter < 0.0) {
+        return NULL_HIT;
+    }
+    let t_exit = min(exit_aabb(ray, bounds), ray.t_max);
+
+    // The direction can be unnormalized in object space, distances are divided by its length
+    let speed = length(ray.direction);
+    let min_step = SDF_MIN_STEP * length(bounds.max.xyz - bounds.min.xyz);
+    var t = max(t_enter, ray.t_min);
+    var d = sdf_distance(object, point_at(ray, t));
+    let side = select(-1.0, 1.0, d >= 0.0);
+
+    for (var n = 0u; n < MAX_SDF_STEPS && t < t_exit; n++) {
+        let t_previous = t;
+        t = min(t + max(abs(d), min_step) / speed, t_exit);
+        d = sdf_distance(object, point_at(ray, t));
+        if (d * side > 0.0) {
+            continue;
+        }
+
+        var lo = t_previous;
+        var hi = t;
+        for (var i = 0u; i < SDF_REFINE_STEPS; i++) {
+            let mid = 0.5 * (lo + hi);
+            if (sdf_distance(object, point_at(ray, mid)) * side > 0.0) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        if (hi <= ray.t_min || hi >= ray.t_max) {
+            return NULL_HIT;
+        }
+
+        let p = point_at(ray, hi);
+        var normal = sdf_normal(object, p, 0.1 * min_step);
+        let frontface = dot(ray.direction, normal) < 0.0;
+        if (!frontface) {
+            normal = -normal;
+        }
+        return HitRec(hi, p, normal, normal, material_buffer.data[object.material], frontface);
+    }
+    return NULL_HIT;
+}
+
+// Nodes joined in order, each smoothly over its blend radius
+fn sdf_distance(object: SceneObject, p: vec3<f32>) -> f32 {
+    var d = RAY_T_MAX;
+    for (var i = object.indices.y; i < object.indices.y + object.indices.z; i += 2u) {
+        let node = vertex_buffer.data[i];
+        let shape = sdf_shape(node, vertex_buffer.data[i + 1u], p);
+        d = smooth_min(d, shape, node.normal.w);
+    }
+    return d;
+}
+
+// Polynomial smooth minimum, the plain minimum for a blend of zero
+fn smooth_min(a: f32, b: f32, k: f32) -> f32 {
+    if (k <= 0.0) {
+        return min(a, b);
+    }
+    let h = max(k - abs(a - b), 0.0) / k;
+    return min(a, b) - h * h * k * 0.25;
+}
+
+fn sdf_shape(node: Vertex, scalars: Vertex, p: vec3<f32>) -> f32 {
+    let shape = u32(node.position.w);
+    let a = node.position.xyz;
+    let b = node.normal.xyz;
+    if (shape == SDF_SPHERE) {
+        return length(p - a) - scalars.position.x;
+    } else if (shape == SDF_BOX) {
+        let q = abs(p - a) - b;
+        return length(max(q, vec3<f32>(0.0))) + min(max(q.x, max(q.y, q.z)), 0.0) - scalars.position.x;
+    } else if (shape == SDF_TORUS) {
+        let q = p - a;
+        return length(vec2<f32>(length(q.xz) - scalars.position.x, q.y)) - scalars.position.y;
+    } else if (shape == SDF_CAPSULE) {
+        let pa = p - a;
+        let ba = b - a;
+        let h = clamp(dot(pa, ba) / max(dot(ba, ba), EPSILON * EPSILON), 0.0, 1.0);
+        return length(pa - ba * h) - scalars.position.x;
+    } else if (shape == SDF_GRID) {
+        return sdf_grid(p, a, b, scalars.position.xyz, scalars.normal.xyz);
+    }
+    // Unknown kinds are never hit
+    return RAY_T_MAX;
+}
+
+// Trilinear between voxel centers like Sdf::distance. Outside the grid bounds both the distance to
+// them and the field at the nearest point less that distance bound the true distance from below.
+fn sdf_grid(p: vec3<f32>, lo: vec3<f32>, hi: vec3<f32>, atlas: vec3<f32>, resolution: vec3<f32>) -> f32 {
+    let q = clamp(p, lo, hi);
+    let g = clamp((q - lo) / (hi - lo) * resolution - 0.5, vec3<f32>(0.0), resolution - 1.0);
+    let cell = min(vec3<i32>(g), max(vec3<i32>(resolution) - 2, vec3<i32>(0)));
+    let f = g - vec3<f32>(cell);
+    let last = vec3<i32>(atlas + resolution) - 1;
+
+    var value = 0.0;
+    for (var corner = 0u; corner < 8u; corner++) {
+        let offset = vec3<u32>(corner & 1u, (corner >> 1u) & 1u, (corner >> 2u) & 1u);
+        let w = select(vec3<f32>(1.0) - f, f, offset == vec3<u32>(1u));
+        let texel = min(vec3<i32>(atlas) + cell + vec3<i32>(offset), last);
+        value += w.x * w.y * w.z * textureLoad(t_sdf, texel, 0).r;
+    }
+
+    let outside = length(p - q);
+    if (outside > 0.0) {
+        return max(outside, value - outside);
+    }
+    return value;
+}
+
+// Gradient of the field from four samples on a tetrahedron around p
+fn sdf_normal(object: SceneObject, p: vec3<f32>, h: f32) -> vec3<f32> {
+    let k = vec2<f32>(1.0, -1.0);
+    return normalize(
+        k.xyy * sdf_distance(object, p + k.xyy * h) +
+        k.yyx * sdf_distance(object, p + k.yyx * h) +
+        k.yxy * sdf_distance(object, p + k.yxy * h) +
+        k.xxx * sdf_distance(object, p + k.xxx * h)
+    );
+}
+
+fn hit_triangle(triangle: SceneObject, ray: Ray) -> HitRec {
+    let a = vertex_buffer.data[triangle.indices.x].position.xyz;
+    let b = vertex_buffer.data[triangle.indices.y].position.xyz;
+    let c = vertex_buffer.data[triangle.indices.z].position.xyz;
     let e1: vec3<f32> = b - a;
     let e2: vec3<f32> = c - a;
     let p: vec3<f32> = cross(ray.direction, e2);
@@ -49,23 +269,115 @@ fn hit_triangle(triangle: Triangle, ray: Ray) -> HitRec {
 
     let t = dot(e2, q) * inv_det;
     
-    if (t > EPSILON) {
-        let p: vec3<f32> = point_at(ray, t);
-        let na = normal_buffer.data[triangle.indices.x].xyz;
-        let nb = normal_buffer.data[triangle.indices.y].xyz;
-        let nc = normal_buffer.data[triangle.indices.z].xyz;
-        var normal: vec3<f32> = normalize((1.0 - u - v) * na + u * nb + v * nc);
-        let frontface = dot(ray.direction, normal) < 0.0;
-        if (!frontface) {
-            normal = -normal;
-        }
-        return HitRec(t, p, normal, material_buffer.data[triangle.material], frontface);
+    if (t > ray.t_min && t < ray.t_max) {
+        return triangle_hit(triangle, ray, t, vec3<f32>(1.0 - u - v, u, v));
     }
     else {
         return NULL_HIT;
     }
 }
 
+// Woop, Benthin and Wald 2013. The vertices are sheared so the ray runs along +z, then the edge
+// functions are 2D cross products of vertex pairs. Triangles sharing an edge evaluate it from
+// the same two vertices, so a ray through the edge can't miss both.
+fn hit_triangle_watertight(triangle: SceneObject, ray: Ray) -> HitRec {
+    let d = ray.direction;
+    let abs_d = abs(d);
+    var kz = 2;
+    if (abs_d.x > abs_d.y && abs_d.x > abs_d.z) {
+        kz = 0;
+    } else if (abs_d.y > abs_d.z) {
+        kz = 1;
+    }
+    var kx = (kz + 1) % 3;
+    var ky = (kz + 2) % 3;
+    if (d[kz] < 0.0) {
+        let swap = kx;
+        kx = ky;
+        ky = swap;
+    }
+    let shear = vec3<f32>(d[kx] / d[kz], d[ky] / d[kz], 1.0 / d[kz]);
+
+    let a = vertex_buffer.data[triangle.indices.x].position.xyz - ray.origin;
+    let b = vertex_buffer.data[triangle.indices.y].position.xyz - ray.origin;
+    let c = vertex_buffer.data[triangle.indices.z].position.xyz - ray.origin;
+    let ax = a[kx] - shear.x * a[kz];
+    let ay = a[ky] - shear.y * a[kz];
+    let bx = b[kx] - shear.x * b[kz];
+    let by = b[ky] - shear.y * b[kz];
+    let cx = c[kx] - shear.x * c[kz];
+    let cy = c[ky] - shear.y * c[kz];
+
+    var u = cx * by - cy * bx;
+    var v = ax * cy - ay * cx;
+    var w = bx * ay - by * ax;
+    // Edges through the ray are redone exactly, as hit_watertight in triangle.rs
+    if (u == 0.0 || v == 0.0 || w == 0.0) {
+        u = difference_of_products(cx, by, cy, bx);
+        v = difference_of_products(ax, cy, ay, cx);
+        w = difference_of_products(bx, ay, by, ax);
+    }
+    if ((u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0)) {
+        return NULL_HIT;
+    }
+    let det = u + v + w;
+    if (det == 0.0) {
+        return NULL_HIT;
+    }
+
+    let t = (u * shear.z * a[kz] + v * shear.z * b[kz] + w * shear.z * c[kz]) / det;
+    if (t > ray.t_min && t < ray.t_max) {
+        return triangle_hit(triangle, ray, t, vec3<f32>(u, v, w) / det);
+    }
+    return NULL_HIT;
+}
+
+// a * b - c * d from the products split into value and error after Dekker. The split and the
+// partial products are exact even when fused into FMAs, but summing the errors still relies on
+// the order written here, so drivers that reassociate floats can lose the sign of near zero
+// results. The CPU version in triangle.rs is the reference.
+fn difference_of_products(a: f32, b: f32, c: f32, d: f32) -> f32 {
+    let ab = two_product(a, b);
+    let cd = two_product(c, d);
+    return (ab.x - cd.x) + (ab.y - cd.y);
+}
+
+// Rounded product and its error
+fn two_product(a: f32, b: f32) -> vec2<f32> {
+    let product = a * b;
+    let a_split = split_f32(a);
+    let b_split = split_f32(b);
+    let error = ((a_split.x * b_split.x - product) + a_split.x * b_split.y + a_split.y * b_split.x) + a_split.y * b_split.y;
+    return vec2<f32>(product, error);
+}
+
+// Halves of 12 significant bits whose products are exact, masked off the bits rather than
+// Veltkamp's 4097 * a, which FMA contraction breaks
+fn split_f32(a: f32) -> vec2<f32> {
+    let hi = bitcast<f32>(bitcast<u32>(a) & 0xfffff000u);
+    return vec2<f32>(hi, a - hi);
+}
+
+// Hit record at barycentrics `bary` of the corners
+fn triangle_hit(triangle: SceneObject, ray: Ray, t: f32, bary: vec3<f32>) -> HitRec {
+    let a = vertex_buffer.data[triangle.indices.x];
+    let b = vertex_buffer.data[triangle.indices.y];
+    let c = vertex_buffer.data[triangle.indices.z];
+
+    // Interpolated rather than o + t * d, the error then doesn't grow with t
+    let p = bary.x * a.position.xyz + bary.y * b.position.xyz + bary.z * c.position.xyz;
+    var normal = normalize(bary.x * a.normal.xyz + bary.y * b.normal.xyz + bary.z * c.normal.xyz);
+    let frontface = dot(ray.direction, normal) < 0.0;
+    if (!frontface) {
+        normal = -normal;
+    }
+    var geometric_normal = normalize(cross(b.position.xyz - a.position.xyz, c.position.xyz - a.position.xyz));
+    if (dot(ray.direction, geometric_normal) > 0.0) {
+        geometric_normal = -geometric_normal;
+    }
+    return HitRec(t, p, normal, geometric_normal, material_buffer.data[triangle.material], frontface);
+}
+
 fn hit_aabb(ray: Ray, box: AABB) -> bool {
     let inv_direction = vec3<f32>(1.0) / ray.direction;
     let t1 = (box.min.xyz - ray.origin) * inv_direction;
@@ -80,6 +392,23 @@ fn hit_aabb(ray: Ray, box: AABB) -> bool {
     return tmax_min >= tmin_max && tmax_min >= 0.0;
 }
 
+fn exit_aabb(ray: Ray, box: AABB) -> f32 {
+    let inv_direction = vec3<f32>(1.0) / ray.direction;
+    let t1 = (box.min.xyz - ray.origin) * inv_direction;
+    let t2 = (box.max.xyz - ray.origin) * inv_direction;
+
+    let tmin = min(t1, t2);
+    let tmax = max(t1, t2);
+
+    let tmin_max = max(max(tmin.x, tmin.y), tmin.z);
+    let tmax_min = min(min(tmax.x, tmax.y), tmax.z);
+
+    if (tmax_min < max(tmin_max, 0.0)) {
+        return 0.0;
+    }
+    return tmax_min;
+}
+
 fn distance_to_aabb(ray: Ray, box: AABB) -> f32 {
     let inv_direction = vec3<f32>(1.0) / ray.direction;
     let t1 = (box.min.xyz - ray.origin) * inv_direction;
@@ -91,108 +420,139 @@ fn distance_to_aabb(ray: Ray, box: AABB) -> f32 {
     let tmin_max = max(max(tmin.x, tmin.y), tmin.z);
     let tmax_min = min(min(tmax.x, tmax.y), tmax.z);
 
-    if (tmax_min < 0.0) {
+    if (tmax_min < 0.0 || tmax_min < tmin_max) {
         return -1.0;
     } else {
         return max(0.0, tmin_max);
     }
 }
 
+// hit_bvh_masked, hit_blas and occluded come from bvh_binary.wgsl or bvh_wide.wgsl
 fn hit_bvh(ray: Ray) -> HitRec {
-    var rec: HitRec = NULL_HIT;
-    var stack: array<i32, 128>;
-    var stack_top: i32 = 0;
-    stack[stack_top] = bvh_buffer.root;
-
-    while (stack_top >= 0) {
-        let node_index: i32 = stack[stack_top];
-        stack_top -= 1;
-
-        let node = bvh_buffer.nodes[node_index];
-
-        if (!hit_aabb(ray, node.aabb)) {
-            continue;
-        }
-
-        while (node.triangle < 0) {
-            // Branch node
-            let left_node = bvh_buffer.nodes[node.left];
-            let right_node = bvh_buffer.nodes[node.right];
-            let left_t = distance_to_aabb(ray, left_node.aabb);
-            let right_t = distance_to_aabb(ray, right_node.aabb);
+    return hit_bvh_masked(ray, false);
+}
 
-            // Both children are behind the ray
-            if (left_t < 0.0 && right_t < 0.0) {
-                break;
-            }
+// Moves a surface point off the surface along n by a fixed number of float steps, which grows
+// with the magnitude of p like its rounding error. Near the origin a small absolute offset is
+// used instead. From Waechter and Binder, Ray Tracing Gems chapter 6.
+fn offset_ray_origin(p: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    let of_i = vec3<i32>(256.0 * n);
+    let p_i = bitcast<vec3<f32>>(bitcast<vec3<i32>>(p) + select(of_i, -of_i, p < vec3<f32>(0.0)));
+    return select(p_i, p + n / 65536.0, abs(p) < vec3<f32>(1.0 / 32.0));
+}
 
-            // Prioritize closest
-            if (left_t >= 0.0 && right_t >= 0.0) {
-                if (left_t < right_t) {
-                    stack_top += 1;
-                    stack[stack_top] = node.right;
-                    stack_top += 1;
-                    stack[stack_top] = node.left;
-                } else {
-                    stack_top += 1;
-                    stack[stack_top] = node.left;
-                    stack_top += 1;
-                    stack[stack_top] = node.right;
-                }
-            } else {
-                // Only add
-                if (left_t >= 0.0) {
-                    stack_top += 1;
-                    stack[stack_top] = node.left;
-                } else {
-                    stack_top += 1;
-                    stack[stack_top] = node.right;
-                }
-            }
+// Shutter time of the path being traced, picked with its camera ray and kept by every ray spawned
+// along it
+var<private> path_time: f32 = 0.0;
 
-            break;
-        }
+fn new_ray(origin: vec3<f32>, direction: vec3<f32>) -> Ray {
+    return Ray(origin, 0.0, direction, RAY_T_MAX, path_time);
+}
 
-        if (node.triangle >= 0) {
-            // Leaf node
-            let hit: HitRec = hit_triangle(triangle_buffer.data[node.triangle], ray);
-            if (hit.t > 0.0 && (rec.t < 0.0 || hit.t < rec.t)) {
-                rec = hit;
-            }
-        }
-    }
-    return rec;
+// Leaves the surface of rec on the side the direction points to
+fn spawn_ray(rec: HitRec, direction: vec3<f32>) -> Ray {
+    let n = select(rec.geometric_normal, -rec.geometric_normal, dot(direction, rec.geometric_normal) < 0.0);
+    return new_ray(offset_ray_origin(rec.p, n), direction);
 }
 
+// Segment from the surface of rec to a point on another surface, both ends offset
+fn spawn_ray_to(rec: HitRec, end_point: vec3<f32>, end_normal: vec3<f32>) -> Ray {
+    let to_end = end_point - rec.p;
+    let n = select(rec.geometric_normal, -rec.geometric_normal, dot(to_end, rec.geometric_normal) < 0.0);
+    let origin = offset_ray_origin(rec.p, n);
+    let end = offset_ray_origin(end_point, select(end_normal, -end_normal, dot(to_end, end_normal) > 0.0));
+    let distance = length(end - origin);
+    return Ray(origin, 0.0, (end - origin) / distance, distance, path_time);
+}
 
-// CAMERA
 fn point_at(ray: Ray, t: f32) -> vec3<f32> {
     return ray.origin + ray.direction * t;
 }
 
-fn get_offset_ray(ray: Ray, pixel_size: vec2<f32>, focus_distance: f32, rng: vec2<f32>) -> Ray {
-    var aaOffset = vec3<f32>((rng.x - 0.5) * pixel_size.x, (rng.y - 0.5) * pixel_size.y, 0.0);
-    var aaDirection = normalize(ray.direction + aaOffset);
-    let dof = random_in_unit_disk(rng) * scene.camera.aperture;
-    let origin = ray.origin + dof;
-    let direction = normalize((aaDirection * focus_distance - dof));
+// Shutter time is a dimension of its own, drawn from the sample seed like the rng pair
+fn sample_time(seed: u32) -> f32 {
+    return hash_u32(seed * scene.config.seed.w);
+}
 
-    return Ray(origin, direction);
+fn get_offset_ray(ray: Ray, pixel_size: vec2<f32>, focus_distance: f32, rng: vec2<f32>, seed: u32) -> Ray {
+    path_time = sample_time(seed);
+    let jittered = jitter_ray(move_with_camera(ray), vec2<f32>((rng.x - 0.5) * pixel_size.x, (rng.y - 0.5) * pixel_size.y));
+    return thin_lens_ray(jittered.origin, jittered.direction, focus_distance, rng);
 }
 
-fn get_strat_offset_ray(ray: Ray, pixel_size: vec2<f32>, focus_distance: f32, rng: vec2<f32>, count: u32) -> Ray {
+fn get_strat_offset_ray(ray: Ray, pixel_size: vec2<f32>, focus_distance: f32, rng: vec2<f32>, count: u32, seed: u32) -> Ray {
     let grid_size = 4.0;
     let wrapped_count = count % u32(grid_size * grid_size);
     let grid_pos = vec2<f32>(f32(wrapped_count % u32(grid_size)), f32(wrapped_count / u32(grid_size)));
     let strat_rng = (grid_pos + rng) / grid_size;
 
-    var aa_offset = vec3<f32>((strat_rng.x - 0.5) * pixel_size.x, (strat_rng.y - 0.5) * pixel_size.y, 0.0);
-    var aa_direction = normalize(ray.direction + aa_offset);
-    var dof = random_in_unit_disk(rng) * scene.camera.aperture;
-    let origin = ray.origin + dof;
-    let direction = normalize((aa_direction * focus_distance - dof));
+    path_time = sample_time(seed);
+    let jittered = jitter_ray(move_with_camera(ray), vec2<f32>((strat_rng.x - 0.5) * pixel_size.x, (strat_rng.y - 0.5) * pixel_size.y));
+    return thin_lens_ray(jittered.origin, jittered.direction, focus_distance, rng);
+}
+
+// Moves the ray within its pixel by `offset` of the image. Orthographic rays are parallel, so
+// their origin moves across the view plane instead of their direction turning.
+fn jitter_ray(ray: Ray, offset: vec2<f32>) -> Ray {
+    if (scene.camera.projection == PROJECTION_ORTHOGRAPHIC) {
+        let basis = camera_basis(path_time);
+        let height = scene.camera.projection_scale;
+        return new_ray(ray.origin + (offset.x * scene.camera.aspect * basis[0] + offset.y * basis[1]) * height, ray.direction);
+    }
+    return new_ray(ray.origin, normalize(ray.direction + vec3<f32>(offset, 0.0)));
+}
+
+// Carries a camera ray, traced from where the camera is at shutter open, along with the camera to
+// where it is at `path_time`
+fn move_with_camera(ray: Ray) -> Ray {
+    let open = camera_basis(0.0);
+    let moved = camera_basis(path_time);
+    let origin = mix(scene.camera.origin.xyz, scene.camera.origin_close.xyz, path_time);
+    let local_origin = transpose(open) * (ray.origin - scene.camera.origin.xyz);
+    return new_ray(origin + moved * local_origin, moved * (transpose(open) * ray.direction));
+}
+
+// Right, up and forward of the camera at `time` in the shutter, as create_primary_ray
+fn camera_basis(time: f32) -> mat3x3<f32> {
+    let origin = mix(scene.camera.origin.xyz, scene.camera.origin_close.xyz, time);
+    let focus = mix(scene.camera.focus.xyz, scene.camera.focus_close.xyz, time);
+    let forward = normalize(focus - origin);
+    let world_up = select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(forward.y) >= 0.999);
+    let right = normalize(cross(world_up, forward));
+    return mat3x3<f32>(right, cross(forward, right), forward);
+}
+
+// Leaves a point of the lens, in the plane of the camera's right and up, toward where the pinhole
+// ray meets the plane of focus. Everything on that plane stays sharp. Panoramic projections have
+// no single plane of focus and stay pinholes.
+fn thin_lens_ray(origin: vec3<f32>, direction: vec3<f32>, focus_distance: f32, rng: vec2<f32>) -> Ray {
+    if (scene.camera.projection > PROJECTION_ORTHOGRAPHIC) {
+        return new_ray(origin, direction);
+    }
+    let basis = camera_basis(path_time);
+    let lens = sample_aperture(rng) * scene.camera.aperture;
+    let offset = lens.x * basis[0] + lens.y * basis[1];
+    let focal_point = direction * (focus_distance / dot(direction, basis[2]));
+    return new_ray(origin + offset, normalize(focal_point - offset));
+}
+
+// Uniform point on the unit disk, or on the regular polygon of the aperture blades inscribed in it
+fn sample_aperture(rng: vec2<f32>) -> vec2<f32> {
+    let blades = scene.camera.blades;
+    if (blades < 3u) {
+        return random_in_unit_disk(rng).xy;
+    }
 
-    return Ray(origin, direction);
+    // The blade is picked with the integer part and the fraction left over places the point along
+    // its edge, the square root spreads it evenly over the triangle to the center
+    let pick = hash_f32(rng.x) * f32(blades);
+    let blade = floor(pick);
+    let along = pick - blade;
+    let angle = TWO_PI / f32(blades);
+    let start = radians(scene.camera.blade_rotation) + blade * angle;
+    let a = vec2<f32>(cos(start), sin(start));
+    let b = vec2<f32>(cos(start + angle), sin(start + angle));
+    return sqrt(hash_f32(rng.y)) * mix(a, b, along);
 }
 
 // RANDOM
@@ -212,6 +572,16 @@ fn hash_u32(seed: u32) -> f32 {
     return f32(s) / 4294967295.0;
 }
 
+// Per-invocation random stream for estimators needing more than the shared rng pair
+fn random_state(rng: vec2<f32>) -> u32 {
+    return u32(rng.x * 16777215.0) ^ (u32(rng.y * 16777215.0) << 8u) ^ scene.config.seed.z;
+}
+
+fn random_next(state: ptr<function, u32>) -> f32 {
+    *state = *state * 747796405u + 2891336453u;
+    return hash_u32(*state);
+}
+
 fn random_unit_vector(rng: vec2<f32>) -> vec3<f32> {
     let r1: f32 = hash_f32(rng.x);
     let r2: f32 = hash_f32(rng.y);
@@ -250,9 +620,8 @@ fn schlick(cosine: f32, ior: f32) -> f32 {
 //     var occlusion = 0.0;
 //     for (var i = 0; i < samples; i = i + 1) {
 //         let sampleDir = cosine_weighted_hemisphere(rec, rng);
-//         let sampleRay = Ray(rec.p, sampleDir);
-//         let sampleRec = hit_bvh(sampleRay);
-//         if (sampleRec.t > 0.0) {
+//         let sampleRay = spawn_ray(rec, sampleDir);
+//         if (occluded(sampleRay)) {
 //             occlusion += 1.0;
 //         }
 //     }
@@ -287,17 +656,151 @@ fn sample_quad_light(rec: HitRec, rng: vec2<f32>) -> LightSample {
     let size = length(cross(light.u.xyz, light.v.xyz)); 
     let to_light = normalize(sample - rec.p);
     let cos = dot(rec.normal.xyz, to_light);
-    let ray = Ray(rec.p, to_light);
-    let light_rec = hit_bvh(ray);
-    if (light_rec.t > 0.0 && light_rec.t < dist){
+    let ray = spawn_ray_to(rec, sample, light.normal.xyz);
+    if (occluded(ray)) {
         return LightSample(vec4<f32>(0.0, 0.0, 0.0, 0.0), to_light);
     }
+    var rng_state = random_state(rng);
+    let transmittance = medium_transmittance(ray, ray.t_max, &rng_state);
     let pdf = dist2 / (size * abs(cos));
     let weight = max(dot(rec.normal.xyz, to_light), 0.0) / pdf;
-    let color = vec4<f32>(light.color * light.intensity / dist2, 0.0);
+    let color = vec4<f32>(light.color * light.intensity * transmittance / dist2, 0.0);
     return LightSample(color * weight, to_light);
 }
 
+
+// PARTICIPATING MEDIA
+fn medium_density(medium: Medium, p: vec3<f32>) -> f32 {
+    let local = (p - medium.bounds.min.xyz) / (medium.bounds.max.xyz - medium.bounds.min.xyz);
+    if (any(local < vec3<f32>(0.0)) || any(local > vec3<f32>(1.0))) {
+        return 0.0;
+    }
+    if (medium.grid < 0) {
+        return medium.density;
+    }
+    let uvw = medium.grid_origin.xyz + local * medium.grid_scale.xyz;
+    return medium.density * textureSampleLevel(t_density, s_density, uvw, 0.0).r;
+}
+
+// Extinction of the global fog plus the medium enclosed by the current boundary
+fn medium_extinction(medium_index: i32, p: vec3<f32>) -> vec3<f32> {
+    var sigma_t = vec3<f32>(0.0);
+    if (scene.media.fog >= 0) {
+        let fog = scene.media.media[scene.media.fog];
+        sigma_t += (fog.sigma_a.xyz + fog.sigma_s.xyz) * medium_density(fog, p);
+    }
+    if (medium_index >= 0) {
+        let medium = scene.media.media[medium_index];
+        sigma_t += (medium.sigma_a.xyz + medium.sigma_s.xyz) * medium_density(medium, p);
+    }
+    return sigma_t;
+}
+
+fn medium_scattering(medium_index: i32, p: vec3<f32>) -> vec3<f32> {
+    var sigma_s = vec3<f32>(0.0);
+    if (scene.media.fog >= 0) {
+        let fog = scene.media.media[scene.media.fog];
+        sigma_s += fog.sigma_s.xyz * medium_density(fog, p);
+    }
+    if (medium_index >= 0) {
+        let medium = scene.media.media[medium_index];
+        sigma_s += medium.sigma_s.xyz * medium_density(medium, p);
+    }
+    return sigma_s;
+}
+
+// Grid densities are normalized on upload, so `density` bounds the whole medium
+fn medium_majorant(medium_index: i32) -> f32 {
+    var majorant = 0.0;
+    if (scene.media.fog >= 0) {
+        let fog = scene.media.media[scene.media.fog];
+        let sigma_t = fog.sigma_a.xyz + fog.sigma_s.xyz;
+        majorant += max(max(sigma_t.x, sigma_t.y), sigma_t.z) * fog.density;
+    }
+    if (medium_index >= 0) {
+        let medium = scene.media.media[medium_index];
+        let sigma_t = medium.sigma_a.xyz + medium.sigma_s.xyz;
+        majorant += max(max(sigma_t.x, sigma_t.y), sigma_t.z) * medium.density;
+    }
+    return majorant;
+}
+
+fn medium_anisotropy(medium_index: i32) -> f32 {
+    if (medium_index >= 0) {
+        return scene.media.media[medium_index].anisotropy;
+    }
+    if (scene.media.fog >= 0) {
+        return scene.media.media[scene.media.fog].anisotropy;
+    }
+    return 0.0;
+}
+
+// Medium the ray travels through up to its next boundary or opaque surface,
+// assumes closed, non-nested boundary meshes: exiting through a backface means we were inside
+fn next_medium_segment(ray: Ray) -> MediumSegment {
+    let rec = hit_bvh_masked(ray, true);
+    var medium = -1;
+    var t_max = 0.0;
+    if (rec.t > 0.0) {
+        t_max = rec.t;
+        if (rec.material.medium >= 0 && !rec.frontface) {
+            medium = rec.material.medium;
+        }
+    } else if (scene.media.fog >= 0) {
+        t_max = exit_aabb(ray, scene.media.media[scene.media.fog].bounds);
+    }
+    return MediumSegment(medium, t_max, rec);
+}
+
+fn ratio_tracking(ray: Ray, t_max: f32, medium_index: i32, state: ptr<function, u32>) -> vec3<f32> {
+    var transmittance = vec3<f32>(1.0);
+    let majorant = medium_majorant(medium_index);
+    if (majorant <= 0.0) {
+        return transmittance;
+    }
+
+    var t = 0.0;
+    for (var i = 0u; i < MAX_TRACKING_STEPS; i = i + 1u) {
+        t -= log(1.0 - random_next(state)) / majorant;
+        if (t >= t_max) {
+            break;
+        }
+        let sigma_t = medium_extinction(medium_index, point_at(ray, t));
+        transmittance *= max(vec3<f32>(0.0), vec3<f32>(1.0) - sigma_t / majorant);
+
+        // Russian roulette once the estimate stops mattering
+        let max_transmittance = max(max(transmittance.x, transmittance.y), transmittance.z);
+        if (max_transmittance < 0.1) {
+            if (random_next(state) < 0.5) {
+                return vec3<f32>(0.0);
+            }
+            transmittance *= 2.0;
+        }
+    }
+    return transmittance;
+}
+
+// Transmittance through the fog and any medium boundaries crossed before t_max
+fn medium_transmittance(ray: Ray, t_max: f32, state: ptr<function, u32>) -> vec3<f32> {
+    var transmittance = vec3<f32>(1.0);
+    if (scene.media.count == 0u) {
+        return transmittance;
+    }
+
+    var segment_ray = ray;
+    var remaining = t_max;
+    for (var i = 0u; i < MAX_MEDIUM_BOUNDARIES; i = i + 1u) {
+        let segment = next_medium_segment(segment_ray);
+        transmittance *= ratio_tracking(segment_ray, min(segment.t_max, remaining), segment.medium, state);
+        if (segment.boundary.t < 0.0 || segment.boundary.material.medium < 0 || segment.t_max >= remaining) {
+            break;
+        }
+        remaining -= segment.t_max;
+        segment_ray = spawn_ray(segment.boundary, segment_ray.direction);
+    }
+    return transmittance;
+}
+
 fn sample_sky(direction: vec3<f32>, intensity: f32) -> vec4<f32> {
     let uv = vec2<f32>(atan2(direction.z, direction.x) / (PI * 2.0) + 0.5, -asin(direction.y) / PI + 0.5);
     let color = textureSampleLevel(t_sky, s_sky, uv, 0.0);
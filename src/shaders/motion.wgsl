@@ -0,0 +1,59 @@
+// Instance motion over the shutter, see motion.rs. Shared by the traversal and compute_refit.wgsl.
+
+struct Pose {
+    translation: vec3<f32>,
+    // Quaternion, xyz then w
+    rotation: vec4<f32>,
+    scale: vec3<f32>,
+}
+
+struct InstanceTransform {
+    object_to_world: mat4x4<f32>,
+    world_to_object: mat4x4<f32>,
+}
+
+// Pose of a moving instance at `time` in the shutter, as `Pose::lerp`
+fn instance_pose(instance: Instance, time: f32) -> Pose {
+    let open = instance.motion[1];
+    let close = select(instance.motion[4], -instance.motion[4], dot(open, instance.motion[4]) < 0.0);
+    return Pose(
+        mix(instance.motion[0].xyz, instance.motion[3].xyz, time),
+        normalize(mix(open, close, time)),
+        mix(instance.motion[2].xyz, instance.motion[5].xyz, time)
+    );
+}
+
+fn instance_transform(instance: Instance, time: f32) -> InstanceTransform {
+    if (instance.moving == 0u) {
+        return InstanceTransform(instance.object_to_world, instance.world_to_object);
+    }
+    return pose_transform(instance_pose(instance, time));
+}
+
+// Scale, then rotation, then translation, and the inverse built from the parts
+fn pose_transform(pose: Pose) -> InstanceTransform {
+    let q = pose.rotation;
+    let rotation = mat3x3<f32>(
+        vec3<f32>(1.0 - 2.0 * (q.y * q.y + q.z * q.z), 2.0 * (q.x * q.y + q.w * q.z), 2.0 * (q.x * q.z - q.w * q.y)),
+        vec3<f32>(2.0 * (q.x * q.y - q.w * q.z), 1.0 - 2.0 * (q.x * q.x + q.z * q.z), 2.0 * (q.y * q.z + q.w * q.x)),
+        vec3<f32>(2.0 * (q.x * q.z + q.w * q.y), 2.0 * (q.y * q.z - q.w * q.x), 1.0 - 2.0 * (q.x * q.x + q.y * q.y))
+    );
+    let s = pose.scale;
+    let t = pose.translation;
+    let object_to_world = mat4x4<f32>(
+        vec4<f32>(rotation[0] * s.x, 0.0),
+        vec4<f32>(rotation[1] * s.y, 0.0),
+        vec4<f32>(rotation[2] * s.z, 0.0),
+        vec4<f32>(t, 1.0)
+    );
+    // Rows of the transposed rotation divided by the scale
+    let inverse = transpose(rotation);
+    let unscale = mat3x3<f32>(inverse[0] / s, inverse[1] / s, inverse[2] / s);
+    let world_to_object = mat4x4<f32>(
+        vec4<f32>(unscale[0], 0.0),
+        vec4<f32>(unscale[1], 0.0),
+        vec4<f32>(unscale[2], 0.0),
+        vec4<f32>(-(unscale * t), 1.0)
+    );
+    return InstanceTransform(object_to_world, world_to_object);
+}
@@ -40,6 +40,7 @@ fn vs_main(
 @group(0) @binding(6) var indirect_specular: texture_2d<f32>;
 @group(0) @binding(7) var sss: texture_2d<f32>;
 @group(0) @binding(8) var sky: texture_2d<f32>;
+@group(0) @binding(9) var volume: texture_2d<f32>;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
@@ -51,9 +52,13 @@ fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
     let indirect_specular_color: vec4<f32> = textureSample(indirect_specular, texture_sampler, in.tex_coords);
     let sss_color: vec4<f32> = textureSample(sss, texture_sampler, in.tex_coords);
     let sky_color: vec4<f32> = textureSample(sky, texture_sampler, in.tex_coords);
+    let volume_color: vec4<f32> = textureSample(volume, texture_sampler, in.tex_coords);
 
-    // Composite
-    let composite = ((direct_diffuse_color + indirect_diffuse_color)) + direct_specular_color + indirect_specular_color + sky_color; // + sss_color
+    // Composite, surfaces are seen through the media transmittance stored in the volume alpha.
+    // Without media the volume pass is skipped and its texture left stale.
+    let surface = ((direct_diffuse_color + indirect_diffuse_color)) + direct_specular_color + indirect_specular_color + sky_color; // + sss_color
+    let media = select(vec4<f32>(0.0, 0.0, 0.0, 1.0), volume_color, scene.media.count > 0u);
+    let composite = vec4<f32>(surface.rgb * media.a + media.rgb, surface.a);
     let accumulated_color: vec4<f32> = composite + accumulation_buffer.data[index];
     accumulation_buffer.data[index] = accumulated_color;
 
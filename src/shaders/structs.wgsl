@@ -2,24 +2,60 @@
 const PI: f32 = 3.1415926535897932384626433832795;
 const TWO_PI: f32 = 2.0 * PI;
 const EPSILON: f32 = 1e-5;
+// Farthest distance of an unbounded ray, as ray::T_MAX on the CPU
+const RAY_T_MAX: f32 = 1e30;
+// Triangles use the watertight test, closing the cracks Moller-Trumbore leaves along shared edges
+const WATERTIGHT_TRIANGLES: bool = true;
 
 // Scene object types in place of an enum
 const TRIANGLE_TYPE: u32 = 0u;
 const SPHERE_TYPE: u32 = 1u;
 const PLANE_TYPE: u32 = 2u;
 const QUADLIGHT_TYPE: u32 = 3u;
+const DISK_TYPE: u32 = 4u;
+const SDF_TYPE: u32 = 5u;
+
+// Distance field shapes, see `SdfNode` in sdf_shape.rs for their vertices
+const SDF_SPHERE: u32 = 0u;
+const SDF_BOX: u32 = 1u;
+const SDF_TORUS: u32 = 2u;
+const SDF_CAPSULE: u32 = 3u;
+const SDF_GRID: u32 = 4u;
+
+// Camera projections
+const PROJECTION_PERSPECTIVE: u32 = 0u;
+const PROJECTION_ORTHOGRAPHIC: u32 = 1u;
+const PROJECTION_EQUIRECTANGULAR: u32 = 2u;
+const PROJECTION_CUBEMAP: u32 = 3u;
+const PROJECTION_FISHEYE: u32 = 4u;
 
 // Nulls
-const NULL_MATERIAL = Material(vec4<f32>(0.0, 0.0, 0.0, 0.0), 0.0, 0.0, 0.0, 0.0, 1.5);
-const NULL_HIT = HitRec(-1.0, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 0.0), NULL_MATERIAL, true);
+const NULL_MATERIAL = Material(vec4<f32>(0.0, 0.0, 0.0, 0.0), 0.0, 0.0, 0.0, 0.0, 1.5, -1);
+const NULL_HIT = HitRec(-1.0, vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 0.0), NULL_MATERIAL, true);
 
 // Sizes
-const MAX_BVH_SIZE: u32 = 1024;
+const MAX_BVH_SIZE: u32 = 1024u;
+const MAX_MEDIA: u32 = 8u;
+const MAX_MEDIUM_BOUNDARIES: u32 = 8u;
+const MAX_TRACKING_STEPS: u32 = 256u;
+const MAX_SDF_STEPS: u32 = 256u;
+// Bisection steps once a sphere traced ray has crossed the surface
+const SDF_REFINE_STEPS: u32 = 16u;
+// Shortest sphere tracing step as a fraction of the diagonal of the object bounds
+const SDF_MIN_STEP: f32 = 1e-3;
+// Poses sampled across the shutter for the bounds of moving instances
+const MOTION_BOUND_STEPS: u32 = 8u;
 
 
+// Indices point into the vertex buffer, see `SceneObject` in scene.rs for the per type layout
 struct SceneObject {
-    objectType: u32,
-    index: u32,
+    indices: vec3<u32>,
+    material: u32,
+    object_type: u32,
+};
+
+struct SceneObjectBuffer {
+    data: array<SceneObject>,
 };
 
 struct QuadLight {
@@ -35,15 +71,6 @@ struct QuadLightBuffer {
     data: array<QuadLight>,
 };
 
-struct Triangle {
-    indices: vec3<u32>,
-    material: u32,
-};
-
-struct TriangleBuffer {
-    data: array<Triangle>,
-}
-
 struct MaterialBuffer {
     data: array<Material>,
 }
@@ -51,9 +78,21 @@ struct MaterialBuffer {
 struct CameraUniform {
     origin: vec4<f32>,
     focus: vec4<f32>,
+    // Lens radius, zero for a pinhole
     aperture: f32,
     fovy: f32,
     aspect: f32,
+    // Along the view direction, to the plane in focus
+    focus_distance: f32,
+    // Fewer than three for a round aperture
+    blades: u32,
+    blade_rotation: f32,
+    projection: u32,
+    // Orthographic view height or fisheye field of view
+    projection_scale: f32,
+    // Placement at shutter close, the same as origin and focus for a still camera
+    origin_close: vec4<f32>,
+    focus_close: vec4<f32>,
 };
 
 struct CameraMatrixUniform {
@@ -74,6 +113,7 @@ struct SphereBuffer {
 struct Scene {
     config: RenderConfig,
     camera: CameraUniform,
+    media: Media,
     // sss: SSSData,
 }
 
@@ -89,9 +129,14 @@ struct RenderConfig {
     sky_color: vec4<f32>,
 }
 
+// Hits count only within (t_min, t_max)
 struct Ray {
     origin: vec3<f32>,
-    direction: vec3<f32>
+    t_min: f32,
+    direction: vec3<f32>,
+    t_max: f32,
+    // Within the shutter, 0 at open and 1 at close
+    time: f32,
 }
 
 struct RayBuffer {
@@ -106,12 +151,15 @@ struct Material {
     metallic: f32,
     refract: f32,
     ior: f32,
+    medium: i32,
 }
 
 struct HitRec {
     t: f32,
     p: vec3<f32>,
     normal: vec3<f32>,
+    // Faces the ray like normal, without shading normal interpolation, secondary rays leave along it
+    geometric_normal: vec3<f32>,
     material: Material,
     frontface: bool,
 }
@@ -125,12 +173,27 @@ struct PixelBuffer {
     data: array<vec4<f32>>
 }
 
+struct Vertex {
+    position: vec4<f32>,
+    normal: vec4<f32>,
+}
+
 struct VertexBuffer {
-    data: array<vec4<f32>>
+    data: array<Vertex>
 }
 
-struct NormalBuffer {
-    data: array<vec3<f32>>
+struct Instance {
+    object_to_world: mat4x4<f32>,
+    world_to_object: mat4x4<f32>,
+    blas_root: i32,
+    // Nonzero when the instance moves over the shutter, the poses replace the matrices
+    moving: u32,
+    // Translation, rotation quaternion and scale at shutter open, then at close
+    motion: array<vec4<f32>, 6>,
+}
+
+struct InstanceBuffer {
+    data: array<Instance>
 }
 
 struct AABB {
@@ -138,11 +201,14 @@ struct AABB {
     max: vec4<f32>,
 }
 
+// Leaves hold `count` primitives starting at `first`, interior nodes have a count of 0
+// Threaded binary node, hits move on to left and misses or finished leaves follow miss
 struct BVHNode {
     aabb: AABB,
     left: i32,
-    right: i32,
-    triangle: i32,
+    miss: i32,
+    first: i32,
+    count: i32,
 }
 
 struct BVHBuffer {
@@ -171,3 +237,26 @@ struct SSSData {
     scale: f32,
     anisotropy: f32,
 }
+
+struct Medium {
+    sigma_a: vec4<f32>,
+    sigma_s: vec4<f32>,
+    bounds: AABB,
+    grid_origin: vec4<f32>,
+    grid_scale: vec4<f32>,
+    anisotropy: f32,
+    density: f32,
+    grid: i32,
+}
+
+struct Media {
+    fog: i32,
+    count: u32,
+    media: array<Medium, MAX_MEDIA>,
+}
+
+struct MediumSegment {
+    medium: i32,
+    t_max: f32,
+    boundary: HitRec,
+}
\ No newline at end of file
@@ -1,10 +1,13 @@
 @group(0) @binding(0) var<storage, read> scene: Scene;
 @group(0) @binding(1) var<storage, read> rays: RayBuffer;
-@group(0) @binding(2) var<storage, read> bvh_buffer: BVHBuffer;
+// Binding 2 is the bvh, declared by the binary or wide traversal
 @group(0) @binding(3) var<storage, read> material_buffer: MaterialBuffer;
-@group(0) @binding(4) var<storage, read> triangle_buffer: TriangleBuffer;
+@group(0) @binding(4) var<storage, read> object_buffer: SceneObjectBuffer;
 @group(0) @binding(5) var<storage, read> vertex_buffer: VertexBuffer;
-@group(0) @binding(6) var<storage, read> normal_buffer: NormalBuffer;
+@group(0) @binding(6) var<storage, read> instance_buffer: InstanceBuffer;
 @group(0) @binding(7) var<storage, read> quad_light_buffer: QuadLightBuffer;
 @group(0) @binding(8) var t_sky: texture_2d<f32>;
-@group(0) @binding(9) var s_sky: sampler;
\ No newline at end of file
+@group(0) @binding(9) var s_sky: sampler;
+@group(0) @binding(12) var t_density: texture_3d<f32>;
+@group(0) @binding(13) var s_density: sampler;
+@group(0) @binding(14) var t_sdf: texture_3d<f32>;
\ No newline at end of file
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::process::subdivision::{SCHEME_CATMULL_CLARK, MODE_NONE};
 
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -7,10 +8,98 @@ pub struct Config {
     pub sky_intensity: f32,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+// Fields the page leaves out keep their defaults
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
 pub struct Camera {
     pub aperture: f32,
     pub fov: f32,
+    pub focus_distance: f32,
+    // Lens in millimetres, applied over aperture and fov whenever either changes
+    pub focal_length: f32,
+    pub f_stop: f32,
+    pub blades: u32,
+    pub blade_rotation: f32,
+    // Bumped by the page to focus on `focus_point`, canvas coordinates from the top left in 0..1
+    pub focus_request: u32,
+    pub focus_point: [f32; 2],
+    // One of the PROJECTION_* kinds in camera.rs
+    pub projection: u32,
+    pub ortho_height: f32,
+    pub fisheye_fov: f32,
+    // Seconds the shutter stays open from each frame's time, 0 for no motion blur
+    pub shutter: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            aperture: 0.0,
+            fov: 50.0,
+            focus_distance: 7.2,
+            focal_length: 50.0,
+            f_stop: 8.0,
+            blades: 0,
+            blade_rotation: 0.0,
+            focus_request: 0,
+            focus_point: [0.5, 0.5],
+            projection: 0,
+            ortho_height: 4.0,
+            fisheye_fov: 180.0,
+            shutter: 0.0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Default)]
+#[serde(default)]
+pub struct Timeline {
+    pub playing: bool,
+    // Seconds, the scene jumps here whenever the page moves it
+    pub time: f32,
+}
+
+// Homogeneous fog filling the scene bounds, off while density is 0
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Fog {
+    pub density: f32,
+    pub color: [f32; 3],
+    pub anisotropy: f32,
+}
+
+impl Default for Fog {
+    fn default() -> Self {
+        Self {
+            density: 0.0,
+            color: [0.8, 0.8, 0.8],
+            anisotropy: 0.3,
+        }
+    }
+}
+
+// Refinement of the glb meshes, read once when the scene loads
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Subdivision {
+    // SCHEME_* and MODE_* kinds in subdivision.rs
+    pub scheme: u32,
+    pub mode: u32,
+    // Uniform levels, or the most the adaptive mode refines
+    pub levels: u32,
+    // Adaptive refinement stops once edges project below this
+    pub edge_pixels: f32,
+}
+
+impl Default for Subdivision {
+    fn default() -> Self {
+        Self {
+            scheme: SCHEME_CATMULL_CLARK,
+            mode: MODE_NONE,
+            levels: 2,
+            edge_pixels: 8.0,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
@@ -26,6 +115,12 @@ pub struct StateJS {
     pub config: Config,
     pub camera: Camera,
     pub sss: SSSData,
+    #[serde(default)]
+    pub timeline: Timeline,
+    #[serde(default)]
+    pub fog: Fog,
+    #[serde(default)]
+    pub subdivision: Subdivision,
 }
 
 impl StateJS {
@@ -35,16 +130,16 @@ impl StateJS {
                 size: [1280, 720],
                 sky_intensity: 1.0,
             },
-            camera: Camera {
-                aperture: 0.0,
-                fov: 50.0,
-            },
+            camera: Camera::default(),
             sss: SSSData {
                 scatter_coeff: [2.1, 1.9, 0.2],
                 absorption_coeff: [0.04, 0.07, 0.2],
                 scale: 1.0,
                 anisotropy: 0.5,
             },
+            timeline: Timeline::default(),
+            fog: Fog::default(),
+            subdivision: Subdivision::default(),
         }
     }
 }
\ No newline at end of file
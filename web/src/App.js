@@ -7,7 +7,7 @@ import { ReactComponent as Logo } from "./assets/logo_horizontal.svg";
 import { AppShell, Burger, Group } from '@mantine/core';
 import { useDisclosure } from '@mantine/hooks';
 import { SliderInput } from "./components/SliderInput";
-import { Accordion, ActionIcon, AccordionControlProps, Center } from '@mantine/core';
+import { Accordion, ActionIcon, AccordionControlProps, Button, Center, Select } from '@mantine/core';
 
 
 function App() {
@@ -19,6 +19,17 @@ function App() {
     camera: {
       aperture: 0.0,
       fov: 50.0,
+      focus_distance: 7.2,
+      focal_length: 50.0,
+      f_stop: 8.0,
+      blades: 0,
+      blade_rotation: 0.0,
+      focus_request: 0,
+      focus_point: [0.5, 0.5],
+      projection: 0,
+      ortho_height: 4.0,
+      fisheye_fov: 180.0,
+      shutter: 0.0,
     },
     sss : {
       scatter_coeff: [1.0, 1.0, 1.0],
@@ -26,6 +37,23 @@ function App() {
       scale: 1.0,
       anisotropy: 0.5,
     },
+    timeline: {
+      playing: false,
+      time: 0.0,
+    },
+    fog: {
+      density: 0.0,
+      color: [0.8, 0.8, 0.8],
+      anisotropy: 0.3,
+    },
+    // Read once when the scene loads, scheme 0 Catmull-Clark or 1 Loop, mode 0 off, 1 uniform
+    // or 2 adaptive
+    subdivision: {
+      scheme: 0,
+      mode: 0,
+      levels: 2,
+      edge_pixels: 8.0,
+    },
     focus: true,
   });  
   const [mobileOpened, { toggle: toggleMobile }] = useDisclosure();
@@ -78,7 +106,24 @@ function App() {
   async function loadKrust() {
     try {
       await init();
-      await run(getStateCallback);
+      // Trees prebuilt by `npm run bvh-cache` on start and build, built in wasm when missing or stale
+      const bvhCache = await fetch("/test.glb.kbvh")
+        .then((response) => (response.ok ? response.arrayBuffer() : null))
+        .catch(() => null);
+      // Optional density grid (KGRD or Mitsuba VOL) shaping the fog
+      const densityGrid = await fetch("/fog.vol")
+        .then((response) => (response.ok ? response.arrayBuffer() : null))
+        .catch(() => null);
+      // Optional distance grid written by `krusty bake-sdf`
+      const sdfGrid = await fetch("/sdf.kgrd")
+        .then((response) => (response.ok ? response.arrayBuffer() : null))
+        .catch(() => null);
+      await run(
+        getStateCallback,
+        bvhCache ? new Uint8Array(bvhCache) : undefined,
+        densityGrid ? new Uint8Array(densityGrid) : undefined,
+        sdfGrid ? new Uint8Array(sdfGrid) : undefined,
+      );
       setState({...state, focus: !state.focus});
     } catch (error) {
       console.error("Web assembly initialization error:", error);
@@ -109,6 +154,63 @@ function App() {
     });
   }
 
+  function changeCamera(key, input) {
+    const value = parseFloat(input);
+    if (!Number.isFinite(value)) {
+      return;
+    }
+    setState({
+      ...state,
+      camera: {
+        ...state.camera,
+        [key]: value,
+      },
+      focus: !state.focus,
+    });
+  }
+
+  // Double clicking the canvas focuses on the surface under the cursor
+  function focusAt(event) {
+    const rect = event.currentTarget.getBoundingClientRect();
+    setState({
+      ...state,
+      camera: {
+        ...state.camera,
+        focus_request: state.camera.focus_request + 1,
+        focus_point: [
+          (event.clientX - rect.left) / rect.width,
+          (event.clientY - rect.top) / rect.height,
+        ],
+      },
+    });
+  }
+
+  function changeTimeline(key, value) {
+    setState({
+      ...state,
+      timeline: {
+        ...state.timeline,
+        [key]: value,
+      },
+      focus: !state.focus,
+    });
+  }
+
+  function changeFog(key, input) {
+    const value = parseFloat(input);
+    if (!Number.isFinite(value)) {
+      return;
+    }
+    setState({
+      ...state,
+      fog: {
+        ...state.fog,
+        [key]: value,
+      },
+      focus: !state.focus,
+    });
+  }
+
   function changeSkyIntensity(input) {
     const value = parseFloat(Math.min(1.0, Math.max(input)).toFixed(2));
     setState({
@@ -197,6 +299,85 @@ function App() {
                 step={0.01}
                 min={10}
                 max={180}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Focus Distance</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("focus_distance", val)}
+                defaultValue={7.2}
+                step={0.01}
+                min={0.1}
+                max={50}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Focal Length (mm)</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("focal_length", val)}
+                defaultValue={50.0}
+                step={1}
+                min={10}
+                max={300}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>f-stop</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("f_stop", val)}
+                defaultValue={8.0}
+                step={0.1}
+                min={0.7}
+                max={22}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Aperture Blades</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("blades", Math.round(parseFloat(val)))}
+                defaultValue={0}
+                step={1}
+                min={0}
+                max={12}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Blade Rotation</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("blade_rotation", val)}
+                defaultValue={0.0}
+                step={1}
+                min={0}
+                max={360}
+                style={{marginBottom: 24}}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Projection</Text>
+              <Select
+                onChange={(val)=>changeCamera("projection", val)}
+                defaultValue="0"
+                allowDeselect={false}
+                data={[
+                  { value: "0", label: "Perspective" },
+                  { value: "1", label: "Orthographic" },
+                  { value: "2", label: "Equirectangular 360" },
+                  { value: "3", label: "Cubemap" },
+                  { value: "4", label: "Fisheye" },
+                ]}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Orthographic Height</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("ortho_height", val)}
+                defaultValue={4.0}
+                step={0.1}
+                min={0.1}
+                max={50}
+                style={{marginBottom: 24}}
+              />
+
+              <Text size="sm" mb="sm" mt={42} fw={400}>Fisheye FOV</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("fisheye_fov", val)}
+                defaultValue={180.0}
+                step={1}
+                min={10}
+                max={360}
                 style={{marginBottom: 24}}
               />
 
@@ -215,6 +396,59 @@ function App() {
                 max={1}
                 style={{marginBottom: 24}}
               />
+
+              <Text size="sm" mb="sm" mt={12} fw={400}>Fog Density</Text>
+              <SliderInput
+                onChange={(val)=>changeFog("density", val)}
+                defaultValue={0.0}
+                step={0.001}
+                min={0}
+                max={0.2}
+                style={{marginBottom: 24}}
+              />
+
+              <Text size="sm" mb="sm" mt={12} fw={400}>Fog Anisotropy</Text>
+              <SliderInput
+                onChange={(val)=>changeFog("anisotropy", val)}
+                defaultValue={0.3}
+                step={0.01}
+                min={-0.9}
+                max={0.9}
+                style={{marginBottom: 24}}
+              />
+            </Accordion.Panel>
+          </Accordion.Item>
+
+          <Accordion.Item value="item-3">
+            <AccordionControl>Animation</AccordionControl>
+            <Accordion.Panel>
+              <Button
+                mt={12}
+                variant="default"
+                onClick={()=>changeTimeline("playing", !state.timeline.playing)}
+              >
+                {state.timeline.playing ? "Pause" : "Play"}
+              </Button>
+
+              <Text size="sm" mb="sm" mt={24} fw={400}>Time (s)</Text>
+              <SliderInput
+                onChange={(val)=>changeTimeline("time", parseFloat(val))}
+                defaultValue={0.0}
+                step={0.01}
+                min={0}
+                max={10}
+                style={{marginBottom: 24}}
+              />
+
+              <Text size="sm" mb="sm" mt={12} fw={400}>Shutter (s)</Text>
+              <SliderInput
+                onChange={(val)=>changeCamera("shutter", val)}
+                defaultValue={0.0}
+                step={0.001}
+                min={0}
+                max={0.1}
+                style={{marginBottom: 24}}
+              />
             </Accordion.Panel>
           </Accordion.Item>
 
@@ -232,6 +466,7 @@ function App() {
             }}
             ref={krustRef}
             tabIndex={0}
+            onDoubleClick={focusAt}
           />
         </AppShell.Main>
       </AppShell>